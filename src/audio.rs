@@ -0,0 +1,975 @@
+//! Audio output through CPAL.
+
+use crate::controls::Command;
+use crate::output::{self, OutputArgs};
+use crate::limiter::{Limiter, LimiterArgs};
+use crate::mirror::{self, Mirror, Tap};
+use crate::priority::{self, Raised, Role};
+use crate::queue::{self, Consumer, Handoff, Producer};
+use crate::record::Capture;
+use crate::synth::Synth;
+use crate::timing::{Seen, Stats, Timer};
+use anyhow::{Context, Result};
+use clap::Args;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// How often a lost output device is looked for again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames to make room for in the callback's buffer when the backend picks the buffer size:
+/// the most `--buffer-size` allows. A bigger buffer is rendered a piece this long at a time.
+pub const MAX_BUFFER_FRAMES: usize = 16_384;
+
+/// How often the output thread looks for late callbacks and failed buffers to warn about.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Frames per block of a paced output, small so its events go out close to when they are due.
+const PACED_FRAMES: usize = 64;
+
+/// How far behind a paced output may fall and still catch up.
+const PACED_CATCH_UP: Duration = Duration::from_millis(100);
+
+/// Where audio goes, shared by the commands that open a stream:
+/// - host: the CPAL host (audio backend) to use instead of the platform default
+/// - device: the output device to use instead of the host's default
+/// - sample_rate: rate to ask the device for instead of its default
+/// - buffer_size: frames per audio callback instead of the platform default
+/// - channel_map: which device channels the synth's left and right go to
+#[derive(Args, Debug, Clone)]
+pub struct AudioArgs {
+    /// Audio backend, e.g. alsa or jack on Linux, wasapi or asio on Windows (see `midi-play devices`)
+    #[arg(long, value_name = "NAME")]
+    pub host: Option<String>,
+    /// Output device, by number from `midi-play devices` or (part of) its name
+    #[arg(long, value_name = "NAME|INDEX")]
+    pub device: Option<String>,
+    /// Sample rate to run the device at, in Hz, if it supports it; the default rate otherwise
+    #[arg(long, value_name = "HZ", value_parser = clap::value_parser!(u32).range(8_000..=192_000))]
+    pub sample_rate: Option<u32>,
+    /// Frames per audio buffer: smaller means less latency, larger fewer dropouts
+    #[arg(long, value_name = "FRAMES", value_parser = clap::value_parser!(u32).range(16..=16_384))]
+    pub buffer_size: Option<u32>,
+    /// Device channels (from 1) for the synth's left and right, e.g. 3,4 on an 8-channel
+    /// interface, or one channel to play in mono
+    #[arg(long, value_name = "L,R", value_parser = parse_channel_map)]
+    pub channel_map: Option<ChannelMap>,
+}
+
+/// How `play` drives its output, beyond where it goes:
+/// - follow_default: move to the new device when the system default output changes
+/// - xrun_warnings: warn about each late audio callback and underrun as it happens
+/// - device_gain: level on the main device
+/// - also_device: a second device that plays the same audio
+/// - also_gain: level on the second device
+/// - limiter: the master limiter
+#[derive(Args, Debug, Clone)]
+pub struct PlaybackArgs {
+    /// Move playback to the new device whenever the system default output changes
+    #[arg(long, conflicts_with = "device")]
+    pub follow_default: bool,
+    /// Warn about every late audio callback and underrun as it happens, not just in the
+    /// summary at the end
+    #[arg(long)]
+    pub xrun_warnings: bool,
+    /// Level on the output device, in dB
+    #[arg(long, value_name = "DB", default_value_t = 0.0, allow_negative_numbers = true)]
+    pub device_gain: f32,
+    /// Play the same audio on a second device as well, e.g. the PA while the performer
+    /// listens on headphones (number or name, as for --device)
+    #[arg(long, value_name = "NAME|INDEX")]
+    pub also_device: Option<String>,
+    /// Level on the --also-device device, in dB
+    #[arg(long, value_name = "DB", default_value_t = 0.0, allow_negative_numbers = true, requires = "also_device")]
+    pub also_gain: f32,
+    #[command(flatten)]
+    pub limiter: LimiterArgs,
+}
+
+/// A level in dB as a factor.
+fn gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// The CPAL host called `wanted` (ignoring case), or the platform default.
+pub fn host(wanted: Option<&str>) -> Result<cpal::Host> {
+    let Some(wanted) = wanted else {
+        return Ok(cpal::default_host());
+    };
+    let hosts = cpal::available_hosts();
+    match hosts.iter().find(|id| id.name().eq_ignore_ascii_case(wanted)) {
+        Some(&id) => {
+            let host = cpal::host_from_id(id).with_context(|| format!("opening the {} host", id.name()))?;
+            info!("Audio host: {}", id.name());
+            Ok(host)
+        }
+        None => {
+            let names: Vec<&str> = hosts.iter().map(|id| id.name()).collect();
+            // Backends behind a cargo feature are missing from builds without it.
+            let feature = match wanted.to_lowercase().as_str() {
+                "asio" if !cfg!(feature = "asio") => " (ASIO needs a build with `--features asio`)",
+                "jack" if !cfg!(feature = "jack") => " (JACK needs a build with `--features jack`)",
+                _ => "",
+            };
+            anyhow::bail!("no audio host called `{wanted}`{feature}. Available: {}", names.join(", "))
+        }
+    }
+}
+
+/// Describe the stream to PipeWire, so desktop volume mixers and patchbays such as qpwgraph
+/// show a `midi-play` node playing the MIDI file as music. If the user has set
+/// `PIPEWIRE_PROPS` already, it is left alone.
+///
+/// PipeWire reads `PIPEWIRE_PROPS` when a stream is created in this process, which only
+/// happens when CPAL's ALSA output goes through PipeWire's ALSA plugin (`pipewire-alsa`, the
+/// default device on most PipeWire desktops). Through the PulseAudio plugin, a `hw:` device
+/// or JACK it has no effect, and the stream keeps the name the server gives it.
+///
+/// Must be called before other threads start, as it changes the environment.
+#[cfg(target_os = "linux")]
+pub fn describe_stream(midi: &str) {
+    if std::env::var_os("PIPEWIRE_PROPS").is_some() {
+        return;
+    }
+    let title = std::path::Path::new(midi).file_name().map_or(midi.into(), |n| n.to_string_lossy());
+    let title = format!("\"{}\"", title.replace('\\', "\\\\").replace('"', "\\\""));
+    let props = format!(
+        "{{ media.role = Music media.category = Playback node.name = midi-play node.description = midi-play \
+         application.name = midi-play media.name = {title} media.title = {title} }}"
+    );
+    debug!("PipeWire stream properties: {props}");
+    // SAFETY: no other thread exists yet to read the environment at the same time.
+    unsafe { std::env::set_var("PIPEWIRE_PROPS", props) };
+}
+
+/// The output device picked by `--host` and `--device`, or the default one of the default
+/// host, with the stream config to open it with.
+pub fn output_device(args: &AudioArgs) -> Result<(cpal::Device, cpal::SupportedStreamConfig)> {
+    let host = host(args.host.as_deref())?;
+    let dev = match args.device.as_deref() {
+        None => host.default_output_device().context("no default output device")?,
+        Some(wanted) => find_device(&host, wanted)?,
+    };
+    let needed = args.channel_map.map_or(1, |m| m.left.max(m.right) + 1);
+    let cfg = negotiate(&dev, args.sample_rate, needed)?;
+    Ok((dev, cfg))
+}
+
+/// The device's default config, or the supported config closest to the default that runs
+/// at `rate` and has at least `channels` channels, when the default does not. Falls back to
+/// the default with a warning if no config does.
+fn negotiate(dev: &cpal::Device, rate: Option<u32>, channels: usize) -> Result<cpal::SupportedStreamConfig> {
+    let default = dev.default_output_config().context("default_output_config")?;
+    let rate = rate.unwrap_or(default.sample_rate().0);
+    if rate == default.sample_rate().0 && usize::from(default.channels()) >= channels {
+        return Ok(default);
+    }
+    let configs: Vec<_> = dev.supported_output_configs().context("listing supported configs")?.collect();
+    // Keep the default's channel count and sample format where possible, and otherwise take
+    // as few channels as will do.
+    let best = configs
+        .into_iter()
+        .filter(|c| (c.min_sample_rate().0..=c.max_sample_rate().0).contains(&rate))
+        .filter(|c| usize::from(c.channels()) >= channels)
+        .filter(|c| is_supported(c.sample_format()))
+        .min_by_key(|c| {
+            (c.channels() != default.channels(), c.channels(), c.sample_format() != default.sample_format())
+        });
+    match best {
+        Some(c) => {
+            let cfg = c.with_sample_rate(cpal::SampleRate(rate));
+            info!("Output: {} Hz, {} ch, {}", rate, cfg.channels(), cfg.sample_format());
+            Ok(cfg)
+        }
+        None if rate != default.sample_rate().0 => {
+            warn!("The device does not support {rate} Hz, using {} Hz", default.sample_rate().0);
+            Ok(default)
+        }
+        None => Ok(default),
+    }
+}
+
+/// Find an output device by its index in the `devices` list, its exact name, or a part of
+/// its name; names are compared ignoring case. Fails with the list of devices if nothing,
+/// or more than one device, matches.
+fn find_device(host: &cpal::Host, wanted: &str) -> Result<cpal::Device> {
+    let devices: Vec<(String, cpal::Device)> = host
+        .output_devices()
+        .context("listing output devices")?
+        .map(|d| (d.name().unwrap_or_else(|_| "(unknown)".to_string()), d))
+        .collect();
+    let list = || {
+        let names: Vec<String> = devices.iter().enumerate().map(|(n, (name, _))| format!("  {n}: {name}")).collect();
+        if names.is_empty() { "  (none)".to_string() } else { names.join("\n") }
+    };
+
+    let wanted_lc = wanted.to_lowercase();
+    let matches: Vec<usize> = match wanted.parse::<usize>() {
+        Ok(n) if n < devices.len() => vec![n],
+        _ => match devices.iter().position(|(name, _)| name.to_lowercase() == wanted_lc) {
+            Some(exact) => vec![exact],
+            None => (0..devices.len()).filter(|&n| devices[n].0.to_lowercase().contains(&wanted_lc)).collect(),
+        },
+    };
+    match matches[..] {
+        [n] => {
+            let (name, dev) = devices.into_iter().nth(n).expect("index from the list");
+            info!("Output device: {name}");
+            Ok(dev)
+        }
+        [] => anyhow::bail!("no output device matches `{wanted}`. Devices:\n{}", list()),
+        _ => {
+            let names: Vec<&str> = matches.iter().map(|&n| devices[n].0.as_str()).collect();
+            anyhow::bail!("`{wanted}` matches several devices ({}). Devices:\n{}", names.join(", "), list())
+        }
+    }
+}
+
+/// The config to open the stream with: `cfg` with a fixed buffer of `frames` if asked for,
+/// kept within what the device supports.
+pub fn stream_config(cfg: &cpal::SupportedStreamConfig, frames: Option<u32>) -> cpal::StreamConfig {
+    let mut stream_cfg = cfg.config();
+    if let Some(frames) = frames {
+        let frames = match *cfg.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } if !(min..=max).contains(&frames) => {
+                let clamped = frames.clamp(min, max);
+                warn!("Buffer size {frames} is outside what the device supports ({min}–{max}), using {clamped}");
+                clamped
+            }
+            _ => frames,
+        };
+        stream_cfg.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    stream_cfg
+}
+
+/// What a stream's first callback saw: the buffer size and latency the stream actually got,
+/// and the priority its thread was raised to.
+#[derive(Clone, Copy)]
+struct FirstBuffer {
+    frames: usize,
+    /// When the backend knows when the audio reaches the speakers.
+    latency: Option<Duration>,
+    raised: Raised,
+}
+
+/// The callback's side: it raises its thread's priority and passes on what it saw on its
+/// first call, through a queue rather than logging, which allocates and takes a lock.
+struct FirstCallback {
+    done: bool,
+    channels: usize,
+    seen: Producer<FirstBuffer>,
+}
+
+impl FirstCallback {
+    fn new(channels: usize, sample_rate: u32) -> (Self, FirstReport) {
+        let (seen, report) = queue::channel(1);
+        (Self { done: false, channels, seen }, FirstReport { report, sample_rate })
+    }
+
+    fn see(&mut self, samples: usize, info: &cpal::OutputCallbackInfo) {
+        if std::mem::replace(&mut self.done, true) {
+            return;
+        }
+        // CPAL leaves the thread at the priority it was made with.
+        let raised = priority::raise_quietly(Role::Audio);
+        let ts = info.timestamp();
+        let latency = ts.playback.duration_since(&ts.callback).filter(|l| !l.is_zero());
+        let _ = self.seen.push(FirstBuffer { frames: samples / self.channels, latency, raised });
+    }
+}
+
+/// The output thread's side, which logs what the first callback saw once it has come.
+struct FirstReport {
+    report: Consumer<FirstBuffer>,
+    sample_rate: u32,
+}
+
+impl FirstReport {
+    /// Log the first buffer if it has been played. True once it has been logged.
+    fn log(&mut self) -> bool {
+        let Some(FirstBuffer { frames, latency, raised }) = self.report.pop() else {
+            return false;
+        };
+        raised.log(Role::Audio);
+        let buffer_ms = frames as f64 * 1000.0 / f64::from(self.sample_rate);
+        match latency {
+            Some(latency) => info!(
+                "Audio buffer: {frames} frames ({buffer_ms:.1} ms), output latency {:.1} ms",
+                latency.as_secs_f64() * 1000.0
+            ),
+            None => info!("Audio buffer: {frames} frames ({buffer_ms:.1} ms)"),
+        }
+        true
+    }
+}
+
+/// Whether the stream can be opened in `format`: every integer and float format CPAL has.
+pub fn is_supported(format: cpal::SampleFormat) -> bool {
+    use cpal::SampleFormat::*;
+    matches!(format, I8 | I16 | I32 | I64 | U8 | U16 | U32 | U64 | F32 | F64)
+}
+
+/// Which device channels (0-based) the synth's left and right go to. Both on the same
+/// channel means the two are mixed down onto it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelMap {
+    pub left: usize,
+    pub right: usize,
+}
+
+impl ChannelMap {
+    /// Mono devices get left and right mixed down, others left and right on the first pair
+    /// (front left and right in the usual layouts).
+    fn default_for(channels: usize) -> Self {
+        if channels == 1 { Self { left: 0, right: 0 } } else { Self { left: 0, right: 1 } }
+    }
+}
+
+/// Parse `--channel-map`: `L,R` device channels counted from 1, or one channel for both.
+pub fn parse_channel_map(s: &str) -> Result<ChannelMap, String> {
+    let channel = |part: &str| match part.trim().parse::<usize>() {
+        Ok(n @ 1..=64) => Ok(n - 1),
+        _ => Err(format!("`{part}` is not a channel number from 1 to 64")),
+    };
+    match s.split_once(',') {
+        Some((l, r)) => Ok(ChannelMap { left: channel(l)?, right: channel(r)? }),
+        None => {
+            let both = channel(s)?;
+            Ok(ChannelMap { left: both, right: both })
+        }
+    }
+}
+
+/// Fill a device buffer of `channels` interleaved channels from `stereo`, the interleaved
+/// stereo f32 the synth renders, scaled by `gain` and converted to the device's format. Left
+/// and right go where `map` says, averaged if that is the same channel, and every other
+/// channel is silent.
+fn route<T>(out: &mut [T], channels: usize, map: ChannelMap, gain: f32, stereo: &[f32])
+where
+    T: cpal::Sample + cpal::FromSample<f32>,
+{
+    for (frame, lr) in out.chunks_exact_mut(channels).zip(stereo.chunks_exact(2)) {
+        frame.fill(T::EQUILIBRIUM);
+        if map.left == map.right {
+            frame[map.left] = T::from_sample((lr[0] + lr[1]) * 0.5 * gain);
+        } else {
+            frame[map.left] = T::from_sample(lr[0] * gain);
+            frame[map.right] = T::from_sample(lr[1] * gain);
+        }
+    }
+}
+
+/// Something a callback owns while its stream runs (the synth, the ends of the queues the
+/// audio goes out through): taken on the first callback, and put back for the next stream
+/// when this one goes. Only the callback touches it in between, so it needs no lock.
+struct Held<T> {
+    home: Arc<Handoff<T>>,
+    value: Option<Box<T>>,
+}
+
+impl<T> Held<T> {
+    /// A home holding `value` for the first stream to take.
+    fn home(value: T) -> Arc<Handoff<T>> {
+        Arc::new(Handoff::new(Some(Box::new(value))))
+    }
+
+    fn new(home: Arc<Handoff<T>>) -> Self {
+        Self { home, value: None }
+    }
+
+    fn get(&mut self) -> Option<&mut T> {
+        if self.value.is_none() {
+            self.value = self.home.take();
+        }
+        self.value.as_deref_mut()
+    }
+}
+
+impl<T> Drop for Held<T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            let _ = self.home.put(value);
+        }
+    }
+}
+
+/// What the main stream's callbacks share with the rest of the player.
+#[derive(Clone)]
+struct Feed {
+    /// Where the synth waits between streams.
+    synth: Arc<Handoff<Synth>>,
+    /// Told when the device goes away.
+    lost: mpsc::Sender<OutputEvent>,
+    stats: Arc<Stats>,
+    /// Gets a copy of everything played, for `--also-device`.
+    mirror: Option<Arc<Handoff<Tap>>>,
+    /// And another for `--record`.
+    record: Option<Arc<Handoff<Capture>>>,
+    /// Limits what the synth renders, before it is copied or played.
+    limiter: Option<LimiterArgs>,
+}
+
+/// Where a stream's audio comes from.
+enum Source {
+    /// The synth, for the main device.
+    Synth(Feed),
+    /// What the main device played, for `--also-device`.
+    Mirror(Mirror),
+}
+
+/// Build the output stream with the buffer size and channel map `args` ask for, and start
+/// it. The callback takes its audio from `source`, routes it to the device's channels at
+/// `gain` and converts it to the device's sample format.
+fn start_stream(
+    dev: &cpal::Device,
+    cfg: &cpal::SupportedStreamConfig,
+    args: &AudioArgs,
+    gain: f32,
+    source: Source,
+) -> Result<(cpal::Stream, FirstReport)> {
+    let stream_cfg = stream_config(cfg, args.buffer_size);
+    let channels = stream_cfg.channels as usize;
+    let map = args.channel_map.unwrap_or(ChannelMap::default_for(channels));
+    if map.left.max(map.right) >= channels {
+        anyhow::bail!(
+            "the channel map needs {} output channels, the device has {channels}",
+            map.left.max(map.right) + 1
+        );
+    }
+    match (channels, args.channel_map) {
+        (1, None) => info!("Mono output: mixing the synth's stereo down"),
+        (2, None) => {}
+        (n, None) => info!("{n}-channel output: playing on channels 1 and 2"),
+        (n, Some(m)) if m.left == m.right => info!("Playing in mono on channel {} of {n}", m.left + 1),
+        (n, Some(m)) => info!("Playing left on channel {} and right on {} of {n}", m.left + 1, m.right + 1),
+    }
+    use cpal::SampleFormat::*;
+    let (stream, first) = match cfg.sample_format() {
+        I8 => build_stream::<i8>(dev, &stream_cfg, map, gain, source),
+        I16 => build_stream::<i16>(dev, &stream_cfg, map, gain, source),
+        I32 => build_stream::<i32>(dev, &stream_cfg, map, gain, source),
+        I64 => build_stream::<i64>(dev, &stream_cfg, map, gain, source),
+        U8 => build_stream::<u8>(dev, &stream_cfg, map, gain, source),
+        U16 => build_stream::<u16>(dev, &stream_cfg, map, gain, source),
+        U32 => build_stream::<u32>(dev, &stream_cfg, map, gain, source),
+        U64 => build_stream::<u64>(dev, &stream_cfg, map, gain, source),
+        F32 => build_stream::<f32>(dev, &stream_cfg, map, gain, source),
+        F64 => build_stream::<f64>(dev, &stream_cfg, map, gain, source),
+        other => anyhow::bail!("the device wants {other} samples, which cannot be played"),
+    }?;
+
+    // Start audio
+    stream.play()?;
+    Ok((stream, first))
+}
+
+/// The output stream for devices taking samples of type `T`, and what will report its first
+/// buffer. Callbacks of the main stream are timed into the stats. Neither kind takes a lock
+/// or allocates: the audio is rendered into a buffer sized up front, a piece at a time if
+/// the device asks for more.
+fn build_stream<T>(
+    dev: &cpal::Device,
+    stream_cfg: &cpal::StreamConfig,
+    map: ChannelMap,
+    gain: f32,
+    source: Source,
+) -> Result<(cpal::Stream, FirstReport)>
+where
+    T: cpal::SizedSample + cpal::FromSample<f32>,
+{
+    let channels = stream_cfg.channels as usize;
+    let sample_rate = stream_cfg.sample_rate.0;
+    let (mut first, report) = FirstCallback::new(channels, sample_rate);
+    let frames = match stream_cfg.buffer_size {
+        cpal::BufferSize::Fixed(frames) => frames as usize,
+        cpal::BufferSize::Default => MAX_BUFFER_FRAMES,
+    };
+    let mut stereo = vec![0.0; frames * 2];
+    let stream = match source {
+        Source::Synth(Feed { synth, lost, stats, mirror, record, limiter }) => {
+            let mut synth = Held::new(synth);
+            let mut mirror = mirror.map(Held::new);
+            let mut record = record.map(Held::new);
+            let mut limiter = limiter.map(|args| Limiter::new(&args, sample_rate));
+            let mut timer = Timer::new();
+            let xruns = Arc::clone(&stats);
+            let err_fn = move |e| match e {
+                cpal::StreamError::DeviceNotAvailable => {
+                    let _ = lost.send(OutputEvent::Lost);
+                }
+                // JACK reports its xruns as errors; CPAL's other hosts recover from them silently.
+                cpal::StreamError::BackendSpecific { err } if err.description.contains("xrun") => {
+                    xruns.xrun();
+                }
+                e => {
+                    error!("stream error: {e}");
+                    output::progress("error", [("message", format!("stream error: {e}").into())]);
+                }
+            };
+            dev.build_output_stream(
+                stream_cfg,
+                move |out: &mut [T], info| {
+                    let entered = Instant::now();
+                    first.see(out.len(), info);
+                    let Some(synth) = synth.get() else {
+                        out.fill(T::EQUILIBRIUM);
+                        return;
+                    };
+                    // For the song clock, which shows where the song is heard.
+                    let ts = info.timestamp();
+                    if let Some(latency) = ts.playback.duration_since(&ts.callback) {
+                        synth.set_output_latency(latency);
+                    }
+                    for out in out.chunks_mut(frames * channels) {
+                        let stereo = &mut stereo[..out.len() / channels * 2];
+                        // The error is left for the output thread to report: logging allocates.
+                        if synth.render(stereo).is_err() {
+                            stats.failed();
+                        }
+                        if let Some(limiter) = &mut limiter {
+                            limiter.process(stereo);
+                        }
+                        if let Some(mirror) = mirror.as_mut().and_then(Held::get) {
+                            mirror.push(stereo);
+                        }
+                        if let Some(record) = record.as_mut().and_then(Held::get) {
+                            record.push(stereo, sample_rate);
+                        }
+                        route(out, channels, map, gain, stereo);
+                    }
+                    timer.callback(&stats, out.len() / channels, sample_rate, entered.elapsed(), info);
+                },
+                err_fn,
+                None,
+            )?
+        }
+        Source::Mirror(mut ring) => {
+            let mut gone = false;
+            let err_fn = move |e| match e {
+                // Playback carries on on the main device.
+                cpal::StreamError::DeviceNotAvailable if !std::mem::replace(&mut gone, true) => {
+                    warn!("The second output device went away");
+                }
+                cpal::StreamError::DeviceNotAvailable => {}
+                e => error!("stream error on the second device: {e}"),
+            };
+            dev.build_output_stream(
+                stream_cfg,
+                move |out: &mut [T], info| {
+                    first.see(out.len(), info);
+                    for out in out.chunks_mut(frames * channels) {
+                        let stereo = &mut stereo[..out.len() / channels * 2];
+                        ring.pull(stereo);
+                        route(out, channels, map, gain, stereo);
+                    }
+                },
+                err_fn,
+                None,
+            )?
+        }
+    };
+    Ok((stream, report))
+}
+
+/// What the output thread is told: by the stream when its device goes away, or by `Output`
+/// when playback is over.
+enum OutputEvent {
+    Lost,
+    Stop,
+}
+
+/// An output opened for playback, not started yet.
+pub enum Sink {
+    /// A CPAL device, with the config to open its stream with.
+    Device(cpal::Device, cpal::SupportedStreamConfig),
+    /// A JACK client of the player's own (`play --jack`).
+    #[cfg(feature = "jack")]
+    Jack(crate::jack::Client),
+    /// No device: a synth that plays elsewhere (`play --midi-out`), rendered at this rate in
+    /// step with the wall clock so the song moves on, and its silence dropped.
+    Paced(u32),
+}
+
+impl Sink {
+    /// The rate the synth has to render at.
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            Sink::Device(_, cfg) => cfg.sample_rate().0,
+            #[cfg(feature = "jack")]
+            Sink::Jack(client) => client.sample_rate(),
+            Sink::Paced(rate) => *rate,
+        }
+    }
+}
+
+/// The running audio output. A CPAL stream lives on a thread of its own (CPAL streams
+/// cannot move between threads), which rebuilds it when the device disappears or, when
+/// following the default, the system default output changes. Dropping this stops the
+/// output.
+pub struct Output(Running);
+
+enum Running {
+    Stream {
+        events: mpsc::Sender<OutputEvent>,
+        thread: Option<JoinHandle<()>>,
+        stats: Arc<Stats>,
+    },
+    // Held for its `Drop`, which closes the client.
+    #[cfg(feature = "jack")]
+    Jack { _client: crate::jack::Active },
+    Paced { stop: Arc<AtomicBool>, thread: Option<JoinHandle<()>> },
+}
+
+impl Output {
+    /// Start playing `synth` on `sink`, which was opened for `args`. While a stream is
+    /// rebuilt the conductor is sent `Command::AudioDown`, and `Command::AudioUp` once it
+    /// runs again. A device that goes away (unplugged, Bluetooth dropout) is replaced by the
+    /// same device when it comes back, or by the host's default. With `follow_default`
+    /// playback moves to whichever device becomes the system default. With `also_device`
+    /// the same audio plays on a second device too (see `mirror`), and with `record` what
+    /// the synth plays is captured for a file (see `record`). A JACK client sends the
+    /// commands that follow the JACK transport instead. Callbacks are timed, and with
+    /// `xrun_warnings` late ones and underruns are warned about as they happen.
+    pub fn start(
+        sink: Sink,
+        args: &AudioArgs,
+        playback: &PlaybackArgs,
+        synth: Synth,
+        commands: mpsc::Sender<Command>,
+        record: Option<Capture>,
+    ) -> Result<Self> {
+        let (dev, cfg) = match sink {
+            Sink::Device(dev, cfg) => (dev, cfg),
+            #[cfg(feature = "jack")]
+            Sink::Jack(client) => {
+                let client = client.activate(synth, playback.limiter.enabled(), commands)?;
+                return Ok(Self(Running::Jack { _client: client }));
+            }
+            Sink::Paced(rate) => {
+                let stop = Arc::new(AtomicBool::new(false));
+                let stopped = Arc::clone(&stop);
+                let thread = thread::spawn(move || pace(synth, rate, &stopped));
+                return Ok(Self(Running::Paced { stop, thread: Some(thread) }));
+            }
+        };
+        let (events, rx) = mpsc::channel();
+        let (started_tx, started) = mpsc::channel();
+        let stats = Arc::new(Stats::default());
+        let (tap, mirror) = playback.also_device.as_ref().map(|_| mirror::channel()).unzip();
+        let limiter = playback.limiter.enabled();
+        let feed = Feed {
+            synth: Held::home(synth),
+            lost: events.clone(),
+            stats: Arc::clone(&stats),
+            mirror: tap.map(Held::home),
+            record: record.map(Held::home),
+            limiter,
+        };
+        let mut out = OutputThread {
+            args: args.clone(),
+            gain: gain(playback.device_gain),
+            feed,
+            events: rx,
+            commands,
+            xrun_warnings: playback.xrun_warnings,
+        };
+        // A new device should run at the rate the synth renders at.
+        out.args.sample_rate = Some(cfg.sample_rate().0);
+        let also = playback.also_device.clone().zip(mirror).map(|(device, mirror)| {
+            let args = AudioArgs { device: Some(device), channel_map: None, ..out.args.clone() };
+            (args, gain(playback.also_gain), mirror)
+        });
+        let follow_default = playback.follow_default;
+        let thread = thread::spawn(move || {
+            let name = device_name(&dev);
+            let started = start_stream(&dev, &cfg, &out.args, out.gain, Source::Synth(out.feed.clone()))
+                .and_then(|(stream, first)| {
+                    let mirror = also.map(|(args, gain, mirror)| out.open_mirror(&args, gain, mirror)).transpose()?;
+                    Ok((stream, first, mirror))
+                });
+            match started {
+                Ok((stream, first, mirror)) => {
+                    let _ = started_tx.send(Ok(()));
+                    let (mirror, mirror_first) = mirror.unzip();
+                    let firsts = std::iter::once(first).chain(mirror_first).collect();
+                    out.run(stream, firsts, name, follow_default);
+                    drop(mirror);
+                }
+                Err(e) => {
+                    let _ = started_tx.send(Err(e));
+                }
+            }
+        });
+        match started.recv() {
+            Ok(Ok(())) => Ok(Self(Running::Stream { events, thread: Some(thread), stats })),
+            Ok(Err(e)) => Err(e),
+            Err(_) => anyhow::bail!("the audio thread stopped unexpectedly"),
+        }
+    }
+}
+
+impl Output {
+    /// Print how the audio callbacks kept up (see `timing`).
+    pub fn report_stats(&self) {
+        match &self.0 {
+            Running::Stream { stats, .. } => stats.report(),
+            #[cfg(feature = "jack")]
+            Running::Jack { .. } => {}
+            Running::Paced { .. } => {}
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        match &mut self.0 {
+            Running::Stream { events, thread, .. } => {
+                let _ = events.send(OutputEvent::Stop);
+                if let Some(thread) = thread.take() {
+                    let _ = thread.join();
+                }
+            }
+            #[cfg(feature = "jack")]
+            Running::Jack { .. } => {}
+            Running::Paced { stop, thread } => {
+                stop.store(true, Ordering::Relaxed);
+                if let Some(thread) = thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+    }
+}
+
+/// Render `synth` at `rate` in blocks of `PACED_FRAMES`, each when the wall clock reaches
+/// it, until told to stop.
+fn pace(mut synth: Synth, rate: u32, stop: &AtomicBool) {
+    priority::raise(Role::Audio);
+    let mut block = vec![0.0; PACED_FRAMES * 2];
+    let period = Duration::from_secs_f64(PACED_FRAMES as f64 / f64::from(rate));
+    let mut due = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        if let Err(e) = synth.render(&mut block) {
+            error!("{e}");
+        }
+        due += period;
+        let now = Instant::now();
+        match due.checked_duration_since(now) {
+            Some(wait) => thread::sleep(wait),
+            // Held up for long (a suspended machine, say): carry on from now rather than
+            // rush through what was missed, as a device would.
+            None if now - due > PACED_CATCH_UP => due = now,
+            None => {}
+        }
+    }
+}
+
+fn device_name(dev: &cpal::Device) -> String {
+    dev.name().unwrap_or_else(|_| "(unknown)".to_string())
+}
+
+/// What the output thread needs to open streams.
+struct OutputThread {
+    args: AudioArgs,
+    /// The main device's level.
+    gain: f32,
+    feed: Feed,
+    events: mpsc::Receiver<OutputEvent>,
+    commands: mpsc::Sender<Command>,
+    /// Warn about late callbacks and underruns as they happen.
+    xrun_warnings: bool,
+}
+
+impl OutputThread {
+    /// Keep `stream`, playing on the device called `name`, running until told to stop,
+    /// replacing it whenever its device goes away or, with `follow_default`, stops being the
+    /// default. Meanwhile log the first buffer of each stream, `firsts` to begin with, and
+    /// warn about what went wrong in the callbacks.
+    fn run(&self, mut stream: cpal::Stream, mut firsts: Vec<FirstReport>, mut name: String, follow_default: bool) {
+        let default = AudioArgs { device: None, ..self.args.clone() };
+        let mut seen = Seen::default();
+        let mut checked = Instant::now();
+        loop {
+            firsts.retain_mut(|first| !first.log());
+            self.feed.stats.warn_new(&mut seen, self.xrun_warnings);
+            match self.events.recv_timeout(WATCH_INTERVAL) {
+                Ok(OutputEvent::Lost) => {
+                    warn!("The audio device went away; pausing until there is one to play on");
+                    output::event("device_lost", []);
+                    let _ = self.commands.send(Command::AudioDown);
+                    drop(stream);
+                    let first;
+                    (stream, first, name) = match self.reconnect() {
+                        Some(opened) => opened,
+                        None => return,
+                    };
+                    firsts.push(first);
+                    info!("Audio is back on {name}");
+                    output::event("device_restored", [("device", name.as_str().into())]);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if !follow_default || checked.elapsed() < RETRY_INTERVAL {
+                        continue;
+                    }
+                    checked = Instant::now();
+                    let Some(new) = default_device_name(&self.args).filter(|new| *new != name) else {
+                        continue;
+                    };
+                    info!("The default output is now {new}, moving there");
+                    let _ = self.commands.send(Command::AudioDown);
+                    drop(stream);
+                    let first;
+                    (stream, first, name) = match self.open(&default) {
+                        Ok(opened) => opened,
+                        Err(e) => {
+                            warn!("Could not open {new}: {e:#}");
+                            match self.reconnect() {
+                                Some(opened) => opened,
+                                None => return,
+                            }
+                        }
+                    };
+                    firsts.push(first);
+                    output::event("device_changed", [("device", name.as_str().into())]);
+                }
+                Ok(OutputEvent::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    let _ = stream.pause();
+                    return;
+                }
+            }
+            // Errors from the old stream may still be queued.
+            while let Ok(OutputEvent::Lost) = self.events.try_recv() {}
+            let _ = self.commands.send(Command::AudioUp);
+        }
+    }
+
+    /// Open the device `wanted` asks for and start a stream on it, with the synth switched
+    /// to the rate it runs at. Returns the stream, what reports its first buffer, and the
+    /// device name.
+    fn open(&self, wanted: &AudioArgs) -> Result<(cpal::Stream, FirstReport, String)> {
+        let (dev, cfg) = output_device(wanted)?;
+        let rate = cfg.sample_rate().0;
+        if Some(rate) != self.args.sample_rate
+            && let Some(mut synth) = self.feed.synth.take()
+        {
+            synth.set_sample_rate(rate as f32);
+            let _ = self.feed.synth.put(synth);
+        }
+        let (stream, first) = start_stream(&dev, &cfg, &self.args, self.gain, Source::Synth(self.feed.clone()))?;
+        Ok((stream, first, device_name(&dev)))
+    }
+
+    /// Start the `--also-device` stream, which plays what the main one does at `gain`. It
+    /// has to run at the synth's rate; it stays up while the main stream is replaced.
+    fn open_mirror(&self, wanted: &AudioArgs, gain: f32, mirror: Mirror) -> Result<(cpal::Stream, FirstReport)> {
+        let (dev, cfg) = output_device(wanted).context("opening the second output device")?;
+        let rate = cfg.sample_rate().0;
+        if Some(rate) != wanted.sample_rate {
+            anyhow::bail!(
+                "the second output device runs at {rate} Hz, not the {} Hz of the first",
+                wanted.sample_rate.unwrap_or_default()
+            );
+        }
+        let started = start_stream(&dev, &cfg, wanted, gain, Source::Mirror(mirror))?;
+        info!("Also playing on {}", device_name(&dev));
+        Ok(started)
+    }
+
+    /// Look for a device to play on until one opens: the one the options ask for, or
+    /// failing that the host's default. Returns `None` if told to stop while waiting.
+    fn reconnect(&self) -> Option<(cpal::Stream, FirstReport, String)> {
+        let default = AudioArgs { device: None, ..self.args.clone() };
+        let candidates = if self.args.device.is_some() { vec![&self.args, &default] } else { vec![&self.args] };
+        let mut reported = false;
+        loop {
+            for wanted in &candidates {
+                match self.open(wanted) {
+                    Ok(opened) => return Some(opened),
+                    Err(e) if !reported => {
+                        warn!("No audio device yet ({e:#}); trying again every {} s", RETRY_INTERVAL.as_secs());
+                        reported = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+            match self.events.recv_timeout(RETRY_INTERVAL) {
+                Ok(OutputEvent::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+                Ok(OutputEvent::Lost) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+        }
+    }
+}
+
+/// The name of the current default output device of the host `args` asks for.
+fn default_device_name(args: &AudioArgs) -> Option<String> {
+    let host = match args.host.as_deref() {
+        None => cpal::default_host(),
+        Some(wanted) => {
+            let id = cpal::available_hosts().into_iter().find(|id| id.name().eq_ignore_ascii_case(wanted))?;
+            cpal::host_from_id(id).ok()?
+        }
+    };
+    host.default_output_device()?.name().ok()
+}
+
+/// `devices` options.
+#[derive(Args, Debug)]
+pub struct DevicesArgs {
+    /// List the devices of this audio backend instead of the default one
+    #[arg(long, value_name = "NAME")]
+    host: Option<String>,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// `devices`: list the available hosts, then the output devices of the chosen host, marking
+/// the default one.
+pub fn list_devices(opt: DevicesArgs) -> Result<()> {
+    let hosts: Vec<&str> = cpal::available_hosts().iter().map(|id| id.name()).collect();
+    let host = host(opt.host.as_deref())?;
+    let default_name = host.default_output_device().and_then(|d| d.name().ok());
+    let mut devices = Vec::new();
+    if !output::is_json() {
+        println!("Host: {} (available: {})", host.id().name(), hosts.join(", "));
+    }
+    for (n, dev) in host.output_devices().context("listing output devices")?.enumerate() {
+        let name = dev.name().unwrap_or_else(|_| "(unknown)".to_string());
+        let is_default = Some(&name) == default_name.as_ref();
+        let cfg = dev.default_output_config().ok();
+        if output::is_json() {
+            devices.push(json!({
+                "index": n,
+                "name": name,
+                "default": is_default,
+                "sample_rate": cfg.as_ref().map(|c| c.sample_rate().0),
+                "channels": cfg.as_ref().map(|c| c.channels()),
+                "format": cfg.as_ref().map(|c| c.sample_format().to_string()),
+            }));
+            continue;
+        }
+        let mark = if is_default { "*" } else { " " };
+        match cfg {
+            Some(cfg) => println!(
+                "{mark} {n}: {name} ({} Hz, {} ch, {})",
+                cfg.sample_rate().0,
+                cfg.channels(),
+                cfg.sample_format()
+            ),
+            None => println!("{mark} {n}: {name}"),
+        }
+    }
+    if output::is_json() {
+        output::print(&json!({
+            "hosts": hosts,
+            "host": host.id().name(),
+            "devices": devices,
+        }));
+    }
+    Ok(())
+}
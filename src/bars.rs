@@ -0,0 +1,127 @@
+//! Song positions in bars and beats, as musicians count them, from the file's time
+//! signatures and tempo map.
+
+use crate::tempo::TempoMap;
+use crate::time::format_duration;
+use midly::{Format, MetaMessage, Smf, Timing, TrackEventKind};
+use std::fmt;
+
+/// A position as bar, beat and tick, the bar and beat counted from 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BarBeat {
+    pub bar: u64,
+    pub beat: u32,
+    /// Ticks into the beat.
+    pub tick: u32,
+}
+
+impl fmt::Display for BarBeat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{:03}", self.bar, self.beat, self.tick)
+    }
+}
+
+/// A time signature that holds from `tick` until the next change.
+#[derive(Clone, Copy)]
+struct Meter {
+    tick: u64,
+    /// The bar starting at `tick`, counted from 0.
+    bar: u64,
+    /// Beats to the bar, the signature's numerator.
+    beats: u32,
+    /// Ticks to the beat, a note of the signature's denominator.
+    beat_ticks: f64,
+}
+
+impl Meter {
+    fn bar_ticks(&self) -> f64 {
+        f64::from(self.beats) * self.beat_ticks
+    }
+}
+
+/// The file's bars: where each time signature starts, and the tempo map to time them.
+///
+/// As with tempo, a time signature holds for every track from its tick on. A change starts a
+/// new bar, cutting short the bar it falls in if it is not on a bar line. SMPTE-timed files
+/// have no beats, and format 2 patterns no shared bars, so neither has a map.
+pub struct BarMap {
+    tempo: TempoMap,
+    /// Ordered by tick; the first starts at tick 0, in 4/4 if the file sets no other.
+    meters: Vec<Meter>,
+}
+
+impl BarMap {
+    pub fn new(smf: &Smf) -> Option<Self> {
+        let Timing::Metrical(ppq) = smf.header.timing else { return None };
+        if smf.header.format == Format::Sequential {
+            return None;
+        }
+        let ppq = f64::from(ppq.as_int().max(1));
+
+        let mut events: Vec<(u64, u8, u8)> = Vec::new();
+        for track in &smf.tracks {
+            let mut tick = 0u64;
+            for ev in track {
+                tick += u64::from(ev.delta.as_int());
+                if let TrackEventKind::Meta(MetaMessage::TimeSignature(numer, denom, _, _)) = ev.kind {
+                    events.push((tick, numer, denom));
+                }
+            }
+        }
+        // Stable, so of simultaneous changes the last in the file wins.
+        events.sort_by_key(|&(tick, _, _)| tick);
+
+        let mut meters = vec![Meter { tick: 0, bar: 0, beats: 4, beat_ticks: ppq }];
+        for (tick, numer, denom) in events {
+            let last = *meters.last().unwrap();
+            let meter = Meter {
+                tick,
+                bar: last.bar + ((tick - last.tick) as f64 / last.bar_ticks()).ceil() as u64,
+                beats: u32::from(numer.max(1)),
+                // A quarter note is `ppq` ticks, and the beat is a 2^denom-th note.
+                beat_ticks: ppq * 4.0 / f64::from(1u32 << denom.min(31)),
+            };
+            if tick == last.tick {
+                *meters.last_mut().unwrap() = Meter { bar: last.bar, ..meter };
+            } else {
+                meters.push(meter);
+            }
+        }
+        Some(Self { tempo: TempoMap::new(smf), meters })
+    }
+
+    /// The bar, beat and tick at `t_us`.
+    pub fn at(&self, t_us: u64) -> BarBeat {
+        let tick = self.tempo.to_tick(t_us);
+        let m = self.meters[self.meters.partition_point(|m| m.tick <= tick) - 1];
+        let into = (tick - m.tick) as f64;
+        let bars = (into / m.bar_ticks()).floor();
+        let in_bar = into - bars * m.bar_ticks();
+        let beat = (in_bar / m.beat_ticks).floor();
+        BarBeat {
+            bar: m.bar + bars as u64 + 1,
+            beat: (beat as u32).min(m.beats - 1) + 1,
+            tick: (in_bar - beat * m.beat_ticks) as u32,
+        }
+    }
+
+    /// The time of beat `beat` of bar `bar`, both counted from 1, or `None` if the bar has
+    /// no such beat.
+    pub fn to_us(&self, bar: u64, beat: u32) -> Option<u64> {
+        let bar = bar.checked_sub(1)?;
+        let m = self.meters[self.meters.partition_point(|m| m.bar <= bar) - 1];
+        if beat == 0 || beat > m.beats {
+            return None;
+        }
+        let tick = m.tick as f64 + (bar - m.bar) as f64 * m.bar_ticks() + f64::from(beat - 1) * m.beat_ticks;
+        Some(self.tempo.to_us(tick.round() as u64))
+    }
+}
+
+/// A position for messages: mm:ss, and bar:beat:tick when the song has bars.
+pub fn describe(t_us: u64, bars: Option<&BarMap>) -> String {
+    match bars {
+        Some(bars) => format!("{} (bar {})", format_duration(t_us), bars.at(t_us)),
+        None => format_duration(t_us),
+    }
+}
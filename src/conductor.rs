@@ -0,0 +1,1013 @@
+//! The conductor: plays the timeline against a song clock and applies transport commands.
+
+use crate::bars::describe;
+use crate::controls::Command;
+use crate::interrupt::INTERRUPTED;
+use crate::output;
+use crate::scheduler::{AudioTime, Event, Events};
+use crate::song::{DRUM_CHANNEL, Marker, Msg, Song, Timed};
+use crate::synth::{MAX_TAIL, SILENCE_HOLD, chase};
+use crate::time::format_duration;
+use serde_json::Value;
+use std::{
+    cell::Cell,
+    sync::{Arc, atomic::{AtomicU64, Ordering}, mpsc::Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// How long to keep rendering after the last event so releases and reverb can ring out,
+/// for a synth that cannot tell when they have.
+pub const TAIL: Duration = Duration::from_secs(2);
+
+/// How long `--max-duration` takes to fade the music out before it stops.
+pub const FADE: Duration = Duration::from_secs(1);
+
+/// The smallest step in level the fade sends to the synth, in dB. Smaller ones are not
+/// heard, and each takes a place in the queue the song's events go through.
+const FADE_STEP_DB: f32 = 0.5;
+
+/// How often `--notify` reports the song position.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long after notes are queued the voices are counted, for them to have started.
+const VOICE_CHECK_DELAY: Duration = Duration::from_millis(20);
+
+/// Accepted range for the playback speed multiplier.
+pub const MIN_SPEED: f64 = 0.1;
+pub const MAX_SPEED: f64 = 4.0;
+
+/// clap value parser for `--speed`.
+pub fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if (MIN_SPEED..=MAX_SPEED).contains(&v) => Ok(v),
+        _ => Err(format!("invalid speed `{s}`, expected a factor between {MIN_SPEED} and {MAX_SPEED}")),
+    }
+}
+
+/// clap value parser for `--practice-step`, in percent. It must be above zero, or practice
+/// mode would never speed up.
+pub fn parse_practice_step(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        _ => Err(format!("invalid step `{s}`, expected a percentage above 0")),
+    }
+}
+
+/// How many times to play through the song.
+#[derive(Clone, Copy, Debug)]
+pub enum Repeat {
+    Times(u32),
+    Forever,
+}
+
+/// clap value parser for `--loop`.
+pub fn parse_repeat(s: &str) -> Result<Repeat, String> {
+    match s {
+        "forever" | "inf" => Ok(Repeat::Forever),
+        n => match n.parse() {
+            Ok(0) | Err(_) => Err(format!("invalid loop count `{s}`, expected a number ≥ 1 or `forever`")),
+            Ok(n) => Ok(Repeat::Times(n)),
+        },
+    }
+}
+
+/// Song clock used by the conductor.
+///
+/// Maps the audio played so far to a position in the timeline (microseconds since song
+/// start). It runs on the audio the synth has rendered rather than the wall clock, which
+/// drifts from the device's clock over a long song; between buffers it goes on by the wall
+/// clock, up to one buffer's worth. The position advances at `speed` song-microseconds per
+/// microsecond of audio, so every event time scales uniformly while pitch stays untouched.
+/// Events go to the synth as the clock reaches them, but are heard later: the scheduler
+/// plays them a buffer after their stamp, and the device takes its output latency more, so
+/// the position shown is that much behind the one events are due at.
+/// Because events are compared in song time, the speed can change mid-song without
+/// recomputing any timestamps. While paused the position is frozen; resuming restarts the
+/// clock from that position so playback continues exactly where it left off.
+struct Clock {
+    audio: AudioTime,
+    /// The audio rendered when last seen to change, and when that was.
+    seen: Cell<(Duration, Instant)>,
+    /// Audio time at `offset_us`.
+    started: Duration,
+    offset_us: u64,
+    speed: f64,
+    paused_at: Option<u64>,
+    /// Where the last seek went, until the conductor has noticed it.
+    sought: Option<u64>,
+}
+
+impl Clock {
+    fn new(speed: f64, audio: AudioTime) -> Self {
+        let rendered = audio.rendered().0;
+        let seen = Cell::new((rendered, Instant::now()));
+        Self { audio, seen, started: rendered, offset_us: 0, speed, paused_at: None, sought: None }
+    }
+
+    /// The audio time now: what has been rendered, and as much of the buffer being played
+    /// as the wall clock says has gone by since.
+    fn audio_now(&self) -> Duration {
+        let (rendered, block) = self.audio.rendered();
+        let (seen, seen_at) = self.seen.get();
+        if rendered != seen {
+            self.seen.set((rendered, Instant::now()));
+            return rendered;
+        }
+        rendered + seen_at.elapsed().min(block)
+    }
+
+    /// The position at audio time `audio`, or where the clock last moved to if that was
+    /// before then.
+    fn position_at(&self, audio: Duration) -> u64 {
+        match self.paused_at {
+            Some(pos) => pos,
+            None => {
+                let played = audio.saturating_sub(self.started);
+                self.offset_us + (played.as_micros() as f64 * self.speed) as u64
+            }
+        }
+    }
+
+    /// How far the song has gone to the synth: the events up to here are due.
+    fn due_us(&self) -> u64 {
+        self.position_at(self.audio_now())
+    }
+
+    /// Where the song is heard now.
+    fn now_us(&self) -> u64 {
+        self.position_at(self.audio_now().saturating_sub(self.audio.lag()))
+    }
+
+    /// The audio time at which the clock reaches `pos_us`, going on at this speed, or
+    /// reached it if it has since the last seek or change of speed. Now while paused.
+    fn audio_time_of(&self, pos_us: u64) -> Duration {
+        if self.paused_at.is_some() {
+            return self.audio_now();
+        }
+        self.started + Duration::from_secs_f64(pos_us.saturating_sub(self.offset_us) as f64 / self.speed / 1_000_000.0)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    fn pause(&mut self) {
+        // What has gone to the synth plays before the pause is heard, so it stops there.
+        if self.paused_at.is_none() {
+            self.paused_at = Some(self.due_us());
+        }
+    }
+
+    fn resume(&mut self) {
+        if let Some(pos) = self.paused_at.take() {
+            self.started = self.audio_now();
+            self.offset_us = pos;
+        }
+    }
+
+    /// Change the rate from the current position on. Re-anchoring at "now" means the
+    /// position never jumps; only the pace of what follows changes.
+    fn set_speed(&mut self, speed: f64) {
+        let pos = self.due_us();
+        self.started = self.audio_now();
+        self.offset_us = pos;
+        self.speed = speed;
+    }
+
+    /// Move the clock to `pos_us`, keeping the paused/playing state.
+    fn seek(&mut self, pos_us: u64) {
+        self.seek_at(pos_us, self.audio_now());
+    }
+
+    /// Move the clock to `pos_us` as of audio time `audio`, which may be a moment ago.
+    fn seek_at(&mut self, pos_us: u64, audio: Duration) {
+        self.started = audio;
+        self.offset_us = pos_us;
+        self.sought = Some(pos_us);
+        if self.paused_at.is_some() {
+            self.paused_at = Some(pos_us);
+        }
+    }
+}
+
+/// The wall-clock time spent playing, which `--max-duration` limits: time paused does not
+/// count, and it starts when the song does, after any count-in.
+struct PlayingTime {
+    played: Duration,
+    ticked: Instant,
+}
+
+impl PlayingTime {
+    fn new() -> Self {
+        Self { played: Duration::ZERO, ticked: Instant::now() }
+    }
+
+    /// Count the time since the last tick, unless the clock was `paused` through it, and
+    /// return the total.
+    fn tick(&mut self, paused: bool) -> Duration {
+        let now = Instant::now();
+        if !paused {
+            self.played += now - self.ticked;
+        }
+        self.ticked = now;
+        self.played
+    }
+}
+
+/// Conductor settings from the command line. Positions are timeline microseconds.
+pub struct PlayOptions {
+    pub start_us: u64,
+    /// Exclusive end: events at or after it are not played. `None` plays to the last event.
+    pub end_us: Option<u64>,
+    /// A–B region to repeat until cleared.
+    pub ab_loop: Option<(u64, u64)>,
+    /// How many times to play from start to end.
+    pub repeat: Repeat,
+    /// Tempo multiplier applied by the song clock.
+    pub speed: f64,
+    /// Initial channel mutes and solos.
+    pub mixer: Mixer,
+    /// How long to keep rendering after a quit so the reverb tail is not cut off.
+    pub stop_tail: Duration,
+    /// Metronome clicks to play before the music starts.
+    pub count_in: Option<CountIn>,
+    /// Tempo ramp applied on every loop pass.
+    pub practice: Option<Practice>,
+    /// Limit on the playing time, repeats included but pauses and the count-in not; the
+    /// last `FADE` of it fades out. Each run, so each `--watch` reload, starts it afresh.
+    pub max_duration: Option<Duration>,
+    /// Kept at the position heard as the song plays, for `--overdub` to time the live input by.
+    pub position: Option<Arc<AtomicU64>>,
+}
+
+/// Why playback stopped before the end of the song.
+#[derive(Clone, Copy, Debug)]
+pub enum Stop {
+    Quit,
+    Interrupted,
+    TimeLimit,
+    Reload,
+}
+
+impl Stop {
+    /// The reason as reported in the `finished` event.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stop::Quit => "quit",
+            Stop::Interrupted => "interrupted",
+            Stop::TimeLimit => "max_duration",
+            Stop::Reload => "reload",
+        }
+    }
+}
+
+/// Practice mode: after each completed loop the speed goes up by `step`, until `target`.
+#[derive(Clone, Copy)]
+pub struct Practice {
+    pub step: f64,
+    pub target: f64,
+}
+
+impl Practice {
+    /// Speed up the clock for the next pass; it stays at the target once there.
+    fn ramp(&self, clock: &mut Clock) {
+        if clock.speed < self.target {
+            let speed = (clock.speed + self.step).min(self.target);
+            clock.set_speed(speed);
+            info!("Practice: next pass at {:.0}%", speed * 100.0);
+            output::event("speed", [("speed", speed.into())]);
+        }
+    }
+}
+
+/// A metronome count-in, derived from the file's initial tempo and time signature.
+pub struct CountIn {
+    pub bars: u32,
+    pub beats_per_bar: u8,
+    /// Length of one beat at normal speed.
+    pub beat_us: f64,
+}
+
+/// GM percussion keys used for the count-in click: claves on the downbeat, side stick on
+/// the other beats.
+const CLICK_ACCENT_KEY: u8 = 75;
+const CLICK_KEY: u8 = 37;
+
+/// Play the count-in clicks on the percussion channel, scaled by the playback speed. The
+/// clicks are timed by the audio clock, as the song's events are, and the song clock is set
+/// to start at `start_us` on the beat after the last one. Returns why playback was stopped if
+/// it was, by a quit or an interrupt; other commands are ignored until the song starts.
+fn count_in(
+    events: &mut Events,
+    count_in: &CountIn,
+    clock: &mut Clock,
+    commands: &Receiver<Command>,
+    start_us: u64,
+) -> Option<Stop> {
+    let beat = Duration::from_secs_f64(count_in.beat_us / clock.speed / 1_000_000.0);
+    let beats = count_in.bars * u32::from(count_in.beats_per_bar);
+    let first = clock.audio_now();
+    let ch = DRUM_CHANNEL;
+    // Each click is released on the next beat, so it rings like a struck instrument.
+    let mut sounding = None;
+
+    // The beat after the last click only releases it.
+    for n in 0..=beats {
+        let at = first + beat * n;
+        // Queued as the audio clock reaches the beat, stamped with its exact time.
+        while clock.audio_now() < at {
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                return Some(Stop::Interrupted);
+            }
+            if commands.try_iter().any(|cmd| matches!(cmd, Command::Quit)) {
+                return Some(Stop::Quit);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        if let Some(prev) = sounding.take() {
+            events.push(at, Msg::NoteOff(ch, prev, 0));
+        }
+        if n < beats {
+            let (key, vel) = if n % u32::from(count_in.beats_per_bar) == 0 {
+                (CLICK_ACCENT_KEY, 127)
+            } else {
+                (CLICK_KEY, 90)
+            };
+            events.push(at, Msg::NoteOn(ch, key, vel));
+            sounding = Some(key);
+        }
+    }
+    // The music comes in where the next click would have been.
+    clock.seek_at(start_us, first + beat * beats);
+    None
+}
+
+/// Per-channel mute and solo state. Channels are 0-based here, 1-based on the command line
+/// and in messages.
+#[derive(Clone, Copy)]
+pub struct Mixer {
+    muted: [bool; 16],
+    soloed: [bool; 16],
+}
+
+impl Mixer {
+    /// Build from 1-based channel lists.
+    pub fn new(mute: &[u8], solo: &[u8]) -> Self {
+        let mut mixer = Self { muted: [false; 16], soloed: [false; 16] };
+        for &ch in mute {
+            mixer.muted[ch as usize - 1] = true;
+        }
+        for &ch in solo {
+            mixer.soloed[ch as usize - 1] = true;
+        }
+        mixer
+    }
+
+    /// The muted and soloed channels (1-based) as JSON event fields.
+    fn json_fields(&self) -> [(&'static str, Value); 2] {
+        let list = |flags: &[bool; 16]| Value::from((1..=16u8).filter(|&c| flags[c as usize - 1]).collect::<Vec<_>>());
+        [("muted", list(&self.muted)), ("soloed", list(&self.soloed))]
+    }
+
+    /// A channel is heard if it is not muted and either nothing or it is soloed.
+    pub fn audible(&self, ch: u8) -> bool {
+        let ch = ch as usize;
+        !self.muted[ch] && (self.soloed[ch] || !self.soloed.contains(&true))
+    }
+}
+
+impl std::fmt::Display for Mixer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |flags: &[bool; 16]| {
+            let chans: Vec<String> = (1..=16).filter(|&c| flags[c - 1]).map(|c| c.to_string()).collect();
+            if chans.is_empty() { "none".to_string() } else { chans.join(", ") }
+        };
+        write!(f, "Muted: {} | Solo: {}", list(&self.muted), list(&self.soloed))
+    }
+}
+
+/// Conductor loop: dispatches timeline events when the song clock reaches them.
+///
+/// Playback begins at the start position; a non-zero start is reached through `jump`, so
+/// the setup events before it (programs, controllers) are chased and instruments are correct
+/// from the first note. When the clock reaches the end all notes are released and the
+/// conductor keeps going until the synth has been silent for `SILENCE_HOLD`, so they can
+/// ring out however long that takes, then returns. A `Quit` command stops immediately.
+/// With `max_duration` set the music fades out over the last `FADE` of the playing time and
+/// then stops as if quit, wherever the song is.
+///
+/// While an A–B loop is set, reaching B jumps back to A through `jump` as well, so every
+/// repeat starts from identical channel state. `--loop` repeats work the same way: at the
+/// end the conductor jumps straight back to the start (no tail, so the repeat is gapless)
+/// with programs, controllers and pitch bend reset between iterations.
+///
+/// Positions are reported with their bar and beat when the song has bars.
+///
+/// Returns why and at which song position playback was stopped early, or `None` if it ran
+/// to the end.
+pub fn conduct(
+    song: &Song,
+    play: &PlayOptions,
+    commands: &Receiver<Command>,
+    events: &mut Events,
+) -> Option<(Stop, u64)> {
+    let gain = events.gain();
+    // The level the fade last set.
+    let mut faded = gain;
+    let mut clock = Clock::new(play.speed, events.time());
+    let (timeline, markers, bars) = (&song.timeline[..], &song.markers[..], song.bars.as_ref());
+    // Up to the End of Track, so a closing rest is waited out before the tail. Percentages
+    // are of it too, as for `--start`.
+    let song_us = song.length_us;
+    let song_end_us = song_us + 1;
+    let stop_us = play.end_us.map_or(song_end_us, |e| e.min(song_end_us));
+    let (mut loop_a, mut loop_b) = play.ab_loop.unzip();
+    let mut mixer = play.mixer;
+    // Wall-clock time at which the notes were released at the end.
+    let mut released: Option<Instant> = None;
+    let mut pass = 1u32;
+    // Markers from this song position on have not been reached yet.
+    let mut markers_from = play.start_us;
+    let mut last_progress = Instant::now();
+    // The most voices heard sounding at once.
+    let mut most_voices = 0usize;
+    // When to count the voices again, after notes have started.
+    let mut voices_check: Option<Instant> = None;
+    // Paused because the audio stream is down, rather than by the user.
+    let mut held = false;
+
+    // Start from a reset synth whatever an earlier run (a `--watch` reload, another song in
+    // the playlist) left it in, as a file without a reset message of its own expects.
+    let mut i = jump(events, timeline, &mut clock, play.start_us);
+    if play.start_us > 0 {
+        info!("Starting at {}", describe(play.start_us, bars));
+    }
+
+    // The count-in runs before the song clock, which then restarts on the downbeat.
+    if let Some(c) = &play.count_in
+        && let Some(reason) = count_in(events, c, &mut clock, commands, play.start_us)
+    {
+        if matches!(reason, Stop::Interrupted) {
+            info!("Interrupted");
+        }
+        stop(events, play.stop_tail);
+        return Some((reason, play.start_us));
+    }
+    output::event("started", [("position", output::secs(play.start_us)), ("speed", play.speed.into())]);
+
+    let mut playing = PlayingTime::new();
+    while released.is_none_or(|t| !tail_over(events, t)) {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            info!("Interrupted");
+            stop(events, play.stop_tail);
+            return Some((Stop::Interrupted, clock.now_us()));
+        }
+
+        // Paused or not since the last time round, to within the few milliseconds between.
+        let played = playing.tick(clock.is_paused());
+        if let Some(limit) = play.max_duration {
+            let left = limit.saturating_sub(played);
+            if left.is_zero() {
+                info!("Stopping after {}", format_duration(limit.as_micros() as u64));
+                stop(events, play.stop_tail);
+                return Some((Stop::TimeLimit, clock.now_us()));
+            }
+            if left < FADE {
+                let level = gain * left.as_secs_f32() / FADE.as_secs_f32();
+                if 20.0 * (faded / level).log10() >= FADE_STEP_DB {
+                    events.send(Event::Gain(level));
+                    faded = level;
+                }
+            }
+        }
+
+        // Apply transport commands before dispatching anything for this tick.
+        for cmd in commands.try_iter() {
+            match cmd {
+                Command::TogglePause | Command::Resume if clock.is_paused() => {
+                    held = false;
+                    clock.resume();
+                    info!("Resumed at {}", describe(clock.now_us(), bars));
+                    output::event("resumed", [("position", output::secs(clock.now_us()))]);
+                }
+                Command::TogglePause | Command::Pause if !clock.is_paused() => {
+                    clock.pause();
+                    events.send(Event::Silence);
+                    restore_pedals(events, &timeline[..i], |_| true);
+                    info!("Paused at {}", describe(clock.now_us(), bars));
+                    output::event("paused", [("position", output::secs(clock.now_us()))]);
+                }
+                Command::Pause | Command::Resume | Command::TogglePause => held = false,
+                Command::SeekBy(delta) => {
+                    let target = clock.now_us().saturating_add_signed(delta).min(stop_us);
+                    i = jump(events, timeline, &mut clock, target);
+                    released = None;
+                    info!("Seek to {}", describe(target, bars));
+                    output::event("seek", [("position", output::secs(target))]);
+                }
+                Command::SeekTo(target) => {
+                    let Some(target) = target.resolve(song_us, bars) else {
+                        warn!("Ignoring seek: the song has no such bar and beat");
+                        continue;
+                    };
+                    let target = target.min(stop_us);
+                    i = jump(events, timeline, &mut clock, target);
+                    released = None;
+                    info!("Seek to {}", describe(target, bars));
+                    output::event("seek", [("position", output::secs(target))]);
+                }
+                Command::NextMarker | Command::PrevMarker | Command::GotoMarker(_) => {
+                    match find_marker(markers, &cmd, clock.now_us()) {
+                        Some(m) => {
+                            i = jump(events, timeline, &mut clock, m.t_us.min(stop_us));
+                            released = None;
+                            info!("Marker: {} ({})", m.name, format_duration(m.t_us));
+                            output::event("marker", [("name", m.name.as_str().into()), ("position", output::secs(m.t_us))]);
+                        }
+                        None => info!("No marker there"),
+                    }
+                }
+                Command::MarkLoopA => {
+                    loop_a = Some(clock.now_us());
+                    info!("Loop A set at {}", describe(clock.now_us(), bars));
+                    output::event("loop_point", [("point", "A".into()), ("position", output::secs(clock.now_us()))]);
+                }
+                Command::MarkLoopB => {
+                    loop_b = Some(clock.now_us());
+                    info!("Loop B set at {}", describe(clock.now_us(), bars));
+                    output::event("loop_point", [("point", "B".into()), ("position", output::secs(clock.now_us()))]);
+                }
+                Command::SetLoop(a, b) => {
+                    let Some((a, b)) = a.resolve(song_us, bars).zip(b.resolve(song_us, bars)) else {
+                        warn!("Ignoring loop: the song has no such bar and beat");
+                        continue;
+                    };
+                    if b <= a {
+                        warn!("Ignoring loop: B must be after A");
+                        continue;
+                    }
+                    (loop_a, loop_b) = (Some(a), Some(b));
+                    info!("Looping {} – {}", describe(a, bars), describe(b, bars));
+                    output::event("loop", [("a", output::secs(a)), ("b", output::secs(b))]);
+                }
+                Command::ClearLoop => {
+                    (loop_a, loop_b) = (None, None);
+                    info!("Loop cleared");
+                    output::event("loop", [("a", Value::Null), ("b", Value::Null)]);
+                }
+                Command::NudgeSpeed(step) => {
+                    let speed = (clock.speed + step).clamp(MIN_SPEED, MAX_SPEED);
+                    clock.set_speed(speed);
+                    info!("Speed {:.0}%", speed * 100.0);
+                    output::event("speed", [("speed", speed.into())]);
+                }
+                Command::SetSpeed(speed) => {
+                    clock.set_speed(speed);
+                    info!("Speed {:.0}%", speed * 100.0);
+                    output::event("speed", [("speed", speed.into())]);
+                }
+                Command::Mute(ch, switch) => {
+                    let before = mixer;
+                    switch.apply(&mut mixer.muted[ch as usize]);
+                    silence_newly_muted(events, &timeline[..i], &before, &mixer);
+                    info!("{mixer}");
+                    output::event("mixer", mixer.json_fields());
+                }
+                Command::Solo(ch, switch) => {
+                    let before = mixer;
+                    switch.apply(&mut mixer.soloed[ch as usize]);
+                    silence_newly_muted(events, &timeline[..i], &before, &mixer);
+                    info!("{mixer}");
+                    output::event("mixer", mixer.json_fields());
+                }
+                Command::Panic => {
+                    events.send(Event::Panic);
+                    info!("Panic: all notes off");
+                    output::event("panic", []);
+                }
+                Command::Voices => match events.voices() {
+                    Some((active, limit)) => {
+                        info!("Voices: {active} of {limit} sounding, at most {most_voices} so far");
+                        let fields = [("active", active.into()), ("limit", limit.into()), ("most", most_voices.into())];
+                        output::event("voices", fields);
+                    }
+                    None => info!("The synth engine does not count its voices"),
+                },
+                Command::Quit => {
+                    stop(events, play.stop_tail);
+                    return Some((Stop::Quit, clock.now_us()));
+                }
+                Command::Reload => {
+                    events.send(Event::Silence);
+                    return Some((Stop::Reload, clock.now_us()));
+                }
+                // Nothing is heard without a stream, so keep the place rather than play on.
+                Command::AudioDown => {
+                    if !clock.is_paused() {
+                        clock.pause();
+                        held = true;
+                    }
+                    events.send(Event::Silence);
+                    restore_pedals(events, &timeline[..i], |_| true);
+                }
+                Command::AudioUp => {
+                    if std::mem::take(&mut held) {
+                        clock.resume();
+                        info!("Resumed at {}", describe(clock.now_us(), bars));
+                    }
+                }
+            }
+        }
+
+        // Reached B: go back to A with the channel state A had the first time round.
+        let ab_loop = loop_a.zip(loop_b).filter(|(a, b)| a < b);
+        if let Some((a, b)) = ab_loop
+            && clock.due_us() >= b
+        {
+            i = jump(events, timeline, &mut clock, a);
+            released = None;
+            if let Some(p) = &play.practice {
+                p.ramp(&mut clock);
+            }
+        }
+
+        // Reached the end with repeats left: start over from a clean channel state.
+        let repeat = match play.repeat {
+            Repeat::Times(n) => pass < n,
+            Repeat::Forever => true,
+        };
+        if repeat && clock.due_us() >= stop_us {
+            pass += 1;
+            i = jump(events, timeline, &mut clock, play.start_us);
+            match play.repeat {
+                Repeat::Times(n) => info!("Repeat {pass}/{n}"),
+                Repeat::Forever => info!("Repeat {pass}"),
+            }
+            output::event("repeat", [("pass", pass.into())]);
+            if let Some(p) = &play.practice {
+                p.ramp(&mut clock);
+            }
+        }
+
+        // Dispatch all events that are due at this moment
+        let due_us = clock.due_us();
+        let now_us = clock.now_us();
+        if let Some(position) = &play.position {
+            position.store(now_us, Ordering::Relaxed);
+        }
+        let due_before = ab_loop.map_or(stop_us, |(_, b)| b.min(stop_us));
+        let mut started = false;
+        while i < timeline.len() && timeline[i].t_us <= due_us && timeline[i].t_us < due_before {
+            match timeline[i].msg {
+                // Muted channels keep all their state changes, they just don't start notes.
+                Msg::NoteOn(ch, ..) if !mixer.audible(ch) => {}
+                // Stamped with when it is due rather than when this loop came round to it, so
+                // the synth can play it at its exact sample. If the queue is full, the rest go
+                // next time round.
+                msg => {
+                    if !events.push(clock.audio_time_of(timeline[i].t_us), msg) {
+                        break;
+                    }
+                    started |= matches!(msg, Msg::NoteOn(..));
+                }
+            }
+            i += 1;
+        }
+        // The voices peak as notes start, a buffer after they are queued; once they run out,
+        // notes are being cut short.
+        if started && voices_check.is_none() {
+            voices_check = Some(Instant::now() + VOICE_CHECK_DELAY);
+        }
+        if voices_check.is_some_and(|t| t <= Instant::now()) {
+            voices_check = None;
+            if let Some((active, limit)) = events.voices() {
+                if active >= limit && most_voices < limit {
+                    warn!("All {limit} voices are in use, so notes are being cut short; raise --polyphony to allow more");
+                }
+                most_voices = most_voices.max(active);
+            }
+        }
+
+        // Markers the clock has played past, not jumped over.
+        if let Some(t) = clock.sought.take() {
+            markers_from = t;
+        }
+        for m in markers.iter().filter(|m| (markers_from..=now_us).contains(&m.t_us)) {
+            output::progress("marker_reached", [("name", m.name.as_str().into()), ("position", output::secs(m.t_us))]);
+        }
+        markers_from = markers_from.max(now_us + 1);
+
+        if !clock.is_paused() && released.is_none() && last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            let voices = events.voices().map(|(active, _)| active);
+            let bar_beat = bars.map(|bars| bars.at(now_us));
+            output::progress(
+                "position",
+                [
+                    ("position", output::secs(now_us)),
+                    ("length", output::secs(song_us)),
+                    ("voices", voices.into()),
+                    ("bar", bar_beat.map(|b| b.bar).into()),
+                    ("beat", bar_beat.map(|b| b.beat).into()),
+                ],
+            );
+        }
+
+        // Past the end: let go of everything once and just let the tail ring.
+        if due_us >= stop_us && released.is_none() {
+            events.send(Event::ReleaseNotes);
+            released = Some(Instant::now());
+        }
+
+        // Short sleep to avoid busy waiting. This is a simple scheduler.
+        thread::sleep(Duration::from_millis(1));
+    }
+    events.send(Event::Silence);
+    None
+}
+
+/// Silence and reset the synth after what is queued already, chase it to `target` (see
+/// `synth::chase`) and move the clock there. Returns the new event index.
+fn jump(events: &mut Events, timeline: &[Timed], clock: &mut Clock, target: u64) -> usize {
+    events.send(Event::Silence);
+    events.send(Event::Reset);
+    let (i, chased) = chase(timeline, target);
+    for msg in chased {
+        events.send(Event::Msg(msg));
+    }
+    clock.seek(target);
+    i
+}
+
+/// Within this distance after a marker, "previous" goes to the marker before it rather than
+/// back to the start of the current one, like the previous-track button on a media player.
+const PREV_MARKER_GRACE_US: u64 = 2_000_000;
+
+/// The marker a marker navigation command points at, relative to `now_us`.
+fn find_marker<'a>(markers: &'a [Marker], cmd: &Command, now_us: u64) -> Option<&'a Marker> {
+    match cmd {
+        Command::NextMarker => markers.iter().find(|m| m.t_us > now_us),
+        Command::PrevMarker => markers
+            .iter()
+            .rev()
+            .find(|m| m.t_us + PREV_MARKER_GRACE_US < now_us),
+        Command::GotoMarker(name) => markers.iter().find(|m| m.name.eq_ignore_ascii_case(name)),
+        _ => None,
+    }
+}
+
+/// Whether the tail after the notes were released at `released` has played out: the synth
+/// has been silent for `SILENCE_HOLD`, or, if it cannot tell, `TAIL` has passed.
+fn tail_over(events: &Events, released: Instant) -> bool {
+    match events.silent_for() {
+        Some(quiet) if quiet >= SILENCE_HOLD => true,
+        Some(_) if released.elapsed() >= MAX_TAIL => {
+            warn!("The sound has not died away {} s after the last event; stopping there", MAX_TAIL.as_secs());
+            true
+        }
+        Some(_) => false,
+        None => released.elapsed() >= TAIL,
+    }
+}
+
+/// Stop playback early: cut every voice, then keep the audio running for up to `tail` so the
+/// reverb and chorus buffers fade out naturally instead of the stream ending on a click.
+/// Once the synth has been silent for `SILENCE_HOLD` there is nothing left to fade.
+fn stop(events: &mut Events, tail: Duration) {
+    events.send(Event::Silence);
+    let began = Instant::now();
+    while began.elapsed() < tail {
+        if events.silent_for().is_some_and(|quiet| quiet >= SILENCE_HOLD) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Cut the notes on every channel that was audible in `before` but is not any more, with
+/// its pedals let up first so none are left held. The pedals then go back to where the
+/// `played` events left them, ready for when the channel is heard again.
+fn silence_newly_muted(events: &mut Events, played: &[Timed], before: &Mixer, after: &Mixer) {
+    let muted = |ch: u8| before.audible(ch) && !after.audible(ch);
+    for ch in (0..16u8).filter(|&ch| muted(ch)) {
+        events.send(Event::Msg(Msg::Control(ch, 64, 0)));  // Sustain off
+        events.send(Event::Msg(Msg::Control(ch, 66, 0)));  // Sostenuto off
+        events.send(Event::Msg(Msg::Control(ch, 123, 0))); // All Notes Off
+        events.send(Event::Msg(Msg::Control(ch, 120, 0))); // All Sound Off
+    }
+    restore_pedals(events, played, muted);
+}
+
+/// Put the sustain and sostenuto pedals on the `channels` picked back down where the `played`
+/// events left them down, after silencing let them up. With no notes sounding, only the
+/// notes struck from here on are held, as they would have been.
+fn restore_pedals(events: &mut Events, played: &[Timed], channels: impl Fn(u8) -> bool) {
+    // The last value of each pedal on each channel since the last reset.
+    let mut pedals = [[None; 2]; 16];
+    for ev in played.iter().rev() {
+        match ev.msg {
+            Msg::Reset(_) => break,
+            Msg::Control(ch, cc @ (64 | 66), value) => {
+                pedals[ch as usize][usize::from(cc == 66)].get_or_insert(value);
+            }
+            _ => {}
+        }
+    }
+    for ch in (0..16u8).filter(|&ch| channels(ch)) {
+        for (cc, value) in [64, 66].into_iter().zip(pedals[ch as usize]) {
+            if let Some(value) = value.filter(|&v| v >= 64) {
+                events.send(Event::Msg(Msg::Control(ch, cc, value)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Scheduler;
+    use crate::synth::Synth;
+    use crate::synth::fake::{Call, Fake, Log};
+
+    /// A frame a millisecond, so frames and milliseconds read the same.
+    const RATE: f32 = 1000.0;
+
+    fn output() -> (Synth, Events, Log) {
+        let (fake, log) = Fake::open();
+        let (synth, events) = Scheduler::wrap(fake, RATE);
+        (synth, events, log)
+    }
+
+    /// Render `ms` of audio, then an empty buffer, so the clock has no buffer in play to go
+    /// on through by the wall clock.
+    fn play(synth: &mut Synth, ms: usize) {
+        synth.render(&mut vec![0.0; ms * 2]).unwrap();
+        synth.render(&mut []).unwrap();
+    }
+
+    fn at(t_us: u64, msg: Msg) -> Timed {
+        Timed { t_us, msg, track: 0 }
+    }
+
+    #[test]
+    fn takes_only_a_practice_step_that_speeds_up() {
+        assert_eq!(parse_practice_step("2.5"), Ok(2.5));
+        for bad in ["0", "-5", "NaN", "inf", "fast"] {
+            assert!(parse_practice_step(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn seeks_from_where_the_audio_is() {
+        let (mut synth, events, _) = output();
+        let mut clock = Clock::new(1.0, events.time());
+        play(&mut synth, 100);
+        assert_eq!(clock.due_us(), 100_000);
+        clock.seek(5_000_000);
+        assert_eq!((clock.due_us(), clock.sought), (5_000_000, Some(5_000_000)));
+        play(&mut synth, 10);
+        assert_eq!(clock.due_us(), 5_010_000);
+        assert_eq!(clock.audio_time_of(5_020_000), Duration::from_millis(120));
+    }
+
+    #[test]
+    fn stays_paused_through_a_seek() {
+        let (mut synth, events, _) = output();
+        let mut clock = Clock::new(1.0, events.time());
+        clock.pause();
+        clock.seek(1_000_000);
+        play(&mut synth, 50);
+        assert!(clock.is_paused());
+        assert_eq!(clock.due_us(), 1_000_000);
+        clock.resume();
+        play(&mut synth, 50);
+        assert_eq!(clock.due_us(), 1_050_000);
+    }
+
+    #[test]
+    fn jumps_with_the_synth_reset_and_chased() {
+        let (mut synth, mut events, log) = output();
+        let mut clock = Clock::new(1.0, events.time());
+        let timeline = [at(0, Msg::Program(1, 40)), at(0, Msg::NoteOn(1, 60, 100)), at(500_000, Msg::NoteOff(1, 60, 0))];
+        assert_eq!(jump(&mut events, &timeline, &mut clock, 200_000), 2);
+        assert_eq!(clock.due_us(), 200_000);
+        // Sent events are played a buffer and the slack after the start.
+        play(&mut synth, 150);
+        play(&mut synth, 150);
+        let log = log.lock().unwrap();
+        let reset = log.iter().position(|&(_, call)| call == Call::Reset).unwrap();
+        assert_eq!(log[reset + 1..], [(153, Call::Program(1, 40))]);
+        assert!(!log.iter().any(|&(_, call)| matches!(call, Call::NoteOn(..))));
+    }
+
+    #[test]
+    fn freezes_the_position_while_paused() {
+        let (mut synth, events, _) = output();
+        let mut clock = Clock::new(1.0, events.time());
+        play(&mut synth, 100);
+        clock.pause();
+        play(&mut synth, 50);
+        assert_eq!((clock.due_us(), clock.now_us()), (100_000, 100_000));
+        clock.pause();
+        clock.resume();
+        assert_eq!(clock.due_us(), 100_000);
+        play(&mut synth, 20);
+        assert_eq!(clock.due_us(), 120_000);
+        // What is heard stays at the pause until the resumed music has come through the
+        // delay of the longest buffer and the slack.
+        assert_eq!(clock.now_us(), 100_000);
+        play(&mut synth, 100);
+        assert_eq!(clock.now_us(), 117_000);
+    }
+
+    #[test]
+    fn counts_only_the_playing_time() {
+        let mut playing = PlayingTime::new();
+        thread::sleep(Duration::from_millis(20));
+        let played = playing.tick(false);
+        assert!(played >= Duration::from_millis(20));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(playing.tick(true), played);
+        assert!(playing.tick(false) < played + Duration::from_millis(20));
+    }
+
+    #[test]
+    fn keeps_the_position_through_a_change_of_speed() {
+        let (mut synth, events, _) = output();
+        let mut clock = Clock::new(1.0, events.time());
+        play(&mut synth, 100);
+        clock.set_speed(2.0);
+        assert_eq!(clock.due_us(), 100_000);
+        play(&mut synth, 100);
+        assert_eq!(clock.due_us(), 300_000);
+        assert_eq!(clock.audio_time_of(400_000), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn counts_in_by_the_audio_clock() {
+        let (mut synth, mut events, log) = output();
+        let mut clock = Clock::new(1.0, events.time());
+        let count = CountIn { bars: 1, beats_per_bar: 2, beat_us: 50_000.0 };
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        // The audio starts once the count-in has, and goes slower than the wall clock.
+        let audio = thread::spawn({
+            let done = done.clone();
+            move || {
+                thread::sleep(Duration::from_millis(20));
+                while !done.load(Ordering::Relaxed) {
+                    play(&mut synth, 1);
+                    thread::sleep(Duration::from_millis(2));
+                }
+            }
+        });
+        let (_tx, rx) = std::sync::mpsc::channel();
+        let stopped = count_in(&mut events, &count, &mut clock, &rx, 2_000_000);
+        done.store(true, Ordering::Relaxed);
+        audio.join().unwrap();
+        assert!(stopped.is_none());
+        // The song starts on the third beat, however late the conductor saw it come.
+        assert_eq!(clock.audio_time_of(2_000_000), Duration::from_millis(100));
+        let calls: Vec<Call> = log.lock().unwrap().iter().map(|&(_, call)| call).collect();
+        let (ch, accent, click) = (DRUM_CHANNEL, CLICK_ACCENT_KEY, CLICK_KEY);
+        let clicks = [Call::NoteOn(ch, accent, 127), Call::NoteOff(ch, accent), Call::NoteOn(ch, click, 90)];
+        assert_eq!(calls[..3], clicks);
+    }
+
+    #[test]
+    fn quits_during_the_count_in() {
+        let (mut synth, mut events, log) = output();
+        let mut clock = Clock::new(1.0, events.time());
+        play(&mut synth, 100);
+        let (tx, rx) = std::sync::mpsc::channel();
+        tx.send(Command::Quit).unwrap();
+        let count = CountIn { bars: 2, beats_per_bar: 4, beat_us: 500_000.0 };
+        let stopped = count_in(&mut events, &count, &mut clock, &rx, 0);
+        assert!(matches!(stopped, Some(Stop::Quit)));
+        // Only the first click was due, stamped with the audio time it was due at.
+        play(&mut synth, 100);
+        play(&mut synth, 100);
+        let log = log.lock().unwrap();
+        assert_eq!(log[..], [(203, Call::NoteOn(DRUM_CHANNEL, CLICK_ACCENT_KEY, 127))]);
+    }
+
+    #[test]
+    fn puts_the_pedals_back_down_after_a_pause() {
+        let (mut synth, mut events, log) = output();
+        let played = [
+            at(0, Msg::Control(0, 64, 127)),
+            at(0, Msg::Control(1, 64, 127)),
+            at(0, Msg::Reset(crate::sysex::Standard::Gm)),
+            at(0, Msg::Control(2, 66, 100)),
+            at(0, Msg::Control(3, 64, 127)),
+            at(0, Msg::Control(3, 64, 0)),
+            at(0, Msg::Control(4, 64, 127)),
+        ];
+        restore_pedals(&mut events, &played, |ch| ch != 4);
+        play(&mut synth, 10);
+        play(&mut synth, 10);
+        let calls: Vec<Call> = log.lock().unwrap().iter().map(|&(_, call)| call).collect();
+        assert_eq!(calls, [Call::Cc(2, 66, 100)]);
+    }
+}
@@ -0,0 +1,299 @@
+//! Transport controls: single key presses from a raw terminal, or text commands from a pipe.
+
+use crate::conductor::parse_speed;
+use crate::time::{Position, parse_position, parse_time};
+use std::{
+    io::{self, BufRead, Read},
+    sync::mpsc::Sender,
+    thread,
+};
+use tracing::warn;
+
+/// How much `+` / `-` change the speed per key press.
+const SPEED_STEP: f64 = 0.05;
+
+/// How far the arrow keys jump.
+const SEEK_SHORT_US: i64 = 5_000_000;
+const SEEK_LONG_US: i64 = 30_000_000;
+
+/// Transport commands sent from the control thread to the conductor.
+pub enum Command {
+    /// Pause if playing, resume if paused.
+    TogglePause,
+    /// Pause (no-op if already paused).
+    Pause,
+    /// Resume (no-op if already playing).
+    Resume,
+    /// Jump relative to the current position, in microseconds.
+    SeekBy(i64),
+    /// Jump to an absolute song position.
+    SeekTo(Position),
+    /// Set loop point A at the current position.
+    MarkLoopA,
+    /// Set loop point B at the current position.
+    MarkLoopB,
+    /// Loop between two absolute positions.
+    SetLoop(Position, Position),
+    /// Stop looping and play on.
+    ClearLoop,
+    /// Change the speed multiplier by this amount.
+    NudgeSpeed(f64),
+    /// Set the speed multiplier.
+    SetSpeed(f64),
+    /// Jump to the next marker.
+    NextMarker,
+    /// Jump to the previous marker.
+    PrevMarker,
+    /// Jump to the marker with this name.
+    GotoMarker(String),
+    /// Kill stuck notes without stopping playback.
+    Panic,
+    /// Report how many voices are sounding.
+    Voices,
+    /// Mute or unmute a channel (0-based).
+    Mute(u8, Switch),
+    /// Solo or unsolo a channel (0-based).
+    Solo(u8, Switch),
+    /// Stop playback and exit.
+    Quit,
+    /// Stop and load the song again, because `--watch` saw it change.
+    Reload,
+    /// The audio stream is down while its device is replaced: hold the song clock.
+    AudioDown,
+    /// Audio plays again after `AudioDown`.
+    AudioUp,
+}
+
+impl Command {
+    /// Map a single key press to a command.
+    fn from_key(key: Key) -> Option<Self> {
+        match key {
+            Key::Char(' ') => Some(Command::TogglePause),
+            // Ctrl-C arrives as a plain byte while the terminal is in raw mode.
+            Key::Char('q') | Key::Char('\x03') => Some(Command::Quit),
+            Key::Left => Some(Command::SeekBy(-SEEK_SHORT_US)),
+            Key::Right => Some(Command::SeekBy(SEEK_SHORT_US)),
+            Key::Down => Some(Command::SeekBy(-SEEK_LONG_US)),
+            Key::Up => Some(Command::SeekBy(SEEK_LONG_US)),
+            Key::Char('[') => Some(Command::MarkLoopA),
+            Key::Char(']') => Some(Command::MarkLoopB),
+            Key::Char('\\') => Some(Command::ClearLoop),
+            Key::Char('+' | '=') => Some(Command::NudgeSpeed(SPEED_STEP)),
+            Key::Char('-') => Some(Command::NudgeSpeed(-SPEED_STEP)),
+            Key::Char('n') => Some(Command::NextMarker),
+            Key::Char('p') => Some(Command::PrevMarker),
+            Key::Char('!') => Some(Command::Panic),
+            Key::Char('v') => Some(Command::Voices),
+            // Like a video player: 0 is the top of the song, 5 is halfway through.
+            Key::Char(c @ '0'..='9') => {
+                let pct = f64::from(c as u8 - b'0') * 10.0;
+                Some(Command::SeekTo(Position::Percent(pct)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse one line of the text control channel, e.g. `pause`, `seek +10`, `seek 1:30`, `seek 35%`, `seek b17`,
+    /// `loop 0:30 0:45`, `loop off`, `speed 0.8`, `faster`, `mute 10` or `marker Chorus`.
+    fn from_line(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let cmd = match (words.next()?, words.next()) {
+            ("pause", None) => Command::Pause,
+            ("resume" | "play", None) => Command::Resume,
+            ("toggle", None) => Command::TogglePause,
+            ("quit" | "stop", None) => Command::Quit,
+            ("mute", Some(ch)) => Command::Mute(parse_channel(ch)?, Switch::On),
+            ("unmute", Some(ch)) => Command::Mute(parse_channel(ch)?, Switch::Off),
+            ("solo", Some(ch)) => Command::Solo(parse_channel(ch)?, Switch::On),
+            ("unsolo", Some(ch)) => Command::Solo(parse_channel(ch)?, Switch::Off),
+            ("panic", None) => Command::Panic,
+            ("voices", None) => Command::Voices,
+            ("next", None) => Command::NextMarker,
+            ("prev", None) => Command::PrevMarker,
+            // Marker names may contain spaces, so take the rest of the line.
+            ("marker", Some(_)) => {
+                let name = line.trim().strip_prefix("marker")?.trim();
+                return Some(Command::GotoMarker(name.to_string()));
+            }
+            ("faster", None) => Command::NudgeSpeed(SPEED_STEP),
+            ("slower", None) => Command::NudgeSpeed(-SPEED_STEP),
+            ("speed", Some(arg)) => Command::SetSpeed(parse_speed(arg).ok()?),
+            ("loop", Some("off")) => Command::ClearLoop,
+            ("loop", Some(a)) => Command::SetLoop(parse_position(a)?, parse_position(words.next()?)?),
+            ("seek", Some(arg)) => {
+                if let Some(rel) = arg.strip_prefix('+') {
+                    Command::SeekBy(parse_time(rel)? as i64)
+                } else if let Some(rel) = arg.strip_prefix('-') {
+                    Command::SeekBy(-(parse_time(rel)? as i64))
+                } else {
+                    Command::SeekTo(parse_position(arg)?)
+                }
+            }
+            _ => return None,
+        };
+        // Reject trailing garbage such as `seek 10 20`.
+        words.next().is_none().then_some(cmd)
+    }
+}
+
+/// How to change an on/off setting.
+#[derive(Clone, Copy)]
+pub enum Switch {
+    On,
+    Off,
+    Toggle,
+}
+
+impl Switch {
+    pub fn apply(self, flag: &mut bool) {
+        *flag = match self {
+            Switch::On => true,
+            Switch::Off => false,
+            Switch::Toggle => !*flag,
+        };
+    }
+}
+
+/// Channel picked by the key after an `m` or `s` prefix:
+/// `1`–`9` are channels 1–9, `0` is channel 10 and `a`–`f` are channels 11–16.
+fn channel_key(c: char) -> Option<u8> {
+    match c {
+        '1'..='9' => Some(c as u8 - b'1'),
+        '0' => Some(9),
+        'a'..='f' => Some(c as u8 - b'a' + 10),
+        _ => None,
+    }
+}
+
+/// A key press read from the terminal.
+enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// Read transport commands from stdin on a background thread.
+///
+/// With a raw terminal every key press is a command. Otherwise stdin is treated as a text
+/// control channel with one command per line (see `Command::from_line`), which lets scripts
+/// drive playback through a pipe or FIFO. The thread ends when stdin closes or the conductor
+/// has gone away.
+pub fn spawn_controls(commands: Sender<Command>, raw: bool) {
+    thread::spawn(move || {
+        let stdin = io::stdin().lock();
+        if raw {
+            let mut bytes = stdin.bytes().map_while(Result::ok);
+            // `m` and `s` wait for a channel key before they become a command.
+            let mut prefix = None;
+            while let Some(key) = read_key(&mut bytes) {
+                let cmd = match (prefix.take(), key) {
+                    (Some('m'), Key::Char(c)) => channel_key(c).map(|ch| Command::Mute(ch, Switch::Toggle)),
+                    (Some('s'), Key::Char(c)) => channel_key(c).map(|ch| Command::Solo(ch, Switch::Toggle)),
+                    (_, Key::Char(c @ ('m' | 's'))) => {
+                        prefix = Some(c);
+                        None
+                    }
+                    (_, key) => Command::from_key(key),
+                };
+                if let Some(cmd) = cmd
+                    && commands.send(cmd).is_err()
+                {
+                    break;
+                }
+            }
+        } else {
+            for line in stdin.lines().map_while(Result::ok) {
+                match Command::from_line(&line) {
+                    Some(cmd) => {
+                        if commands.send(cmd).is_err() {
+                            break;
+                        }
+                    }
+                    None if line.trim().is_empty() => {}
+                    None => warn!("Unknown command: {}", line.trim()),
+                }
+            }
+        }
+    });
+}
+
+/// Decode one key press from raw terminal input, including ANSI arrow key sequences
+/// (`ESC [ A` … `ESC [ D`). Returns `None` at end of input.
+fn read_key(bytes: &mut impl Iterator<Item = u8>) -> Option<Key> {
+    let b = bytes.next()?;
+    if b != 0x1b {
+        return Some(Key::Char(b as char));
+    }
+    if bytes.next()? != b'[' {
+        return Some(Key::Char('\x1b'));
+    }
+    Some(match bytes.next()? {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        other => Key::Char(other as char),
+    })
+}
+
+/// Puts the terminal into unbuffered, no-echo mode so single key presses reach us
+/// without waiting for Enter. The previous settings are restored on drop.
+#[cfg(unix)]
+pub struct RawTerminal {
+    saved: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawTerminal {
+    pub fn enable() -> Option<Self> {
+        // SAFETY: termios is plain old data and only touched through the libc calls below.
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return None;
+            }
+            let mut t: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut t) != 0 {
+                return None;
+            }
+            let saved = t;
+            t.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            t.c_cc[libc::VMIN] = 1;
+            t.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &t) != 0 {
+                return None;
+            }
+            Some(Self { saved })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings captured in `enable`.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+/// Other platforms keep the line-buffered console: type the key, then press Enter.
+#[cfg(not(unix))]
+pub struct RawTerminal;
+
+#[cfg(not(unix))]
+impl RawTerminal {
+    pub fn enable() -> Option<Self> {
+        None
+    }
+}
+
+/// Parse a 1-based channel number (1–16) into a 0-based channel.
+fn parse_channel(s: &str) -> Option<u8> {
+    match s.parse::<u8>() {
+        Ok(ch @ 1..=16) => Some(ch - 1),
+        _ => None,
+    }
+}
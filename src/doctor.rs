@@ -0,0 +1,218 @@
+//! The `doctor` subcommand: check the audio device and SoundFont and report what is wrong
+//! when there is no sound.
+
+use crate::output::{self, OutputArgs};
+use crate::audio::{self, AudioArgs};
+use crate::{soundfont, synth};
+use anyhow::{Context, Result};
+use clap::Args;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{SampleFormat, SizedSample};
+use serde_json::{Value, json};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::error;
+
+/// `doctor` options.
+#[derive(Args, Debug)]
+pub struct DoctorArgs {
+    /// SoundFont to check; found the same way as for `play` if left out
+    #[arg(value_name = "FONT.sf2")]
+    soundfont: Option<String>,
+    #[command(flatten)]
+    audio: AudioArgs,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// How long the test stream runs while its callbacks are timed.
+const PROBE_TIME: Duration = Duration::from_millis(500);
+
+/// Output latency above this makes the controls feel sluggish.
+const MAX_LATENCY: Duration = Duration::from_millis(100);
+
+/// The results so far, printed as each check finishes.
+#[derive(Default)]
+struct Report {
+    checks: Vec<Value>,
+    failed: usize,
+}
+
+impl Report {
+    fn add(&mut self, name: &'static str, result: Result<String>) {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, format!("{e:#}")),
+        };
+        if !ok {
+            self.failed += 1;
+        }
+        if !output::is_json() {
+            println!("[{}] {name}: {detail}", if ok { "PASS" } else { "FAIL" });
+        }
+        self.checks.push(json!({ "check": name, "ok": ok, "detail": detail }));
+    }
+}
+
+/// What the test stream saw.
+#[derive(Default)]
+struct Probe {
+    callbacks: u32,
+    max_frames: usize,
+    max_latency: Option<Duration>,
+}
+
+/// Run every check and print a pass/fail line for each. Fails if any check did.
+pub fn run(opt: DoctorArgs) -> Result<()> {
+    let mut report = Report::default();
+
+    match audio::output_device(&opt.audio) {
+        Err(e) => report.add("Output device", Err(e)),
+        Ok((dev, cfg)) => {
+            let name = dev.name().unwrap_or_else(|_| "(unknown)".to_string());
+            let host = opt.audio.host.as_deref().unwrap_or(cpal::default_host().id().name()).to_string();
+            report.add("Output device", Ok(format!("{name} on {host}")));
+            report.add("Sample format", check_format(&dev, &cfg));
+            let frames = opt.audio.buffer_size;
+            let probe = match cfg.sample_format() {
+                SampleFormat::I8 => probe_stream::<i8>(&dev, &cfg, frames),
+                SampleFormat::I16 => probe_stream::<i16>(&dev, &cfg, frames),
+                SampleFormat::I32 => probe_stream::<i32>(&dev, &cfg, frames),
+                SampleFormat::I64 => probe_stream::<i64>(&dev, &cfg, frames),
+                SampleFormat::U8 => probe_stream::<u8>(&dev, &cfg, frames),
+                SampleFormat::U16 => probe_stream::<u16>(&dev, &cfg, frames),
+                SampleFormat::U32 => probe_stream::<u32>(&dev, &cfg, frames),
+                SampleFormat::U64 => probe_stream::<u64>(&dev, &cfg, frames),
+                SampleFormat::F64 => probe_stream::<f64>(&dev, &cfg, frames),
+                _ => probe_stream::<f32>(&dev, &cfg, frames),
+            };
+            match probe {
+                Err(e) => report.add("Open stream", Err(e)),
+                Ok(probe) => {
+                    let opened = if probe.callbacks == 0 {
+                        Err(anyhow::anyhow!("the stream opened but the device never asked for audio"))
+                    } else {
+                        Ok(format!("{} callbacks in {} ms", probe.callbacks, PROBE_TIME.as_millis()))
+                    };
+                    let ran = opened.is_ok();
+                    report.add("Open stream", opened);
+                    if ran {
+                        report.add("Latency", check_latency(&probe, cfg.sample_rate().0));
+                    }
+                }
+            }
+        }
+    }
+
+    match soundfont::resolve(opt.soundfont.as_deref()) {
+        Err(e) => report.add("SoundFont", Err(e)),
+        Ok(path) => {
+            let loaded = synth::open(std::slice::from_ref(&path), 0.7, 44_100.0, Default::default()).map(|_| path.clone());
+            let ok = loaded.is_ok();
+            report.add("SoundFont", loaded);
+            if ok {
+                report.add("Presets", check_presets(&path));
+            }
+        }
+    }
+
+    let total = report.checks.len();
+    if output::is_json() {
+        output::print(&json!({ "checks": report.checks, "failed": report.failed }));
+    }
+    if report.failed > 0 {
+        anyhow::bail!("{} of {total} checks failed", report.failed);
+    }
+    if !output::is_json() {
+        println!("All {total} checks passed.");
+    }
+    Ok(())
+}
+
+/// The player converts to every integer and float format CPAL knows, but the stream's
+/// format must still be one of them.
+fn check_format(dev: &cpal::Device, cfg: &cpal::SupportedStreamConfig) -> Result<String> {
+    let mut formats: Vec<String> = dev
+        .supported_output_configs()
+        .context("listing supported configs")?
+        .map(|c| c.sample_format().to_string())
+        .collect();
+    formats.sort();
+    formats.dedup();
+    let detail = format!(
+        "using {} Hz, {} ch, {}; supported: {}",
+        cfg.sample_rate().0,
+        cfg.channels(),
+        cfg.sample_format(),
+        formats.join(", ")
+    );
+    if !audio::is_supported(cfg.sample_format()) {
+        anyhow::bail!("{detail}; {} samples cannot be played", cfg.sample_format());
+    }
+    Ok(detail)
+}
+
+/// Play silence for `PROBE_TIME`, counting callbacks and their size and latency.
+fn probe_stream<T: SizedSample + Send + 'static>(
+    dev: &cpal::Device,
+    cfg: &cpal::SupportedStreamConfig,
+    buffer_frames: Option<u32>,
+) -> Result<Probe> {
+    let probe = Arc::new(Mutex::new(Probe::default()));
+    let seen = Arc::clone(&probe);
+    let channels = cfg.channels() as usize;
+    let stream = dev
+        .build_output_stream(
+            &audio::stream_config(cfg, buffer_frames),
+            move |out: &mut [T], info: &cpal::OutputCallbackInfo| {
+                out.fill(T::EQUILIBRIUM);
+                let ts = info.timestamp();
+                let mut p = seen.lock().unwrap();
+                p.callbacks += 1;
+                p.max_frames = p.max_frames.max(out.len() / channels);
+                if let Some(latency) = ts.playback.duration_since(&ts.callback) {
+                    p.max_latency = p.max_latency.max(Some(latency));
+                }
+            },
+            |e| error!("stream error: {e}"),
+            None,
+        )
+        .context("building the output stream")?;
+    stream.play().context("starting the output stream")?;
+    thread::sleep(PROBE_TIME);
+    drop(stream);
+    Ok(std::mem::take(&mut *probe.lock().unwrap()))
+}
+
+/// Latency is the larger of the callback period and what the device reports.
+fn check_latency(probe: &Probe, sample_rate: u32) -> Result<String> {
+    let buffer = Duration::from_secs_f64(probe.max_frames as f64 / f64::from(sample_rate));
+    let latency = probe.max_latency.unwrap_or(buffer).max(buffer);
+    let detail = format!(
+        "{} frames per callback ({:.1} ms), {:.1} ms to the speakers",
+        probe.max_frames,
+        buffer.as_secs_f64() * 1000.0,
+        latency.as_secs_f64() * 1000.0
+    );
+    if latency > MAX_LATENCY {
+        anyhow::bail!("{detail}; over {} ms, so controls will lag", MAX_LATENCY.as_millis());
+    }
+    Ok(detail)
+}
+
+/// A General MIDI file needs the 128 melodic presets in bank 0, and drums in bank 128.
+fn check_presets(path: &str) -> Result<String> {
+    let presets = soundfont::presets(path)?;
+    let (missing, drums) = soundfont::missing_gm(&presets);
+    let detail = format!(
+        "{} presets, {} of 128 General MIDI instruments, {}",
+        presets.len(),
+        128 - missing.len(),
+        if drums { "drum kit present" } else { "no drum kit" }
+    );
+    if presets.is_empty() {
+        anyhow::bail!("{detail}; nothing can play");
+    }
+    Ok(detail)
+}
@@ -0,0 +1,192 @@
+//! The `export-midi` subcommand: write a song back out as a Standard MIDI File with the
+//! changes `play` would make to it (transposed, channels muted or soloed, tracks left out,
+//! sped up or slowed down), for use in other programs.
+//!
+//! The file is changed event by event in its own ticks, rather than rebuilt from the
+//! timeline `play` uses, so its resolution, track layout and meta events (names, lyrics,
+//! markers, signatures) come through as they were.
+
+use crate::conductor::{Mixer, parse_speed};
+use crate::lenient;
+use crate::sidecar;
+use crate::song::{DrumChannels, SongArgs, parse_drum_channels, select_tracks, track_name, transpose_key};
+use crate::synth::EffectsArgs;
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use midly::num::{u7, u24, u28};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use std::{fs, path::PathBuf};
+use tracing::{info, warn};
+
+/// `export-midi` options:
+/// - midi: the file to read
+/// - output: the file to write
+/// - transpose, drum_channels, mute_channel, solo_channel, tracks, no_sidecar, lenient: as
+///   for `play`
+/// - speed: tempo multiplier, written into the tempo changes
+#[derive(Args, Debug)]
+pub struct ExportMidiArgs {
+    /// Path to .mid file
+    midi: String,
+    /// Write the changed song to this file
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+    /// Transpose every channel except percussion (channel 10) by this many semitones
+    #[arg(long, value_name = "SEMITONES", default_value_t = 0, allow_negative_numbers = true,
+          value_parser = clap::value_parser!(i8).range(-48..=48))]
+    transpose: i8,
+    /// The channels transposing leaves alone as drums (1–16, comma separated), or none
+    #[arg(long, value_name = "CH,...|none", value_parser = parse_drum_channels)]
+    drum_channels: Option<DrumChannels>,
+    /// Leave out the notes of these MIDI channels (1–16, comma separated)
+    #[arg(long, value_name = "CH,...", value_delimiter = ',', value_parser = clap::value_parser!(u8).range(1..=16))]
+    mute_channel: Vec<u8>,
+    /// Keep only the notes of these MIDI channels (1–16, comma separated)
+    #[arg(long, value_name = "CH,...", value_delimiter = ',', value_parser = clap::value_parser!(u8).range(1..=16))]
+    solo_channel: Vec<u8>,
+    /// Keep only these tracks, by number (1 = first track) or TrackName (comma separated);
+    /// the others keep just their meta events, so tempo changes and markers stay
+    #[arg(long, value_name = "TRACK,...", value_delimiter = ',')]
+    tracks: Vec<String>,
+    /// Speed the song up or slow it down by this factor, e.g. 0.75, by scaling every tempo
+    #[arg(long, value_name = "FACTOR", value_parser = parse_speed)]
+    speed: Option<f64>,
+    /// Ignore the settings in SONG.mid.toml next to the MIDI file
+    #[arg(long)]
+    no_sidecar: bool,
+    /// Read a damaged file anyway, keeping whatever can be read; the copy written is sound
+    #[arg(long)]
+    lenient: bool,
+}
+
+/// The slowest tempo a file can hold, in microseconds per quarter note.
+const MAX_TEMPO: u32 = 0xff_ffff;
+
+/// Read the song, make the changes asked for and write it to the output file.
+pub fn run(opt: ExportMidiArgs) -> Result<()> {
+    let mut song = SongArgs {
+        midi: opt.midi,
+        soundfont: None,
+        soundfonts: Vec::new(),
+        transpose: opt.transpose,
+        mute_channel: opt.mute_channel,
+        solo_channel: opt.solo_channel,
+        tracks: opt.tracks,
+        programs: Vec::new(),
+        no_sidecar: opt.no_sidecar,
+        lenient: opt.lenient,
+        effects: EffectsArgs { drum_channels: opt.drum_channels, ..Default::default() },
+    };
+    let drums = song.effects.drum_channels.unwrap_or_default();
+    let sidecar = sidecar::load_for(&mut song)?;
+    let speed = opt.speed.or_else(|| sidecar.and_then(|sc| sc.speed)).unwrap_or(1.0);
+
+    let bytes = fs::read(&song.midi).with_context(|| "reading MIDI file")?;
+    let mut smf = if song.lenient {
+        lenient::parse(&bytes).with_context(|| "parsing MIDI")?
+    } else {
+        Smf::parse(&bytes).with_context(|| "parsing MIDI (--lenient reads what it can of a damaged file)")?
+    };
+    let names: Vec<Option<String>> = smf.tracks.iter().map(|tr| track_name(tr)).collect();
+    let included = select_tracks(&names, &song.tracks)?;
+    let mixer = Mixer::new(&song.mute_channel, &song.solo_channel);
+    if speed != 1.0 && matches!(smf.header.timing, Timing::Timecode(..)) {
+        bail!("the file is timed in SMPTE frames rather than by tempo, so --speed cannot change it");
+    }
+
+    let mut dropped = 0usize;
+    for (track, &include) in smf.tracks.iter_mut().zip(&included) {
+        let mut kept: Vec<TrackEvent> = Vec::with_capacity(track.len());
+        // Ticks of the events left out since the last one kept, added to the next one's delta.
+        let mut carried = 0u32;
+        for ev in track.drain(..) {
+            let Some(kind) = change(ev.kind, include, &mixer, song.transpose, drums, speed) else {
+                carried = carried.saturating_add(ev.delta.as_int());
+                dropped += 1;
+                continue;
+            };
+            let delta = carried.saturating_add(ev.delta.as_int());
+            let delta = u28::try_from(delta).context("a gap between events is too long to write")?;
+            kept.push(TrackEvent { delta, kind });
+            carried = 0;
+        }
+        *track = kept;
+    }
+    if speed != 1.0 && !has_tempo_at_start(&smf) {
+        // Without a tempo at the start the file plays at 120 BPM until its first change.
+        let tempo = u24::new(((500_000.0 / speed).round() as u32).min(MAX_TEMPO));
+        let kind = TrackEventKind::Meta(MetaMessage::Tempo(tempo));
+        if let Some(first) = smf.tracks.first_mut() {
+            first.insert(0, TrackEvent { delta: u28::new(0), kind });
+        }
+    }
+
+    if !song.mute_channel.is_empty() || !song.solo_channel.is_empty() {
+        info!("{mixer}");
+    }
+    if song.transpose != 0 {
+        info!("Transposed by {:+} semitones", song.transpose);
+    }
+    if speed != 1.0 {
+        info!("Speed: {:.0}%", speed * 100.0);
+    }
+    if dropped > 0 {
+        info!("Left out {dropped} events");
+    }
+    smf.save(&opt.output).with_context(|| format!("writing {}", opt.output.display()))?;
+    info!("Wrote {}", opt.output.display());
+    Ok(())
+}
+
+/// An event as it is to be written, or `None` to leave it out. Tracks that are not
+/// `included` keep only their meta events, and the notes (and key aftertouch) of channels
+/// `mixer` silences are left out; their programs and controllers stay. Notes are
+/// transposed on every channel but the `drums`.
+fn change<'a>(
+    kind: TrackEventKind<'a>,
+    included: bool,
+    mixer: &Mixer,
+    transpose: i8,
+    drums: DrumChannels,
+    speed: f64,
+) -> Option<TrackEventKind<'a>> {
+    match kind {
+        TrackEventKind::Meta(MetaMessage::Tempo(tempo)) if speed != 1.0 => {
+            let us_per_qn = (f64::from(tempo.as_int()) / speed).round() as u32;
+            if us_per_qn > MAX_TEMPO {
+                warn!("A tempo this slow cannot be written; the slowest a file can hold is used instead");
+            }
+            Some(TrackEventKind::Meta(MetaMessage::Tempo(u24::new(us_per_qn.min(MAX_TEMPO)))))
+        }
+        TrackEventKind::Meta(_) => Some(kind),
+        _ if !included => None,
+        TrackEventKind::Midi { channel, message } => {
+            let ch = channel.as_int();
+            let key = |key: u7| u7::new(transpose_key(drums, ch, key.as_int(), transpose));
+            let message = match message {
+                MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. } | MidiMessage::Aftertouch { .. }
+                    if !mixer.audible(ch) =>
+                {
+                    return None;
+                }
+                _ if transpose == 0 || drums.contains(ch) => message,
+                MidiMessage::NoteOn { key: k, vel } => MidiMessage::NoteOn { key: key(k), vel },
+                MidiMessage::NoteOff { key: k, vel } => MidiMessage::NoteOff { key: key(k), vel },
+                MidiMessage::Aftertouch { key: k, vel } => MidiMessage::Aftertouch { key: key(k), vel },
+                other => other,
+            };
+            Some(TrackEventKind::Midi { channel, message })
+        }
+        _ => Some(kind),
+    }
+}
+
+/// Whether some track sets the tempo before the first tick has passed.
+fn has_tempo_at_start(smf: &Smf) -> bool {
+    smf.tracks.iter().any(|track| {
+        track
+            .iter()
+            .take_while(|ev| ev.delta.as_int() == 0)
+            .any(|ev| matches!(ev.kind, TrackEventKind::Meta(MetaMessage::Tempo(_))))
+    })
+}
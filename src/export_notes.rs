@@ -0,0 +1,128 @@
+//! The `export-notes` subcommand: list every note in a MIDI file, one row each, for
+//! analysis in a spreadsheet or a script.
+//!
+//! Notes are found by pairing each Note On with the next Note Off (or Note On at velocity 0)
+//! for the same key and channel in the same track, oldest first when a key is struck again
+//! before it is released. Times come from the tempo map of the whole file.
+
+use crate::output::{self, OutputArgs};
+use crate::tempo::TempoMap;
+use anyhow::{Context, Result};
+use clap::Args;
+use midly::{MidiMessage, Smf, TrackEventKind};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use tracing::warn;
+
+/// `export-notes` options:
+/// - midi: the file to read
+/// - output: CSV (`text`) or one JSON object per note (`json`)
+#[derive(Args, Debug)]
+pub struct ExportNotesArgs {
+    /// Path to .mid file
+    midi: String,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// One note, in ticks.
+pub struct Note {
+    pub start: u64,
+    pub end: u64,
+    /// 0–15.
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8,
+    /// 0-based.
+    pub track: usize,
+}
+
+/// Print the file's notes on stdout in the order they start.
+pub fn run(opt: ExportNotesArgs) -> Result<()> {
+    let bytes = fs::read(&opt.midi).with_context(|| "reading MIDI file")?;
+    let smf = Smf::parse(&bytes).with_context(|| "parsing MIDI")?;
+    let tempo = TempoMap::new(&smf);
+    let notes = notes(&smf);
+
+    match write_notes(&notes, &tempo) {
+        // Stopped reading, as `head` does: the rest is not wanted.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.with_context(|| "writing the notes"),
+    }
+}
+
+/// Pair up the Note Ons and Note Offs of every track, ordered by start. Notes still sounding
+/// when their track ends last until the end of the song.
+pub fn notes(smf: &Smf) -> Vec<Note> {
+    let mut notes = Vec::new();
+    let mut unreleased = Vec::new();
+    let mut end_tick = 0u64;
+    for (track, events) in smf.tracks.iter().enumerate() {
+        // Start tick and velocity of the notes held on each (channel, key), oldest first.
+        let mut held: HashMap<(u8, u8), VecDeque<(u64, u8)>> = HashMap::new();
+        let mut tick = 0u64;
+        for ev in events {
+            tick += u64::from(ev.delta.as_int());
+            let TrackEventKind::Midi { channel, message } = ev.kind else { continue };
+            let channel = channel.as_int();
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                    held.entry((channel, key.as_int())).or_default().push_back((tick, vel.as_int()));
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    let key = key.as_int();
+                    if let Some((start, velocity)) = held.get_mut(&(channel, key)).and_then(VecDeque::pop_front) {
+                        notes.push(Note { start, end: tick, channel, key, velocity, track });
+                    }
+                }
+                _ => {}
+            }
+        }
+        end_tick = end_tick.max(tick);
+        for ((channel, key), starts) in held {
+            unreleased.extend(starts.into_iter().map(|(start, velocity)| (start, channel, key, velocity, track)));
+        }
+    }
+
+    if !unreleased.is_empty() {
+        warn!("{} notes are never released; they last until the end of the song", unreleased.len());
+    }
+    notes.extend(
+        unreleased
+            .into_iter()
+            .map(|(start, channel, key, velocity, track)| Note { start, end: end_tick, channel, key, velocity, track }),
+    );
+    notes.sort_by_key(|n| (n.start, n.track, n.channel, n.key));
+    notes
+}
+
+/// Write the notes as CSV with a header, or as one JSON object per line. Times are in
+/// seconds; channels and tracks count from 1, as elsewhere.
+fn write_notes(notes: &[Note], tempo: &TempoMap) -> io::Result<()> {
+    let secs = |us: u64| us as f64 / 1_000_000.0;
+    let mut out = BufWriter::new(io::stdout().lock());
+    if !output::is_json() {
+        writeln!(out, "start,duration,channel,key,velocity,track")?;
+    }
+    for note in notes {
+        let start_us = tempo.to_us(note.start);
+        let (start, duration) = (secs(start_us), secs(tempo.to_us(note.end) - start_us));
+        if output::is_json() {
+            let obj = json!({
+                "start": start,
+                "duration": duration,
+                "channel": note.channel + 1,
+                "key": note.key,
+                "velocity": note.velocity,
+                "track": note.track + 1,
+            });
+            writeln!(out, "{obj}")?;
+        } else {
+            let (channel, key, velocity, track) = (note.channel + 1, note.key, note.velocity, note.track + 1);
+            writeln!(out, "{start:.6},{duration:.6},{channel},{key},{velocity},{track}")?;
+        }
+    }
+    out.flush()
+}
@@ -0,0 +1,135 @@
+//! The presets a song asks for that its SoundFonts lack, and what the synth plays instead:
+//! worked out ahead of time, so the player can say why the sax sounds like a piano.
+
+use crate::song::{DRUM_CHANNEL, GM_PROGRAMS, Msg, Song};
+use crate::soundfont;
+use crate::time::format_duration;
+use std::fmt;
+use tracing::{debug, warn};
+
+/// What the synth plays for a missing preset. Both engines fall back as FluidSynth does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fallback {
+    /// The same program in bank 0, for a variation bank the font does not have.
+    Bank0,
+    /// The first preset of bank 0, a piano in a General MIDI font.
+    Piano,
+    /// The standard drum kit (bank 128, program 0), for a missing kit.
+    StandardKit,
+    /// Nothing: the channel is silent until its next Program Change.
+    Silence,
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Fallback::Bank0 => "the same program in bank 0",
+            Fallback::Piano => "the default piano (bank 0, program 0)",
+            Fallback::StandardKit => "the standard drum kit",
+            Fallback::Silence => "nothing, so the channel is silent",
+        })
+    }
+}
+
+/// A preset a channel asks for and the font lacks.
+pub struct Missing {
+    /// When it is first asked for.
+    pub t_us: u64,
+    /// 0–15
+    pub channel: u8,
+    pub bank: u16,
+    pub program: u8,
+    pub fallback: Fallback,
+    /// How many Program Changes ask for it.
+    pub times: usize,
+}
+
+impl Missing {
+    /// The preset asked for, by its General MIDI name.
+    fn name(&self) -> String {
+        match self.bank {
+            128 => format!("drum kit {}", self.program),
+            0 => format!("program {} ({})", self.program, GM_PROGRAMS[self.program as usize]),
+            bank => format!("bank {bank}, program {} ({} variation)", self.program, GM_PROGRAMS[self.program as usize]),
+        }
+    }
+}
+
+/// The presets the song's Program Changes ask for that `presets`, the (bank, program) pairs
+/// of every SoundFont loaded, lack, once per channel, bank and program, in order of time.
+/// Channel 10 has the drum kits whatever bank it selects, unless it is not a drum channel.
+pub fn find(song: &Song, presets: &[(u16, u16)]) -> Vec<Missing> {
+    let has = |bank: u16, program: u8| presets.binary_search(&(bank, u16::from(program))).is_ok();
+    let drums = song.drums.contains(DRUM_CHANNEL);
+    let mut banks = [0u16; 16];
+    let mut missing: Vec<Missing> = Vec::new();
+    for ev in &song.timeline {
+        match ev.msg {
+            Msg::Reset(_) => banks = [0; 16],
+            Msg::Bank(ch, bank) => banks[ch as usize] = bank,
+            Msg::Program(ch, program) => {
+                let bank = if ch == DRUM_CHANNEL && drums { 128 } else { banks[ch as usize] };
+                if has(bank, program) {
+                    continue;
+                }
+                if let Some(m) = missing.iter_mut().find(|m| (m.channel, m.bank, m.program) == (ch, bank, program)) {
+                    m.times += 1;
+                    continue;
+                }
+                let fallback = match bank {
+                    128 if has(128, 0) => Fallback::StandardKit,
+                    128 => Fallback::Silence,
+                    _ if has(0, program) => Fallback::Bank0,
+                    _ if program != 0 && has(0, 0) => Fallback::Piano,
+                    _ => Fallback::Silence,
+                };
+                missing.push(Missing { t_us: ev.t_us, channel: ch, bank, program, fallback, times: 1 });
+            }
+            _ => {}
+        }
+    }
+    missing
+}
+
+/// Find the presets `song` lacks in `soundfonts` and warn about each. A font whose presets
+/// cannot be read is left to the synth to complain about.
+pub fn check(song: &Song, soundfonts: &[String]) -> Vec<Missing> {
+    let presets = match soundfont::preset_numbers(soundfonts) {
+        Ok(presets) => presets,
+        Err(e) => {
+            debug!("Not checking for missing presets: {e:#}");
+            return Vec::new();
+        }
+    };
+    let missing = find(song, &presets);
+    for m in &missing {
+        warn!(
+            "{} channel {}: the SoundFont has no {}; playing {}",
+            format_duration(m.t_us),
+            m.channel + 1,
+            m.name(),
+            m.fallback
+        );
+    }
+    missing
+}
+
+/// Sum up the missing presets once the song is over, so the warnings are not lost in what
+/// scrolled past while it played.
+pub fn summarize(missing: &[Missing]) {
+    if missing.is_empty() {
+        return;
+    }
+    warn!("{} preset(s) the song asks for are missing from the SoundFont:", missing.len());
+    for m in missing {
+        let times = if m.times > 1 { format!(", asked for {} times", m.times) } else { String::new() };
+        warn!(
+            "  channel {}: {}, first at {}{times}; played {}",
+            m.channel + 1,
+            m.name(),
+            format_duration(m.t_us),
+            m.fallback
+        );
+    }
+    warn!("`midi-play soundfont info` lists the presets the SoundFont has");
+}
@@ -0,0 +1,244 @@
+//! The FluidLite engine: FluidSynth's C core, cut down, as a `Synthesizer`.
+//!
+//! It is driven through `fluidlite-sys` rather than the `fluidlite` wrapper, which keeps its
+//! `fluid_synth_t` to itself and leaves the interpolation methods unexported.
+
+use crate::song::DRUM_CHANNEL;
+use crate::synth::{Chorus, DEFAULT_CHORUS, DEFAULT_REVERB, EffectsArgs, Interp, Reverb, Synth, Synthesizer};
+use anyhow::{Result, bail};
+use fluidlite_sys as ffi;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_int, c_void};
+use tracing::debug;
+
+/// A FluidLite synth, and the interpolation to restore after a system reset, which puts
+/// every channel back to FluidLite's default.
+///
+/// The synth is created in `open` and deleted, with its settings, on drop. FluidLite is not
+/// thread-safe, but a `Fluid` is `Send` and not `Sync`, so one thread at a time calls it.
+pub struct Fluid {
+    synth: *mut ffi::fluid_synth_t,
+    /// The method `--interp` chose.
+    interp: ffi::fluid_interp,
+    /// Room for a pointer to every voice, for `voices` to count the ones playing without
+    /// allocating in the audio callback.
+    voice_list: RefCell<Vec<*mut ffi::fluid_voice_t>>,
+}
+
+// SAFETY: FluidLite keeps no thread-local state, so the synth can move between threads.
+unsafe impl Send for Fluid {}
+
+/// FluidLite's number for the method.
+fn interp_code(interp: Interp) -> ffi::fluid_interp {
+    match interp {
+        Interp::None => ffi::fluid_interp_FLUID_INTERP_NONE,
+        Interp::Linear => ffi::fluid_interp_FLUID_INTERP_LINEAR,
+        Interp::Fourth => ffi::fluid_interp_FLUID_INTERP_4THORDER,
+        Interp::Seventh => ffi::fluid_interp_FLUID_INTERP_7THORDER,
+    }
+}
+
+/// Create a FluidLite synth, as `synth::open` describes. With `--no-effects` FluidLite is
+/// told before it starts, so it mixes no effect sends.
+pub fn open(soundfonts: &[String], gain: f32, sample_rate: f32, effects: EffectsArgs) -> Result<Synth> {
+    // SAFETY: the settings are FluidLite's own, and are handed to the synth, or deleted if
+    // there is none to take them.
+    let synth = unsafe {
+        let settings = ffi::new_fluid_settings();
+        if settings.is_null() {
+            bail!("FluidLite could not create its settings");
+        }
+        if let Some(polyphony) = effects.polyphony {
+            ffi::fluid_settings_setint(settings, c"synth.polyphony".as_ptr(), polyphony.into());
+        }
+        // Channel 10 plays drums unless left out of `--drum-channels`; the others are put on
+        // the percussion bank by the song.
+        if !effects.drum_channels.unwrap_or_default().contains(DRUM_CHANNEL) {
+            ffi::fluid_settings_setstr(settings, c"synth.drums-channel.active".as_ptr(), c"no".as_ptr());
+        }
+        if effects.no_effects {
+            for name in [c"synth.reverb.active", c"synth.chorus.active"] {
+                ffi::fluid_settings_setstr(settings, name.as_ptr(), c"no".as_ptr());
+            }
+        }
+        let synth = ffi::new_fluid_synth(settings);
+        if synth.is_null() {
+            ffi::delete_fluid_settings(settings);
+            bail!("FluidLite could not create a synth");
+        }
+        synth
+    };
+    // SAFETY: a plain call on the synth just made.
+    let polyphony = usize::try_from(unsafe { ffi::fluid_synth_get_polyphony(synth) }).unwrap_or(0);
+    let mut fluid = Fluid {
+        synth,
+        interp: interp_code(effects.interp.unwrap_or(Interp::Fourth)),
+        voice_list: RefCell::new(vec![std::ptr::null_mut(); polyphony]),
+    };
+
+    for soundfont in soundfonts.iter().rev() {
+        let Ok(path) = CString::new(soundfont.as_str()) else {
+            bail!("loading SoundFont {soundfont}: the path has a NUL byte in it");
+        };
+        // SAFETY: the path is a C string that outlives the call.
+        let id = unsafe { ffi::fluid_synth_sfload(fluid.synth, path.as_ptr(), 1) };
+        if id < 0 {
+            bail!("loading SoundFont {soundfont}: FluidLite could not read it");
+        }
+        debug!("Loaded SoundFont: {} (id={})", soundfont, id);
+    }
+
+    fluid.set_gain(gain);
+
+    let Reverb { room, damp, width, level } = effects.reverb_params.unwrap_or(DEFAULT_REVERB);
+    let reverb = !effects.no_effects && effects.reverb.unwrap_or(true);
+    let Chorus { voices, level: chorus_level, speed, depth } = effects.chorus_params.unwrap_or(DEFAULT_CHORUS);
+    let chorus = !effects.no_effects && effects.chorus.unwrap_or(true);
+    // SAFETY: plain calls on the synth `fluid` owns.
+    unsafe {
+        ffi::fluid_synth_set_reverb_on(fluid.synth, reverb.into());
+        ffi::fluid_synth_set_reverb(fluid.synth, room, damp, width, level);
+        ffi::fluid_synth_set_chorus_on(fluid.synth, chorus.into());
+        let sine = ffi::fluid_chorus_mod_FLUID_CHORUS_MOD_SINE as c_int;
+        ffi::fluid_synth_set_chorus(fluid.synth, voices as c_int, chorus_level, speed, depth, sine);
+    }
+
+    // Tell FluidLite the output sample rate so it renders at the correct rate.
+    fluid.set_sample_rate(sample_rate);
+
+    // clean start
+    fluid.reset();
+    Ok(Box::new(fluid))
+}
+
+impl Fluid {
+    /// Render `len` frames, the left channel from `left` and the right from `right`, each
+    /// a sample every `step`.
+    ///
+    /// # Safety
+    ///
+    /// Both must have room for `len` frames at that spacing.
+    unsafe fn write(&mut self, len: usize, left: *mut f32, right: *mut f32, step: c_int) -> Result<()> {
+        let (left, right) = (left.cast::<c_void>(), right.cast::<c_void>());
+        // SAFETY: as the caller promises.
+        let status = unsafe { ffi::fluid_synth_write_float(self.synth, len as c_int, left, 0, step, right, 0, step) };
+        if status != ffi::FLUID_OK {
+            bail!("fluid write: {}", self.error());
+        }
+        Ok(())
+    }
+
+    /// FluidLite's last error message.
+    fn error(&self) -> String {
+        // SAFETY: FluidLite keeps the message in a NUL-terminated buffer of its own.
+        unsafe { CStr::from_ptr(ffi::fluid_synth_error(self.synth)) }.to_string_lossy().into_owned()
+    }
+}
+
+// SAFETY, for the calls below: the synth is FluidLite's, alive until `drop`, and the
+// `&mut self` or `&self` each holds means no other call is under way.
+impl Synthesizer for Fluid {
+    fn note_on(&mut self, ch: u8, key: u8, vel: u8) {
+        unsafe { ffi::fluid_synth_noteon(self.synth, ch.into(), key.into(), vel.into()) };
+    }
+
+    fn note_off(&mut self, ch: u8, key: u8) {
+        unsafe { ffi::fluid_synth_noteoff(self.synth, ch.into(), key.into()) };
+    }
+
+    fn program(&mut self, ch: u8, program: u8) {
+        unsafe { ffi::fluid_synth_program_change(self.synth, ch.into(), program.into()) };
+    }
+
+    fn cc(&mut self, ch: u8, cc: u8, value: u8) {
+        unsafe { ffi::fluid_synth_cc(self.synth, ch.into(), cc.into(), value.into()) };
+    }
+
+    // FluidLite falls back to bank 0 at the Program Change if the bank lacks the program.
+    fn bank(&mut self, ch: u8, bank: u16) {
+        unsafe { ffi::fluid_synth_bank_select(self.synth, ch.into(), bank.into()) };
+    }
+
+    fn bend_range(&mut self, ch: u8, semitones: u8) {
+        unsafe { ffi::fluid_synth_pitch_wheel_sens(self.synth, ch.into(), semitones.into()) };
+    }
+
+    fn pitch_bend(&mut self, ch: u8, bend: u16) {
+        unsafe { ffi::fluid_synth_pitch_bend(self.synth, ch.into(), bend.into()) };
+    }
+
+    fn key_pressure(&mut self, ch: u8, key: u8, value: u8) {
+        unsafe { ffi::fluid_synth_key_pressure(self.synth, ch.into(), key.into(), value.into()) };
+    }
+
+    fn channel_pressure(&mut self, ch: u8, value: u8) {
+        unsafe { ffi::fluid_synth_channel_pressure(self.synth, ch.into(), value.into()) };
+    }
+
+    fn reset(&mut self) {
+        unsafe {
+            ffi::fluid_synth_system_reset(self.synth);
+            // -1 sets every channel.
+            ffi::fluid_synth_set_interp_method(self.synth, -1, self.interp as c_int);
+        }
+    }
+
+    // FluidLite takes the message without its 0xF0 and 0xF7, and acts on MIDI Tuning only.
+    fn sysex(&mut self, data: &'static [u8]) {
+        let Some(body) = data.get(1..data.len() - 1).filter(|body| !body.is_empty()) else {
+            return;
+        };
+        let (data, len) = (body.as_ptr().cast(), body.len() as c_int);
+        use std::ptr::null_mut;
+        unsafe { ffi::fluid_synth_sysex(self.synth, data, len, null_mut(), null_mut(), null_mut(), 0) };
+    }
+
+    fn gain(&self) -> f32 {
+        unsafe { ffi::fluid_synth_get_gain(self.synth) }
+    }
+
+    fn set_gain(&mut self, gain: f32) {
+        unsafe { ffi::fluid_synth_set_gain(self.synth, gain) };
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        unsafe { ffi::fluid_synth_set_sample_rate(self.synth, rate) };
+    }
+
+    fn render(&mut self, out: &mut [f32]) -> Result<()> {
+        let frames = out.len() / 2;
+        let left = out.as_mut_ptr();
+        // SAFETY: `out` holds `frames` interleaved pairs, the right sample one after the left.
+        unsafe { self.write(frames, left, left.add(1), 2) }
+    }
+
+    #[cfg(feature = "jack")]
+    fn render_split(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<()> {
+        let frames = left.len().min(right.len());
+        // SAFETY: each buffer holds at least `frames` samples, one after another.
+        unsafe { self.write(frames, left.as_mut_ptr(), right.as_mut_ptr(), 1) }
+    }
+
+    /// FluidLite lists the voices playing, ended by a null pointer when fewer than the
+    /// list holds.
+    fn voices(&self) -> Option<(usize, usize)> {
+        let mut list = self.voice_list.borrow_mut();
+        let room = list.len();
+        // SAFETY: the list has room for `room` pointers, which FluidLite writes no more than.
+        unsafe { ffi::fluid_synth_get_voicelist(self.synth, list.as_mut_ptr(), room as c_int, -1) };
+        let playing = list.iter().position(|voice| voice.is_null()).unwrap_or(room);
+        Some((playing, room))
+    }
+}
+
+impl Drop for Fluid {
+    fn drop(&mut self) {
+        // SAFETY: the synth is not used again, and its settings go with it, as in FluidLite's
+        // own examples.
+        unsafe {
+            let settings = ffi::fluid_synth_get_settings(self.synth);
+            ffi::delete_fluid_synth(self.synth);
+            ffi::delete_fluid_settings(settings);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! The `gain-scan` subcommand: measure how loud songs are, as ReplayGain 2.0 does (EBU R128
+//! loudness against a -18 LUFS reference), so a playlist can be played at an even level.
+//!
+//! MIDI files are rendered in memory as `render` would, with their sidecar settings; WAV
+//! files (earlier renders, say) are measured as they are.
+
+use crate::conductor::Mixer;
+use crate::loudness;
+use crate::output::{self, OutputArgs};
+use crate::render::{Rendering, Tail, parse_lufs, render_all};
+use crate::riff;
+use crate::rmid;
+use crate::sidecar;
+use crate::song::{Song, SongArgs, is_midi};
+use crate::soundfont;
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use serde_json::json;
+use std::{fs, path::Path, path::PathBuf};
+use tracing::{info, warn};
+
+/// `gain-scan` options:
+/// - files: what to measure
+/// - soundfont: SoundFont to render the MIDI files with
+/// - target: loudness the gains bring the files to
+/// - write_sidecar: save each MIDI file's gain for `play`
+#[derive(Args, Debug)]
+pub struct GainScanArgs {
+    /// MIDI files to render and measure, or WAV files to measure as they are
+    #[arg(required = true, value_name = "FILE")]
+    files: Vec<PathBuf>,
+    /// SoundFont to render MIDI files with; searched for in the usual places if left out.
+    /// Repeat to layer several, those given first winning
+    #[arg(long, value_name = "FONT")]
+    soundfont: Vec<String>,
+    /// Loudness the gains aim for, in LUFS (ReplayGain 2.0's reference is -18)
+    #[arg(long, value_name = "LUFS", default_value_t = -18.0, allow_hyphen_values = true, value_parser = parse_lufs)]
+    target: f64,
+    /// Save each MIDI file's gain as `replaygain` in its SONG.mid.toml, where `play` picks
+    /// it up
+    #[arg(long)]
+    write_sidecar: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Rate MIDI files are rendered at to be measured.
+const SAMPLE_RATE: u32 = 44_100;
+
+/// Measure each file and print its loudness, gain and true peak. Files that cannot be read
+/// are reported and skipped.
+pub fn run(opt: GainScanArgs) -> Result<()> {
+    let mut failed = 0;
+    for path in &opt.files {
+        if let Err(e) = scan(&opt, path) {
+            warn!("{}: {e:#}", path.display());
+            if output::is_json() {
+                output::print(&json!({ "file": path.display().to_string(), "error": format!("{e:#}") }));
+            }
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} files could not be scanned", opt.files.len());
+    }
+    Ok(())
+}
+
+/// Measure one file, print the result and, if asked, save it to the sidecar.
+fn scan(opt: &GainScanArgs, path: &Path) -> Result<()> {
+    let is_midi = is_midi(path);
+    let (audio, channels, rate) = if is_midi { render_midi(opt, path)? } else { read_wav(path)? };
+    let file = path.display().to_string();
+    let Some(lufs) = loudness::integrated(&audio, channels, rate) else {
+        if output::is_json() {
+            output::print(&json!({ "file": file, "loudness_lufs": null, "gain_db": null }));
+        } else {
+            println!("{:<42}{file}", "   silent");
+        }
+        return Ok(());
+    };
+    let gain = opt.target - lufs;
+    let peak = loudness::frame_peaks(&audio, channels).into_iter().fold(0.0, f32::max);
+    let peak_db = 20.0 * f64::from(peak).log10();
+    if peak_db + gain > 0.0 {
+        warn!("{file}: at {gain:+.2} dB its peaks would reach {:+.1} dBTP and clip", peak_db + gain);
+    }
+
+    if output::is_json() {
+        output::print(&json!({
+            "file": file,
+            "loudness_lufs": round2(lufs),
+            "gain_db": round2(gain),
+            "peak_dbtp": round2(peak_db),
+        }));
+    } else {
+        println!("{gain:+7.2} dB  {lufs:6.1} LUFS  peak {peak_db:5.1} dBTP  {file}");
+    }
+
+    if opt.write_sidecar {
+        if is_midi {
+            let midi = path.to_string_lossy();
+            sidecar::set(&midi, "replaygain", &format!("{gain:.2}"))?;
+            info!("Saved the gain to {midi}.toml");
+        } else {
+            warn!("{file}: only MIDI files have sidecars, so its gain is not saved");
+        }
+    }
+    Ok(())
+}
+
+/// Render a MIDI file as `render` would, with the settings from its sidecar, into memory.
+fn render_midi(opt: &GainScanArgs, path: &Path) -> Result<(Vec<f32>, usize, u32)> {
+    let mut args = SongArgs {
+        midi: path.to_string_lossy().into_owned(),
+        soundfont: None,
+        soundfonts: opt.soundfont.clone(),
+        transpose: 0,
+        mute_channel: Vec::new(),
+        solo_channel: Vec::new(),
+        tracks: Vec::new(),
+        programs: Vec::new(),
+        no_sidecar: false,
+        lenient: false,
+        effects: Default::default(),
+    };
+    sidecar::load_for(&mut args)?;
+    rmid::layer_bank(&mut args)?;
+    let soundfonts = soundfont::resolve_all(args.soundfont.as_deref(), &args.soundfonts)?;
+    let bytes = fs::read(path).context("reading MIDI file")?;
+    let drums = args.effects.drum_channels.unwrap_or_default();
+    let mut song = Song::parse(&bytes, &args.tracks, args.transpose, drums, args.lenient)?;
+    song.override_programs(&args.programs);
+    let mixer = Mixer::new(&args.mute_channel, &args.solo_channel);
+    let rendering = Rendering { sample_rate: SAMPLE_RATE, tail: Tail::Auto, limiter: None, effects: args.effects };
+    Ok((render_all(&song, &mixer, &soundfonts, rendering)?, 2, SAMPLE_RATE))
+}
+
+/// Read a WAV file as interleaved floats, with its channel count and rate. Takes 16, 24 and
+/// 32-bit integer PCM and 32-bit float.
+fn read_wav(path: &Path) -> Result<(Vec<f32>, usize, u32)> {
+    let bytes = fs::read(path).context("reading the file")?;
+    let wave = riff::form(&bytes, b"WAVE").context("not a MIDI or WAV file")?;
+    let u16_at = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+    let u32_at = |b: &[u8], i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+    let mut format = None;
+    for chunk in riff::chunks(wave) {
+        let body = chunk.body();
+        match chunk.id() {
+            b"fmt " if body.len() >= 16 => {
+                let mut tag = u16_at(body, 0);
+                // WAVE_FORMAT_EXTENSIBLE keeps the real format at the start of its subformat.
+                if tag == 0xfffe && body.len() >= 26 {
+                    tag = u16_at(body, 24);
+                }
+                format = Some((tag, u16_at(body, 2) as usize, u32_at(body, 4), u16_at(body, 14)));
+            }
+            b"data" => {
+                let (tag, channels, rate, bits) = format.context("the audio comes before its format")?;
+                let samples: Vec<f32> = match (tag, bits) {
+                    (1, 16) => body.chunks_exact(2).map(|b| f32::from(u16_at(b, 0) as i16) / 32_768.0).collect(),
+                    (1, 24) => body
+                        .chunks_exact(3)
+                        .map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0)
+                        .collect(),
+                    (1, 32) => body.chunks_exact(4).map(|b| u32_at(b, 0) as i32 as f32 / 2_147_483_648.0).collect(),
+                    (3, 32) => body.chunks_exact(4).map(|b| f32::from_bits(u32_at(b, 0))).collect(),
+                    _ => bail!("WAV files of {bits}-bit samples in format {tag} cannot be read"),
+                };
+                if channels == 0 || rate == 0 {
+                    bail!("the WAV header has no channels or rate");
+                }
+                return Ok((samples, channels, rate));
+            }
+            _ => {}
+        }
+    }
+    bail!("the WAV file has no audio")
+}
+
+fn round2(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
@@ -0,0 +1,276 @@
+//! The `info` subcommand: describe a MIDI file without opening any audio device.
+
+use crate::song::{DRUM_CHANNEL, GM_PROGRAMS};
+use crate::output::{self, OutputArgs};
+use crate::tempo::{self, TempoMap};
+use crate::time::format_duration;
+use anyhow::{Context, Result};
+use clap::Args;
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use serde_json::json;
+use std::fs;
+
+/// `info` options.
+#[derive(Args, Debug)]
+pub struct InfoArgs {
+    /// Path to .mid file
+    midi: String,
+    /// Print just the tempo map: every tempo and time signature change with its tick and
+    /// time, as CSV, or as JSON with --output json
+    #[arg(long)]
+    tempo_map: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// What one channel does over the whole file.
+#[derive(Default)]
+struct ChannelUse {
+    /// Programs in the order they are first selected.
+    programs: Vec<u8>,
+    notes: usize,
+}
+
+/// Print the file's header, tracks, channels, tempo and signature changes, markers and length.
+pub fn run(opt: InfoArgs) -> Result<()> {
+    let bytes = fs::read(&opt.midi).with_context(|| "reading MIDI file")?;
+    let smf = Smf::parse(&bytes).with_context(|| "parsing MIDI")?;
+    let tempo = TempoMap::new(&smf);
+
+    let mut tracks = Vec::new();
+    let mut channels: [ChannelUse; 16] = Default::default();
+    // As (tick, numerator, denominator as a power of two).
+    let mut signatures = Vec::new();
+    let mut key_sigs = Vec::new();
+    let mut markers = Vec::new();
+    let mut lyrics = 0usize;
+
+    for track in &smf.tracks {
+        let mut tick = 0u64;
+        let mut name = None;
+        for ev in track {
+            tick += u64::from(ev.delta.as_int());
+            match ev.kind {
+                TrackEventKind::Midi { channel, message } => {
+                    let ch = &mut channels[channel.as_int() as usize];
+                    match message {
+                        MidiMessage::NoteOn { vel, .. } if vel.as_int() > 0 => ch.notes += 1,
+                        MidiMessage::ProgramChange { program } if !ch.programs.contains(&program.as_int()) => {
+                            ch.programs.push(program.as_int());
+                        }
+                        _ => {}
+                    }
+                }
+                TrackEventKind::Meta(MetaMessage::TrackName(s)) if name.is_none() => {
+                    name = Some(String::from_utf8_lossy(s).trim().to_string()).filter(|s| !s.is_empty());
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numer, denom, _, _)) => {
+                    signatures.push((tick, numer, denom));
+                }
+                TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => {
+                    key_sigs.push((tick, key_name(sharps, minor)));
+                }
+                TrackEventKind::Meta(MetaMessage::Marker(s) | MetaMessage::CuePoint(s)) => {
+                    markers.push((tick, String::from_utf8_lossy(s).trim().to_string()));
+                }
+                TrackEventKind::Meta(MetaMessage::Lyric(_)) => lyrics += 1,
+                _ => {}
+            }
+        }
+        tracks.push((name, track.len()));
+    }
+    if opt.tempo_map {
+        signatures.sort_by_key(|&(tick, ..)| tick);
+        print_tempo_map(&tempo, &signatures);
+        return Ok(());
+    }
+    let time_sigs: Vec<(u64, String)> =
+        signatures.iter().map(|&(tick, numer, denom)| (tick, time_signature(numer, denom))).collect();
+    let length_us = tempo::length_us(&smf);
+    let format = match smf.header.format {
+        Format::SingleTrack => 0,
+        Format::Parallel => 1,
+        Format::Sequential => 2,
+    };
+    // Channels that play or at least select an instrument, with what they play.
+    let channels: Vec<(usize, String, usize)> = channels
+        .iter()
+        .enumerate()
+        .filter(|(_, u)| u.notes > 0 || !u.programs.is_empty())
+        .map(|(ch, used)| (ch, instruments(ch, used), used.notes))
+        .collect();
+
+    if output::is_json() {
+        let timed = |items: &[(u64, String)], key: &'static str| {
+            let list = items.iter().map(|(tick, what)| json!({ "time": output::secs(tempo.to_us(*tick)), key: what }));
+            list.collect::<Vec<_>>()
+        };
+        let timing = match smf.header.timing {
+            Timing::Metrical(ppq) => json!({ "ppq": ppq.as_int() }),
+            Timing::Timecode(fps, sub) => {
+                json!({ "smpte_fps": f64::from(fps.as_f32()), "ticks_per_frame": sub })
+            }
+        };
+        output::print(&json!({
+            "file": opt.midi.as_str(),
+            "format": format,
+            "timing": timing,
+            "tracks": tracks
+                .iter()
+                .map(|(name, events)| json!({ "name": name, "events": events }))
+                .collect::<Vec<_>>(),
+            "channels": channels
+                .iter()
+                .map(|(ch, instruments, notes)| json!({ "channel": ch + 1, "instruments": instruments, "notes": notes }))
+                .collect::<Vec<_>>(),
+            "tempo_changes": tempo
+                .changes()
+                .iter()
+                .map(|c| json!({ "time": output::secs(c.t_us as u64), "bpm": 60_000_000.0 / c.us_per_qn }))
+                .collect::<Vec<_>>(),
+            "time_signatures": timed(&time_sigs, "signature"),
+            "key_signatures": timed(&key_sigs, "key"),
+            "markers": timed(&markers, "name"),
+            "lyrics": lyrics,
+            "duration": output::secs(length_us),
+        }));
+        return Ok(());
+    }
+
+    println!("File: {}", opt.midi);
+    println!(
+        "Format: {}",
+        match format {
+            0 => "0 (single track)",
+            1 => "1 (simultaneous tracks)",
+            _ => "2 (independent patterns)",
+        }
+    );
+    match smf.header.timing {
+        Timing::Metrical(ppq) => println!("Timing: {} ticks per quarter note", ppq.as_int()),
+        Timing::Timecode(fps, sub) => println!("Timing: SMPTE {} fps, {} ticks per frame", fps.as_f32(), sub),
+    }
+
+    println!("Tracks: {}", tracks.len());
+    for (n, (name, events)) in tracks.iter().enumerate() {
+        println!("  {}: {} ({} events)", n + 1, name.as_deref().unwrap_or("(unnamed)"), events);
+    }
+
+    println!("Channels:");
+    for (ch, instruments, notes) in &channels {
+        println!("  {:>2}: {} ({} notes)", ch + 1, instruments, notes);
+    }
+
+    let changes = tempo.changes();
+    if !changes.is_empty() {
+        println!("Tempo changes:");
+        for c in changes {
+            println!("  {}  {:.1} BPM", format_duration(c.t_us as u64), 60_000_000.0 / c.us_per_qn);
+        }
+    }
+    let list = |title: &str, items: &[(u64, String)]| {
+        if !items.is_empty() {
+            println!("{title}:");
+            for (tick, what) in items {
+                println!("  {}  {}", format_duration(tempo.to_us(*tick)), what);
+            }
+        }
+    };
+    list("Time signatures", &time_sigs);
+    list("Key signatures", &key_sigs);
+    list("Markers", &markers);
+    if lyrics > 0 {
+        println!("Lyrics: {lyrics} events");
+    } else {
+        println!("Lyrics: none");
+    }
+    println!("Duration: {} ({:.3} s)", format_duration(length_us), length_us as f64 / 1_000_000.0);
+    Ok(())
+}
+
+/// Print the tempo changes and time signatures as one list ordered by tick, each with its
+/// time in seconds: CSV rows with a header, or a JSON object of two lists.
+fn print_tempo_map(tempo: &TempoMap, signatures: &[(u64, u8, u8)]) {
+    let secs = |tick: u64| tempo.to_us(tick) as f64 / 1_000_000.0;
+    if output::is_json() {
+        let tempos = tempo.changes().iter().map(|c| {
+            json!({
+                "tick": c.tick,
+                "time": secs(c.tick),
+                "us_per_qn": c.us_per_qn,
+                "bpm": 60_000_000.0 / c.us_per_qn,
+            })
+        });
+        let signatures = signatures.iter().map(|&(tick, numer, denom)| {
+            json!({
+                "tick": tick,
+                "time": secs(tick),
+                "numerator": numer,
+                "denominator": denominator(denom),
+            })
+        });
+        output::print(&json!({
+            "tempo_changes": tempos.collect::<Vec<_>>(),
+            "time_signatures": signatures.collect::<Vec<_>>(),
+        }));
+        return;
+    }
+
+    // Merged by tick, tempo first where both change at once.
+    let mut rows: Vec<(u64, String)> = tempo
+        .changes()
+        .iter()
+        .map(|c| (c.tick, format!("tempo,{},{:.3},,", c.us_per_qn, 60_000_000.0 / c.us_per_qn)))
+        .collect();
+    rows.extend(
+        signatures
+            .iter()
+            .map(|&(tick, numer, denom)| {
+                let denom = denominator(denom).map_or(String::new(), |d| d.to_string());
+                (tick, format!("time_signature,,,{numer},{denom}"))
+            }),
+    );
+    rows.sort_by_key(|&(tick, _)| tick);
+    println!("tick,time,event,us_per_qn,bpm,numerator,denominator");
+    for (tick, row) in rows {
+        println!("{tick},{:.6},{row}", secs(tick));
+    }
+}
+
+/// Describe what a channel plays: its GM programs in order of use, or the drum kit.
+fn instruments(ch: usize, used: &ChannelUse) -> String {
+    if ch == DRUM_CHANNEL as usize {
+        "Drum kit".to_string()
+    } else if used.programs.is_empty() {
+        // GM channels start on program 0 until told otherwise.
+        format!("{} (default)", GM_PROGRAMS[0])
+    } else {
+        let names: Vec<&str> = used.programs.iter().map(|&p| GM_PROGRAMS[p as usize]).collect();
+        names.join(", ")
+    }
+}
+
+/// Name a key signature given as sharps (positive) or flats (negative) and mode.
+fn key_name(sharps: i8, minor: bool) -> String {
+    const MAJOR: [&str; 15] = ["Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#"];
+    const MINOR: [&str; 15] = ["Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#"];
+    match usize::try_from(i16::from(sharps) + 7).ok().filter(|&i| i < 15) {
+        Some(i) if minor => format!("{} minor", MINOR[i]),
+        Some(i) => format!("{} major", MAJOR[i]),
+        None => format!("{sharps} sharps ({})", if minor { "minor" } else { "major" }),
+    }
+}
+
+/// A time signature's denominator, which the file gives as a power of two; `None` for one
+/// too large to be a note value.
+fn denominator(power: u8) -> Option<u32> {
+    1u32.checked_shl(power.into())
+}
+
+/// A time signature as `4/4`, or with an impossible denominator as the power it gives.
+fn time_signature(numer: u8, power: u8) -> String {
+    match denominator(power) {
+        Some(denom) => format!("{numer}/{denom}"),
+        None => format!("{numer}/2^{power}"),
+    }
+}
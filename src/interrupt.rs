@@ -0,0 +1,40 @@
+//! Ctrl-C handling.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set on Ctrl-C. The conductor polls it and shuts down gracefully.
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // A second Ctrl-C means the user really wants out, tail or not.
+        // SAFETY: _exit is async-signal-safe.
+        unsafe {
+            libc::_exit(130);
+        }
+    }
+}
+
+/// Route SIGINT (Ctrl-C) to `on_interrupt`.
+#[cfg(unix)]
+pub fn install_interrupt_handler() {
+    // SAFETY: the handler only touches an atomic (and exits on a second signal).
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+/// Other platforms have no SIGINT; `ctrlc` hears Ctrl-C (on Windows, the console's control
+/// handler) and calls back on a thread of its own.
+#[cfg(not(unix))]
+pub fn install_interrupt_handler() {
+    let handler = || {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            // A second Ctrl-C means the user really wants out, tail or not.
+            std::process::exit(130);
+        }
+    };
+    // Only fails if a handler is set already, and this is called once per run.
+    let _ = ctrlc::set_handler(handler);
+}
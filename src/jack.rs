@@ -0,0 +1,321 @@
+//! Native JACK output (`play --jack`, with the `jack` feature). The player becomes a JACK
+//! client with a left and right output port, connected to the first physical outputs, and
+//! follows the JACK transport: starting, stopping and locating it in a DAW starts, pauses
+//! and moves the song.
+//!
+//! This talks to libjack directly rather than through CPAL, which can neither name the
+//! ports nor see the transport.
+
+use crate::controls::Command;
+use crate::limiter::{Limiter, LimiterArgs};
+use crate::synth::Synth;
+use crate::time::Position;
+use anyhow::{Result, bail};
+use std::cell::UnsafeCell;
+use std::ffi::{CStr, CString, c_char, c_int, c_ulong, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// How often the transport is checked for starts, stops and jumps.
+const TRANSPORT_POLL: Duration = Duration::from_millis(20);
+
+/// A transport position further than this from where the song should be is a locate.
+const LOCATE_SLACK: f64 = 0.05;
+
+#[repr(C)]
+struct RawClient {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct RawPort {
+    _private: [u8; 0],
+}
+
+/// The start of `jack_position_t`. JACK writes the whole struct, so the rest is padding
+/// comfortably bigger than any version of it.
+#[repr(C)]
+struct TransportPosition {
+    unique: u64,
+    usecs: u64,
+    frame_rate: u32,
+    frame: u32,
+    rest: [u8; 256],
+}
+
+const JACK_NO_START_SERVER: c_int = 0x01;
+const JACK_PORT_IS_INPUT: c_ulong = 0x1;
+const JACK_PORT_IS_OUTPUT: c_ulong = 0x2;
+const JACK_PORT_IS_PHYSICAL: c_ulong = 0x4;
+const JACK_TRANSPORT_ROLLING: c_int = 1;
+const AUDIO_TYPE: &CStr = c"32 bit float mono audio";
+
+#[link(name = "jack")]
+unsafe extern "C" {
+    fn jack_client_open(name: *const c_char, options: c_int, status: *mut c_int, ...) -> *mut RawClient;
+    fn jack_client_close(client: *mut RawClient) -> c_int;
+    fn jack_get_sample_rate(client: *mut RawClient) -> u32;
+    fn jack_port_register(
+        client: *mut RawClient,
+        name: *const c_char,
+        port_type: *const c_char,
+        flags: c_ulong,
+        buffer_size: c_ulong,
+    ) -> *mut RawPort;
+    fn jack_port_name(port: *const RawPort) -> *const c_char;
+    fn jack_port_get_buffer(port: *mut RawPort, frames: u32) -> *mut c_void;
+    fn jack_set_process_callback(
+        client: *mut RawClient,
+        callback: extern "C" fn(u32, *mut c_void) -> c_int,
+        arg: *mut c_void,
+    ) -> c_int;
+    fn jack_on_shutdown(client: *mut RawClient, callback: extern "C" fn(*mut c_void), arg: *mut c_void);
+    fn jack_activate(client: *mut RawClient) -> c_int;
+    fn jack_deactivate(client: *mut RawClient) -> c_int;
+    fn jack_get_ports(
+        client: *mut RawClient,
+        name_pattern: *const c_char,
+        type_pattern: *const c_char,
+        flags: c_ulong,
+    ) -> *mut *const c_char;
+    fn jack_connect(client: *mut RawClient, source: *const c_char, destination: *const c_char) -> c_int;
+    fn jack_free(ptr: *mut c_void);
+    fn jack_transport_query(client: *const RawClient, pos: *mut TransportPosition) -> c_int;
+}
+
+/// A client registered with the JACK server, with its ports, not running yet.
+pub struct Client {
+    raw: *mut RawClient,
+    left: *mut RawPort,
+    right: *mut RawPort,
+}
+
+/// What the process callback works with. Only the callback touches the synth and the
+/// limiter once the client is active, so they need no lock.
+struct Process {
+    synth: UnsafeCell<Synth>,
+    limiter: UnsafeCell<Option<Limiter>>,
+    // Buffers the synth failed to render, for the transport thread to report.
+    failed: Arc<AtomicU64>,
+    left: *mut RawPort,
+    right: *mut RawPort,
+}
+
+/// The client pointer, for the transport thread. JACK's transport calls may be made from
+/// any thread.
+struct Shared(*mut RawClient);
+
+unsafe impl Send for Shared {}
+
+/// A running client. Dropping it stops following the transport and closes the client.
+pub struct Active {
+    raw: *mut RawClient,
+    // Read by the process callback until the client is closed.
+    _process: Box<Process>,
+    // Set by JACK if the server goes away.
+    shut_down: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    transport: Option<JoinHandle<()>>,
+}
+
+impl Client {
+    /// Register as `name` with the running JACK server (one is never started) and create
+    /// the output ports.
+    pub fn open(name: &str) -> Result<Self> {
+        let c_name = CString::new(name)?;
+        let mut status = 0;
+        let raw = unsafe { jack_client_open(c_name.as_ptr(), JACK_NO_START_SERVER, &mut status) };
+        if raw.is_null() {
+            bail!("could not connect to the JACK server (status {status:#x}); is it running?");
+        }
+        let port = |name: &CStr| unsafe {
+            jack_port_register(raw, name.as_ptr(), AUDIO_TYPE.as_ptr(), JACK_PORT_IS_OUTPUT, 0)
+        };
+        let (left, right) = (port(c"out_left"), port(c"out_right"));
+        if left.is_null() || right.is_null() {
+            unsafe { jack_client_close(raw) };
+            bail!("could not register the JACK output ports");
+        }
+        info!("JACK client `{name}` at {} Hz", unsafe { jack_get_sample_rate(raw) });
+        Ok(Self { raw, left, right })
+    }
+
+    /// The server's sample rate, which the synth has to render at.
+    pub fn sample_rate(&self) -> u32 {
+        unsafe { jack_get_sample_rate(self.raw) }
+    }
+
+    /// Start rendering `synth` into the ports, through `limiter` if there is one, connect
+    /// them to the first physical outputs, and send the conductor transport changes as
+    /// commands from here on.
+    pub fn activate(
+        self,
+        synth: Synth,
+        limiter: Option<LimiterArgs>,
+        commands: mpsc::Sender<Command>,
+    ) -> Result<Active> {
+        let limiter = UnsafeCell::new(limiter.map(|args| Limiter::new(&args, self.sample_rate())));
+        let failed = Arc::new(AtomicU64::new(0));
+        let process = Box::new(Process {
+            synth: UnsafeCell::new(synth),
+            limiter,
+            failed: Arc::clone(&failed),
+            left: self.left,
+            right: self.right,
+        });
+        let shut_down = Arc::new(AtomicBool::new(false));
+        unsafe {
+            jack_set_process_callback(self.raw, process_callback, &*process as *const Process as *mut c_void);
+            jack_on_shutdown(self.raw, shutdown_callback, Arc::as_ptr(&shut_down) as *mut c_void);
+            if jack_activate(self.raw) != 0 {
+                jack_client_close(self.raw);
+                bail!("could not activate the JACK client");
+            }
+        }
+        self.connect_outputs();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let client = Shared(self.raw);
+        let stopped = Arc::clone(&stop);
+        let gone = Arc::clone(&shut_down);
+        let transport = thread::spawn(move || {
+            // Bind the wrapper whole, so it is what moves into the thread.
+            let client = client;
+            follow_transport(client.0, &gone, &stopped, &failed, &commands);
+        });
+        Ok(Active { raw: self.raw, _process: process, shut_down, stop, transport: Some(transport) })
+    }
+
+    /// Connect left and right to the first two physical playback ports, or both to the only
+    /// one. Without any, the ports are left for the user to patch.
+    fn connect_outputs(&self) {
+        let flags = JACK_PORT_IS_PHYSICAL | JACK_PORT_IS_INPUT;
+        let list = unsafe { jack_get_ports(self.raw, ptr::null(), AUDIO_TYPE.as_ptr(), flags) };
+        if list.is_null() {
+            warn!("No physical JACK outputs; connect midi-play's ports yourself");
+            return;
+        }
+        let mut targets = Vec::new();
+        unsafe {
+            while targets.len() < 2 && !(*list.add(targets.len())).is_null() {
+                targets.push(*list.add(targets.len()));
+            }
+        }
+        if targets.is_empty() {
+            warn!("No physical JACK outputs; connect midi-play's ports yourself");
+            unsafe { jack_free(list as *mut c_void) };
+            return;
+        }
+        let pairs = [(self.left, targets[0]), (self.right, *targets.last().expect("checked above"))];
+        for (port, target) in pairs {
+            unsafe {
+                if jack_connect(self.raw, jack_port_name(port), target) != 0 {
+                    let target = CStr::from_ptr(target).to_string_lossy();
+                    warn!("Could not connect to JACK port {target}");
+                }
+            }
+        }
+        unsafe { jack_free(list as *mut c_void) };
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.transport.take() {
+            let _ = thread.join();
+        }
+        if !self.shut_down.load(Ordering::SeqCst) {
+            unsafe {
+                jack_deactivate(self.raw);
+                jack_client_close(self.raw);
+            }
+        }
+    }
+}
+
+/// The JACK process callback: render straight into the two port buffers.
+extern "C" fn process_callback(frames: u32, arg: *mut c_void) -> c_int {
+    let p = unsafe { &*(arg as *const Process) };
+    let (left, right) = unsafe {
+        (
+            std::slice::from_raw_parts_mut(jack_port_get_buffer(p.left, frames) as *mut f32, frames as usize),
+            std::slice::from_raw_parts_mut(jack_port_get_buffer(p.right, frames) as *mut f32, frames as usize),
+        )
+    };
+    // SAFETY: JACK runs one process callback at a time, and nothing else reaches these.
+    let (synth, limiter) = unsafe { (&mut *p.synth.get(), &mut *p.limiter.get()) };
+    if synth.render_split(left, right).is_err() {
+        p.failed.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(limiter) = limiter {
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            [*l, *r] = limiter.limit([*l, *r]);
+        }
+    }
+    0
+}
+
+extern "C" fn shutdown_callback(arg: *mut c_void) {
+    let gone = unsafe { &*(arg as *const AtomicBool) };
+    gone.store(true, Ordering::SeqCst);
+}
+
+/// Poll the transport until `stop` is set, turning starts, stops and locates into
+/// commands. The song takes the transport's position on the first poll, and is paused
+/// if the transport is not rolling. Song time is transport time, so this assumes speed 1.
+/// Buffers the process callback counted in `failed` are reported as they come.
+fn follow_transport(
+    client: *mut RawClient,
+    gone: &AtomicBool,
+    stop: &AtomicBool,
+    failed: &AtomicU64,
+    commands: &mpsc::Sender<Command>,
+) {
+    let query = || {
+        let mut pos = TransportPosition { unique: 0, usecs: 0, frame_rate: 0, frame: 0, rest: [0; 256] };
+        let state = unsafe { jack_transport_query(client, &mut pos) };
+        (state == JACK_TRANSPORT_ROLLING, pos.frame, pos.frame_rate.max(1))
+    };
+    let seconds = |frame: u32, rate: u32| f64::from(frame) / f64::from(rate);
+
+    let (mut rolling, mut frame, rate) = query();
+    let mut polled = Instant::now();
+    let mut reported = 0;
+    if !rolling {
+        let _ = commands.send(Command::Pause);
+    }
+    if frame > 0 {
+        let _ = commands.send(Command::SeekTo(Position::Time((seconds(frame, rate) * 1e6) as u64)));
+    }
+    info!("Following the JACK transport");
+
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(TRANSPORT_POLL);
+        if gone.load(Ordering::SeqCst) {
+            error!("The JACK server shut down");
+            let _ = commands.send(Command::Quit);
+            return;
+        }
+        let now_failed = failed.load(Ordering::Relaxed);
+        if now_failed > reported {
+            error!("The synth failed to render {} audio buffer(s)", now_failed - reported);
+            reported = now_failed;
+        }
+        let (now_rolling, now_frame, rate) = query();
+        let expected = seconds(frame, rate) + if rolling { polled.elapsed().as_secs_f64() } else { 0.0 };
+        polled = Instant::now();
+        let at = seconds(now_frame, rate);
+        if (at - expected).abs() > LOCATE_SLACK {
+            let _ = commands.send(Command::SeekTo(Position::Time((at * 1e6) as u64)));
+        }
+        if now_rolling != rolling {
+            let _ = commands.send(if now_rolling { Command::Resume } else { Command::Pause });
+        }
+        (rolling, frame) = (now_rolling, now_frame);
+    }
+}
@@ -0,0 +1,198 @@
+//! The `latency` subcommand: play a few clicks on the output device and measure how long
+//! they take to get out, to tune `--buffer-size` and to explain notes that feel late.
+//!
+//! Each click is asked for from this thread, the way the conductor sends a note-on, and
+//! starts at the beginning of the next buffer the device asks for. What it waited for
+//! that callback plus what the device reports from the callback to the speakers is the
+//! MIDI-to-audio latency; the first part varies by up to a buffer from click to click.
+
+use crate::audio::{self, AudioArgs};
+use crate::output::{self, OutputArgs};
+use anyhow::{Context, Result};
+use clap::Args;
+use cpal::SizedSample;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// `latency` options.
+#[derive(Args, Debug)]
+pub struct LatencyArgs {
+    /// How many clicks to time
+    #[arg(long, value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..=100))]
+    clicks: u32,
+    #[command(flatten)]
+    audio: AudioArgs,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Time between clicks, long enough for each to be heard on its own.
+const CLICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a click sounds.
+const CLICK_LENGTH: Duration = Duration::from_millis(5);
+
+/// What one click measured.
+struct Click {
+    /// From asking for the click to the callback that played it.
+    wait: Duration,
+    /// From that callback to the speakers, if the backend knows.
+    output: Option<Duration>,
+}
+
+/// What the stream callback and this thread share.
+#[derive(Default)]
+struct Shared {
+    /// When the next click was asked for.
+    requested: Option<Instant>,
+    clicks: Vec<Click>,
+    max_frames: usize,
+}
+
+/// Open the device, time the clicks and print the results.
+pub fn run(opt: LatencyArgs) -> Result<()> {
+    let (dev, cfg) = audio::output_device(&opt.audio)?;
+    let rate = cfg.sample_rate().0;
+    let shared = Arc::new(Mutex::new(Shared::default()));
+    let stream = match cfg.sample_format() {
+        cpal::SampleFormat::I8 => click_stream::<i8>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::I16 => click_stream::<i16>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::I32 => click_stream::<i32>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::I64 => click_stream::<i64>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::U8 => click_stream::<u8>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::U16 => click_stream::<u16>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::U32 => click_stream::<u32>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::U64 => click_stream::<u64>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::F32 => click_stream::<f32>(&dev, &cfg, &opt.audio, &shared),
+        cpal::SampleFormat::F64 => click_stream::<f64>(&dev, &cfg, &opt.audio, &shared),
+        other => anyhow::bail!("the device wants {other} samples, which cannot be played"),
+    }?;
+    stream.play().context("starting the output stream")?;
+    info!("Playing {} clicks", opt.clicks);
+
+    // Let the stream settle before the first click.
+    thread::sleep(CLICK_INTERVAL);
+    for _ in 0..opt.clicks {
+        shared.lock().unwrap().requested = Some(Instant::now());
+        thread::sleep(CLICK_INTERVAL);
+    }
+    drop(stream);
+
+    let shared = std::mem::take(&mut *shared.lock().unwrap());
+    if shared.clicks.is_empty() {
+        anyhow::bail!("the stream opened but the device never asked for audio");
+    }
+    report(&opt, &shared, rate);
+    Ok(())
+}
+
+/// The output stream for devices taking samples of type `T`, playing a click at the start
+/// of the first buffer after each one is asked for.
+fn click_stream<T: SizedSample + cpal::FromSample<f32> + Send + 'static>(
+    dev: &cpal::Device,
+    cfg: &cpal::SupportedStreamConfig,
+    args: &AudioArgs,
+    shared: &Arc<Mutex<Shared>>,
+) -> Result<cpal::Stream> {
+    let shared = Arc::clone(shared);
+    let channels = cfg.channels() as usize;
+    let click_frames = (CLICK_LENGTH.as_secs_f64() * f64::from(cfg.sample_rate().0)) as usize;
+    // Frames of the current click still to play, which can run into the next buffer.
+    let mut left = 0;
+    dev.build_output_stream(
+        &audio::stream_config(cfg, args.buffer_size),
+        move |out: &mut [T], info: &cpal::OutputCallbackInfo| {
+            let entered = Instant::now();
+            let mut s = shared.lock().unwrap();
+            s.max_frames = s.max_frames.max(out.len() / channels);
+            if let Some(requested) = s.requested.take() {
+                let ts = info.timestamp();
+                let output = ts.playback.duration_since(&ts.callback).filter(|l| !l.is_zero());
+                s.clicks.push(Click { wait: entered.saturating_duration_since(requested), output });
+                left = click_frames;
+            }
+            drop(s);
+            for frame in out.chunks_exact_mut(channels) {
+                // A decaying square wave: short, and loud enough to hear over a fan.
+                let v = if left > 0 {
+                    let sign = if ((click_frames - left) / 8).is_multiple_of(2) { 1.0 } else { -1.0 };
+                    left -= 1;
+                    sign * 0.5 * left as f32 / click_frames as f32
+                } else {
+                    0.0
+                };
+                frame.fill(T::from_sample(v));
+            }
+        },
+        |e| error!("stream error: {e}"),
+        None,
+    )
+    .context("building the output stream")
+}
+
+/// Print the buffer size, the stream's output latency and the MIDI-to-audio latency, as
+/// text or one JSON object.
+fn report(opt: &LatencyArgs, shared: &Shared, rate: u32) {
+    let buffer = Duration::from_secs_f64(shared.max_frames as f64 / f64::from(rate));
+    // Without a figure from the backend, a buffer is the least it can be.
+    let outputs: Vec<Duration> = shared.clicks.iter().map(|c| c.output.unwrap_or(buffer)).collect();
+    let totals: Vec<Duration> = shared.clicks.iter().zip(&outputs).map(|(c, &o)| c.wait + o).collect();
+    let reported = shared.clicks.iter().any(|c| c.output.is_some());
+    let (output_mean, output_max) = (mean(&outputs), max(&outputs));
+    let (total_min, total_mean, total_max) = (min(&totals), mean(&totals), max(&totals));
+
+    if output::is_json() {
+        output::print(&json!({
+            "sample_rate": rate,
+            "requested_buffer": opt.audio.buffer_size,
+            "buffer_frames": shared.max_frames,
+            "buffer_ms": ms(buffer),
+            "clicks": shared.clicks.len(),
+            "output_latency_reported": reported,
+            "output_latency_ms": ms(output_mean),
+            "output_latency_max_ms": ms(output_max),
+            "midi_to_audio_min_ms": ms(total_min),
+            "midi_to_audio_ms": ms(total_mean),
+            "midi_to_audio_max_ms": ms(total_max),
+        }));
+        return;
+    }
+    let requested = match opt.audio.buffer_size {
+        Some(frames) => format!("{frames} frames asked for"),
+        None => "platform default".to_string(),
+    };
+    println!("Buffer:         {} frames ({:.1} ms at {rate} Hz; {requested})", shared.max_frames, ms(buffer));
+    if reported {
+        println!("Stream latency: {:.1} ms on average, {:.1} ms at most", ms(output_mean), ms(output_max));
+    } else {
+        println!("Stream latency: not reported by this backend; counted as one buffer");
+    }
+    println!(
+        "MIDI to audio:  {:.1} ms on average ({:.1}–{:.1} ms over {} clicks)",
+        ms(total_mean),
+        ms(total_min),
+        ms(total_max),
+        shared.clicks.len()
+    );
+}
+
+fn mean(ds: &[Duration]) -> Duration {
+    ds.iter().sum::<Duration>() / ds.len() as u32
+}
+
+fn min(ds: &[Duration]) -> Duration {
+    ds.iter().copied().min().unwrap_or_default()
+}
+
+fn max(ds: &[Duration]) -> Duration {
+    ds.iter().copied().max().unwrap_or_default()
+}
+
+/// Milliseconds, to the microsecond.
+fn ms(d: Duration) -> f64 {
+    (d.as_secs_f64() * 1e6).round() / 1000.0
+}
@@ -0,0 +1,210 @@
+//! `--lenient`: playing what can be recovered from a damaged MIDI file.
+//!
+//! Old game rips and web downloads are often slightly broken: junk before the header, a
+//! track chunk claiming more bytes than the file has left, garbage between tracks, an event
+//! cut in half. The normal reading gives up on the whole file at some of these and quietly
+//! drops the rest of the file or track at others; this one takes the file apart chunk by
+//! chunk and track by track, keeps whatever parses, and warns about what it had to leave out.
+
+use crate::rmid;
+use anyhow::{Result, bail};
+use midly::num::u15;
+use midly::{EventIter, Format, Fps, Header, Smf, Timing, Track};
+use tracing::{debug, warn};
+
+/// Read as much of the file in `bytes` as can be made sense of.
+pub fn parse(bytes: &[u8]) -> Result<Smf<'_>> {
+    let bytes = rmid::smf_data(bytes);
+    let Some(start) = find(bytes, b"MThd") else {
+        bail!("no MIDI header (MThd) anywhere in the file");
+    };
+    if start > 0 {
+        warn!("Skipping {start} bytes of junk before the MIDI header");
+    }
+    let mut rest = &bytes[start + 4..];
+    let (header, declared) = header(rest.get(4..10).unwrap_or_default())?;
+    // A header of any other length is damaged too; the six bytes that matter are read anyway.
+    let header_len = read_u32(rest).filter(|&len| len as usize <= rest.len() - 4).unwrap_or(6);
+    rest = &rest[4 + header_len as usize..];
+
+    let mut smf = Smf::new(header);
+    while rest.len() >= 8 {
+        let (id, len) = (&rest[..4], read_u32(&rest[4..]).unwrap_or_default() as usize);
+        let body = &rest[8..];
+        if id == b"MTrk" {
+            let number = smf.tracks.len() + 1;
+            if len > body.len() {
+                warn!("Track {number} is cut short: {} of its {len} bytes are missing", len - body.len());
+            }
+            let data = &body[..len.min(body.len())];
+            smf.tracks.push(track(data, number));
+            rest = &body[data.len()..];
+        } else if id.iter().all(|b| b.is_ascii_alphanumeric() || *b == b' ') && len <= body.len() {
+            debug!("Skipping a chunk of type {}", String::from_utf8_lossy(id));
+            rest = &body[len..];
+        } else {
+            let number = smf.tracks.len() + 1;
+            match find(rest, b"MTrk") {
+                Some(skip) => {
+                    warn!("Skipping {skip} bytes of garbage before track {number}");
+                    rest = &rest[skip..];
+                }
+                None => {
+                    warn!("Ignoring {} bytes of garbage after track {}", rest.len(), number - 1);
+                    rest = &[];
+                }
+            }
+        }
+    }
+    if smf.tracks.is_empty() {
+        bail!("no tracks could be recovered");
+    }
+    if smf.tracks.len() != usize::from(declared) {
+        warn!("The header names {declared} tracks, but {} were found", smf.tracks.len());
+    }
+    Ok(smf)
+}
+
+/// The header chunk's six bytes: format, track count and timing. A format out of range is
+/// read as 1, the most common, and an impossible SMPTE rate as 25 fps.
+fn header(raw: &[u8]) -> Result<(Header, u16)> {
+    let [f0, f1, n0, n1, t0, t1] = *raw else {
+        bail!("the MIDI header is cut short");
+    };
+    let format = match u16::from_be_bytes([f0, f1]) {
+        0 => Format::SingleTrack,
+        1 => Format::Parallel,
+        2 => Format::Sequential,
+        other => {
+            warn!("The header gives format {other}; reading the file as format 1");
+            Format::Parallel
+        }
+    };
+    let timing = if t0 & 0x80 != 0 {
+        let fps = Fps::from_int((t0 as i8).unsigned_abs()).unwrap_or_else(|| {
+            warn!("The header gives an SMPTE rate of {} fps; reading it as 25", (t0 as i8).unsigned_abs());
+            Fps::Fps25
+        });
+        Timing::Timecode(fps, t1)
+    } else {
+        Timing::Metrical(u15::new(u16::from_be_bytes([t0, t1])))
+    };
+    Ok((Header::new(format, timing), u16::from_be_bytes([n0, n1])))
+}
+
+/// The events of track `number` up to the first that cannot be read.
+fn track(data: &[u8], number: usize) -> Track<'_> {
+    let mut events = EventIter::new(data);
+    let mut track = Vec::new();
+    loop {
+        let left = events.unread().len();
+        match events.next() {
+            Some(Ok(ev)) => track.push(ev),
+            // Without midly's `strict` feature a bad event just ends the iteration.
+            Some(Err(_)) | None if left > 0 => {
+                let at = data.len() - left;
+                warn!("Track {number}: unreadable event at byte {at}; the rest of the track is left out");
+                break;
+            }
+            _ => break,
+        }
+    }
+    track
+}
+
+fn find(bytes: &[u8], magic: &[u8; 4]) -> Option<usize> {
+    bytes.windows(4).position(|w| w == magic)
+}
+
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{MidiMessage, TrackEventKind};
+
+    /// A note and the end of the track.
+    const EVENTS: &[u8] = &[0x00, 0x90, 0x3c, 0x64, 0x60, 0x80, 0x3c, 0x00, 0x00, 0xff, 0x2f, 0x00];
+
+    fn header(format: u16, tracks: u16) -> Vec<u8> {
+        let mut bytes = b"MThd\0\0\0\x06".to_vec();
+        for n in [format, tracks, 96] {
+            bytes.extend(n.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn track(len: usize, events: &[u8]) -> Vec<u8> {
+        let mut bytes = b"MTrk".to_vec();
+        bytes.extend((len as u32).to_be_bytes());
+        bytes.extend(events);
+        bytes
+    }
+
+    fn is_note_on(ev: &midly::TrackEvent) -> bool {
+        matches!(ev.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. })
+    }
+
+    #[test]
+    fn reads_an_intact_file_as_midly_does() {
+        let bytes = [header(1, 2), track(EVENTS.len(), EVENTS), track(EVENTS.len(), EVENTS)].concat();
+        let smf = parse(&bytes).unwrap();
+        assert_eq!(smf, Smf::parse(&bytes).unwrap());
+    }
+
+    #[test]
+    fn skips_junk_before_the_header() {
+        let bytes = [b"\0\x01junk".to_vec(), header(0, 1), track(EVENTS.len(), EVENTS)].concat();
+        let smf = parse(&bytes).unwrap();
+        assert_eq!(smf.tracks.len(), 1);
+        assert_eq!(smf.tracks[0].len(), 3);
+    }
+
+    #[test]
+    fn keeps_what_there_is_of_a_track_cut_short() {
+        let bytes = [header(0, 1), track(100, &EVENTS[..7])].concat();
+        let smf = parse(&bytes).unwrap();
+        assert_eq!(smf.tracks[0].len(), 1);
+        assert!(is_note_on(&smf.tracks[0][0]));
+    }
+
+    #[test]
+    fn keeps_a_track_up_to_an_unreadable_event() {
+        let events = [&EVENTS[..4], &[0x00, 0xf4, 0x00], EVENTS].concat();
+        let bytes = [header(0, 1), track(events.len(), &events)].concat();
+        let smf = parse(&bytes).unwrap();
+        assert_eq!(smf.tracks[0].len(), 1);
+    }
+
+    #[test]
+    fn skips_garbage_and_other_chunks_between_tracks() {
+        let bytes = [
+            header(1, 2),
+            track(EVENTS.len(), EVENTS),
+            b"\xde\xad\xbe".to_vec(),
+            b"XFIH\0\0\0\x02ab".to_vec(),
+            track(EVENTS.len(), EVENTS),
+            b"\xde\xad".to_vec(),
+        ]
+        .concat();
+        assert_eq!(parse(&bytes).unwrap().tracks.len(), 2);
+    }
+
+    #[test]
+    fn reads_a_bad_format_as_1() {
+        let bytes = [header(7, 1), track(EVENTS.len(), EVENTS)].concat();
+        assert_eq!(parse(&bytes).unwrap().header.format, Format::Parallel);
+    }
+
+    #[test]
+    fn fails_with_nothing_to_recover() {
+        let no_header = parse(&track(EVENTS.len(), EVENTS)).unwrap_err();
+        assert_eq!(no_header.to_string(), "no MIDI header (MThd) anywhere in the file");
+        let no_tracks = parse(&[header(1, 1), b"garbage!".to_vec()].concat()).unwrap_err();
+        assert_eq!(no_tracks.to_string(), "no tracks could be recovered");
+        let short = parse(b"MThd\0\0\0\x06\0\x01").unwrap_err();
+        assert_eq!(short.to_string(), "the MIDI header is cut short");
+    }
+}
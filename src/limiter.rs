@@ -0,0 +1,141 @@
+//! The master limiter (`--limiter`): a look-ahead brickwall limiter on the synth's output,
+//! for SoundFonts that clip on dense passages. It sees each peak a few milliseconds before
+//! it is heard and turns the level down smoothly in time for it, so nothing goes over the
+//! threshold and nothing is clipped, then lets the level back up over the release time.
+//!
+//! It works a frame at a time, so the same limiter serves the audio callbacks, which hand it
+//! a buffer at a time, and offline renders. Looking ahead delays the sound by `LOOKAHEAD`.
+
+use clap::Args;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How far ahead the limiter looks, which is also how long it takes to turn down.
+pub const LOOKAHEAD: Duration = Duration::from_millis(5);
+
+/// Master limiter options, shared by `play` and `render`:
+/// - limiter: limit the output at all
+/// - limiter_threshold: the level peaks are kept under
+/// - limiter_release: how quickly the level comes back after a peak
+#[derive(Args, Clone, Copy, Debug)]
+pub struct LimiterArgs {
+    /// Keep the synth's output from clipping with a look-ahead brickwall limiter, at the
+    /// cost of 5 ms of latency
+    #[arg(long)]
+    pub limiter: bool,
+    /// Level the limiter keeps peaks under, in dBFS
+    #[arg(long, value_name = "DB", default_value_t = -1.0, allow_negative_numbers = true,
+          value_parser = parse_threshold, requires = "limiter")]
+    pub limiter_threshold: f32,
+    /// How long the limiter takes to bring the level back after a peak, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 100.0,
+          value_parser = parse_release, requires = "limiter")]
+    pub limiter_release: f32,
+}
+
+impl LimiterArgs {
+    /// These options if the limiter is on.
+    pub fn enabled(self) -> Option<Self> {
+        self.limiter.then_some(self)
+    }
+}
+
+/// clap value parser for `--limiter-threshold`.
+fn parse_threshold(s: &str) -> Result<f32, String> {
+    match s.trim().trim_end_matches("dBFS").trim_end_matches("dB").trim().parse::<f32>() {
+        Ok(v) if (-30.0..=0.0).contains(&v) => Ok(v),
+        _ => Err(format!("invalid threshold `{s}`, expected dBFS between -30 and 0")),
+    }
+}
+
+/// clap value parser for `--limiter-release`.
+fn parse_release(s: &str) -> Result<f32, String> {
+    match s.trim().trim_end_matches("ms").trim().parse::<f32>() {
+        Ok(v) if (1.0..=5_000.0).contains(&v) => Ok(v),
+        _ => Err(format!("invalid release `{s}`, expected milliseconds between 1 and 5000")),
+    }
+}
+
+/// A limiter for interleaved stereo at one sample rate.
+pub struct Limiter {
+    threshold: f32,
+    /// How far the gain moves back towards 1.0 each frame.
+    release: f32,
+    /// `LOOKAHEAD` in frames.
+    lookahead: usize,
+    frame: u64,
+    /// Frames waiting to be played, oldest first.
+    delay: VecDeque<[f32; 2]>,
+    /// The gains the last `lookahead` frames need, as (frame, gain) with the gains rising,
+    /// so the front is the lowest: those of earlier frames that needed more are dropped.
+    needs: VecDeque<(u64, f32)>,
+    /// The lowest need at each of the last `lookahead` frames, and their sum. Their average
+    /// ramps the gain down over `lookahead` frames and reaches each need as its frame plays.
+    lowest: VecDeque<f32>,
+    lowest_sum: f64,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(args: &LimiterArgs, sample_rate: u32) -> Self {
+        let lookahead = ((LOOKAHEAD.as_secs_f64() * f64::from(sample_rate)) as usize).max(1);
+        let release_frames = f64::from(args.limiter_release) / 1000.0 * f64::from(sample_rate);
+        let mut limiter = Self {
+            threshold: 10f32.powf(args.limiter_threshold / 20.0),
+            release: (1.0 - (-1.0 / release_frames.max(1.0)).exp()) as f32,
+            lookahead,
+            frame: 0,
+            // Sized up front, so the audio callback never allocates.
+            delay: VecDeque::with_capacity(lookahead),
+            needs: VecDeque::with_capacity(lookahead + 1),
+            lowest: VecDeque::with_capacity(lookahead + 1),
+            lowest_sum: lookahead as f64,
+            gain: 1.0,
+        };
+        limiter.delay.extend(std::iter::repeat_n([0.0; 2], lookahead - 1));
+        limiter.lowest.extend(std::iter::repeat_n(1.0, lookahead));
+        limiter
+    }
+
+    /// The delay the limiter adds, in frames.
+    pub fn latency(&self) -> usize {
+        self.lookahead - 1
+    }
+
+    /// Limit interleaved stereo in place. What comes out is what went in `latency` frames
+    /// earlier.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(2) {
+            let [l, r] = self.limit([frame[0], frame[1]]);
+            (frame[0], frame[1]) = (l, r);
+        }
+    }
+
+    /// Limit one frame, for audio kept a channel to a buffer; see `process`.
+    pub fn limit(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let peak = frame[0].abs().max(frame[1].abs());
+        let need = if peak > self.threshold { self.threshold / peak } else { 1.0 };
+        while self.needs.back().is_some_and(|&(_, g)| g >= need) {
+            self.needs.pop_back();
+        }
+        self.needs.push_back((self.frame, need));
+        while self.needs.front().is_some_and(|&(at, _)| at + self.lookahead as u64 <= self.frame) {
+            self.needs.pop_front();
+        }
+        self.frame += 1;
+
+        let lowest = self.needs.front().map_or(1.0, |&(_, g)| g);
+        self.lowest.push_back(lowest);
+        self.lowest_sum += f64::from(lowest);
+        if let Some(oldest) = self.lowest.pop_front() {
+            self.lowest_sum -= f64::from(oldest);
+        }
+        let target = (self.lowest_sum / self.lookahead as f64) as f32;
+        self.gain = if target < self.gain { target } else { self.gain + (target - self.gain) * self.release };
+
+        self.delay.push_back(frame);
+        let out = self.delay.pop_front().unwrap_or_default();
+        // The clamp only catches rounding in the running sum.
+        out.map(|s| (s * self.gain).clamp(-self.threshold, self.threshold))
+    }
+}
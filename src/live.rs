@@ -0,0 +1,108 @@
+//! The `live` subcommand: the SoundFont synth as an instrument, played from a MIDI port with
+//! no song at all. `play --midi-in` does the same alongside a song.
+
+use crate::audio::{self, AudioArgs, PlaybackArgs};
+use crate::controls::{Command, RawTerminal, spawn_controls};
+use crate::interrupt::{INTERRUPTED, install_interrupt_handler};
+use crate::midi_in;
+use crate::record_midi::MidiRecorder;
+use crate::scheduler::{Event, Scheduler};
+use crate::soundfont;
+use crate::synth::{self, EffectsArgs};
+use anyhow::Result;
+use clap::Args;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// `live` options:
+/// - midi_in: the MIDI port to play from
+/// - midi_in_channel: the channel to play everything on, whichever it comes in on
+/// - record_midi: file to write what was played to
+/// - soundfont / soundfonts: the SoundFont, and any layered over it, as for `play`
+/// - effects, playback, audio: the synth and the output, as for `play`
+#[derive(Args, Debug)]
+pub struct LiveArgs {
+    /// The MIDI port to play from, by (part of) its name or on Linux `client:port`
+    #[arg(long, value_name = "PORT")]
+    midi_in: String,
+    /// Play the messages on this channel (1–16), whichever they come in on
+    #[arg(long, value_name = "CH", value_parser = clap::value_parser!(u8).range(1..=16))]
+    midi_in_channel: Option<u8>,
+    /// Write what comes in on --midi-in to this Standard MIDI File when you quit
+    #[arg(long, value_name = "FILE.mid")]
+    record_midi: Option<PathBuf>,
+    /// Path to GM SoundFont (.sf2 or .sf3); searched for in the usual places if left out
+    soundfont: Option<String>,
+    /// Layer this SoundFont over the main one; repeat for more, those given first winning
+    #[arg(long = "soundfont", value_name = "FONT.sf2")]
+    soundfonts: Vec<String>,
+    #[command(flatten)]
+    effects: EffectsArgs,
+    #[command(flatten)]
+    playback: PlaybackArgs,
+    #[command(flatten)]
+    audio: AudioArgs,
+}
+
+/// Play what comes in on the MIDI port until the user quits.
+pub fn run(opt: LiveArgs) -> Result<()> {
+    let soundfonts = soundfont::resolve_all(opt.soundfont.as_deref(), &opt.soundfonts)?;
+    info!("Using SoundFont: {}", soundfonts.join(" over "));
+    let (dev, cfg) = audio::output_device(&opt.audio)?;
+    let sample_rate = cfg.sample_rate().0 as f32;
+    // The gain `play` starts at. There is no song to schedule, but the panic key and the
+    // silence at the end reach the callback's synth through the scheduler's queue.
+    let synth = synth::open(&soundfonts, 0.7, sample_rate, opt.effects)?;
+    let (synth, mut events) = Scheduler::wrap(synth, sample_rate);
+
+    install_interrupt_handler();
+    let (cmd_tx, cmd_rx) = mpsc::channel();
+    let sink = audio::Sink::Device(dev, cfg);
+    let output = audio::Output::start(sink, &opt.audio, &opt.playback, synth, cmd_tx.clone(), None)?;
+    let recorder = opt.record_midi.as_deref().map(MidiRecorder::start).transpose()?;
+    let input = {
+        let mut live = events.live().expect("only taken here");
+        let take = recorder.as_ref().map(MidiRecorder::take);
+        let channel = opt.midi_in_channel.map(|ch| ch - 1);
+        midi_in::open(&opt.midi_in, channel, move |msg| {
+            if let Some(take) = &take {
+                take.lock().unwrap().push(msg);
+            }
+            live.play(msg);
+        })?
+    };
+
+    let raw = RawTerminal::enable();
+    if raw.is_some() {
+        info!("Controls: q = quit, ! = panic (all notes off)");
+    }
+    spawn_controls(cmd_tx, raw.is_some());
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        match cmd_rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(Command::Quit) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Ok(Command::Panic) => {
+                events.send(Event::Panic);
+                info!("Panic: all notes off");
+            }
+            Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+    }
+
+    drop(input);
+    events.send(Event::Silence);
+    output.report_stats();
+    drop(output);
+    drop(raw);
+    if let Some(recorder) = recorder
+        && let Err(e) = recorder.finish()
+    {
+        warn!("{e:#}");
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        std::process::exit(130);
+    }
+    Ok(())
+}
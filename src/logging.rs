@@ -0,0 +1,93 @@
+//! Console logging for the `tracing` macros, with the level picked by `-v` / `-q`.
+//!
+//! Info messages are the player's normal output and go to stdout as plain lines. Warnings,
+//! errors and the debug detail enabled by `-v` go to stderr with a level prefix, so they stay
+//! out of anything a script reads from stdout. With `--output json` every message is a JSON
+//! line on stderr, and when stdout carries audio (`render -o -`) info messages go to stderr as
+//! well. Libraries that log through `log`, such as OxiSynth, are forwarded to the same place.
+
+use crate::output;
+use serde_json::json;
+use std::cell::Cell;
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Writes each event as a line on stdout or stderr.
+struct Console;
+
+static STDOUT_TAKEN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Set on threads whose progress is reported for them, which only log warnings and errors.
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The text of an event's `message` field; other fields are not used.
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{value:?}");
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Console {
+    fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
+        // Debug detail is only wanted from this program, not from the libraries it uses.
+        *metadata.level() <= Level::INFO || metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let level = *event.metadata().level();
+        // Checked here rather than in `enabled`, whose answer is kept for every thread.
+        if level > Level::WARN && QUIET.get() {
+            return;
+        }
+        let mut message = Message::default();
+        event.record(&mut message);
+        let message = message.0;
+        if output::is_json() {
+            // Keep stdout for the JSON results and events; messages go to stderr as JSON too.
+            eprintln!("{}", json!({ "level": level.as_str().to_lowercase(), "message": message }));
+            return;
+        }
+        match level {
+            Level::INFO if STDOUT_TAKEN.load(Ordering::Relaxed) => eprintln!("{message}"),
+            Level::INFO => println!("{message}"),
+            level => eprintln!("{}: {message}", level.as_str().to_lowercase()),
+        }
+    }
+}
+
+/// Install the logger. Each `-v` adds a level of detail and each `-q` takes one away,
+/// starting from info.
+pub fn init(verbose: u8, quiet: u8) {
+    let level = match i16::from(verbose) - i16::from(quiet) {
+        ..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        2.. => LevelFilter::TRACE,
+    };
+    // Only fails if a subscriber is already set, and main calls this once.
+    let _ = tracing_subscriber::registry().with(level).with(Console).try_init();
+}
+
+/// Keep stdout for data the command writes there: info messages go to stderr from now on.
+pub fn stdout_taken() {
+    STDOUT_TAKEN.store(true, Ordering::Relaxed);
+}
+
+/// Only log warnings and errors from the current thread, such as a worker of `render
+/// --recursive`, whose messages would be interleaved with the other workers'.
+pub fn quiet_thread() {
+    QUIET.set(true);
+}
@@ -0,0 +1,216 @@
+//! Loudness as ITU-R BS.1770 and EBU R128 measure it, for `render --normalize`: the
+//! integrated loudness of a whole render, its true peak, and a limiter that keeps the true
+//! peak under a ceiling once the gain is applied.
+//!
+//! Renders are measured after the fact, in memory, so nothing here has to work in real time:
+//! the limiter looks as far ahead as it likes.
+
+use std::f64::consts::PI;
+
+/// Gating blocks are 400 ms long and start every 100 ms.
+const STEP_MS: u32 = 100;
+const BLOCK_STEPS: usize = 4;
+
+/// Blocks quieter than this never count, in LUFS.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Nor do blocks this far below the loudness of those left, in LU.
+const RELATIVE_GATE: f64 = -10.0;
+
+/// True peaks are looked for at 4× the sample rate, as BS.1770 asks for at 48 kHz.
+const OVERSAMPLE: usize = 4;
+
+/// Samples used on each side of an interpolated point.
+const HALF_TAPS: isize = 6;
+
+/// A biquad filter, direct form I.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn run(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting filter at `rate`: a high shelf for the head's effect on sound, then a
+/// high pass that ignores the lowest bass. BS.1770 only gives coefficients for 48 kHz, so
+/// they are worked out from the analog prototypes as libebur128 does.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = f64::from(rate);
+
+    let (f0, gain_db, q) = (1_681.974_450_955_533, 3.999_843_853_973_347, 0.707_175_236_955_419_6);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.135_470_876_024_44, 0.500_327_037_323_877_3);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    [shelf, high_pass]
+}
+
+/// The integrated loudness of interleaved `samples` at `rate`, in LUFS, or `None` if there
+/// is nothing above the absolute gate to measure (silence, or less than 400 ms). Every
+/// channel is weighted alike, as BS.1770 has it for left and right.
+pub fn integrated(samples: &[f32], channels: usize, rate: u32) -> Option<f64> {
+    let step = (rate * STEP_MS / 1000) as usize;
+    let mut filters: Vec<[Biquad; 2]> = (0..channels).map(|_| k_weighting(rate)).collect();
+
+    // The mean square of each 100 ms step, summed over channels.
+    let mut steps = Vec::new();
+    let (mut sum, mut frames) = (0.0, 0);
+    for frame in samples.chunks_exact(channels) {
+        for (&s, [shelf, high_pass]) in frame.iter().zip(&mut filters) {
+            let y = high_pass.run(shelf.run(f64::from(s)));
+            sum += y * y;
+        }
+        frames += 1;
+        if frames == step {
+            steps.push(sum / step as f64);
+            (sum, frames) = (0.0, 0);
+        }
+    }
+
+    let blocks: Vec<f64> = steps.windows(BLOCK_STEPS).map(|w| w.iter().sum::<f64>() / BLOCK_STEPS as f64).collect();
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    // The mean power of the blocks louder than `gate`.
+    let gated = |gate: f64| {
+        let kept: Vec<f64> = blocks.iter().copied().filter(|&p| loudness(p) > gate).collect();
+        (!kept.is_empty()).then(|| kept.iter().sum::<f64>() / kept.len() as f64)
+    };
+    let power = gated(ABSOLUTE_GATE)?;
+    let power = gated(loudness(power) + RELATIVE_GATE)?;
+    Some(loudness(power))
+}
+
+/// The true peak around each frame of interleaved `samples`: the largest magnitude on any
+/// channel at the frame or on the way to the next one, which can be above both when the
+/// waveform is rebuilt between them.
+pub fn frame_peaks(samples: &[f32], channels: usize) -> Vec<f32> {
+    let frames = samples.len() / channels;
+    // A Hann-windowed sinc for each point between two samples.
+    let taps: Vec<Vec<f64>> = (1..OVERSAMPLE)
+        .map(|p| {
+            let frac = p as f64 / OVERSAMPLE as f64;
+            (1 - HALF_TAPS..=HALF_TAPS)
+                .map(|k| {
+                    let t = frac - k as f64;
+                    let window = 0.5 * (1.0 + (PI * t / HALF_TAPS as f64).cos());
+                    (PI * t).sin() / (PI * t) * window
+                })
+                .collect()
+        })
+        .collect();
+    let at = |i: isize, c: usize| match usize::try_from(i) {
+        Ok(i) if i < frames => f64::from(samples[i * channels + c]),
+        _ => 0.0,
+    };
+
+    (0..frames as isize)
+        .map(|i| {
+            let mut peak = 0f64;
+            for c in 0..channels {
+                peak = peak.max(at(i, c).abs());
+                for taps in &taps {
+                    let v: f64 = taps.iter().zip(1 - HALF_TAPS..=HALF_TAPS).map(|(h, k)| h * at(i + k, c)).sum();
+                    peak = peak.max(v.abs());
+                }
+            }
+            peak as f32
+        })
+        .collect()
+}
+
+/// Turn interleaved `samples` down wherever their `peaks` (from `frame_peaks`) go over
+/// `ceiling`: the gain ramps down over `attack` frames before each peak, so the peak itself
+/// is never clipped, and recovers with a time constant of `release` frames after it.
+/// Returns whether any frame was turned down.
+pub fn limit(samples: &mut [f32], channels: usize, peaks: &[f32], ceiling: f32, attack: usize, release: f64) -> bool {
+    let mut gains: Vec<f32> = peaks.iter().map(|&p| if p > ceiling { ceiling / p } else { 1.0 }).collect();
+    if gains.iter().all(|&g| g == 1.0) {
+        return false;
+    }
+    // Backwards, so each dip starts early enough; then forwards, so it recovers slowly.
+    let step = 1.0 / attack.max(1) as f32;
+    for i in (0..gains.len().saturating_sub(1)).rev() {
+        gains[i] = gains[i].min(gains[i + 1] + step);
+    }
+    let recover = (1.0 - (-1.0 / release.max(1.0)).exp()) as f32;
+    for i in 1..gains.len() {
+        gains[i] = gains[i].min(gains[i - 1] + (1.0 - gains[i - 1]) * recover);
+    }
+    for (frame, gain) in samples.chunks_exact_mut(channels).zip(gains) {
+        frame.iter_mut().for_each(|s| *s *= gain);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    /// Stereo 1 kHz sine, both channels in phase, at each `(dBFS, seconds)` in turn: the
+    /// signals of EBU Tech 3341.
+    fn sine(parts: &[(f64, f64)]) -> Vec<f32> {
+        let mut samples = Vec::new();
+        let mut n = 0u64;
+        for &(db, secs) in parts {
+            let amplitude = 10f64.powf(db / 20.0);
+            for _ in 0..(secs * f64::from(RATE)) as u64 {
+                let s = (amplitude * (2.0 * PI * 1000.0 * n as f64 / f64::from(RATE)).sin()) as f32;
+                samples.extend([s, s]);
+                n += 1;
+            }
+        }
+        samples
+    }
+
+    fn assert_lufs(samples: &[f32], expected: f64) {
+        let lufs = integrated(samples, 2, RATE).unwrap();
+        assert!((lufs - expected).abs() <= 0.1, "{lufs} LUFS, expected {expected}");
+    }
+
+    #[test]
+    fn measures_a_sine_at_minus_23_dbfs_as_minus_23_lufs() {
+        assert_lufs(&sine(&[(-23.0, 20.0)]), -23.0); // Tech 3341 case 1
+        assert_lufs(&sine(&[(-33.0, 20.0)]), -33.0); // case 2
+    }
+
+    #[test]
+    fn gates_out_quieter_passages() {
+        // Case 3: the quiet ends are 13 LU down, past the relative gate.
+        assert_lufs(&sine(&[(-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0)]), -23.0);
+        // Case 4: the ends under -70 LUFS never count at all.
+        let parts = [(-72.0, 20.0), (-26.0, 20.0), (-20.0, 20.1), (-26.0, 20.0), (-72.0, 20.0)];
+        assert_lufs(&sine(&parts), -23.0);
+    }
+
+    #[test]
+    fn finds_nothing_to_measure_in_silence() {
+        assert_eq!(integrated(&vec![0.0; RATE as usize * 4], 2, RATE), None);
+        assert_eq!(integrated(&sine(&[(-23.0, 0.3)]), 2, RATE), None);
+    }
+}
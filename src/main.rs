@@ -1,352 +1,171 @@
-use anyhow::{Context, Result};
-use clap::Parser;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use fluidlite::{Settings, Synth};
-use midly::{MetaMessage, Smf, TrackEventKind};
-use std::{
-    fs, sync::{Arc, Mutex}, thread, time::{Duration, Instant}
-};
-
-/// CLI options:
-/// - midi: path to a Standard MIDI file
-/// - soundfont: path to a GM .sf2 SoundFont
+mod audio;
+mod bars;
+mod conductor;
+mod controls;
+mod dither;
+mod doctor;
+mod export_midi;
+mod export_notes;
+mod fallback;
+mod flac;
+#[cfg(feature = "fluidlite")]
+mod fluid;
+mod gain_scan;
+mod info;
+mod interrupt;
+#[cfg(feature = "jack")]
+mod jack;
+mod latency;
+mod lenient;
+mod limiter;
+mod live;
+mod logging;
+mod loudness;
+mod midi_in;
+mod midi_out;
+mod mirror;
+#[cfg(feature = "mp3")]
+mod mp3;
+#[cfg(any(feature = "opus", feature = "vorbis"))]
+mod ogg;
+mod output;
+#[cfg(feature = "oxisynth")]
+mod oxi;
+mod play;
+mod png;
+mod priority;
+mod queue;
+mod record;
+mod record_midi;
+mod render;
+mod render_image;
+mod resume;
+mod riff;
+mod rmid;
+mod scheduler;
+mod sidecar;
+mod song;
+mod soundfont;
+mod soundfont_info;
+mod spectrogram;
+mod synth;
+mod sysex;
+mod tempo;
+mod time;
+mod timing;
+mod watch;
+
+#[cfg(not(any(feature = "fluidlite", feature = "oxisynth")))]
+compile_error!("midi-play needs a synth engine: build with the `fluidlite` or `oxisynth` feature");
+
+use anyhow::Result;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use std::ffi::OsString;
+
+/// Play Standard MIDI Files through a SoundFont synthesizer.
+///
+/// `midi-play song.mid font.sf2` is short for `midi-play play song.mid font.sf2`.
 #[derive(Parser, Debug)]
-struct Opt {
-    /// Path to .mid file
-    midi: String,
-    /// Path to GM SoundFont (.sf2)
-    soundfont: String,
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Cmd,
+    /// Show more detail: -v for file events and timing, -vv for everything
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Show less: -q for warnings and errors only, -qq for errors only
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
 }
 
-fn main() -> Result<()> {
-    let opt = Opt::parse();
-
-    println!("Playing MIDI file: {}", opt.midi);
-    println!("Using SoundFont: {}", opt.soundfont);
-
-    // 1) Read and parse the MIDI file into an in-memory SMF structure.
-    let bytes = fs::read(&opt.midi).with_context(|| "reading MIDI file")?;
-    let smf = Smf::parse(&bytes).with_context(|| "parsing MIDI")?;
-
-    // 2) Timing setup.
-    // PPQ = pulses (ticks) per quarter note. We need this to convert MIDI delta ticks to time.
-    let ppq = match smf.header.timing {
-        midly::Timing::Metrical(t) => t.as_int() as f64,
-        _ => 480.0, // fallback if file uses SMPTE timing
-    };
-    println!("PPQ (ticks per quarter note): {}", ppq);
-
-    // Default tempo if the file does not set one: 120 BPM = 500_000 microseconds per quarter note.
-    let mut default_us_per_qn: f64 = 500_000.0;
-    // Scan tracks for the first Tempo meta event to seed the initial tempo.
-    'scan: for tr in &smf.tracks {
-        for ev in tr {
-            if let TrackEventKind::Meta(midly::MetaMessage::Tempo(tp)) = ev.kind {
-                default_us_per_qn = tp.as_int() as f64;
-                break 'scan;
-            }
-        }
-    }
-    println!("Initial tempo: {} µs per quarter note (~{:.1} BPM)", 
-         default_us_per_qn, 60_000_000.0 / default_us_per_qn);
-
-    // 3) Build a single timeline of timestamped events.
-    // We convert each track’s delta ticks to absolute time in microseconds, then merge.
-    #[derive(Clone, Copy)]
-    /// Represents a MIDI message extracted from the timeline.
-    ///
-    /// Each variant corresponds to a MIDI event type.
-    /// Fields follow the MIDI message structure:
-    /// - First parameter is usually the channel (0–15)
-    /// - Subsequent parameters depend on the event type
-    enum Msg {
-        /// Note On: Start playing a note.
-        /// - channel: 0–15
-        /// - key: MIDI note number (0–127)
-        /// - velocity: 0–127
-        NoteOn(u8, u8, u8),
-
-        /// Note Off: Stop playing a note.
-        /// - channel: 0–15
-        /// - key: MIDI note number (0–127)
-        /// - velocity: release velocity (0–127, often unused)
-        NoteOff(u8, u8, u8),
-
-        /// Program Change: Change the program (also known as instrument) for a channel.
-        /// - channel: 0–15
-        /// - program: instrument/patch number (0–127)
-        Program(u8, u8),
-
-        /// Control: Modify the value of a MIDI controller.
-        /// - channel: 0–15
-        /// - controller: controller number (0–127)
-        /// - value: controller value (0–127)
-        Control(u8, u8, u8),
-
-        /// Pitch Bend: Set the pitch bend value for the entire channel.
-        /// - channel: 0–15
-        /// - bend value: 14-bit signed value, 0–16383
-        ///   - center (no bend) = 8192
-        ///   - <8192 = bend down, >8192 = bend up
-        PitchBend(u8, u16),
-
-        /// Aftertouch (Polyphonic): Modify the velocity of a note after it has been played.
-        /// - channel: 0–15
-        /// - key: MIDI note number (0–127)
-        /// - velocity: 0–127, The velocity of the key
-        AfterTouch(u8, u8, u8),
-
-        /// ChannelAftertouch: Change the note velocity of a whole channel at once, without starting new notes.
-        /// - channel: 0–15
-        /// - pressure: 0–127
-        ChannelAftertouch(u8, u8),
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Play a MIDI file in real time, with interactive controls
+    Play(Box<play::PlayArgs>),
+    /// Play the synth live from a MIDI keyboard or controller, without a song
+    Live(Box<live::LiveArgs>),
+    /// Render a MIDI file to an audio file (WAV, FLAC, Opus, Vorbis, MP3) without playing it
+    Render(Box<render::RenderArgs>),
+    /// Draw the song as a piano roll to a PNG or SVG image
+    RenderImage(render_image::RenderImageArgs),
+    /// Describe a MIDI file: format, tracks, instruments, tempo, markers and length
+    Info(info::InfoArgs),
+    /// List the audio output devices
+    Devices(audio::DevicesArgs),
+    /// Check the audio device and SoundFont, for when there is no sound
+    Doctor(doctor::DoctorArgs),
+    /// Play clicks on the audio device and measure how late they come out
+    Latency(latency::LatencyArgs),
+    /// Measure the loudness of MIDI files or renders and work out their ReplayGain
+    GainScan(gain_scan::GainScanArgs),
+    /// Write the song back out as a MIDI file, transposed, muted or sped up as for `play`
+    ExportMidi(export_midi::ExportMidiArgs),
+    /// List every note with its start, duration, channel, key, velocity and track, as CSV or JSON
+    ExportNotes(export_notes::ExportNotesArgs),
+    /// Inspect a SoundFont: its presets and samples, and the General MIDI presets it lacks
+    Soundfont(soundfont_info::SoundfontArgs),
+}
 
-        /// Tempo change: (microseconds per quarter note)
-        /// - value is in µs per quarter note (not BPM)
-        /// - To convert to BPM: bpm = 60_000_000 / value
-        #[allow(dead_code)]
-        Tempo(f64),
+fn main() -> Result<()> {
+    let cli = Cli::parse_from(with_default_command(std::env::args_os().collect()));
+    // Set before logging starts, since JSON mode also changes how messages are written.
+    output::set_format(match &cli.command {
+        Cmd::Play(args) => args.output.format,
+        Cmd::Info(args) => args.output.format,
+        Cmd::Devices(args) => args.output.format,
+        Cmd::Doctor(args) => args.output.format,
+        Cmd::Latency(args) => args.output.format,
+        Cmd::GainScan(args) => args.output.format,
+        Cmd::ExportNotes(args) => args.output.format,
+        Cmd::Soundfont(args) => args.output().format,
+        Cmd::Live(_) | Cmd::Render(_) | Cmd::RenderImage(_) | Cmd::ExportMidi(_) => output::OutputFormat::Text,
+    });
+    if let Cmd::Play(args) = &cli.command {
+        output::set_notify(args.notify);
     }
-    
-    #[derive(Clone, Copy)]
-    struct Timed {
-        t_us: u64, // absolute time in microseconds since start
-        msg: Msg,
+    logging::init(cli.verbose, cli.quiet);
+    // It changes the environment, so it goes before anything starts a thread.
+    #[cfg(target_os = "linux")]
+    if let Cmd::Play(args) = &cli.command {
+        audio::describe_stream(&args.song.midi);
     }
-
-    let mut timeline: Vec<Timed> = Vec::new();
-
-    // Walk every track and accumulate absolute tick count.
-    // Convert ticks to time using the current tempo, which can change mid track.
-    for tr in &smf.tracks {
-        let mut abs_ticks: u64 = 0;
-        let mut us_per_qn = default_us_per_qn;
-
-        for ev in tr {
-            abs_ticks += ev.delta.as_int() as u64;
-
-            // ticks -> seconds -> microseconds, using the current tempo
-            let t_sec = (abs_ticks as f64) / ppq * (us_per_qn / 1_000_000.0);
-            let t_us = (t_sec * 1_000_000.0) as u64;
-
-            match ev.kind {
-                // Metadata
-                TrackEventKind::Meta(m) => {
-                    match m {
-                        // Tempo changes affect future events in this track.
-                        MetaMessage::Tempo(tp) => {
-                            us_per_qn = tp.as_int() as f64;
-                            timeline.push(Timed { t_us, msg: Msg::Tempo(us_per_qn) });
-                            println!("Tempo change at {} µs: {:.1} BPM", t_us, 60_000_000.0 / us_per_qn);
-                        }
-                        MetaMessage::TimeSignature(numer, denom, _, _) => {
-                            println!("Time signature: {}/{}", numer, 1 << denom);
-                        }
-                        MetaMessage::KeySignature(key, scale) => {
-                            println!("Key signature: {:?} ({})", key, if !scale { "major" } else { "minor" });
-                        }
-                        MetaMessage::TrackName(name) => {
-                            if let Ok(s) = std::str::from_utf8(name) {
-                                println!("Track name: {}", s);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                // MIDI messages
-                TrackEventKind::Midi { channel, message } => {
-                    let ch = u8::from(channel);
-                    use midly::MidiMessage::*;
-                    match message {
-                        NoteOn { key, vel } if vel.as_int() == 0 => {
-                            // normalize to NoteOff to avoid any synth-specific ambiguity
-                            timeline.push(Timed { t_us, msg: Msg::NoteOff(ch, key.as_int(), 0) });
-                        }
-                        NoteOn { key, vel } => {
-                            timeline.push(Timed { t_us, msg: Msg::NoteOn(ch, key.as_int(), vel.as_int()) });
-                        }
-                        NoteOff { key, vel } => {
-                            timeline.push(Timed { t_us, msg: Msg::NoteOff(ch, key.as_int(), vel.as_int()) });
-                        }
-                        ProgramChange { program } => {
-                            timeline.push(Timed { t_us, msg: Msg::Program(ch, program.as_int()) });
-                        }
-                        Controller { controller, value } => {
-                            timeline.push(Timed { t_us, msg: Msg::Control(ch, controller.as_int(), value.as_int()) });
-                        }
-                        PitchBend { bend } => {
-                            let raw = bend.0.as_int(); 
-                            timeline.push(Timed { t_us, msg: Msg::PitchBend(ch, raw) });
-                        }
-                        Aftertouch { key, vel } => {
-                            timeline.push(Timed { t_us, msg: Msg::AfterTouch(ch, key.as_int(), vel.as_int()) }); 
-                        }
-                        ChannelAftertouch { vel } => {
-                            timeline.push(Timed { t_us, msg: Msg::ChannelAftertouch(ch, vel.as_int()) });
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
+    let result = match cli.command {
+        Cmd::Play(args) => play::run(*args),
+        Cmd::Live(args) => live::run(*args),
+        Cmd::Render(args) => render::run(*args),
+        Cmd::RenderImage(args) => render_image::run(args),
+        Cmd::Info(args) => info::run(args),
+        Cmd::Devices(args) => audio::list_devices(args),
+        Cmd::Doctor(args) => doctor::run(args),
+        Cmd::Latency(args) => latency::run(args),
+        Cmd::GainScan(args) => gain_scan::run(args),
+        Cmd::ExportMidi(args) => export_midi::run(args),
+        Cmd::ExportNotes(args) => export_notes::run(args),
+        Cmd::Soundfont(args) => soundfont_info::run(args),
+    };
+    if let Err(e) = &result {
+        output::progress("error", [("message", format!("{e:#}").into())]);
     }
+    result
+}
 
-    // Merge and order events from all tracks by absolute time.
-    timeline.sort_by_key(|e| e.t_us);
-    let last_t_us = timeline.last().map(|e| e.t_us).unwrap_or(0);
-
-    println!("Total events parsed: {}", timeline.len());
-    println!("Estimated track length: {}", format_duration(last_t_us));
-
-    // 4) Create a FluidLite synth, load the SoundFont, and share it across threads.
-    let settings = Settings::new()?;
-
-    let fl = Synth::new(settings)?;
-    fl.sfload(&opt.soundfont, true).context("loading soundfont")?;
-
-    let id = fl.sfload(&opt.soundfont, true).context("loading soundfont")?;
-    println!("Loaded SoundFont: {} (id={})", opt.soundfont, id);
-    
-    // Master gain
-    fl.set_gain(0.7);
-
-    // Reverb
-    fl.set_reverb_on(true);
-    fl.set_reverb_params(0.7, 0.2, 0.9, 0.5); // roomsize, damp, width, level
-
-    // Chorus
-    fl.set_chorus_on(true);
-    fl.set_chorus_params(3, 1.2, 0.30, 8.0, Default::default()); // the default should be Sine
-    
-    let synth = Arc::new(Mutex::new(fl));
-    
-    // 5) Set up audio output with CPAL and let FluidLite fill the audio buffers.
-    let host = cpal::default_host();
-    let dev = host.default_output_device().context("no default output device")?;
-    let cfg = dev.default_output_config().context("default_output_config")?;
-
-    // Tell FluidLite the audio device sample rate so it renders at the correct rate.
-    let sample_rate = cfg.sample_rate().0 as f32;
+/// Insert `play` when the first argument after any `-v` / `-q` flags is not a subcommand or
+/// another flag, so the original `midi-play song.mid font.sf2` form keeps working.
+fn with_default_command(mut args: Vec<OsString>) -> Vec<OsString> {
+    let cli = Cli::command();
+    let is_command = |a: &str| a == "help" || cli.find_subcommand(a).is_some();
+    let is_verbosity = |a: &str| match a.strip_prefix('-') {
+        Some("-verbose" | "-quiet") => true,
+        Some(f) => !f.is_empty() && (f.bytes().all(|c| c == b'v') || f.bytes().all(|c| c == b'q')),
+        None => false,
+    };
+    let first = args.iter().skip(1).position(|a| !a.to_str().is_some_and(is_verbosity)).map(|i| i + 1);
+    if let Some(i) = first
+        && let Some(arg) = args[i].to_str()
+        && !arg.starts_with('-')
+        && !is_command(arg)
     {
-        let s = synth.lock().unwrap();
-        s.set_sample_rate(sample_rate);
-
-        // clean start
-        for ch in 0..16u32 {
-            let _ = s.pitch_bend(ch, 8192); // center
-            let _ = s.cc(ch, 121, 0);       // Reset All Controllers
-            let _ = s.cc(ch, 120, 0);       // All Sound Off (optional)
-        }
+        args.insert(i, "play".into());
     }
-    let fmt = cfg.sample_format();
-    let stream_cfg = cfg.config();
-
-    println!("Sample rate set to {}", sample_rate);
-
-    // 6) Start a simple "conductor" thread.
-    // It schedules MIDI events in wall-clock time and sends them to the synth.
-    // The CPAL audio callback runs in parallel and pulls audio from the synth.
-    let synth_for_midi = synth.clone();
-    let timeline_for_midi = timeline.clone();
-    thread::spawn(move || {
-        let start = Instant::now();
-        let mut i = 0usize;
-
-        while i < timeline_for_midi.len() {
-            let now_us = start.elapsed().as_micros() as u64;
-
-            // Dispatch all events that are due at this moment
-            while i < timeline_for_midi.len() && timeline_for_midi[i].t_us <= now_us {
-                let s = synth_for_midi.lock().unwrap();
-                match timeline_for_midi[i].msg {
-                    Msg::NoteOn(ch, key, vel) => {
-                        let _ = s.note_on(ch as u32, key as u32, vel as u32);
-                    }
-                    Msg::NoteOff(ch, key, _vel) => {
-                        let _ = s.note_off(ch as u32, key as u32);
-                    }
-                    Msg::Program(ch, prog) => {
-                        let _ = s.program_change(ch as u32, prog as u32);
-                    }
-                    Msg::Control(ch, cc, val) => {
-                        let _ = s.cc(ch as u32, cc as u32, val as u32);
-                    }
-                    Msg::PitchBend(ch, bend) => {
-                        if bend > 16383 {
-                            eprintln!("Dropping out-of-range raw bend {}", bend);
-                        } else {
-                            let _ = s.pitch_bend(ch as u32, bend as u32);
-                        }
-                    }
-                    Msg::AfterTouch(ch, key, vel) => {
-                        let _ = s.key_pressure(ch as u32, key as u32, vel as u32);
-                    }
-                    Msg::ChannelAftertouch(ch, vel) => {
-                        let _ = s.channel_pressure(ch as u32, vel as u32);
-                    }
-                    Msg::Tempo(_) => {
-                        // Timeline already has absolute times, so no rescale is needed here.
-                    }
-                }
-                i += 1;
-            }
-
-            // Short sleep to avoid busy waiting. This is a simple scheduler.
-            thread::sleep(Duration::from_millis(1));
-        }
-
-        // After the last event, let tails ring out
-        thread::sleep(Duration::from_secs(2));
-    });
-
-    // 7) Build the CPAL output stream. We support f32 or i16, call the matching Synth::write.
-    let err_fn = |e| eprintln!("stream error: {e}");
-    let stream = match fmt {
-        cpal::SampleFormat::I16 => {
-            dev.build_output_stream(
-                &stream_cfg,
-                {
-                    let synth = synth.clone();
-                    move |out: &mut [i16], _| {
-                        if let Err(e) = synth.lock().unwrap().write(out) {
-                            eprintln!("fluid write i16: {e}");
-                        }
-                    }
-                },
-                err_fn,
-                None,
-            )?
-        }
-        _ => {
-            // Default to f32. This is the common format on macOS.
-            dev.build_output_stream(
-                &stream_cfg,
-                {
-                    let synth = synth.clone();
-                    move |out: &mut [f32], _| {
-                        if let Err(e) = synth.lock().unwrap().write(out) {
-                            eprintln!("fluid write f32: {e}");
-                        }
-                    }
-                },
-                err_fn,
-                None,
-            )?
-        }
-    };
-
-    // Start audio
-    stream.play()?;
-
-    // Keep main alive until the song finishes plus a short tail
-    let secs = (last_t_us as f64) / 1_000_000.0 + 3.0;
-    thread::sleep(Duration::from_secs_f64(secs));
-    Ok(())
+    args
 }
-
-fn format_duration(us: u64) -> String {
-    let total_secs = us / 1_000_000;
-    let mins = total_secs / 60;
-    let secs = total_secs % 60;
-    format!("{:02}:{:02}", mins, secs)
-}
\ No newline at end of file
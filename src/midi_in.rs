@@ -0,0 +1,71 @@
+//! `--midi-in`: playing the synth from a keyboard, a controller or another program.
+//!
+//! The port is opened through midir, as for `--midi-out`, and midir's own thread waits on
+//! it. Notes, controllers, programs, pressure and pitch bend are handed on as they arrive,
+//! through a queue of their own rather than behind the song's, so they are heard with the
+//! next audio buffer.
+
+use crate::midi_out::find_port;
+use crate::song::Msg;
+use anyhow::{Context, Result, anyhow};
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use tracing::info;
+
+/// A MIDI input being listened to. Dropping it stops listening.
+pub struct Input {
+    _connection: MidiInputConnection<()>,
+}
+
+/// Open the MIDI port `wanted` names and hand each message it sends to `play` as it comes,
+/// moved to `channel` (0–15) if given, whichever channel it was sent on.
+pub fn open(wanted: &str, channel: Option<u8>, mut play: impl FnMut(Msg) + Send + 'static) -> Result<Input> {
+    let mut input = MidiInput::new("midi-play").context("opening MIDI input")?;
+    // SysEx, clock and active sensing do not play the synth.
+    input.ignore(Ignore::All);
+    let (port, name) = find_port(&input, wanted)?;
+    let on_message = move |_: u64, bytes: &[u8], _: &mut ()| {
+        if let Some(msg) = message(bytes, channel) {
+            play(msg);
+        }
+    };
+    let connection =
+        input.connect(&port, "in", on_message, ()).map_err(|e| anyhow!("connecting from {name}: {}", e.kind()))?;
+    info!("Playing MIDI from {name}");
+    Ok(Input { _connection: connection })
+}
+
+/// The message `bytes` make, on `channel` if given, or `None` for one that does not play
+/// the synth.
+fn message(bytes: &[u8], channel: Option<u8>) -> Option<Msg> {
+    let (&status, data) = bytes.split_first()?;
+    let ch = channel.unwrap_or(status & 0x0f);
+    let data = |n: usize| data.get(n).map(|b| b & 0x7f);
+    Some(match status & 0xf0 {
+        0x90 => match (data(0)?, data(1)?) {
+            (key, 0) => Msg::NoteOff(ch, key, 0),
+            (key, vel) => Msg::NoteOn(ch, key, vel),
+        },
+        0x80 => Msg::NoteOff(ch, data(0)?, data(1)?),
+        0xa0 => Msg::AfterTouch(ch, data(0)?, data(1)?),
+        0xb0 => Msg::Control(ch, data(0)?, data(1)?),
+        0xc0 => Msg::Program(ch, data(0)?),
+        0xd0 => Msg::ChannelAftertouch(ch, data(0)?),
+        0xe0 => Msg::PitchBend(ch, u16::from(data(0)?) | u16::from(data(1)?) << 7),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_channel_messages_onto_the_channel_asked_for() {
+        assert!(matches!(message(&[0x91, 60, 100], None), Some(Msg::NoteOn(1, 60, 100))));
+        assert!(matches!(message(&[0x91, 60, 0], Some(4)), Some(Msg::NoteOff(4, 60, 0))));
+        assert!(matches!(message(&[0xe0, 0x00, 0x40], None), Some(Msg::PitchBend(0, 8192))));
+        assert!(matches!(message(&[0xc2, 5], None), Some(Msg::Program(2, 5))));
+        assert!(message(&[0xf8], None).is_none());
+        assert!(message(&[0x90, 60], None).is_none());
+    }
+}
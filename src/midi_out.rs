@@ -0,0 +1,437 @@
+//! `play --midi-out`: sending the song to an external synth, a keyboard or another program
+//! instead of playing it on the SoundFont synth.
+//!
+//! The port is opened through midir: the ALSA sequencer on Linux, where the player becomes
+//! a client called `midi-play`, CoreMIDI on macOS and the Windows MIDI API. The "synth" here
+//! renders silence, and sends each message it gets: the scheduler hands it the song's
+//! messages as they fall due, and with no audio device to pull the audio, a paced output
+//! (`audio::Sink::Paced`) renders it in step with the wall clock. It is driven from the
+//! audio callback, which must not wait on a port, so it only queues the messages, and a
+//! thread of its own sends them.
+//!
+//! With `--also-synth` the song plays on both: a `Layered` synth passes every message to
+//! the SoundFont synth and the port, and the device pulls the audio as usual. The port then
+//! hears of each message as its buffer is rendered, ahead of the synth's audio by the
+//! output's latency; `--midi-out-delay` holds the port's messages back to line the two up,
+//! the sending thread sending each that long after it was queued.
+
+use crate::queue::{self, Consumer, Producer};
+use crate::song::Msg;
+use crate::synth::{Synth, Synthesizer};
+use anyhow::{Context, Result, anyhow, bail};
+use midir::{MidiIO, MidiOutput, MidiOutputConnection};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// The longest message put together here: a Master Volume. SysEx from the file and the
+/// resets are sent from where they are kept, however long.
+const MAX_MESSAGE: usize = 8;
+
+/// How many messages can wait to be sent: far more than are ever due within a buffer.
+const QUEUE_LEN: usize = 4096;
+
+/// How long the sending thread sleeps when there is nothing to send.
+const POLL: Duration = Duration::from_millis(1);
+
+/// A message waiting to be sent, and when it was queued.
+#[derive(Clone, Copy)]
+struct Packet {
+    message: Message,
+    queued: Instant,
+}
+
+/// A message, copied into the queue or, for SysEx, left where it is kept.
+#[derive(Clone, Copy)]
+enum Message {
+    Built([u8; MAX_MESSAGE], u8),
+    Kept(&'static [u8]),
+}
+
+impl Message {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Message::Built(bytes, len) => &bytes[..usize::from(*len)],
+            Message::Kept(bytes) => bytes,
+        }
+    }
+}
+
+/// A synth that plays on a MIDI port rather than making sound.
+pub struct MidiOut {
+    queue: Producer<Packet>,
+    /// Messages there was no room for, for the sending thread to report.
+    dropped: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    sender: Option<JoinHandle<()>>,
+    /// The gain the player started with, which is full Master Volume; the fade at the end of
+    /// `--max-duration` is sent as a Master Volume below it.
+    full: f32,
+    gain: f32,
+    /// The Master Volume last sent, if any.
+    volume: Option<u16>,
+}
+
+/// Open the MIDI port `wanted` names and play on it, as if at `gain`, each message `delay`
+/// after it is sent.
+pub fn open(wanted: &str, gain: f32, delay: Duration) -> Result<Synth> {
+    let output = MidiOutput::new("midi-play").context("opening MIDI output")?;
+    let (port, name) = find_port(&output, wanted)?;
+    let connection = output.connect(&port, "out").map_err(|e| anyhow!("connecting to {name}: {}", e.kind()))?;
+    info!("Sending MIDI to {name}");
+    if !delay.is_zero() {
+        info!("Holding the MIDI back by {} ms", delay.as_millis());
+    }
+    let (queue, queued) = queue::channel(QUEUE_LEN);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (lost, stopped) = (Arc::clone(&dropped), Arc::clone(&stop));
+    let sender = thread::spawn(move || send_queued(connection, queued, delay, &stopped, &lost));
+    Ok(Box::new(MidiOut { queue, dropped, stop, sender: Some(sender), full: gain, gain, volume: None }))
+}
+
+/// Send what is queued as it falls due, `delay` after it was queued, until `stop` is set
+/// and the queue is empty.
+fn send_queued(
+    mut connection: MidiOutputConnection,
+    mut queue: Consumer<Packet>,
+    delay: Duration,
+    stop: &AtomicBool,
+    dropped: &AtomicU64,
+) {
+    let mut reported = 0;
+    // Set once `stop` is, and then the queue is emptied once more: the last messages can
+    // go in after it was last found empty.
+    let mut stopping = false;
+    loop {
+        let Some(packet) = queue.pop() else {
+            if stopping {
+                break;
+            }
+            stopping = stop.load(Ordering::Acquire);
+            if !stopping {
+                thread::sleep(POLL);
+            }
+            continue;
+        };
+        if let Some(wait) = (packet.queued + delay).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        // Fails if the port goes away, and then there is no one to send to.
+        if let Err(e) = connection.send(packet.message.bytes()) {
+            debug!("Could not send a MIDI message: {e}");
+        }
+        let now_dropped = dropped.load(Ordering::Relaxed);
+        if now_dropped > reported {
+            warn!("{} MIDI message(s) were dropped: more were due than could wait", now_dropped - reported);
+            reported = now_dropped;
+        }
+    }
+}
+
+impl MidiOut {
+    /// Queue one complete MIDI message for the sending thread.
+    fn queue(&mut self, bytes: &[u8]) {
+        let mut built = [0; MAX_MESSAGE];
+        built[..bytes.len()].copy_from_slice(bytes);
+        self.queue_message(Message::Built(built, bytes.len() as u8));
+    }
+
+    fn queue_message(&mut self, message: Message) {
+        if self.queue.push(Packet { message, queued: Instant::now() }).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn channel(&mut self, status: u8, ch: u8, data: &[u8]) {
+        let mut bytes = [status | ch, 0, 0];
+        bytes[1..=data.len()].copy_from_slice(data);
+        self.queue(&bytes[..=data.len()]);
+    }
+
+    /// Set the channel's pitch bend range through RPN 0, then deselect it, so a stray Data
+    /// Entry in the file cannot change it.
+    fn rpn_bend_range(&mut self, ch: u8, semitones: u8, cents: u8) {
+        for (cc, value) in [(101, 0), (100, 0), (6, semitones), (38, cents), (101, 127), (100, 127)] {
+            self.cc_raw(ch, cc, value);
+        }
+    }
+
+    fn cc_raw(&mut self, ch: u8, cc: u8, value: u8) {
+        self.channel(0xb0, ch, &[cc, value]);
+    }
+}
+
+impl Synthesizer for MidiOut {
+    fn note_on(&mut self, ch: u8, key: u8, vel: u8) {
+        self.channel(0x90, ch, &[key, vel]);
+    }
+    fn note_off(&mut self, ch: u8, key: u8) {
+        self.channel(0x80, ch, &[key, 0]);
+    }
+    fn program(&mut self, ch: u8, program: u8) {
+        self.channel(0xc0, ch, &[program]);
+    }
+    fn cc(&mut self, ch: u8, cc: u8, value: u8) {
+        self.cc_raw(ch, cc, value);
+    }
+    /// Sent as the Bank Select MSB, which is what GS synths and most others go by. The
+    /// percussion bank has no number over MIDI: a drum channel is the synth's to know.
+    fn bank(&mut self, ch: u8, bank: u16) {
+        if bank < 128 {
+            self.cc_raw(ch, 0, bank as u8);
+            self.cc_raw(ch, 32, 0);
+        } else {
+            debug!("Not sending the percussion bank to channel {} over MIDI", ch + 1);
+        }
+    }
+    fn bend_range(&mut self, ch: u8, semitones: u8) {
+        self.rpn_bend_range(ch, semitones, 0);
+    }
+    fn pitch_bend(&mut self, ch: u8, bend: u16) {
+        self.channel(0xe0, ch, &[(bend & 0x7f) as u8, (bend >> 7) as u8]);
+    }
+    fn key_pressure(&mut self, ch: u8, key: u8, value: u8) {
+        self.channel(0xa0, ch, &[key, value]);
+    }
+    fn channel_pressure(&mut self, ch: u8, value: u8) {
+        self.channel(0xd0, ch, &[value]);
+    }
+    /// Each channel's controllers, bank, program, volume, pan and bend range put back as a
+    /// GM synth powers up. Not with a GM System On, which many synths take tens of
+    /// milliseconds to carry out, dropping what is sent meanwhile; that is sent only where
+    /// the file has a reset of its own.
+    fn reset(&mut self) {
+        for ch in 0..16 {
+            self.cc_raw(ch, 121, 0); // Reset All Controllers
+            self.cc_raw(ch, 0, 0);
+            self.cc_raw(ch, 32, 0);
+            self.channel(0xc0, ch, &[0]);
+            self.cc_raw(ch, 7, 100); // Volume
+            self.cc_raw(ch, 10, 64); // Pan
+            self.rpn_bend_range(ch, 2, 0);
+        }
+    }
+    fn sysex(&mut self, data: &'static [u8]) {
+        self.queue_message(Message::Kept(data));
+    }
+    fn gain(&self) -> f32 {
+        self.gain
+    }
+    fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+        let volume = ((gain / self.full).clamp(0.0, 1.0) * 16383.0) as u16;
+        if self.volume != Some(volume) {
+            self.volume = Some(volume);
+            // Universal Real Time Master Volume, 14 bits, LSB first.
+            self.queue(&[0xf0, 0x7f, 0x7f, 0x04, 0x01, (volume & 0x7f) as u8, (volume >> 7) as u8, 0xf7]);
+        }
+    }
+    fn set_sample_rate(&mut self, _rate: f32) {}
+
+    fn render(&mut self, out: &mut [f32]) -> Result<()> {
+        out.fill(0.0);
+        Ok(())
+    }
+    #[cfg(feature = "jack")]
+    fn render_split(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<()> {
+        left.fill(0.0);
+        right.fill(0.0);
+        Ok(())
+    }
+
+    /// The voices are the external synth's, which cannot be asked.
+    fn voices(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// The messages as the file has them, as far as MIDI can carry them: the bend range
+    /// with its cents, the resets as the SysEx of their standard and other SysEx whole, so
+    /// the setup a file sends an MT-32 or a GS synth reaches it.
+    fn send(&mut self, msg: Msg) {
+        match msg {
+            Msg::NoteOn(ch, key, vel) => self.note_on(ch, key, vel),
+            Msg::NoteOff(ch, key, vel) => self.channel(0x80, ch, &[key, vel]),
+            Msg::Program(ch, prog) => self.program(ch, prog),
+            Msg::Control(ch, cc, val) => self.cc(ch, cc, val),
+            Msg::Bank(ch, bank) => self.bank(ch, bank),
+            Msg::BendRange(ch, semitones, cents) => self.rpn_bend_range(ch, semitones, cents),
+            Msg::PitchBend(ch, bend) => self.pitch_bend(ch, bend.min(16383)),
+            Msg::AfterTouch(ch, key, vel) => self.key_pressure(ch, key, vel),
+            Msg::ChannelAftertouch(ch, vel) => self.channel_pressure(ch, vel),
+            Msg::Reset(standard) => self.sysex(standard.reset_message()),
+            Msg::SysEx(data) => self.sysex(data),
+            Msg::MasterVolume(_) | Msg::Tempo(_) => {}
+        }
+    }
+}
+
+impl Drop for MidiOut {
+    /// Leave the synth with nothing sounding and at full volume, however playback ended,
+    /// and wait for the sending thread to send that and what was queued before it.
+    fn drop(&mut self) {
+        self.silence();
+        if self.volume.is_some() {
+            self.set_gain(self.full);
+        }
+        self.stop.store(true, Ordering::Release);
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.join();
+        }
+    }
+}
+
+/// A synth that plays on a MIDI port as well: `synth` makes the sound, and `midi` is told
+/// everything it is.
+pub struct Layered {
+    synth: Synth,
+    midi: Synth,
+}
+
+impl Layered {
+    pub fn wrap(synth: Synth, midi: Synth) -> Synth {
+        Box::new(Self { synth, midi })
+    }
+}
+
+impl Synthesizer for Layered {
+    fn note_on(&mut self, ch: u8, key: u8, vel: u8) {
+        self.synth.note_on(ch, key, vel);
+        self.midi.note_on(ch, key, vel);
+    }
+    fn note_off(&mut self, ch: u8, key: u8) {
+        self.synth.note_off(ch, key);
+        self.midi.note_off(ch, key);
+    }
+    fn program(&mut self, ch: u8, program: u8) {
+        self.synth.program(ch, program);
+        self.midi.program(ch, program);
+    }
+    fn cc(&mut self, ch: u8, cc: u8, value: u8) {
+        self.synth.cc(ch, cc, value);
+        self.midi.cc(ch, cc, value);
+    }
+    fn bank(&mut self, ch: u8, bank: u16) {
+        self.synth.bank(ch, bank);
+        self.midi.bank(ch, bank);
+    }
+    fn bend_range(&mut self, ch: u8, semitones: u8) {
+        self.synth.bend_range(ch, semitones);
+        self.midi.bend_range(ch, semitones);
+    }
+    fn pitch_bend(&mut self, ch: u8, bend: u16) {
+        self.synth.pitch_bend(ch, bend);
+        self.midi.pitch_bend(ch, bend);
+    }
+    fn key_pressure(&mut self, ch: u8, key: u8, value: u8) {
+        self.synth.key_pressure(ch, key, value);
+        self.midi.key_pressure(ch, key, value);
+    }
+    fn channel_pressure(&mut self, ch: u8, value: u8) {
+        self.synth.channel_pressure(ch, value);
+        self.midi.channel_pressure(ch, value);
+    }
+    fn reset(&mut self) {
+        self.synth.reset();
+        self.midi.reset();
+    }
+    fn sysex(&mut self, data: &'static [u8]) {
+        self.synth.sysex(data);
+        self.midi.sysex(data);
+    }
+    fn gain(&self) -> f32 {
+        self.synth.gain()
+    }
+    fn set_gain(&mut self, gain: f32) {
+        self.synth.set_gain(gain);
+        self.midi.set_gain(gain);
+    }
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.synth.set_sample_rate(rate);
+    }
+    fn render(&mut self, out: &mut [f32]) -> Result<()> {
+        self.synth.render(out)
+    }
+    #[cfg(feature = "jack")]
+    fn render_split(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<()> {
+        self.synth.render_split(left, right)
+    }
+    fn voices(&self) -> Option<(usize, usize)> {
+        self.synth.voices()
+    }
+    fn silent_for(&self) -> Option<Duration> {
+        self.synth.silent_for()
+    }
+
+    // The rest are passed on whole, since the port sends messages its own way.
+    fn send(&mut self, msg: Msg) {
+        self.synth.send(msg);
+        self.midi.send(msg);
+    }
+    fn release_notes(&mut self) {
+        self.synth.release_notes();
+        self.midi.release_notes();
+    }
+    fn midi_panic(&mut self) {
+        self.synth.midi_panic();
+        self.midi.midi_panic();
+    }
+    fn silence(&mut self) {
+        self.synth.silence();
+        self.midi.silence();
+    }
+}
+
+/// Find the port of `io` that `wanted` names: by its id (on Linux, the `client:port`
+/// numbers `aconnect -l` shows), its exact name or a part of it; names are compared
+/// ignoring case. Fails with the list of ports if nothing, or more than one port, matches.
+pub fn find_port<T: MidiIO>(io: &T, wanted: &str) -> Result<(T::Port, String)>
+where
+    T::Port: PortId,
+{
+    // A port that goes away while they are listed is left out.
+    let mut ports: Vec<_> = io.ports().into_iter().filter_map(|port| Some((io.port_name(&port).ok()?, port))).collect();
+    let list = || {
+        let names: Vec<String> = ports.iter().map(|(name, _)| format!("  {name}")).collect();
+        if names.is_empty() { "  (none)".to_string() } else { names.join("\n") }
+    };
+
+    let wanted_lc = wanted.to_lowercase();
+    let matches: Vec<usize> = match ports.iter().position(|(_, port)| port.port_id() == wanted) {
+        Some(by_id) => vec![by_id],
+        None => match ports.iter().position(|(name, _)| name.to_lowercase() == wanted_lc) {
+            Some(exact) => vec![exact],
+            None => (0..ports.len()).filter(|&n| ports[n].0.to_lowercase().contains(&wanted_lc)).collect(),
+        },
+    };
+    match matches[..] {
+        [n] => {
+            let (name, port) = ports.swap_remove(n);
+            Ok((port, name))
+        }
+        [] => bail!("no MIDI port matches `{wanted}`. Ports:\n{}", list()),
+        _ => {
+            let names: Vec<&str> = matches.iter().map(|&n| ports[n].0.as_str()).collect();
+            bail!("`{wanted}` matches several MIDI ports ({}). Ports:\n{}", names.join(", "), list())
+        }
+    }
+}
+
+/// midir's ports have ids, but not through `MidiIO`.
+pub trait PortId {
+    fn port_id(&self) -> String;
+}
+
+impl PortId for midir::MidiOutputPort {
+    fn port_id(&self) -> String {
+        self.id()
+    }
+}
+
+impl PortId for midir::MidiInputPort {
+    fn port_id(&self) -> String {
+        self.id()
+    }
+}
@@ -0,0 +1,92 @@
+//! `--also-device`: the audio the main device plays, passed on to a second device.
+//!
+//! The two devices run on clocks of their own that drift apart slowly, so the second one
+//! reads from a buffer the main one fills and keeps its level steady by dropping or
+//! repeating a single frame per callback, which is inaudible at that rate. The buffer is a
+//! lock-free queue, so neither callback ever waits for the other.
+
+use crate::audio::MAX_BUFFER_FRAMES;
+use crate::queue::{self, Consumer, Producer};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Samples the buffer holds: room for the largest callbacks of both devices several times
+/// over.
+const CAPACITY: usize = 4 * 2 * MAX_BUFFER_FRAMES * 2;
+
+/// What the two sides share besides the samples.
+#[derive(Default)]
+struct Shared {
+    /// The largest callback seen from the main device, in frames.
+    push_frames: AtomicUsize,
+    /// Set when the buffer filled up because the second device stopped asking (unplugged,
+    /// say), so it starts again from the newest audio.
+    overflowed: AtomicBool,
+}
+
+/// The main device's side.
+pub struct Tap {
+    samples: Producer<f32>,
+    shared: Arc<Shared>,
+}
+
+/// The second device's side: interleaved stereo frames rendered for the main device and
+/// not yet played on the second.
+pub struct Mirror {
+    samples: Consumer<f32>,
+    shared: Arc<Shared>,
+    /// The largest callback seen from the second device, in frames.
+    pull_frames: usize,
+}
+
+/// A buffer from the main device to the second.
+pub fn channel() -> (Tap, Mirror) {
+    let (samples_in, samples) = queue::channel(CAPACITY);
+    let shared = Arc::new(Shared::default());
+    (Tap { samples: samples_in, shared: Arc::clone(&shared) }, Mirror { samples, shared, pull_frames: 0 })
+}
+
+impl Tap {
+    /// Add what the main device just played.
+    pub fn push(&mut self, stereo: &[f32]) {
+        self.shared.push_frames.fetch_max(stereo.len() / 2, Ordering::Relaxed);
+        if self.samples.push_slice(stereo) < stereo.len() {
+            self.shared.overflowed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Mirror {
+    /// The level to keep, in frames: a callback's worth for each device.
+    fn target(&self) -> usize {
+        self.shared.push_frames.load(Ordering::Relaxed) + self.pull_frames
+    }
+
+    /// Fill `stereo` for the second device. Silence when the main device has not caught up.
+    pub fn pull(&mut self, stereo: &mut [f32]) {
+        let frames = stereo.len() / 2;
+        self.pull_frames = self.pull_frames.max(frames);
+        if self.shared.overflowed.swap(false, Ordering::Relaxed) {
+            // What is waiting is old: drop it and wait for new audio.
+            self.samples.skip(usize::MAX);
+        }
+        let level = self.samples.waiting() / 2;
+        if level < frames {
+            // Nothing to play yet, or the main device stalled.
+            stereo.fill(0.0);
+            return;
+        }
+        if level > self.target() + frames {
+            // The second device is slower: skip a frame.
+            self.samples.skip(2);
+        }
+        if level < self.target() && frames > 1 {
+            // The second device is faster: play the last frame twice.
+            let n = (frames - 1) * 2;
+            self.samples.pop_slice(&mut stereo[..n]);
+            stereo.copy_within(n - 2..n, n);
+        } else {
+            self.samples.pop_slice(stereo);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! MP3 output for `render -o out.mp3` (`mp3` feature), through libmp3lame. The file starts
+//! with an ID3v2 tag, and the LAME tag frame after it is filled in at the end, so players
+//! know the exact length and, for VBR, can seek.
+//!
+//! Like `jack`, this talks to the C library directly; LAME's API is small and stable.
+
+use anyhow::{Context, Result, bail};
+use std::ffi::{CString, c_char, c_float, c_int, c_uchar};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+    ptr,
+};
+
+#[repr(C)]
+struct RawLame {
+    _private: [u8; 0],
+}
+
+/// `vbr_off` and `vbr_mtrh` (LAME's default VBR mode) of `vbr_mode`.
+const VBR_OFF: c_int = 0;
+const VBR_MTRH: c_int = 4;
+
+/// LAME's algorithm quality: 2 is its "high quality", at a fair speed.
+const QUALITY: c_int = 2;
+
+#[link(name = "mp3lame")]
+unsafe extern "C" {
+    fn lame_init() -> *mut RawLame;
+    fn lame_close(gfp: *mut RawLame) -> c_int;
+    fn lame_set_in_samplerate(gfp: *mut RawLame, rate: c_int) -> c_int;
+    fn lame_set_num_channels(gfp: *mut RawLame, channels: c_int) -> c_int;
+    fn lame_set_brate(gfp: *mut RawLame, kbps: c_int) -> c_int;
+    fn lame_set_quality(gfp: *mut RawLame, quality: c_int) -> c_int;
+    fn lame_set_VBR(gfp: *mut RawLame, mode: c_int) -> c_int;
+    fn lame_set_VBR_q(gfp: *mut RawLame, quality: c_int) -> c_int;
+    fn lame_set_write_id3tag_automatic(gfp: *mut RawLame, automatic: c_int);
+    fn lame_init_params(gfp: *mut RawLame) -> c_int;
+    fn lame_encode_buffer_interleaved_ieee_float(
+        gfp: *mut RawLame,
+        pcm: *const c_float,
+        frames: c_int,
+        mp3buf: *mut c_uchar,
+        mp3buf_size: c_int,
+    ) -> c_int;
+    fn lame_encode_flush(gfp: *mut RawLame, mp3buf: *mut c_uchar, size: c_int) -> c_int;
+    fn lame_get_lametag_frame(gfp: *const RawLame, buffer: *mut c_uchar, size: usize) -> usize;
+    fn id3tag_init(gfp: *mut RawLame);
+    fn id3tag_add_v2(gfp: *mut RawLame);
+    fn id3tag_set_title(gfp: *mut RawLame, title: *const c_char);
+    fn id3tag_set_fieldvalue(gfp: *mut RawLame, fieldvalue: *const c_char) -> c_int;
+    fn lame_get_id3v1_tag(gfp: *mut RawLame, buffer: *mut c_uchar, size: usize) -> usize;
+    fn lame_get_id3v2_tag(gfp: *mut RawLame, buffer: *mut c_uchar, size: usize) -> usize;
+}
+
+/// How the MP3 spends its bits.
+#[derive(Clone, Copy, Debug)]
+pub enum Rate {
+    /// A constant bitrate, in kbit/s.
+    Constant(u32),
+    /// Variable bitrate at LAME's quality 0 (best) to 9 (smallest), as `lame -V`.
+    Variable(u8),
+}
+
+/// What goes in the ID3 tags.
+pub struct Id3<'a> {
+    pub title: &'a str,
+    pub copyright: Option<&'a str>,
+    pub length_us: Option<u64>,
+}
+
+/// A streaming MP3 writer.
+pub struct Mp3Writer {
+    lame: *mut RawLame,
+    out: BufWriter<File>,
+    channels: usize,
+    /// Where the LAME tag frame goes, after the ID3v2 tag.
+    lametag_at: u64,
+    buf: Vec<u8>,
+}
+
+impl Mp3Writer {
+    /// Create the file and write its ID3v2 tag.
+    pub fn create(path: &Path, sample_rate: u32, channels: u16, rate: Rate, tags: &Id3) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        let lame = unsafe { lame_init() };
+        if lame.is_null() {
+            bail!("could not start the MP3 encoder");
+        }
+        // Closed by Drop from here on, whatever goes wrong.
+        let mut writer = Self {
+            lame,
+            out: BufWriter::new(file),
+            channels: channels as usize,
+            lametag_at: 0,
+            buf: Vec::new(),
+        };
+        let title = latin1(tags.title);
+        let copyright = tags.copyright.map(|c| latin1(&format!("TCOP={c}")));
+        let length = tags.length_us.map(|us| latin1(&format!("TLEN={}", us / 1000)));
+        unsafe {
+            lame_set_in_samplerate(lame, sample_rate as c_int);
+            lame_set_num_channels(lame, c_int::from(channels));
+            lame_set_quality(lame, QUALITY);
+            match rate {
+                Rate::Constant(kbps) => {
+                    lame_set_VBR(lame, VBR_OFF);
+                    lame_set_brate(lame, kbps as c_int);
+                }
+                Rate::Variable(q) => {
+                    lame_set_VBR(lame, VBR_MTRH);
+                    lame_set_VBR_q(lame, c_int::from(q));
+                }
+            }
+            id3tag_init(lame);
+            id3tag_add_v2(lame);
+            id3tag_set_title(lame, title.as_ptr());
+            if let Some(copyright) = &copyright {
+                id3tag_set_fieldvalue(lame, copyright.as_ptr());
+            }
+            if let Some(length) = &length {
+                id3tag_set_fieldvalue(lame, length.as_ptr());
+            }
+            // The tags are written here rather than mixed into the encoded stream, so the
+            // LAME tag frame can be put back after the ID3v2 tag at the end.
+            lame_set_write_id3tag_automatic(lame, 0);
+            if lame_init_params(lame) < 0 {
+                bail!("the MP3 encoder does not support these settings ({rate:?} at {sample_rate} Hz)");
+            }
+            let size = lame_get_id3v2_tag(lame, ptr::null_mut(), 0);
+            let mut tag = vec![0; size];
+            let size = lame_get_id3v2_tag(lame, tag.as_mut_ptr(), tag.len());
+            writer.out.write_all(&tag[..size])?;
+            writer.lametag_at = size as u64;
+        }
+        Ok(writer)
+    }
+
+    /// Append interleaved samples.
+    pub fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let frames = samples.len() / self.channels;
+        // LAME's worst case.
+        self.buf.resize(frames * 5 / 4 + 7200, 0);
+        let len = unsafe {
+            lame_encode_buffer_interleaved_ieee_float(
+                self.lame,
+                samples.as_ptr(),
+                frames as c_int,
+                self.buf.as_mut_ptr(),
+                self.buf.len() as c_int,
+            )
+        };
+        if len < 0 {
+            bail!("MP3 encoding failed (LAME error {len})");
+        }
+        self.out.write_all(&self.buf[..len as usize])?;
+        Ok(())
+    }
+
+    /// Encode what LAME still holds, add the ID3v1 tag, and fill in the LAME tag frame.
+    pub fn finish(mut self) -> Result<()> {
+        self.buf.resize(7200, 0);
+        let len = unsafe { lame_encode_flush(self.lame, self.buf.as_mut_ptr(), self.buf.len() as c_int) };
+        if len < 0 {
+            bail!("MP3 encoding failed (LAME error {len})");
+        }
+        self.out.write_all(&self.buf[..len as usize])?;
+
+        let mut tag = [0; 128];
+        let len = unsafe { lame_get_id3v1_tag(self.lame, tag.as_mut_ptr(), tag.len()) };
+        self.out.write_all(&tag[..len.min(tag.len())])?;
+
+        let len = unsafe { lame_get_lametag_frame(self.lame, ptr::null_mut(), 0) };
+        let mut frame = vec![0; len];
+        let len = unsafe { lame_get_lametag_frame(self.lame, frame.as_mut_ptr(), frame.len()) };
+        if len > 0 {
+            self.out.seek(SeekFrom::Start(self.lametag_at))?;
+            self.out.write_all(&frame[..len])?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Mp3Writer {
+    fn drop(&mut self) {
+        unsafe { lame_close(self.lame) };
+    }
+}
+
+/// `text` in Latin-1, which is what LAME writes ID3 text in, with anything outside it and
+/// any NUL replaced by `?`.
+fn latin1(text: &str) -> CString {
+    let bytes = text.chars().map(|c| u8::try_from(c).ok().filter(|&b| b != 0).unwrap_or(b'?')).collect::<Vec<u8>>();
+    CString::new(bytes).expect("no NULs left")
+}
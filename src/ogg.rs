@@ -0,0 +1,284 @@
+//! Ogg output for `render`: Opus (`-o out.opus`, `opus` feature) through libopus, in Ogg
+//! pages written here as RFC 7845 lays them out, and Vorbis (`-o out.ogg`, `vorbis`
+//! feature) through libvorbis, which writes its own pages.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Opus always counts time in 48 kHz samples, whatever rate it encodes at.
+#[cfg(feature = "opus")]
+const GRANULE_RATE: u32 = 48_000;
+
+/// Opus frame length: 20 ms, the usual for music.
+#[cfg(feature = "opus")]
+const FRAME_MS: u32 = 20;
+
+/// Most packets put on one Ogg page: a second of 20 ms frames.
+#[cfg(feature = "opus")]
+const PAGE_PACKETS: usize = 50;
+
+/// The largest Opus packet.
+#[cfg(feature = "opus")]
+const MAX_PACKET: usize = 1275;
+
+/// A streaming Ogg Opus writer.
+#[cfg(feature = "opus")]
+pub struct OpusWriter {
+    pages: PageWriter,
+    encoder: opus::Encoder,
+    channels: usize,
+    /// Interleaved samples per frame.
+    frame_samples: usize,
+    /// Interleaved samples not yet encoded, less than a frame.
+    pending: Vec<f32>,
+    /// 48 kHz samples per sample at the encoding rate.
+    scale: u64,
+    /// The encoder's delay, in 48 kHz samples, which players skip.
+    pre_skip: u64,
+    /// Frames written, and encoded including the padding of the last frame.
+    frames: u64,
+    encoded: u64,
+}
+
+#[cfg(feature = "opus")]
+impl OpusWriter {
+    /// Create the file at `bitrate` kbit/s, with the headers: the identification header
+    /// and the comment header holding `tags`, e.g. `("TITLE", ...)`. `sample_rate` must be
+    /// one Opus encodes at (8, 12, 16, 24 or 48 kHz).
+    pub fn create(
+        path: &Path,
+        sample_rate: u32,
+        channels: u16,
+        bitrate: u32,
+        tags: &[(&str, String)],
+    ) -> Result<Self> {
+        let layout = if channels == 1 { opus::Channels::Mono } else { opus::Channels::Stereo };
+        let mut encoder =
+            opus::Encoder::new(sample_rate, layout, opus::Application::Audio).context("starting the Opus encoder")?;
+        encoder.set_bitrate(opus::Bitrate::Bits(bitrate as i32 * 1000)).context("setting the Opus bitrate")?;
+        let scale = u64::from(GRANULE_RATE / sample_rate);
+        let pre_skip = encoder.get_lookahead().context("asking the Opus encoder for its delay")? as u64 * scale;
+
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(channels as u8);
+        head.extend((pre_skip as u16).to_le_bytes());
+        head.extend(sample_rate.to_le_bytes());
+        head.extend(0i16.to_le_bytes()); // output gain
+        head.push(0); // mapping family: mono or stereo
+        let mut comments = b"OpusTags".to_vec();
+        comments.extend(vorbis_comment(tags));
+
+        let mut pages = PageWriter::create(path)?;
+        // The headers each go on a page of their own.
+        pages.write(&[head], 0, BEGINNING)?;
+        pages.write(&[comments], 0, 0)?;
+        Ok(Self {
+            pages,
+            encoder,
+            channels: channels as usize,
+            frame_samples: (sample_rate * FRAME_MS / 1000) as usize * channels as usize,
+            pending: Vec::new(),
+            scale,
+            pre_skip,
+            frames: 0,
+            encoded: 0,
+        })
+    }
+
+    /// Append interleaved samples.
+    pub fn write(&mut self, samples: &[f32]) -> Result<()> {
+        self.frames += (samples.len() / self.channels) as u64;
+        self.pending.extend_from_slice(samples);
+        while self.pending.len() >= self.frame_samples {
+            let rest = self.pending.split_off(self.frame_samples);
+            let frame = std::mem::replace(&mut self.pending, rest);
+            self.encode(&frame, false)?;
+        }
+        Ok(())
+    }
+
+    /// Encode the rest, padded with silence until the encoder's delay is flushed out too,
+    /// and end the stream on a page whose position trims the padding off again.
+    pub fn finish(mut self) -> Result<()> {
+        let delay = (self.pre_skip / self.scale) as usize * self.channels;
+        let mut rest = std::mem::take(&mut self.pending);
+        rest.resize((rest.len() + delay).div_ceil(self.frame_samples).max(1) * self.frame_samples, 0.0);
+        let frames: Vec<&[f32]> = rest.chunks(self.frame_samples).collect();
+        for (i, frame) in frames.iter().enumerate() {
+            self.encode(frame, i + 1 == frames.len())?;
+        }
+        self.pages.finish()
+    }
+
+    /// Encode one frame and queue the packet; the `last` one ends the stream.
+    fn encode(&mut self, frame: &[f32], last: bool) -> Result<()> {
+        let mut packet = vec![0; MAX_PACKET];
+        let len = self.encoder.encode_float(frame, &mut packet).context("Opus encoding")?;
+        packet.truncate(len);
+        self.encoded += (frame.len() / self.channels) as u64;
+        // The position of the last sample a page completes, counting the skipped delay.
+        let granule = if last { self.frames } else { self.encoded } * self.scale + self.pre_skip;
+        self.pages.queue(packet, granule, last)
+    }
+}
+
+/// Page flags.
+#[cfg(feature = "opus")]
+const CONTINUED: u8 = 0x01;
+#[cfg(feature = "opus")]
+const BEGINNING: u8 = 0x02;
+#[cfg(feature = "opus")]
+const END: u8 = 0x04;
+
+/// Packets framed into the pages of one logical Ogg stream.
+#[cfg(feature = "opus")]
+struct PageWriter {
+    out: BufWriter<File>,
+    serial: u32,
+    sequence: u32,
+    /// Packets for the next page, and the position it ends at.
+    queued: Vec<Vec<u8>>,
+    granule: u64,
+}
+
+#[cfg(feature = "opus")]
+impl PageWriter {
+    fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        // Any number will do while the file holds a single stream.
+        let serial = std::process::id();
+        Ok(Self { out: BufWriter::new(file), serial, sequence: 0, queued: Vec::new(), granule: 0 })
+    }
+
+    /// Add a packet ending at `granule` to the next page, starting a new page first if it
+    /// would not fit. The `last` packet ends the stream.
+    fn queue(&mut self, packet: Vec<u8>, granule: u64, last: bool) -> Result<()> {
+        let lacing = |packets: &[Vec<u8>]| packets.iter().map(|p| p.len() / 255 + 1).sum::<usize>();
+        if self.queued.len() == PAGE_PACKETS || lacing(&self.queued) + packet.len() / 255 + 1 > 255 {
+            let packets = std::mem::take(&mut self.queued);
+            self.write(&packets, self.granule, 0)?;
+        }
+        self.queued.push(packet);
+        self.granule = granule;
+        if last {
+            let packets = std::mem::take(&mut self.queued);
+            self.write(&packets, self.granule, END)?;
+        }
+        Ok(())
+    }
+
+    /// Write one page holding `packets`, the last of which ends at `granule`. A packet of
+    /// more than 255 × 255 bytes, only possible for headers with huge tags, is split over
+    /// pages.
+    fn write(&mut self, packets: &[Vec<u8>], granule: u64, flags: u8) -> Result<()> {
+        let mut lacing = Vec::new();
+        let mut body = Vec::new();
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(255, packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+            body.extend(packet);
+        }
+        let mut flags = flags;
+        while lacing.len() > 255 {
+            // A page that ends mid-packet has no position of its own.
+            let rest = lacing.split_off(255);
+            let tail = body.split_off(lacing.iter().map(|&l| usize::from(l)).sum());
+            self.page(&lacing, &body, u64::MAX, flags & !END)?;
+            (lacing, body) = (rest, tail);
+            flags = CONTINUED | (flags & END);
+        }
+        self.page(&lacing, &body, granule, flags)
+    }
+
+    fn page(&mut self, lacing: &[u8], body: &[u8], granule: u64, flags: u8) -> Result<()> {
+        let mut page = b"OggS".to_vec();
+        page.push(0); // version
+        page.push(flags);
+        page.extend(granule.to_le_bytes());
+        page.extend(self.serial.to_le_bytes());
+        page.extend(self.sequence.to_le_bytes());
+        page.extend(0u32.to_le_bytes()); // CRC, filled in below
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(body);
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.out.write_all(&page)?;
+        self.sequence += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// The CRC of an Ogg page (polynomial 0x04c11db7, not reflected).
+#[cfg(feature = "opus")]
+fn crc32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0u32, |crc, &b| {
+        (0..8).fold(crc ^ u32::from(b) << 24, |c, _| if c & 0x8000_0000 != 0 { c << 1 ^ 0x04c1_1db7 } else { c << 1 })
+    })
+}
+
+/// A Vorbis comment block, as Opus's comment header carries it after its magic.
+#[cfg(feature = "opus")]
+fn vorbis_comment(tags: &[(&str, String)]) -> Vec<u8> {
+    let vendor = concat!("midi-play ", env!("CARGO_PKG_VERSION"));
+    let mut block = Vec::new();
+    block.extend((vendor.len() as u32).to_le_bytes());
+    block.extend(vendor.as_bytes());
+    block.extend((tags.len() as u32).to_le_bytes());
+    for (key, value) in tags {
+        let field = format!("{key}={value}");
+        block.extend((field.len() as u32).to_le_bytes());
+        block.extend(field.as_bytes());
+    }
+    block
+}
+
+/// A streaming Ogg Vorbis writer.
+#[cfg(feature = "vorbis")]
+pub struct VorbisWriter {
+    encoder: vorbis_rs::VorbisEncoder<BufWriter<File>>,
+    channels: usize,
+}
+
+#[cfg(feature = "vorbis")]
+impl VorbisWriter {
+    /// Create the file, encoding at an average of `bitrate` kbit/s.
+    pub fn create(path: &Path, sample_rate: u32, channels: u16, bitrate: u32) -> Result<Self> {
+        use std::num::{NonZeroU8, NonZeroU32};
+        use vorbis_rs::{VorbisBitrateManagementStrategy, VorbisEncoderBuilder};
+
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        let rate = NonZeroU32::new(sample_rate).context("a sample rate of 0")?;
+        let layout = NonZeroU8::new(channels as u8).context("no channels")?;
+        let average_bitrate = NonZeroU32::new(bitrate * 1000).context("a bitrate of 0")?;
+        let encoder = VorbisEncoderBuilder::new(rate, layout, BufWriter::new(file))
+            .context("starting the Vorbis encoder")?
+            .bitrate_management_strategy(VorbisBitrateManagementStrategy::Abr { average_bitrate })
+            .build()
+            .context("starting the Vorbis encoder")?;
+        Ok(Self { encoder, channels: channels as usize })
+    }
+
+    /// Append interleaved samples.
+    pub fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let planar: Vec<Vec<f32>> =
+            (0..self.channels).map(|c| samples.iter().skip(c).step_by(self.channels).copied().collect()).collect();
+        self.encoder.encode_audio_block(&planar).context("Vorbis encoding")?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.encoder.finish().context("Vorbis encoding")?.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! The OxiSynth engine: a port of FluidSynth to pure Rust, for builds without a C compiler.
+
+use crate::song::DRUM_CHANNEL;
+use crate::soundfont;
+use crate::synth::{Chorus, DEFAULT_CHORUS, DEFAULT_REVERB, EffectsArgs, Interp, Reverb, Synth, Synthesizer};
+use anyhow::{Context, Result, anyhow, bail};
+use oxisynth::chorus::{ChorusMode, ChorusParams};
+use oxisynth::reverb::ReverbParams;
+use oxisynth::{MidiEvent, SoundFont, SynthDescriptor};
+use std::fs::File;
+use tracing::{debug, warn};
+
+/// An OxiSynth synth.
+pub struct Oxi {
+    synth: oxisynth::Synth,
+}
+
+/// Create an OxiSynth synth, as `synth::open` describes.
+pub fn open(soundfonts: &[String], gain: f32, sample_rate: f32, effects: EffectsArgs) -> Result<Synth> {
+    let mut synth = oxisynth::Synth::new(SynthDescriptor {
+        sample_rate,
+        gain,
+        polyphony: effects.polyphony.unwrap_or(256),
+        reverb_active: !effects.no_effects && effects.reverb.unwrap_or(true),
+        chorus_active: !effects.no_effects && effects.chorus.unwrap_or(true),
+        drums_channel_active: effects.drum_channels.unwrap_or_default().contains(DRUM_CHANNEL),
+        ..Default::default()
+    })
+    .map_err(|e| anyhow!("starting OxiSynth: {e:?}"))?;
+
+    // OxiSynth looks presets up newest first, as FluidLite does.
+    for soundfont in soundfonts.iter().rev() {
+        if soundfont::compressed(soundfont)? {
+            bail!("{soundfont} is an SF3 SoundFont, which OxiSynth cannot load; play it with `--engine fluidlite`");
+        }
+        let mut file = File::open(soundfont).with_context(|| format!("opening SoundFont {soundfont}"))?;
+        let font = SoundFont::load(&mut file).map_err(|e| anyhow!("loading SoundFont {soundfont}: {e:?}"))?;
+        let id = synth.add_font(font, true);
+        debug!("Loaded SoundFont: {} (id={:?})", soundfont, id);
+    }
+
+    let Reverb { room, damp, width, level } = effects.reverb_params.unwrap_or(DEFAULT_REVERB);
+    synth.get_reverb_mut().set_reverb(&ReverbParams {
+        roomsize: room as f32,
+        damp: damp as f32,
+        width: width as f32,
+        level: level as f32,
+    });
+    let Chorus { voices, level, speed, depth } = effects.chorus_params.unwrap_or(DEFAULT_CHORUS);
+    synth.chorus_mut().set_chorus(&ChorusParams {
+        nr: voices,
+        level: level as f32,
+        speed: speed as f32,
+        depth: depth as f32,
+        mode: ChorusMode::Sine,
+    });
+
+    // OxiSynth 0.0.5 keeps its interpolation type private, so it stays at its fourth-order
+    // default.
+    if !matches!(effects.interp, None | Some(Interp::Fourth)) {
+        warn!("OxiSynth cannot change its interpolation; ignoring --interp");
+    }
+    let mut oxi = Oxi { synth };
+    // clean start
+    oxi.reset();
+    Ok(Box::new(oxi))
+}
+
+impl Oxi {
+    fn event(&mut self, event: MidiEvent) {
+        let _ = self.synth.send_event(event);
+    }
+}
+
+impl Synthesizer for Oxi {
+    fn note_on(&mut self, channel: u8, key: u8, vel: u8) {
+        self.event(MidiEvent::NoteOn { channel, key, vel });
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        self.event(MidiEvent::NoteOff { channel, key });
+    }
+
+    fn program(&mut self, channel: u8, program_id: u8) {
+        self.event(MidiEvent::ProgramChange { channel, program_id });
+    }
+
+    fn cc(&mut self, channel: u8, ctrl: u8, value: u8) {
+        self.event(MidiEvent::ControlChange { channel, ctrl, value });
+    }
+
+    fn bank(&mut self, ch: u8, bank: u16) {
+        let _ = self.synth.bank_select(ch, bank.into());
+    }
+
+    fn bend_range(&mut self, ch: u8, semitones: u8) {
+        let _ = self.synth.pitch_wheel_sens(ch, semitones);
+    }
+
+    fn pitch_bend(&mut self, channel: u8, value: u16) {
+        self.event(MidiEvent::PitchBend { channel, value });
+    }
+
+    fn key_pressure(&mut self, channel: u8, key: u8, value: u8) {
+        self.event(MidiEvent::PolyphonicKeyPressure { channel, key, value });
+    }
+
+    fn channel_pressure(&mut self, channel: u8, value: u8) {
+        self.event(MidiEvent::ChannelPressure { channel, value });
+    }
+
+    fn reset(&mut self) {
+        self.event(MidiEvent::SystemReset);
+    }
+
+    fn gain(&self) -> f32 {
+        self.synth.gain()
+    }
+
+    fn set_gain(&mut self, gain: f32) {
+        self.synth.set_gain(gain);
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.synth.set_sample_rate(rate);
+    }
+
+    fn render(&mut self, out: &mut [f32]) -> Result<()> {
+        self.synth.write(out);
+        Ok(())
+    }
+
+    #[cfg(feature = "jack")]
+    fn render_split(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<()> {
+        self.synth.write((left, right));
+        Ok(())
+    }
+
+    /// OxiSynth does not count its active voices.
+    fn voices(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
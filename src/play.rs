@@ -0,0 +1,606 @@
+//! The `play` subcommand: real-time playback with interactive transport controls.
+
+use crate::audio::{self, AudioArgs, PlaybackArgs};
+use crate::bars::describe;
+use crate::conductor::{
+    CountIn, MAX_SPEED, MIN_SPEED, Mixer, PlayOptions, Practice, Repeat, Stop, conduct, parse_practice_step, parse_repeat,
+    parse_speed,
+};
+use crate::controls::{Command, RawTerminal, spawn_controls};
+use crate::fallback;
+use crate::interrupt::{INTERRUPTED, install_interrupt_handler};
+use crate::midi_in;
+use crate::midi_out::{self, Layered};
+use crate::output::{self, OutputArgs};
+use crate::priority::{self, Role};
+use crate::record::Recorder;
+use crate::record_midi::MidiRecorder;
+use crate::resume::{fnv1a, load_position, save_position};
+use crate::rmid;
+use crate::sidecar;
+use crate::song::{Marker, Song, SongArgs, channel_instrument};
+use crate::soundfont;
+use crate::scheduler::Scheduler;
+use crate::synth::{self, Metered, Synth};
+use crate::sysex::Standard;
+use crate::time::{Position, format_duration, parse_position_arg, parse_time_arg};
+use crate::watch;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::{AtomicU64, Ordering}, mpsc},
+    thread,
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+/// The rate the song is timed at when it is sent to a MIDI port: any will do, since nothing
+/// is heard.
+const PACED_RATE: u32 = 48_000;
+
+/// `play` options, on top of the shared song options:
+/// - start / end / duration: optional segment of the song to play
+/// - loop_a / loop_b: optional A–B region to repeat
+/// - repeat: how many times to play the song (or segment)
+/// - speed: tempo multiplier (pitch is unaffected)
+/// - start_marker: Marker / Cue Point to begin playback from
+/// - stop_tail: how long the reverb may ring after quitting or Ctrl-C
+/// - count_in: bars of metronome click before the music starts
+/// - practice*: loop with a tempo ramp towards full speed
+/// - minus_one: channel to leave out for play-along, with an optional boost for the rest
+/// - resume: continue from where the last run of this file stopped
+/// - max_duration: hard limit on the playing time, pauses and the count-in left out
+/// - record: file to write what plays to
+/// - dry_run: check everything up to opening the audio device, then stop
+/// - notify: print progress events on stdout for scripts
+/// - watch: play again whenever the MIDI file or SoundFont changes
+/// - jack: play as a JACK client following the JACK transport (`jack` feature)
+/// - midi_out: send the song to a MIDI port instead of playing it
+/// - also_synth: play on the SoundFont synth as well as the MIDI port
+/// - midi_out_delay: how long to hold the MIDI port's messages back
+/// - midi_in / midi_in_channel: a MIDI port to play the synth from live, and the channel
+/// - record_midi: file to write what came in on the MIDI port to
+/// - overdub: write the song with what came in added as a track, rather than that alone
+/// - playback: following the default device, underrun warnings, levels and a second device
+/// - audio: the audio host and output device
+#[derive(Args, Debug)]
+pub struct PlayArgs {
+    #[command(flatten)]
+    pub song: SongArgs,
+    #[command(flatten)]
+    pub output: OutputArgs,
+    /// Start playback at this position (seconds, mm:ss, a percentage such as 35% or a bar such as b17:1)
+    #[arg(long, value_name = "POS", value_parser = parse_position_arg)]
+    start: Option<Position>,
+    /// Stop playback at this position (seconds, mm:ss, a percentage or a bar)
+    #[arg(long, value_name = "POS", value_parser = parse_position_arg, conflicts_with = "duration")]
+    end: Option<Position>,
+    /// Stop playback after playing this long (seconds or mm:ss)
+    #[arg(long, value_name = "LEN", value_parser = parse_time_arg)]
+    duration: Option<u64>,
+    /// Loop start (A) for A–B looping (seconds, mm:ss, a percentage or a bar)
+    #[arg(long, value_name = "POS", value_parser = parse_position_arg, requires = "loop_b")]
+    loop_a: Option<Position>,
+    /// Loop end (B) for A–B looping (seconds, mm:ss, a percentage or a bar)
+    #[arg(long, value_name = "POS", value_parser = parse_position_arg, requires = "loop_a")]
+    loop_b: Option<Position>,
+    /// Play the song N times in total, or `forever`
+    #[arg(long = "loop", value_name = "N|forever", value_parser = parse_repeat, default_value = "1")]
+    repeat: Repeat,
+    /// Playback speed multiplier, e.g. 0.75 to slow down or 1.25 to speed up
+    #[arg(long, value_name = "FACTOR", default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f64,
+    /// Start playback at the marker or cue point with this name
+    #[arg(long, value_name = "NAME", conflicts_with = "start")]
+    start_marker: Option<String>,
+    /// Seconds to let the reverb ring out after quitting or Ctrl-C
+    #[arg(long, value_name = "SECS", default_value_t = 0.5)]
+    stop_tail: f64,
+    /// Play this many bars of click before the first note
+    #[arg(long, value_name = "BARS")]
+    count_in: Option<u32>,
+    /// Practice mode: loop the A–B region (or the whole segment), speeding up after every pass
+    #[arg(long, conflicts_with = "speed")]
+    practice: bool,
+    /// Practice mode starting speed, in percent
+    #[arg(long, value_name = "PCT", default_value_t = 60.0, requires = "practice")]
+    practice_start: f64,
+    /// Practice mode speed increase per pass, in percent
+    #[arg(long, value_name = "PCT", default_value_t = 5.0, value_parser = parse_practice_step, requires = "practice")]
+    practice_step: f64,
+    /// Practice mode final speed, in percent
+    #[arg(long, value_name = "PCT", default_value_t = 100.0, requires = "practice")]
+    practice_target: f64,
+    /// Play-along mode: leave this channel (1–16) out so you can play the part yourself
+    #[arg(long, value_name = "CH", value_parser = clap::value_parser!(u8).range(1..=16))]
+    minus_one: Option<u8>,
+    /// With --minus-one, raise the remaining mix by this many dB (2 dB if no value is given)
+    #[arg(long, value_name = "DB", num_args = 0..=1, default_missing_value = "2", requires = "minus_one")]
+    minus_one_boost: Option<f32>,
+    /// Continue from where playback of this file last stopped
+    #[arg(long, conflicts_with_all = ["start", "start_marker"])]
+    resume: bool,
+    /// Stop after this much playing time (seconds or mm:ss), fading out, however long the song;
+    /// pauses and the count-in do not count, and a --watch reload starts it again
+    #[arg(long, value_name = "LEN", value_parser = parse_time_arg)]
+    max_duration: Option<u64>,
+    /// Record what plays to this file as well, including tempo changes, mutes and seeks made
+    /// while playing: WAV, or FLAC, Opus, Vorbis or MP3 by extension as for `render`
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Load the file and read the SoundFont, print what would be played, and exit without
+    /// opening an audio device
+    #[arg(long)]
+    dry_run: bool,
+    /// Print playback events on stdout for scripts: started, position every second, markers
+    /// reached, pauses, seeks, finished and errors
+    #[arg(long)]
+    pub notify: bool,
+    /// Reload and play again whenever the MIDI file or SoundFont changes, and wait for the
+    /// next change instead of exiting at the end
+    #[arg(long)]
+    watch: bool,
+    /// Play as a JACK client with its own ports, following the JACK transport (start, stop,
+    /// locate) so a DAW session can drive playback
+    #[cfg(feature = "jack")]
+    #[arg(long, conflicts_with_all = [
+        "device", "host", "follow_default", "also_device", "device_gain", "sample_rate", "buffer_size", "record",
+    ])]
+    jack: bool,
+    /// Send the song to this MIDI port, by (part of) its name or on Linux `client:port`,
+    /// instead of playing it: to an external synth, a keyboard or another program
+    #[arg(long, value_name = "PORT")]
+    midi_out: Option<String>,
+    /// With --midi-out, play on the SoundFont synth as well, layering the two
+    #[arg(long, requires = "midi_out")]
+    also_synth: bool,
+    /// Hold the --midi-out messages back this many milliseconds, to line the port up with
+    /// the synth's audio or with another device
+    #[arg(long, value_name = "MS", default_value_t = 0, requires = "midi_out")]
+    midi_out_delay: u64,
+    /// Play the synth live from this MIDI port, by (part of) its name or on Linux
+    /// `client:port`, as the song plays and after it ends, until you quit
+    #[arg(long, value_name = "PORT")]
+    midi_in: Option<String>,
+    /// Play the --midi-in messages on this channel (1–16), whichever they come in on
+    #[arg(long, value_name = "CH", value_parser = clap::value_parser!(u8).range(1..=16), requires = "midi_in")]
+    midi_in_channel: Option<u8>,
+    /// Write what comes in on --midi-in to this Standard MIDI File when playback stops
+    #[arg(long, value_name = "FILE.mid", requires = "midi_in")]
+    record_midi: Option<PathBuf>,
+    /// With --record-midi, write a copy of the song with what was played added as a new
+    /// track, in time with the song's tempo map; playback stops at the end of the song
+    #[arg(long, requires = "record_midi", conflicts_with = "watch")]
+    overdub: bool,
+    #[command(flatten)]
+    playback: PlaybackArgs,
+    #[command(flatten)]
+    audio: AudioArgs,
+}
+
+/// Play a song on the default output device until it ends or the user quits. With `--watch`
+/// the song is loaded and played again whenever the file changes, and the player waits for
+/// the next change instead of exiting at the end.
+pub fn run(mut opt: PlayArgs) -> Result<()> {
+    info!("Playing MIDI file: {}", opt.song.midi);
+    #[cfg(feature = "jack")]
+    let jack = opt.jack;
+    #[cfg(not(feature = "jack"))]
+    let jack = false;
+    let audio_options = opt.audio.device.is_some() || opt.playback.also_device.is_some() || opt.record.is_some();
+    if opt.midi_only() && (audio_options || jack) {
+        anyhow::bail!("with --midi-out alone nothing plays on an audio device; add --also-synth to play on one too");
+    }
+    let sidecar = sidecar::load_for(&mut opt.song)?.unwrap_or_default();
+    if opt.speed == 1.0
+        && let Some(speed) = sidecar.speed
+    {
+        opt.speed = speed;
+    }
+    // Sent to a MIDI port, the song plays on whatever instruments the port has.
+    let soundfonts = if opt.midi_only() {
+        Vec::new()
+    } else {
+        rmid::layer_bank(&mut opt.song)?;
+        let soundfonts = soundfont::resolve_all(opt.song.soundfont.as_deref(), &opt.song.soundfonts)?;
+        info!("Using SoundFont: {}", soundfonts.join(" over "));
+        soundfonts
+    };
+    let check_presets = |song: &Song| if opt.midi_only() { Vec::new() } else { fallback::check(song, &soundfonts) };
+
+    let (mut song, mut play, mut file_key) = load(&opt, &soundfonts, &sidecar.markers)?;
+    let mut missing = check_presets(&song);
+    if opt.dry_run {
+        let presets = if opt.midi_only() {
+            String::new()
+        } else {
+            format!("; SoundFont has {} presets", soundfont::preset_numbers(&soundfonts)?.len())
+        };
+        info!(
+            "Dry run: {} events, {} markers, {} long{presets}",
+            song.timeline.len(),
+            song.markers.len(),
+            format_duration(song.length_us),
+        );
+        return Ok(());
+    }
+
+    // 2) Find the audio output. FluidLite has to render at the device sample rate.
+    let sink = open_output(&opt)?;
+    let sample_rate = sink.sample_rate() as f32;
+
+    // 3) Create a FluidLite synth and load the SoundFont.
+    // Master gain, raised in minus-one mode to make up for the missing part, and set to the
+    // song's ReplayGain from the sidecar so a playlist plays at an even loudness.
+    let boost_db = opt.minus_one_boost.unwrap_or(0.0);
+    let replaygain_db = sidecar.replaygain.unwrap_or(0.0) as f32;
+    let gain = 0.7 * 10f32.powf((boost_db + replaygain_db) / 20.0);
+    let synth = open_synth(&opt, &soundfonts, gain, sample_rate)?;
+    if boost_db != 0.0 {
+        info!("Boosting the remaining channels by {boost_db:+.1} dB");
+    }
+    if replaygain_db != 0.0 {
+        info!("ReplayGain: {replaygain_db:+.1} dB");
+    }
+    if opt.playback.limiter.limiter {
+        info!("Limiting peaks to {:.1} dBFS", opt.playback.limiter.limiter_threshold);
+    }
+    let (synth, mut events) = Scheduler::wrap(player_synth(&opt, synth, sample_rate), sample_rate);
+    debug!("Sample rate set to {}", sample_rate);
+
+    // Ctrl-C should stop the music gracefully rather than kill it mid-note.
+    install_interrupt_handler();
+
+    // Transport commands (pause, quit) reach the conductor over a channel from the keyboard
+    // thread, the file watcher and the audio output.
+    let (cmd_tx, mut cmd_rx) = mpsc::channel();
+
+    let (recorder, capture) = match &opt.record {
+        Some(path) => {
+            let title = song.title.clone().unwrap_or_else(|| {
+                Path::new(&opt.song.midi).file_stem().unwrap_or_default().to_string_lossy().into_owned()
+            });
+            Some(Recorder::start(path, sample_rate as u32, title, song.copyright.clone())?)
+        }
+        None => None,
+    }
+    .unzip();
+
+    // 4) Start audio: the CPAL callback owns the synth from here on, and pulls samples from it.
+    let output = audio::Output::start(sink, &opt.audio, &opt.playback, synth, cmd_tx.clone(), capture)?;
+    // Live input goes to the synth alongside the song, with the next buffer.
+    let midi_recorder = match &opt.record_midi {
+        Some(path) if opt.overdub => {
+            let position = Arc::new(AtomicU64::new(play.start_us));
+            play.position = Some(Arc::clone(&position));
+            Some(MidiRecorder::overdub(path, &opt.song, position)?)
+        }
+        Some(path) => Some(MidiRecorder::start(path)?),
+        None => None,
+    };
+    let input = match &opt.midi_in {
+        Some(port) => {
+            let mut live = events.live().expect("only taken here");
+            let take = midi_recorder.as_ref().map(MidiRecorder::take);
+            let channel = opt.midi_in_channel.map(|ch| ch - 1);
+            Some(midi_in::open(port, channel, move |msg| {
+                if let Some(take) = &take {
+                    take.lock().unwrap().push(msg);
+                }
+                live.play(msg);
+            })?)
+        }
+        None => None,
+    };
+    let font_stamps = || soundfonts.iter().map(|sf| watch::stamp(sf)).collect::<Vec<_>>();
+    let mut font_stamp = font_stamps();
+    if opt.watch {
+        let mut paths = vec![opt.song.midi.clone()];
+        paths.extend(soundfonts.iter().cloned());
+        info!("Watching {} for changes", paths.join(", "));
+        watch::spawn_watcher(paths, cmd_tx.clone());
+    }
+
+    // Take single key presses from the terminal while the song plays. When stdin is not a
+    // terminal (a pipe or FIFO) it becomes a line-based control channel instead.
+    let raw = RawTerminal::enable();
+    if raw.is_some() {
+        info!("Controls: space = pause/resume, ←/→ = seek 5s, ↑/↓ = seek 30s, q = quit");
+        info!("          [ / ] = set loop A / B, \\ = clear loop, + / - = tempo");
+        info!("          m / s then 1-9, 0 (=10), a-f (=11-16) = toggle mute / solo");
+        info!("          n / p = next / previous marker, ! = panic (all notes off)");
+        info!("          0-9 = jump to 0%-90% of the song");
+    }
+    spawn_controls(cmd_tx, raw.is_some());
+
+    loop {
+        // 5) Run the "conductor" until the song and its tail have played or it is stopped.
+        // It schedules MIDI events against a pausable song clock and sends them to the synth,
+        // while the CPAL audio callback runs in parallel and pulls audio from the synth.
+        // It gets a thread of its own at real-time priority; this one stays at normal
+        // priority to reload the song and the SoundFont between passes.
+        let stopped = thread::scope(|scope| {
+            let (song, play, commands, events) = (&song, &play, &mut cmd_rx, &mut events);
+            scope
+                .spawn(move || {
+                    priority::raise(Role::Conductor);
+                    conduct(song, play, commands, events)
+                })
+                .join()
+        })
+        .expect("the conductor panicked");
+        let reason = stopped.map_or("end", |(why, _)| why.name());
+        let stopped_at = stopped.map(|(_, at)| at);
+        output::event("finished", [("reason", reason.into()), ("position", stopped_at.map(output::secs).into())]);
+
+        // Remember where we stopped for --resume; a song that played to the end starts over.
+        if let Err(e) = save_position(file_key, stopped_at) {
+            warn!("Could not save the playback position: {e:#}");
+        }
+
+        let reload = match stopped {
+            Some((Stop::Reload, _)) => true,
+            None if opt.watch => {
+                info!("Waiting for {} to change", opt.song.midi);
+                wait_for_reload(&cmd_rx)
+            }
+            None if input.is_some() && !opt.overdub => {
+                info!("The song is over; the MIDI input plays on until you quit");
+                wait_for_reload(&cmd_rx)
+            }
+            _ => false,
+        };
+        if !reload {
+            break;
+        }
+        // A file that fails to load (say, half written) is reported, and the next change
+        // tried instead.
+        loop {
+            info!("Reloading {}", opt.song.midi);
+            match load(&opt, &soundfonts, &sidecar.markers) {
+                Ok(loaded) => {
+                    (song, play, file_key) = loaded;
+                    missing = check_presets(&song);
+                    break;
+                }
+                Err(e) => warn!("Could not load {}: {e:#}", opt.song.midi),
+            }
+            if !wait_for_reload(&cmd_rx) {
+                break;
+            }
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            break;
+        }
+        if font_stamps() != font_stamp {
+            font_stamp = font_stamps();
+            info!("Reloading SoundFont {}", soundfonts.join(", "));
+            match open_synth(&opt, &soundfonts, gain, sample_rate) {
+                // Handed to the callback, which lets go of the old one at the next buffer.
+                Ok(fresh) => events.replace(player_synth(&opt, fresh, sample_rate)),
+                Err(e) => warn!("Could not load the SoundFont, keeping the old one: {e:#}"),
+            }
+        }
+    }
+
+    // Stop the stream before the synth goes away, and hand the terminal back.
+    output.report_stats();
+    fallback::summarize(&missing);
+    drop(input);
+    drop(output);
+    drop(raw);
+    if let Some(recorder) = recorder
+        && let Err(e) = recorder.finish()
+    {
+        warn!("{e:#}");
+    }
+    if let Some(recorder) = midi_recorder
+        && let Err(e) = recorder.finish()
+    {
+        warn!("{e:#}");
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        std::process::exit(130);
+    }
+    Ok(())
+}
+
+impl PlayArgs {
+    /// Whether the song goes to a MIDI port and nowhere else.
+    fn midi_only(&self) -> bool {
+        self.midi_out.is_some() && !self.also_synth
+    }
+}
+
+/// The SoundFont synth, the MIDI port or both, as the options ask.
+fn open_synth(opt: &PlayArgs, soundfonts: &[String], gain: f32, sample_rate: f32) -> Result<Synth> {
+    let Some(port) = &opt.midi_out else {
+        return synth::open(soundfonts, gain, sample_rate, opt.song.effects);
+    };
+    let midi = midi_out::open(port, gain, Duration::from_millis(opt.midi_out_delay))?;
+    if !opt.also_synth {
+        return Ok(midi);
+    }
+    Ok(Layered::wrap(synth::open(soundfonts, gain, sample_rate, opt.song.effects)?, midi))
+}
+
+/// The synth as the player drives it: the output is listened to, so playback can end when
+/// the sound has died away. With only a MIDI port there is nothing to listen to, so the tail
+/// is waited out for a fixed time.
+fn player_synth(opt: &PlayArgs, synth: Synth, sample_rate: f32) -> Synth {
+    if opt.midi_only() { synth } else { Metered::wrap(synth, sample_rate) }
+}
+
+/// The device chosen by the audio options, with `--jack` a JACK client of our own, or with
+/// `--midi-out` none at all.
+fn open_output(opt: &PlayArgs) -> Result<audio::Sink> {
+    if opt.midi_only() {
+        return Ok(audio::Sink::Paced(PACED_RATE));
+    }
+    #[cfg(feature = "jack")]
+    if opt.jack {
+        return Ok(audio::Sink::Jack(crate::jack::Client::open("midi-play")?));
+    }
+    let (dev, cfg) = audio::output_device(&opt.audio)?;
+    Ok(audio::Sink::Device(dev, cfg))
+}
+
+/// Block until the watcher reports a change (true), or the user quits (false).
+fn wait_for_reload(commands: &mpsc::Receiver<Command>) -> bool {
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        match commands.recv_timeout(Duration::from_millis(100)) {
+            Ok(Command::Reload) => return true,
+            Ok(Command::Quit) | Err(mpsc::RecvTimeoutError::Disconnected) => return false,
+            Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+    }
+    false
+}
+
+/// Read the MIDI file, build its timeline and work out the playback settings for it, with
+/// `extra_markers` from the sidecar added to the file's own.
+/// Returns the song, the conductor options and the key `--resume` stores positions under.
+fn load(opt: &PlayArgs, soundfonts: &[String], extra_markers: &[Marker]) -> Result<(Song, PlayOptions, u64)> {
+    // 1) Read the MIDI file and build a single timeline of timestamped events.
+    let bytes = fs::read(&opt.song.midi).with_context(|| "reading MIDI file")?;
+    let drums = opt.song.effects.drum_channels.unwrap_or_default();
+    let mut song = Song::parse(&bytes, &opt.song.tracks, opt.song.transpose, drums, opt.song.lenient)?;
+    song.override_programs(&opt.song.programs);
+    if !extra_markers.is_empty() {
+        info!("Markers from settings:");
+        for m in extra_markers {
+            info!("  {}  {}", format_duration(m.t_us), m.name);
+        }
+        song.markers.extend_from_slice(extra_markers);
+        song.markers.sort_by_key(|m| m.t_us);
+    }
+    let file_key = fnv1a(&bytes);
+    output::event(
+        "loaded",
+        [
+            ("file", opt.song.midi.as_str().into()),
+            // The main SoundFont, and any layered over it.
+            ("soundfont", soundfonts.last().cloned().into()),
+            ("layers", soundfonts[..soundfonts.len().saturating_sub(1)].to_vec().into()),
+            ("length", output::secs(song.length_us)),
+            ("standard", song.standard.map(Standard::name).into()),
+        ],
+    );
+
+    if opt.song.transpose != 0 {
+        info!("Transposed by {:+} semitones (channel 10 untouched)", opt.song.transpose);
+    }
+    if opt.speed != 1.0 {
+        info!(
+            "Speed {:.2}×: plays in {}",
+            opt.speed,
+            format_duration((song.length_us as f64 / opt.speed) as u64)
+        );
+    }
+
+    let at = |pos: Option<Position>| -> Result<Option<u64>> {
+        let Some(pos) = pos else { return Ok(None) };
+        match (pos.resolve(song.length_us, song.bars.as_ref()), pos) {
+            (Some(t), _) => Ok(Some(t)),
+            (None, _) if song.bars.is_none() => {
+                anyhow::bail!("this file has no bars to count: it is SMPTE-timed or a format 2 file")
+            }
+            (None, Position::Bar(bar, beat)) => anyhow::bail!("bar {bar} has no beat {beat}"),
+            (None, _) => unreachable!("only bar positions can fail to resolve"),
+        }
+    };
+    let ab_loop = at(opt.loop_a)?.zip(at(opt.loop_b)?);
+    if let Some((a, b)) = ab_loop {
+        if b <= a {
+            anyhow::bail!("loop end (B) must be after loop start (A)");
+        }
+        info!("Looping {} – {}", describe(a, song.bars.as_ref()), describe(b, song.bars.as_ref()));
+    }
+
+    let marker_start = match &opt.start_marker {
+        Some(name) => match song.markers.iter().find(|m| m.name.eq_ignore_ascii_case(name)) {
+            Some(m) => Some(m.t_us),
+            None if song.markers.is_empty() => anyhow::bail!("no marker named `{name}`: the file has no markers"),
+            None => {
+                let names: Vec<&str> = song.markers.iter().map(|m| m.name.as_str()).collect();
+                anyhow::bail!("no marker named `{name}`. Markers: {}", names.join(", "));
+            }
+        },
+        None => None,
+    };
+
+    let resumed = opt.resume.then(|| load_position(file_key).filter(|&t| t < song.length_us)).flatten();
+    if opt.resume && resumed.is_none() {
+        info!("No saved position for this file, starting from the top");
+    }
+
+    // An A–B loop starts at A unless told otherwise.
+    let start_us = at(opt.start)?.or(marker_start).or(resumed).or(ab_loop.map(|(a, _)| a)).unwrap_or(0);
+    if start_us > song.length_us {
+        anyhow::bail!(
+            "start position {} is past the end of the song ({})",
+            format_duration(start_us),
+            format_duration(song.length_us)
+        );
+    }
+    if let Some(ch) = opt.minus_one {
+        info!("Minus-one: channel {ch} ({}) is left out", channel_instrument(&song, ch - 1));
+    }
+
+    let practice = opt.practice.then(|| Practice {
+        step: opt.practice_step / 100.0,
+        target: (opt.practice_target / 100.0).clamp(MIN_SPEED, MAX_SPEED),
+    });
+    let speed = match practice {
+        Some(_) => (opt.practice_start / 100.0).clamp(MIN_SPEED, MAX_SPEED),
+        None => opt.speed,
+    };
+    if practice.is_some() {
+        info!(
+            "Practice mode: {:.0}% → {:.0}% in steps of {:.0}%",
+            speed * 100.0,
+            opt.practice_target,
+            opt.practice_step
+        );
+    }
+
+    let denominator = 1u32 << song.initial_time_sig.1;
+    let play = PlayOptions {
+        start_us,
+        end_us: at(opt.end)?.or(opt.duration.map(|d| start_us + d)),
+        ab_loop,
+        // Without an A–B region, practice mode loops the whole segment.
+        repeat: if practice.is_some() { Repeat::Forever } else { opt.repeat },
+        speed,
+        practice,
+        mixer: Mixer::new(&[&opt.song.mute_channel[..], opt.minus_one.as_slice()].concat(), &opt.song.solo_channel),
+        stop_tail: Duration::from_secs_f64(opt.stop_tail.max(0.0)),
+        count_in: opt.count_in.filter(|&bars| bars > 0).map(|bars| CountIn {
+            bars,
+            beats_per_bar: song.initial_time_sig.0,
+            // One beat is one denominator note: a quarter note scaled by 4 / denominator.
+            beat_us: song.initial_us_per_qn * 4.0 / f64::from(denominator),
+        }),
+        max_duration: opt.max_duration.filter(|&us| us > 0).map(Duration::from_micros),
+        position: None,
+    };
+    if let Some(c) = &play.count_in {
+        info!("Count-in: {} bar(s) of {}/{denominator}", c.bars, c.beats_per_bar);
+    }
+    if !opt.song.mute_channel.is_empty() || !opt.song.solo_channel.is_empty() {
+        info!("{}", play.mixer);
+    }
+    if let Some(limit) = play.max_duration {
+        info!("Stopping after {} at most", format_duration(limit.as_micros() as u64));
+    }
+    if let Some(end_us) = play.end_us {
+        if end_us <= start_us {
+            anyhow::bail!("end position must be after the start position");
+        }
+        info!("Playing segment {} – {}", format_duration(start_us), format_duration(end_us));
+    }
+
+    Ok((song, play, file_key))
+}
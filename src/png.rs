@@ -0,0 +1,42 @@
+//! PNG output for `render-image -o roll.png` and the spectrogram: 8-bit palette images,
+//! written with the `png` crate.
+
+use anyhow::{Context, Result};
+use std::{fs::File, io::BufWriter, path::Path};
+
+/// Write `pixels`, one palette index per pixel row by row, as a PNG with `palette` (at most
+/// 256 colours).
+pub fn write(path: &Path, width: u32, height: u32, palette: &[[u8; 3]], pixels: &[u8]) -> Result<()> {
+    debug_assert_eq!(pixels.len(), width as usize * height as usize);
+    let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    let mut encoder = ::png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(::png::ColorType::Indexed);
+    encoder.set_depth(::png::BitDepth::Eight);
+    encoder.set_palette(palette.as_flattened());
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish().with_context(|| format!("writing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_palette_image_that_decodes_back() {
+        let path = std::env::temp_dir().join(format!("midi-play-{}-roll.png", std::process::id()));
+        let palette = [[0, 0, 0], [255, 128, 0], [10, 20, 30]];
+        let pixels: Vec<u8> = (0..40 * 30).map(|n| (n / 7 % 3) as u8).collect();
+        write(&path, 40, 30, &palette, &pixels).unwrap();
+
+        let decoder = ::png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height, info.color_type), (40, 30, ::png::ColorType::Indexed));
+        assert_eq!(info.palette.as_deref(), Some(palette.as_flattened()));
+        let mut decoded = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut decoded).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(decoded, pixels);
+    }
+}
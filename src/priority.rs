@@ -0,0 +1,95 @@
+//! Real-time scheduling for the threads that keep the music going: the audio callback,
+//! which has a buffer's time to render, and the conductor, which stamps the events. At
+//! normal priority a burst of load elsewhere (a compile, a browser tab) can hold either back
+//! long enough to be heard.
+
+use tracing::debug;
+
+/// A thread the player asks real-time priority for. The audio thread gets the higher one,
+/// since the conductor works a buffer ahead of it.
+#[derive(Clone, Copy, Debug)]
+pub enum Role {
+    Audio,
+    Conductor,
+}
+
+/// How far `raise_quietly` got: the errors are the OS error numbers the real-time request
+/// failed with.
+#[derive(Clone, Copy, Debug)]
+pub enum Raised {
+    #[cfg(unix)]
+    RealTime,
+    /// A higher nice value instead.
+    #[cfg(unix)]
+    Niced(i32),
+    #[cfg(unix)]
+    Neither(i32),
+    /// Not tried on this platform.
+    #[cfg(not(unix))]
+    Unsupported,
+}
+
+impl Raised {
+    /// Say with `-v` what the `role` thread got.
+    pub fn log(self, role: Role) {
+        #[cfg(unix)]
+        let why = |err| std::io::Error::from_raw_os_error(err);
+        match self {
+            #[cfg(unix)]
+            Raised::RealTime => debug!("The {role:?} thread runs at real-time priority"),
+            #[cfg(unix)]
+            Raised::Niced(err) => {
+                let nice = levels(role).1;
+                debug!("No real-time priority for the {role:?} thread ({}); running at nice {nice} instead", why(err));
+            }
+            #[cfg(unix)]
+            Raised::Neither(err) => debug!("No real-time priority for the {role:?} thread ({}), nor a raised one", why(err)),
+            #[cfg(not(unix))]
+            Raised::Unsupported => debug!("Not raising the priority of the {role:?} thread on this platform"),
+        }
+    }
+}
+
+/// The `SCHED_FIFO` priority and the nice value asked for the thread.
+#[cfg(unix)]
+fn levels(role: Role) -> (i32, i32) {
+    match role {
+        Role::Audio => (70, -11),
+        Role::Conductor => (60, -10),
+    }
+}
+
+/// Ask for real-time priority for the calling thread, or failing that a higher one, and say
+/// with `-v` which it got.
+pub fn raise(role: Role) {
+    raise_quietly(role).log(role);
+}
+
+/// Ask for real-time priority for the calling thread, or failing that a higher one, without
+/// logging, for the audio callback. Neither is a must: without the rights to them (on
+/// Linux, `rtprio` in `/etc/security/limits.conf`, which the `audio` group usually has)
+/// playback carries on as before.
+#[cfg(unix)]
+pub fn raise_quietly(role: Role) -> Raised {
+    let (fifo, nice) = levels(role);
+    // SAFETY: plain calls on the current thread, with a parameter block made here.
+    let err = unsafe {
+        let mut param: libc::sched_param = std::mem::zeroed();
+        let (min, max) = (libc::sched_get_priority_min(libc::SCHED_FIFO), libc::sched_get_priority_max(libc::SCHED_FIFO));
+        param.sched_priority = fifo.clamp(min, max);
+        libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param)
+    };
+    if err == 0 {
+        return Raised::RealTime;
+    }
+    // On Linux the nice value is the thread's own; elsewhere this raises the whole process.
+    // SAFETY: as above.
+    let reniced = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == 0;
+    if reniced { Raised::Niced(err) } else { Raised::Neither(err) }
+}
+
+/// Other platforms keep the priority they give the thread.
+#[cfg(not(unix))]
+pub fn raise_quietly(_: Role) -> Raised {
+    Raised::Unsupported
+}
@@ -0,0 +1,253 @@
+//! A bounded single-producer, single-consumer queue that neither side ever waits on: the
+//! conductor pushes timed messages in, the audio callback takes them out, and neither holds
+//! a lock the other could be stuck behind.
+//!
+//! The audio the callback plays leaves the same way, a buffer at a time, for
+//! `--also-device` and `--record`. A `Handoff` passes one boxed value across without a
+//! lock too: the callback owns the synth while it plays, and puts it back when its stream
+//! stops.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// The slots, and how many values have been pushed and popped so far. The counts only grow
+/// (wrapping), so their difference is how many are waiting.
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    pushed: AtomicUsize,
+    popped: AtomicUsize,
+}
+
+// SAFETY: a slot is written only by the producer while it is free, and read only by the
+// consumer once the release store of `pushed` has published it, so no slot is ever touched
+// from both sides at once.
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+/// The sending end. There is one, so it takes `&mut self`.
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// The receiving end. There is one, so it takes `&mut self`.
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// A queue holding up to `capacity` values, rounded up to a power of two so that a count
+/// still picks the right slot when it wraps. Only `Copy` values, so none is ever left
+/// needing a drop.
+pub fn channel<T: Copy + Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let slots = (0..capacity.max(1).next_power_of_two()).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+    let ring = Arc::new(Ring { slots, pushed: AtomicUsize::new(0), popped: AtomicUsize::new(0) });
+    (Producer { ring: Arc::clone(&ring) }, Consumer { ring })
+}
+
+impl<T: Copy + Send> Producer<T> {
+    /// Add `value` at the back, or hand it back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let ring = &*self.ring;
+        let pushed = ring.pushed.load(Ordering::Relaxed);
+        if pushed.wrapping_sub(ring.popped.load(Ordering::Acquire)) == ring.slots.len() {
+            return Err(value);
+        }
+        // SAFETY: the slot is free (the consumer has popped it, as the acquire load shows)
+        // and only this one producer writes.
+        unsafe { (*ring.slots[pushed % ring.slots.len()].get()).write(value) };
+        ring.pushed.store(pushed.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Add as many of `values` at the back as there is room for, and return how many.
+    pub fn push_slice(&mut self, values: &[T]) -> usize {
+        let ring = &*self.ring;
+        let pushed = ring.pushed.load(Ordering::Relaxed);
+        let free = ring.slots.len() - pushed.wrapping_sub(ring.popped.load(Ordering::Acquire));
+        let n = values.len().min(free);
+        for (i, &value) in values[..n].iter().enumerate() {
+            // SAFETY: as in `push`, for each of the free slots.
+            unsafe { (*ring.slots[pushed.wrapping_add(i) % ring.slots.len()].get()).write(value) };
+        }
+        ring.pushed.store(pushed.wrapping_add(n), Ordering::Release);
+        n
+    }
+}
+
+impl<T: Copy + Send> Consumer<T> {
+    /// How many values are waiting. More may be pushed at any moment.
+    pub fn waiting(&self) -> usize {
+        let ring = &*self.ring;
+        ring.pushed.load(Ordering::Acquire).wrapping_sub(ring.popped.load(Ordering::Relaxed))
+    }
+
+    /// Take the value at the front, if there is one.
+    pub fn pop(&mut self) -> Option<T> {
+        let ring = &*self.ring;
+        let popped = ring.popped.load(Ordering::Relaxed);
+        if popped == ring.pushed.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: the slot was written before `pushed` moved past it, as the acquire load
+        // shows, and only this one consumer reads.
+        let value = unsafe { (*ring.slots[popped % ring.slots.len()].get()).assume_init_read() };
+        ring.popped.store(popped.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Fill the front of `out` with as many values as are waiting, and return how many.
+    pub fn pop_slice(&mut self, out: &mut [T]) -> usize {
+        let ring = &*self.ring;
+        let popped = ring.popped.load(Ordering::Relaxed);
+        let n = out.len().min(ring.pushed.load(Ordering::Acquire).wrapping_sub(popped));
+        for (i, value) in out[..n].iter_mut().enumerate() {
+            // SAFETY: as in `pop`, for each of the slots written.
+            *value = unsafe { (*ring.slots[popped.wrapping_add(i) % ring.slots.len()].get()).assume_init_read() };
+        }
+        ring.popped.store(popped.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    /// Drop up to `n` values from the front, and return how many were dropped.
+    pub fn skip(&mut self, n: usize) -> usize {
+        let n = n.min(self.waiting());
+        let ring = &*self.ring;
+        ring.popped.store(ring.popped.load(Ordering::Relaxed).wrapping_add(n), Ordering::Release);
+        n
+    }
+}
+
+/// A place for one boxed value, which whoever takes it owns until they put it back. Taking
+/// and putting never wait and never allocate, so the audio callback can do both.
+pub struct Handoff<T>(AtomicPtr<T>);
+
+// SAFETY: the value is only ever reached through the box one side has taken, so it moves
+// between threads but is never shared.
+unsafe impl<T: Send> Sync for Handoff<T> {}
+
+impl<T> Handoff<T> {
+    pub fn new(value: Option<Box<T>>) -> Self {
+        Self(AtomicPtr::new(value.map_or(ptr::null_mut(), Box::into_raw)))
+    }
+
+    /// Take the value, if it is there.
+    pub fn take(&self) -> Option<Box<T>> {
+        let value = self.0.swap(ptr::null_mut(), Ordering::AcqRel);
+        // SAFETY: a non-null pointer came from `Box::into_raw`, and the swap made it ours alone.
+        (!value.is_null()).then(|| unsafe { Box::from_raw(value) })
+    }
+
+    /// Put `value` in, and hand back whatever was there.
+    pub fn put(&self, value: Box<T>) -> Option<Box<T>> {
+        let old = self.0.swap(Box::into_raw(value), Ordering::AcqRel);
+        // SAFETY: as in `take`.
+        (!old.is_null()).then(|| unsafe { Box::from_raw(old) })
+    }
+
+    /// Whether a value is there to take. Either side may change that at any moment.
+    pub fn is_full(&self) -> bool {
+        !self.0.load(Ordering::Acquire).is_null()
+    }
+}
+
+impl<T> Drop for Handoff<T> {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_a_value_when_full() {
+        let (mut tx, mut rx) = channel(4);
+        for i in 0..4 {
+            assert_eq!(tx.push(i), Ok(()));
+        }
+        assert_eq!(tx.push(4), Err(4));
+        assert_eq!(rx.waiting(), 4);
+        assert_eq!(rx.pop(), Some(0));
+        assert_eq!(tx.push(4), Ok(()));
+        assert_eq!((1..5).map(|_| rx.pop().unwrap()).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[test]
+    fn keeps_order_round_the_ring() {
+        let (mut tx, mut rx) = channel(3);
+        let mut next = 0;
+        for i in 0..1000 {
+            tx.push(i).unwrap();
+            if i % 3 == 2 {
+                while let Some(value) = rx.pop() {
+                    assert_eq!(value, next);
+                    next += 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_order_as_the_counts_wrap() {
+        let (mut tx, mut rx) = channel(3);
+        let start = usize::MAX - 5;
+        tx.ring.pushed.store(start, Ordering::Relaxed);
+        tx.ring.popped.store(start, Ordering::Relaxed);
+        let (mut sent, mut received) = (0, 0);
+        for _ in 0..20 {
+            while tx.push(sent).is_ok() {
+                sent += 1;
+            }
+            assert_eq!(sent - received, 4);
+            for _ in 0..3 {
+                assert_eq!(rx.pop(), Some(received));
+                received += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn works_across_threads() {
+        let (mut tx, mut rx) = channel(16);
+        let sender = std::thread::spawn(move || {
+            for i in 0..10_000u32 {
+                while tx.push(i).is_err() {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut next = 0;
+        while next < 10_000 {
+            if let Some(value) = rx.pop() {
+                assert_eq!(value, next);
+                next += 1;
+            }
+        }
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn moves_slices_as_far_as_there_is_room() {
+        let (mut tx, mut rx) = channel(8);
+        assert_eq!(tx.push_slice(&[1, 2, 3, 4, 5, 6]), 6);
+        assert_eq!(rx.skip(1), 1);
+        assert_eq!(tx.push_slice(&[7, 8, 9, 10]), 3);
+        let mut out = [0; 10];
+        assert_eq!(rx.pop_slice(&mut out), 8);
+        assert_eq!(out[..8], [2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(rx.skip(5), 0);
+    }
+
+    #[test]
+    fn hands_a_value_over_once() {
+        let handoff = Handoff::new(Some(Box::new(1)));
+        assert!(handoff.is_full());
+        assert_eq!(handoff.put(Box::new(2)).as_deref(), Some(&1));
+        assert_eq!(handoff.take().as_deref(), Some(&2));
+        assert_eq!(handoff.take(), None);
+        assert!(!handoff.is_full());
+    }
+}
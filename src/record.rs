@@ -0,0 +1,153 @@
+//! `play --record`: everything the synth plays, written to a file as it plays, so the
+//! recording has the tempo changes, mutes and seeks made along the way.
+//!
+//! The audio callback only appends to a lock-free queue; a thread of its own takes what has
+//! piled up a few times a second and encodes it, so the callback never waits on the disk
+//! or the encoder.
+
+use crate::queue::{self, Consumer, Producer};
+use crate::render::AudioFile;
+use crate::time::format_duration;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often the writer takes what the callback has captured.
+const WRITE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How much audio the queue holds, far more than piles up between two writes.
+const CAPACITY: Duration = Duration::from_secs(2);
+
+/// What the audio callback captures for the writer.
+pub struct Capture {
+    /// Interleaved stereo played and not yet written.
+    samples: Producer<f32>,
+    /// The rate the file is written at.
+    sample_rate: u32,
+    shared: Arc<Shared>,
+}
+
+/// What the callback tells the writer besides the samples.
+#[derive(Default)]
+struct Shared {
+    /// Set when the device moved to another rate, which ends the recording.
+    stopped_at_rate: AtomicU32,
+    /// Samples that found the queue full, because the writer fell behind or failed.
+    lost: AtomicU64,
+}
+
+impl Capture {
+    /// Add what was just played at `sample_rate`.
+    pub fn push(&mut self, stereo: &[f32], sample_rate: u32) {
+        if sample_rate != self.sample_rate {
+            let _ = self.shared.stopped_at_rate.compare_exchange(0, sample_rate, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        if self.shared.stopped_at_rate.load(Ordering::Relaxed) == 0 {
+            let lost = stereo.len() - self.samples.push_slice(stereo);
+            self.shared.lost.fetch_add(lost as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A recording in progress.
+pub struct Recorder {
+    stop: mpsc::Sender<()>,
+    /// Returns the length recorded.
+    thread: JoinHandle<Result<u64>>,
+    path: PathBuf,
+}
+
+impl Recorder {
+    /// Create the file at `path` for stereo at `sample_rate` and start the writer, and
+    /// return it with the end the audio callback appends to. The file is created before
+    /// this returns, so a bad name fails before playback starts.
+    pub fn start(
+        path: &Path,
+        sample_rate: u32,
+        title: String,
+        copyright: Option<String>,
+    ) -> Result<(Self, Capture)> {
+        let capacity = (CAPACITY.as_secs_f64() * f64::from(sample_rate)) as usize * 2;
+        let (samples, written) = queue::channel(capacity);
+        let shared = Arc::new(Shared::default());
+        let capture = Capture { samples, sample_rate, shared: Arc::clone(&shared) };
+        let (stop, stopped) = mpsc::channel();
+        let (created_tx, created) = mpsc::channel();
+        let file_path = path.to_owned();
+        // The encoders are not all `Send`, so the file is created on the thread that writes it.
+        let thread = thread::spawn(move || {
+            let mut file = match AudioFile::create(&file_path, sample_rate, title, copyright) {
+                Ok(file) => {
+                    let _ = created_tx.send(Ok(()));
+                    file
+                }
+                Err(e) => {
+                    let _ = created_tx.send(Err(e));
+                    return Ok(0);
+                }
+            };
+            let frames = write(&mut file, written, &shared, &stopped, sample_rate)?;
+            file.finish()?;
+            Ok(frames * 1_000_000 / u64::from(sample_rate))
+        });
+        match created.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e.context(format!("starting the recording to {}", path.display()))),
+            Err(_) => anyhow::bail!("the recording thread stopped unexpectedly"),
+        }
+        info!("Recording to {}", path.display());
+        Ok((Self { stop, thread, path: path.to_owned() }, capture))
+    }
+
+    /// Write what is left and finish the file. Call once playback has stopped.
+    pub fn finish(self) -> Result<()> {
+        let _ = self.stop.send(());
+        let length_us = match self.thread.join() {
+            Ok(recorded) => recorded.with_context(|| format!("recording to {}", self.path.display()))?,
+            Err(_) => anyhow::bail!("the recording thread stopped unexpectedly"),
+        };
+        info!("Recorded {} to {}", format_duration(length_us), self.path.display());
+        Ok(())
+    }
+}
+
+/// Write what comes in on `samples` to `file` until told to `stop`, then what is left.
+/// Returns the frames written.
+fn write(
+    file: &mut AudioFile,
+    mut samples: Consumer<f32>,
+    shared: &Shared,
+    stop: &mpsc::Receiver<()>,
+    sample_rate: u32,
+) -> Result<u64> {
+    let mut block = vec![0.0; (CAPACITY.as_secs_f64() * f64::from(sample_rate)) as usize * 2];
+    let mut frames = 0u64;
+    let (mut warned_rate, mut warned_lost) = (false, false);
+    loop {
+        let last = !matches!(stop.recv_timeout(WRITE_INTERVAL), Err(RecvTimeoutError::Timeout));
+        let rate = shared.stopped_at_rate.load(Ordering::Relaxed);
+        if rate != 0 && !std::mem::replace(&mut warned_rate, true) {
+            warn!("The audio device now runs at {rate} Hz, not {sample_rate} Hz; the recording stops here");
+        }
+        let lost = shared.lost.load(Ordering::Relaxed);
+        if lost > 0 && !std::mem::replace(&mut warned_lost, true) {
+            warn!("The recording could not keep up and has gaps");
+        }
+        loop {
+            let n = samples.pop_slice(&mut block);
+            if n == 0 {
+                break;
+            }
+            file.write(&block[..n])?;
+            frames += n as u64 / 2;
+        }
+        if last {
+            return Ok(frames);
+        }
+    }
+}
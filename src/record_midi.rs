@@ -0,0 +1,206 @@
+//! `--record-midi`: what comes in on `--midi-in`, written to a Standard MIDI File.
+//!
+//! The input thread stamps each message with the time since the first one and adds it to a
+//! take kept in memory, and the file is written in one go when the player stops. It has a
+//! single track at 120 BPM and 960 ticks per quarter note, about half a millisecond a tick,
+//! fine enough that it plays back as it was played.
+//!
+//! With `--overdub` each message is stamped with the song position the conductor has
+//! reached instead, and the take is written as a new track of a copy of the song, put into
+//! the song's own ticks through its tempo map, so it lines up with the music however the
+//! tempo changes and at whatever `--speed` it was played. The position is the one heard,
+//! behind the events being sent by the output latency, and a take played along with the
+//! song `--transpose`d is transposed back to go with the song as written.
+
+use crate::lenient;
+use crate::song::{DrumChannels, Msg, SongArgs, transpose_key};
+use crate::tempo::TempoMap;
+use crate::time::format_duration;
+use anyhow::{Context, Result, bail};
+use midly::num::{u4, u7, u14, u15, u24, u28};
+use midly::{Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::info;
+
+/// Ticks per quarter note.
+const PPQ: u16 = 960;
+/// 120 BPM, in microseconds per quarter note.
+const TEMPO: u32 = 500_000;
+
+/// The messages recorded so far.
+#[derive(Default)]
+pub struct Take {
+    /// When the first message came in.
+    started: Option<Instant>,
+    /// When overdubbing, the song position to stamp messages with instead.
+    position: Option<Arc<AtomicU64>>,
+    /// Each message with its time in microseconds, since the first or in the song.
+    events: Vec<(u64, Msg)>,
+}
+
+impl Take {
+    /// Add a message that has just come in.
+    pub fn push(&mut self, msg: Msg) {
+        let t_us = match &self.position {
+            Some(position) => position.load(Ordering::Relaxed),
+            None => {
+                let now = Instant::now();
+                (now - *self.started.get_or_insert(now)).as_micros() as u64
+            }
+        };
+        self.events.push((t_us, msg));
+    }
+}
+
+/// A MIDI recording in progress.
+pub struct MidiRecorder {
+    take: Arc<Mutex<Take>>,
+    path: PathBuf,
+    /// When overdubbing, the song the take is added to.
+    backing: Option<Backing>,
+}
+
+/// The file an overdub is added to.
+struct Backing {
+    path: String,
+    bytes: Vec<u8>,
+    lenient: bool,
+    /// What the song was transposed by while the take was played, on all but the drums.
+    transpose: i8,
+    drums: DrumChannels,
+}
+
+impl Backing {
+    fn parse(&self) -> Result<Smf<'_>> {
+        if self.lenient {
+            lenient::parse(&self.bytes).with_context(|| format!("parsing {}", self.path))
+        } else {
+            Smf::parse(&self.bytes).with_context(|| format!("parsing {}", self.path))
+        }
+    }
+}
+
+impl MidiRecorder {
+    /// Create the file at `path`, empty until `finish`, so a bad name fails before anything
+    /// plays.
+    pub fn start(path: &Path) -> Result<Self> {
+        File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        info!("Recording MIDI to {}", path.display());
+        Ok(Self { take: Arc::default(), path: path.to_owned(), backing: None })
+    }
+
+    /// Like `start`, but for adding the take to the MIDI file `song` plays as a new track,
+    /// timed by the song `position` the conductor keeps up to date.
+    pub fn overdub(path: &Path, song: &SongArgs, position: Arc<AtomicU64>) -> Result<Self> {
+        let bytes = fs::read(&song.midi).with_context(|| format!("reading {}", song.midi))?;
+        let backing = Backing {
+            path: song.midi.clone(),
+            bytes,
+            lenient: song.lenient,
+            transpose: song.transpose,
+            drums: song.effects.drum_channels.unwrap_or_default(),
+        };
+        if backing.parse()?.header.format == Format::Sequential {
+            bail!("--overdub cannot line a take up with a format 2 file, whose tracks play one after another");
+        }
+        let mut recorder = Self::start(path)?;
+        recorder.take.lock().unwrap().position = Some(position);
+        recorder.backing = Some(backing);
+        Ok(recorder)
+    }
+
+    /// The take for the input thread to add to.
+    pub fn take(&self) -> Arc<Mutex<Take>> {
+        Arc::clone(&self.take)
+    }
+
+    /// Write the file. Call once the input has stopped.
+    pub fn finish(self) -> Result<()> {
+        let mut events = std::mem::take(&mut self.take.lock().unwrap().events);
+        let count = events.len();
+        let messages = if count == 1 { "message" } else { "messages" };
+        let Some(backing) = &self.backing else {
+            let header = Header::new(Format::SingleTrack, Timing::Metrical(u15::new(PPQ)));
+            let mut smf = Smf::new(header);
+            let meta = [MetaMessage::Tempo(u24::new(TEMPO)), MetaMessage::TimeSignature(4, 2, 24, 8)];
+            let tick = |t_us| (t_us * u64::from(PPQ) + u64::from(TEMPO) / 2) / u64::from(TEMPO);
+            smf.tracks.push(track(&events, &meta, tick)?);
+            smf.save(&self.path).with_context(|| format!("writing {}", self.path.display()))?;
+            let length_us = events.last().map_or(0, |&(t_us, _)| t_us);
+            info!("Recorded {count} {messages} ({}) to {}", format_duration(length_us), self.path.display());
+            return Ok(());
+        };
+
+        let mut smf = backing.parse()?;
+        // Seeks and loops take the song position back, and what was played with it.
+        events.sort_by_key(|&(t_us, _)| t_us);
+        if backing.transpose != 0 {
+            for (_, msg) in &mut events {
+                *msg = transpose(*msg, backing.drums, -backing.transpose);
+            }
+        }
+        let map = TempoMap::new(&smf);
+        smf.tracks.push(track(&events, &[MetaMessage::TrackName(b"Overdub")], |t_us| map.to_tick(t_us))?);
+        // A format 0 file has room for one track only.
+        if smf.header.format == Format::SingleTrack {
+            smf.header.format = Format::Parallel;
+        }
+        smf.save(&self.path).with_context(|| format!("writing {}", self.path.display()))?;
+        info!("Overdubbed {count} {messages} onto {} in {}", backing.path, self.path.display());
+        Ok(())
+    }
+}
+
+/// A track of `events` in time order, with the `meta` events at its start, each message at
+/// the tick `tick` gives for its time.
+fn track(
+    events: &[(u64, Msg)],
+    meta: &[MetaMessage<'static>],
+    tick: impl Fn(u64) -> u64,
+) -> Result<Vec<TrackEvent<'static>>> {
+    let at_start = |kind| TrackEvent { delta: u28::new(0), kind };
+    let mut track: Vec<_> = meta.iter().map(|&meta| at_start(TrackEventKind::Meta(meta))).collect();
+    let mut last = 0u64;
+    for &(t_us, msg) in events {
+        let Some(kind) = event(msg) else { continue };
+        let tick = tick(t_us);
+        let delta = u32::try_from(tick - last).ok().and_then(u28::try_from);
+        let delta = delta.context("a gap between messages is too long to write")?;
+        track.push(TrackEvent { delta, kind });
+        last = tick;
+    }
+    track.push(at_start(TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+    Ok(track)
+}
+
+/// `msg` with its key moved by `semitones`, unless it is on one of the `drums`.
+fn transpose(msg: Msg, drums: DrumChannels, semitones: i8) -> Msg {
+    let key = |ch, key| transpose_key(drums, ch, key, semitones);
+    match msg {
+        Msg::NoteOn(ch, k, vel) => Msg::NoteOn(ch, key(ch, k), vel),
+        Msg::NoteOff(ch, k, vel) => Msg::NoteOff(ch, key(ch, k), vel),
+        Msg::AfterTouch(ch, k, vel) => Msg::AfterTouch(ch, key(ch, k), vel),
+        msg => msg,
+    }
+}
+
+/// The channel message `msg` is in a file, or `None` for one that is not a channel message.
+fn event(msg: Msg) -> Option<TrackEventKind<'static>> {
+    let (ch, message) = match msg {
+        Msg::NoteOn(ch, key, vel) => (ch, MidiMessage::NoteOn { key: u7::new(key), vel: u7::new(vel) }),
+        Msg::NoteOff(ch, key, vel) => (ch, MidiMessage::NoteOff { key: u7::new(key), vel: u7::new(vel) }),
+        Msg::AfterTouch(ch, key, vel) => (ch, MidiMessage::Aftertouch { key: u7::new(key), vel: u7::new(vel) }),
+        Msg::Control(ch, controller, value) => {
+            (ch, MidiMessage::Controller { controller: u7::new(controller), value: u7::new(value) })
+        }
+        Msg::Program(ch, program) => (ch, MidiMessage::ProgramChange { program: u7::new(program) }),
+        Msg::ChannelAftertouch(ch, vel) => (ch, MidiMessage::ChannelAftertouch { vel: u7::new(vel) }),
+        Msg::PitchBend(ch, bend) => (ch, MidiMessage::PitchBend { bend: PitchBend(u14::new(bend)) }),
+        _ => return None,
+    };
+    Some(TrackEventKind::Midi { channel: u4::new(ch), message })
+}
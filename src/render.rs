@@ -0,0 +1,996 @@
+//! The `render` subcommand: play a song offline into a WAV, FLAC, Opus, Vorbis or MP3
+//! file, or as raw PCM to stdout for another program, as fast as the CPU allows.
+
+use crate::conductor::Mixer;
+use crate::dither::{Dither, Quantizer};
+use crate::fallback;
+use crate::flac::FlacWriter;
+use crate::limiter::{Limiter, LimiterArgs};
+use crate::logging;
+use crate::loudness;
+use crate::rmid;
+use crate::song::{Msg, Song, SongArgs, Timed, channel_instrument, is_midi};
+use crate::sidecar;
+use crate::soundfont;
+use crate::spectrogram::Spectrogram;
+use crate::synth::{self, EffectsArgs, MAX_TAIL, SILENCE_DBFS, SILENCE_HOLD, Synth};
+use crate::time::{format_duration, parse_time};
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+/// `render` options, on top of the shared song options:
+/// - output: the file to write, in the format its extension names, or `-` for stdout
+/// - format: the format to write, whatever the extension
+/// - sample_format: sample type of raw PCM
+/// - sample_rate: rate to render at
+/// - bitrate: bitrate of lossy formats
+/// - vbr: variable bitrate quality for MP3
+/// - stems: render each channel or track to a file of its own
+/// - normalize: integrated loudness to bring the render to
+/// - true_peak: ceiling for the true peak after normalizing
+/// - dither: how 16-bit output is rounded
+/// - tail: how long to render after the last event
+/// - limiter: the master limiter
+/// - spectrogram: draw a spectrogram of the render beside it
+/// - recursive: render a folder of songs into a folder
+/// - jobs: how many songs of a folder to render at once
+#[derive(Args, Debug)]
+pub struct RenderArgs {
+    #[command(flatten)]
+    song: SongArgs,
+    /// Write the audio to this file: FLAC if it ends in .flac, Opus for .opus, Vorbis for
+    /// .ogg, MP3 for .mp3, WAV otherwise. `-` streams raw PCM to stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+    /// Format to write instead of the one the file name implies
+    #[arg(long, value_name = "FORMAT", value_enum)]
+    format: Option<Kind>,
+    /// Sample type of raw PCM: 32-bit float or 16-bit signed integer, both little-endian
+    #[arg(long, value_name = "TYPE", value_enum, default_value_t)]
+    sample_format: Sample,
+    /// Sample rate of the rendered audio, in Hz
+    #[arg(long, value_name = "HZ", default_value_t = 44_100,
+          value_parser = clap::value_parser!(u32).range(8_000..=192_000))]
+    sample_rate: u32,
+    /// Bitrate of Opus, Vorbis or MP3 output, in kbit/s [default: 96 for Opus, 128 for
+    /// Vorbis, 192 for MP3]
+    #[arg(long, value_name = "KBPS", value_parser = clap::value_parser!(u32).range(6..=500))]
+    bitrate: Option<u32>,
+    /// Encode MP3 at a variable bitrate of this quality, 0 (best) to 9 (smallest), as `lame -V`
+    #[arg(long, value_name = "Q", conflicts_with = "bitrate", value_parser = clap::value_parser!(u8).range(0..=9))]
+    vbr: Option<u8>,
+    /// Render each channel or track that plays notes to a file of its own, named after the
+    /// output with `-ch01` or `-track01` added, for mixing in a DAW
+    #[arg(long, value_name = "BY", value_enum)]
+    stems: Option<Stems>,
+    /// Bring the render to this integrated loudness (EBU R128), e.g. -16LUFS or -23LUFS
+    #[arg(long, value_name = "LUFS", allow_hyphen_values = true, value_parser = parse_lufs, conflicts_with = "stems")]
+    normalize: Option<f64>,
+    /// Limit the true peak of a normalized render to this, in dBTP
+    #[arg(long, value_name = "DBTP", default_value_t = -1.0, allow_negative_numbers = true,
+          value_parser = parse_dbtp, requires = "normalize")]
+    true_peak: f64,
+    /// Dither for 16-bit output (WAV, FLAC, raw s16): `tpdf` [default], `shaped` to push the
+    /// noise up to where it is least heard, or `none` to just round
+    #[arg(long, value_name = "KIND", value_enum)]
+    dither: Option<Dither>,
+    /// How long to keep rendering after the last event so notes and reverb can ring out:
+    /// `auto` stops once the sound has died away, or give a length in seconds or mm:ss
+    #[arg(long, value_name = "SECS|auto", default_value = "auto", value_parser = parse_tail)]
+    tail: Tail,
+    #[command(flatten)]
+    limiter: LimiterArgs,
+    /// Also draw a spectrogram of the render, with a strip marking where it clips, to
+    /// OUT.spectrogram.png beside it
+    #[arg(long, conflicts_with = "stems")]
+    spectrogram: bool,
+    /// Render every MIDI file in the folder given as the song, and in the folders within it,
+    /// to the same place in the folder -o names, in --format [default: wav]. Songs already
+    /// rendered since they last changed are skipped
+    #[arg(short, long, conflicts_with = "stems")]
+    recursive: bool,
+    /// Songs to render at once with --recursive [default: one per CPU core]
+    #[arg(short, long, value_name = "N", requires = "recursive", value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: Option<u16>,
+}
+
+/// clap value parser for `--normalize`: a loudness like `-16`, `-16LUFS` or `-16 LUFS`.
+pub fn parse_lufs(s: &str) -> Result<f64, String> {
+    let number = s.trim();
+    let number = number.strip_suffix("LUFS").or_else(|| number.strip_suffix("lufs")).unwrap_or(number);
+    match number.trim().parse::<f64>() {
+        Ok(v) if (-70.0..=0.0).contains(&v) => Ok(v),
+        _ => Err(format!("invalid loudness `{s}`, expected LUFS between -70 and 0, like -16LUFS")),
+    }
+}
+
+/// clap value parser for `--true-peak`.
+fn parse_dbtp(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(v) if (-20.0..=0.0).contains(&v) => Ok(v),
+        _ => Err(format!("invalid true peak `{s}`, expected dBTP between -20 and 0")),
+    }
+}
+
+/// clap value parser for `--tail`: `auto` or a length, as for `parse_time`.
+fn parse_tail(s: &str) -> Result<Tail, String> {
+    if s.trim().eq_ignore_ascii_case("auto") {
+        return Ok(Tail::Auto);
+    }
+    parse_time(s).map(Tail::Fixed).ok_or_else(|| format!("invalid tail `{s}`, expected auto, seconds or mm:ss"))
+}
+
+/// How long a render goes on after the last event, once every note has been released.
+#[derive(Clone, Copy, Debug)]
+pub enum Tail {
+    /// Until the sound stays below `SILENCE_DBFS` for `SILENCE_HOLD`, or for at most
+    /// `MAX_TAIL`.
+    Auto,
+    /// This many microseconds.
+    Fixed(u64),
+}
+
+/// How `--stems` splits the song.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Stems {
+    /// One file per MIDI channel
+    Channel,
+    /// One file per track
+    Track,
+}
+
+/// Formats `render` can be asked for with `--format`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Wav,
+    Flac,
+    Opus,
+    Vorbis,
+    Mp3,
+    /// Interleaved samples with no header
+    Raw,
+}
+
+/// Sample types of raw PCM.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum Sample {
+    /// 32-bit float, as the synth renders (`f32le` to ffmpeg)
+    #[default]
+    F32,
+    /// 16-bit signed integer (`s16le` to ffmpeg)
+    S16,
+}
+
+/// The synth always renders interleaved stereo.
+const CHANNELS: u16 = 2;
+
+/// Frames rendered per synth call between events.
+const BLOCK_FRAMES: usize = 64;
+
+/// How fast the limiter turns a normalized render down before a peak, and back up after it.
+const LIMITER_ATTACK: Duration = Duration::from_millis(5);
+const LIMITER_RELEASE: Duration = Duration::from_millis(100);
+
+/// Rates Opus encodes at. Opus output is rendered at 48 kHz unless one of these is asked for.
+const OPUS_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+
+/// Render a song to a stereo audio file, followed by the release and reverb tail, or with
+/// `--recursive` every song in a folder.
+pub fn run(mut opt: RenderArgs) -> Result<()> {
+    let to_stdout = opt.output.as_os_str() == "-";
+    if to_stdout {
+        logging::stdout_taken();
+        if opt.recursive {
+            bail!("--recursive writes a file for each song, so -o needs a folder to put them in");
+        }
+    }
+    // With --recursive the output is a folder, whose name says nothing about the format.
+    let kind = opt.format.unwrap_or_else(|| {
+        if to_stdout {
+            Kind::Raw
+        } else if opt.recursive {
+            Kind::Wav
+        } else {
+            Kind::of(&opt.output)
+        }
+    });
+    if to_stdout && kind != Kind::Raw {
+        // The other formats go back to fill in their headers once the length is known.
+        bail!("only raw PCM can be written to stdout; leave out --format or use --format raw");
+    }
+    if to_stdout && opt.stems.is_some() {
+        bail!("--stems writes several files, so it needs a file name for -o to number");
+    }
+    if to_stdout && opt.spectrogram {
+        bail!("--spectrogram is written beside the audio, so it needs a file name for -o");
+    }
+    let settings = Settings::new(&opt, kind);
+    if opt.recursive {
+        return batch(&opt, &settings);
+    }
+
+    info!("Rendering MIDI file: {}", opt.song.midi);
+    let spectrogram = opt.spectrogram.then(|| spectrogram_path(&opt.output));
+    let length_us = match render_file(&mut opt.song, &opt.output, spectrogram.as_deref(), &settings) {
+        // The program reading stdout has had enough (`head`, or ffmpeg with `-t`): not an error.
+        Err(e) if to_stdout && broken_pipe(&e) => {
+            info!("Stopped rendering: stdout was closed");
+            return Ok(());
+        }
+        result => result?,
+    };
+    if settings.stems.is_none() {
+        let target = if to_stdout { "stdout".into() } else { opt.output.display().to_string() };
+        info!("Rendered {} to {target}", format_duration(length_us));
+    }
+    Ok(())
+}
+
+/// How every file of a render is written, worked out once from the options.
+struct Settings {
+    format: Format,
+    rendering: Rendering,
+    dither: Dither,
+    stems: Option<Stems>,
+    normalize: Option<f64>,
+    true_peak: f64,
+    spectrogram: bool,
+}
+
+/// How the synth's output is rendered, whatever it is written to.
+#[derive(Clone, Copy)]
+pub struct Rendering {
+    pub sample_rate: u32,
+    pub tail: Tail,
+    pub limiter: Option<LimiterArgs>,
+    pub effects: EffectsArgs,
+}
+
+impl Settings {
+    /// The settings for writing `kind`, reporting options that do not apply to it.
+    fn new(opt: &RenderArgs, kind: Kind) -> Self {
+        let format = Format::new(kind, opt.bitrate, opt.vbr, opt.sample_format);
+        let sample_rate = match format {
+            Format::Opus { .. } if !OPUS_RATES.contains(&opt.sample_rate) => {
+                info!("Opus does not encode at {} Hz, rendering at 48000 Hz", opt.sample_rate);
+                48_000
+            }
+            _ => opt.sample_rate,
+        };
+        match format {
+            Format::Mp3 { vbr: Some(q), .. } => info!("Encoding at variable bitrate, quality {q}"),
+            Format::Opus { kbps } | Format::Vorbis { kbps } | Format::Mp3 { kbps, .. } => {
+                info!("Encoding at {kbps} kbit/s");
+            }
+            Format::Wav | Format::Flac | Format::Raw(_) => {
+                if opt.bitrate.is_some() {
+                    warn!("--bitrate only applies to Opus, Vorbis and MP3 output");
+                }
+            }
+        }
+        if let Format::Raw(sample) = format {
+            // Whatever reads the samples has to be told what they are.
+            let name = match sample {
+                Sample::F32 => "f32le",
+                Sample::S16 => "s16le",
+            };
+            info!("Raw PCM: {sample_rate} Hz, {CHANNELS} channels, {name} interleaved");
+        } else if opt.sample_format != Sample::default() {
+            warn!("--sample-format only applies to raw output");
+        }
+        if opt.vbr.is_some() && !matches!(format, Format::Mp3 { .. }) {
+            warn!("--vbr only applies to MP3 output");
+        }
+        let sixteen_bit = matches!(format, Format::Wav | Format::Flac | Format::Raw(Sample::S16));
+        if opt.dither.is_some() && !sixteen_bit {
+            warn!("--dither only applies to 16-bit output: WAV, FLAC and raw s16");
+        }
+        if opt.limiter.limiter {
+            info!("Limiting peaks to {:.1} dBFS", opt.limiter.limiter_threshold);
+        }
+        Self {
+            format,
+            rendering: Rendering {
+                sample_rate,
+                tail: opt.tail,
+                limiter: opt.limiter.enabled(),
+                effects: opt.song.effects,
+            },
+            dither: opt.dither.unwrap_or_default(),
+            stems: opt.stems,
+            normalize: opt.normalize,
+            true_peak: opt.true_peak,
+            spectrogram: opt.spectrogram,
+        }
+    }
+}
+
+/// Render the song `song` names to `output`, or to its stems, and draw its spectrogram to
+/// `spectrogram`. Returns the song's length.
+fn render_file(song: &mut SongArgs, output: &Path, spectrogram: Option<&Path>, settings: &Settings) -> Result<u64> {
+    sidecar::load_for(song)?;
+    rmid::layer_bank(song)?;
+    let soundfonts = soundfont::resolve_all(song.soundfont.as_deref(), &song.soundfonts)?;
+    info!("Using SoundFont: {}", soundfonts.join(" over "));
+
+    let bytes = fs::read(&song.midi).with_context(|| "reading MIDI file")?;
+    let mixer = Mixer::new(&song.mute_channel, &song.solo_channel);
+    if !song.mute_channel.is_empty() || !song.solo_channel.is_empty() {
+        info!("{mixer}");
+    }
+    let title = Path::new(&song.midi).file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let effects = song.effects;
+    let drums = song.effects.drum_channels.unwrap_or_default();
+    let mut parsed = Song::parse(&bytes, &song.tracks, song.transpose, drums, song.lenient)?;
+    parsed.override_programs(&song.programs);
+    let song = parsed;
+    let missing = fallback::check(&song, &soundfonts);
+    let title = song.title.clone().unwrap_or(title);
+    let Settings { format, mut rendering, dither, .. } = *settings;
+    // The song's sidecar may set its own.
+    rendering.effects = effects;
+    let sample_rate = rendering.sample_rate;
+
+    if let Some(stems) = settings.stems {
+        let parts = Part::stems(&song, &mixer, stems);
+        if parts.is_empty() {
+            bail!("no channel plays any notes, so there are no stems to render");
+        }
+        if let Tail::Auto = rendering.tail {
+            // Every stem gets the full mix's tail, so they all line up and add up to the mix.
+            let tail_us = render(&song, &mixer, Part::All, &soundfonts, rendering, |_| Ok(()))?;
+            info!("Tail: {:.1} s until the mix dies away", tail_us as f64 / 1e6);
+            rendering.tail = Tail::Fixed(tail_us);
+        }
+        let length_us = tags_length(song.length_us, rendering.tail);
+        info!("Rendering {} stems", parts.len());
+        for (part, suffix, name) in &parts {
+            let path = stem_path(output, suffix);
+            info!("  {}: {name}", path.display());
+            let tags = Tags { title: format!("{title} ({name})"), copyright: song.copyright.clone(), length_us };
+            let mut file = Encoder::create(format, &path, sample_rate, &tags, dither)?;
+            render(&song, &mixer, *part, &soundfonts, rendering, |block| file.write(block))?;
+            file.finish()?;
+        }
+        info!("Rendered {} stems of {}", parts.len(), format_duration(song.length_us));
+        fallback::summarize(&missing);
+        return Ok(song.length_us);
+    }
+
+    let length_us = tags_length(song.length_us, rendering.tail);
+    let tags = Tags { title, copyright: song.copyright.clone(), length_us };
+    let mut file = Encoder::create(format, output, sample_rate, &tags, dither)?;
+    // Drawn from what is written, after normalizing.
+    let mut analysis = spectrogram.map(|_| Spectrogram::default());
+    let mut write = |block: &[f32]| {
+        if let Some(analysis) = &mut analysis {
+            analysis.add(block);
+        }
+        file.write(block)
+    };
+    let tail_us = match settings.normalize {
+        // The loudness is only known once the whole song is rendered, so it is kept in
+        // memory until then: about 20 MB a minute.
+        Some(target) => {
+            let mut audio = Vec::new();
+            let tail_us = render(&song, &mixer, Part::All, &soundfonts, rendering, |block| {
+                audio.extend_from_slice(block);
+                Ok(())
+            })?;
+            normalize(&mut audio, sample_rate, target, settings.true_peak);
+            for block in audio.chunks(BLOCK_FRAMES * CHANNELS as usize) {
+                write(block)?;
+            }
+            tail_us
+        }
+        None => render(&song, &mixer, Part::All, &soundfonts, rendering, write)?,
+    };
+    file.finish()?;
+    if let (Some(analysis), Some(path)) = (analysis, spectrogram) {
+        analysis.write(path)?;
+        info!("Spectrogram: {}", path.display());
+    }
+    if let Tail::Auto = rendering.tail {
+        info!("Tail: {:.1} s until the sound died away", tail_us as f64 / 1e6);
+    }
+    fallback::summarize(&missing);
+    Ok(song.length_us)
+}
+
+/// `render --recursive`: every MIDI file under the folder `opt.song.midi` names, rendered
+/// to the same place under the folder `-o` names, several at once. Songs rendered since
+/// they (or their sidecars) last changed are skipped, so an interrupted batch picks up
+/// where it stopped.
+fn batch(opt: &RenderArgs, settings: &Settings) -> Result<()> {
+    let root = Path::new(&opt.song.midi);
+    if !root.is_dir() {
+        bail!("{} is not a folder; --recursive renders the MIDI files in one", root.display());
+    }
+    let mut songs = Vec::new();
+    find_midi(root, &mut songs).with_context(|| format!("reading {}", root.display()))?;
+    let total = songs.len();
+    let jobs: Vec<(PathBuf, PathBuf)> = songs
+        .into_iter()
+        .map(|midi| {
+            let relative = midi.strip_prefix(root).unwrap_or(&midi);
+            let output = opt.output.join(relative).with_extension(settings.format.extension());
+            (midi, output)
+        })
+        .filter(|(midi, output)| {
+            !up_to_date(midi, output) || settings.spectrogram && !spectrogram_path(output).exists()
+        })
+        .collect();
+    if jobs.is_empty() {
+        info!("All {total} MIDI files in {} are already rendered", root.display());
+        return Ok(());
+    }
+    let parallel = thread::available_parallelism().map_or(1, |n| n.get());
+    let workers = opt.jobs.map_or(parallel, usize::from).min(jobs.len());
+    info!("Rendering {} of {total} MIDI files, {workers} at a time", jobs.len());
+
+    // Each worker takes the next song until there are none left, and reports back here, so
+    // the progress lines come out whole and in the order songs finish.
+    let next = AtomicUsize::new(0);
+    let (done_tx, done) = mpsc::channel();
+    let mut failed = 0;
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let (next, jobs, done_tx) = (&next, &jobs, done_tx.clone());
+            scope.spawn(move || {
+                logging::quiet_thread();
+                while let Some((midi, output)) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let mut song = SongArgs { midi: midi.to_string_lossy().into_owned(), ..opt.song.clone() };
+                    let _ = done_tx.send((midi, output, render_into(&mut song, output, settings)));
+                }
+            });
+        }
+        drop(done_tx);
+        for (n, (midi, output, result)) in done.iter().enumerate() {
+            match result {
+                Ok(length_us) => {
+                    info!("[{}/{}] {} ({})", n + 1, jobs.len(), output.display(), format_duration(length_us));
+                }
+                Err(e) => {
+                    warn!("[{}/{}] {}: {e:#}", n + 1, jobs.len(), midi.display());
+                    failed += 1;
+                }
+            }
+        }
+    });
+    if failed > 0 {
+        bail!("{failed} of {} songs could not be rendered", jobs.len());
+    }
+    info!("Rendered {} songs to {}", jobs.len(), opt.output.display());
+    Ok(())
+}
+
+/// Render to `output` by way of a `.part` file beside it, so a render that fails or is cut
+/// short is not taken for a finished one next time.
+fn render_into(song: &mut SongArgs, output: &Path, settings: &Settings) -> Result<u64> {
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let mut part = output.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let spectrogram = settings.spectrogram.then(|| spectrogram_path(output));
+    let rendered = render_file(song, &part, spectrogram.as_deref(), settings).and_then(|length_us| {
+        fs::rename(&part, output).with_context(|| format!("renaming {}", part.display()))?;
+        Ok(length_us)
+    });
+    if rendered.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    rendered
+}
+
+/// Add the MIDI files under `dir` to `found`, in name order. Links to folders are not
+/// followed, so a link back up the tree cannot loop.
+fn find_midi(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_midi(&path, found)?;
+        } else if is_midi(&path) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `output` was written after `midi` and its sidecar last changed.
+fn up_to_date(midi: &Path, output: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let Some(rendered) = modified(output) else {
+        return false;
+    };
+    let mut sidecar = midi.as_os_str().to_owned();
+    sidecar.push(".toml");
+    [midi, Path::new(&sidecar)].into_iter().filter_map(modified).all(|changed| changed <= rendered)
+}
+
+/// Render `part` of the song on a synth of its own, then release every note, as playback
+/// does at the end, and render the tail for them to ring out. Each block of interleaved
+/// samples is handed to `out`, through the limiter if there is one. Returns the length of
+/// the tail.
+fn render(
+    song: &Song,
+    mixer: &Mixer,
+    part: Part,
+    soundfonts: &[String],
+    rendering: Rendering,
+    mut out: impl FnMut(&[f32]) -> Result<()>,
+) -> Result<u64> {
+    let Rendering { sample_rate, tail, limiter, effects } = rendering;
+    let mut synth = synth::open(soundfonts, 0.7, sample_rate as f32, effects)?;
+    let mut limiter = limiter.map(|args| Limiter::new(&args, sample_rate));
+    // The limiter delays the sound, so that much is left off the start and rendered on past
+    // the end, to keep the timing and length the same.
+    let latency = limiter.as_ref().map_or(0, Limiter::latency) as u64;
+    let mut skip = latency as usize * CHANNELS as usize;
+
+    // Each event is sent once the audio up to its time has been rendered, so timing is
+    // exact to the frame instead of depending on a real-time scheduler.
+    let frame_of = |t_us: u64| t_us * u64::from(sample_rate) / 1_000_000;
+    let mut block = [0f32; BLOCK_FRAMES * CHANNELS as usize];
+    let mut rendered = 0u64;
+    // Renders up to `frame` and returns the peak level of what it rendered.
+    let mut render_until = |synth: &mut Synth, frame: u64| -> Result<f32> {
+        let mut peak = 0f32;
+        while rendered < frame {
+            let frames = (frame - rendered).min(BLOCK_FRAMES as u64) as usize;
+            let buf = &mut block[..frames * CHANNELS as usize];
+            synth.render(buf)?;
+            peak = buf.iter().fold(peak, |p, s| p.max(s.abs()));
+            if let Some(limiter) = &mut limiter {
+                limiter.process(buf);
+            }
+            let skipped = skip.min(buf.len());
+            skip -= skipped;
+            if skipped < buf.len() {
+                out(&buf[skipped..])?;
+            }
+            rendered += frames as u64;
+        }
+        Ok(peak)
+    };
+
+    // The most voices sounding at once, to tell when notes were cut short for want of them.
+    let mut most_voices: Option<(usize, usize)> = None;
+    for ev in &song.timeline {
+        render_until(&mut synth, frame_of(ev.t_us))?;
+        match ev.msg {
+            Msg::NoteOn(ch, ..) if !mixer.audible(ch) || !part.plays(ev) => {}
+            msg => {
+                synth.send(msg);
+                if let Msg::NoteOn(..) = msg {
+                    most_voices = most_voices.max(synth.voices());
+                }
+            }
+        }
+    }
+    match most_voices {
+        Some((most, limit)) if most >= limit => {
+            warn!("All {limit} voices were in use at times, so notes were cut short; raise --polyphony to allow more");
+        }
+        Some((most, _)) => debug!("At most {most} voices at once"),
+        None => {}
+    }
+    let end = frame_of(song.length_us);
+    render_until(&mut synth, end)?;
+    synth.release_notes();
+
+    let tail_frames = match tail {
+        Tail::Fixed(tail_us) => frame_of(tail_us),
+        Tail::Auto => {
+            let silence = 10f32.powf(SILENCE_DBFS / 20.0);
+            let (hold, most) = (frame_of(SILENCE_HOLD.as_micros() as u64), frame_of(MAX_TAIL.as_micros() as u64));
+            let (mut frames, mut quiet) = (0, 0);
+            while quiet < hold {
+                if frames >= most {
+                    warn!("The sound has not died away {} s after the last event; stopping there", MAX_TAIL.as_secs());
+                    break;
+                }
+                let peak = render_until(&mut synth, end + frames + BLOCK_FRAMES as u64)?;
+                frames += BLOCK_FRAMES as u64;
+                quiet = if peak < silence { quiet + BLOCK_FRAMES as u64 } else { 0 };
+            }
+            frames
+        }
+    };
+    render_until(&mut synth, end + tail_frames + latency)?;
+    Ok(tail_frames * 1_000_000 / u64::from(sample_rate))
+}
+
+/// The whole song rendered into memory, as interleaved stereo.
+pub fn render_all(song: &Song, mixer: &Mixer, soundfonts: &[String], rendering: Rendering) -> Result<Vec<f32>> {
+    let mut audio = Vec::new();
+    render(song, mixer, Part::All, soundfonts, rendering, |block| {
+        audio.extend_from_slice(block);
+        Ok(())
+    })?;
+    Ok(audio)
+}
+
+/// The length to tag a render of a song of `song_us` with: all of it with a fixed tail. An
+/// automatic tail is only known once it is rendered, after the tags are written, so that
+/// length is just the song's.
+fn tags_length(song_us: u64, tail: Tail) -> Option<u64> {
+    Some(match tail {
+        Tail::Fixed(tail_us) => song_us + tail_us,
+        Tail::Auto => song_us,
+    })
+}
+
+/// Bring a whole render to `target` LUFS, then limit its true peak to `ceiling` dBTP.
+fn normalize(audio: &mut [f32], sample_rate: u32, target: f64, ceiling: f64) {
+    let channels = CHANNELS as usize;
+    let Some(lufs) = loudness::integrated(audio, channels, sample_rate) else {
+        warn!("The render is too quiet to measure its loudness, so it is left as it is");
+        return;
+    };
+    let gain_db = target - lufs;
+    let gain = 10f32.powf(gain_db as f32 / 20.0);
+    audio.iter_mut().for_each(|s| *s *= gain);
+    info!("Loudness: {lufs:.1} LUFS, gain {gain_db:+.1} dB to {target:.1} LUFS");
+
+    let peaks = loudness::frame_peaks(audio, channels);
+    let peak = peaks.iter().copied().fold(0.0, f32::max);
+    let rate = f64::from(sample_rate);
+    let attack = (LIMITER_ATTACK.as_secs_f64() * rate) as usize;
+    let release = LIMITER_RELEASE.as_secs_f64() * rate;
+    if loudness::limit(audio, channels, &peaks, 10f32.powf(ceiling as f32 / 20.0), attack, release) {
+        info!("True peak: {:.1} dBTP, limited to {ceiling:.1} dBTP", 20.0 * peak.log10());
+    }
+}
+
+/// Which notes a render plays. Every stem still gets all the other messages, so programs
+/// and controllers are set up as in the full mix, and runs the length of the song, so the
+/// stems line up when imported together.
+#[derive(Clone, Copy)]
+enum Part {
+    All,
+    /// A 0-based channel.
+    Channel(u8),
+    /// A 0-based track.
+    Track(usize),
+}
+
+impl Part {
+    /// The stems `stems` splits the song into: each channel or track with notes that are
+    /// heard, with the suffix for its file name and a description.
+    fn stems(song: &Song, mixer: &Mixer, stems: Stems) -> Vec<(Self, String, String)> {
+        let heard: Vec<&Timed> = song
+            .timeline
+            .iter()
+            .filter(|ev| matches!(ev.msg, Msg::NoteOn(ch, _, vel) if vel > 0 && mixer.audible(ch)))
+            .collect();
+        match stems {
+            Stems::Channel => (0..16u8)
+                .filter(|&ch| heard.iter().any(|ev| matches!(ev.msg, Msg::NoteOn(c, ..) if c == ch)))
+                .map(|ch| {
+                    let name = format!("channel {}, {}", ch + 1, channel_instrument(song, ch));
+                    (Self::Channel(ch), format!("ch{:02}", ch + 1), name)
+                })
+                .collect(),
+            Stems::Track => (0..song.track_names.len())
+                .filter(|&track| heard.iter().any(|ev| ev.track == track))
+                .map(|track| {
+                    let name = song.track_names[track].as_deref().filter(|n| !n.is_empty()).unwrap_or("unnamed");
+                    (Self::Track(track), format!("track{:02}", track + 1), format!("track {}, {name}", track + 1))
+                })
+                .collect(),
+        }
+    }
+
+    fn plays(self, ev: &Timed) -> bool {
+        match (self, ev.msg) {
+            (Self::Channel(only), Msg::NoteOn(ch, ..)) => ch == only,
+            (Self::Track(only), Msg::NoteOn(..)) => ev.track == only,
+            _ => true,
+        }
+    }
+}
+
+/// `output` with `-suffix` added to the file name before the extension.
+fn stem_path(output: &Path, suffix: &str) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(ext) => format!("{stem}-{suffix}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{suffix}"),
+    };
+    output.with_file_name(name)
+}
+
+/// Where `render --spectrogram` draws the spectrogram of `output`: `out.wav` gets
+/// `out.spectrogram.png`.
+fn spectrogram_path(output: &Path) -> PathBuf {
+    output.with_extension("spectrogram.png")
+}
+
+fn broken_pipe(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| c.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe))
+}
+
+impl Kind {
+    /// The format the file name's extension asks for, WAV if none does.
+    fn of(path: &Path) -> Self {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+        match ext.as_str() {
+            "flac" => Self::Flac,
+            "opus" => Self::Opus,
+            "ogg" | "oga" => Self::Vorbis,
+            "mp3" => Self::Mp3,
+            "raw" | "pcm" => Self::Raw,
+            _ => Self::Wav,
+        }
+    }
+}
+
+/// The formats `render` writes, lossy ones with their bitrate in kbit/s. MP3 can instead
+/// have a variable bitrate of a LAME quality.
+#[derive(Clone, Copy)]
+enum Format {
+    Wav,
+    Flac,
+    Opus { kbps: u32 },
+    Vorbis { kbps: u32 },
+    Mp3 { kbps: u32, vbr: Option<u8> },
+    Raw(Sample),
+}
+
+impl Format {
+    /// The extension of files in this format.
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+            Self::Opus { .. } => "opus",
+            Self::Vorbis { .. } => "ogg",
+            Self::Mp3 { .. } => "mp3",
+            Self::Raw(_) => "raw",
+        }
+    }
+
+    /// `kind` at `bitrate` or the format's default.
+    fn new(kind: Kind, bitrate: Option<u32>, vbr: Option<u8>, sample: Sample) -> Self {
+        match kind {
+            Kind::Wav => Self::Wav,
+            Kind::Flac => Self::Flac,
+            Kind::Opus => Self::Opus { kbps: bitrate.unwrap_or(96) },
+            Kind::Vorbis => Self::Vorbis { kbps: bitrate.unwrap_or(128) },
+            Kind::Mp3 => Self::Mp3 { kbps: bitrate.unwrap_or(192), vbr },
+            Kind::Raw => Self::Raw(sample),
+        }
+    }
+}
+
+/// What the file is tagged with, in formats that have tags.
+struct Tags {
+    /// The song's title, or failing that the file name.
+    title: String,
+    copyright: Option<String>,
+    /// The length of the audio, when it is known before it is written.
+    length_us: Option<u64>,
+}
+
+impl Tags {
+    /// As Vorbis comments, the tags of FLAC and Ogg files.
+    fn comments(&self) -> Vec<(&'static str, String)> {
+        let mut comments = vec![("TITLE", self.title.clone())];
+        if let Some(length_us) = self.length_us {
+            comments.push(("DURATION", format_duration(length_us)));
+        }
+        if let Some(copyright) = &self.copyright {
+            comments.push(("COPYRIGHT", copyright.clone()));
+        }
+        comments
+    }
+}
+
+/// A recording made while playing (`play --record`), in whichever format `render` would
+/// write for its name, at that format's default settings.
+pub struct AudioFile(Encoder);
+
+impl AudioFile {
+    pub fn create(path: &Path, sample_rate: u32, title: String, copyright: Option<String>) -> Result<Self> {
+        if path.as_os_str() == "-" {
+            bail!("recordings need a file name; stdout is for the player's messages");
+        }
+        let format = Format::new(Kind::of(path), None, None, Sample::default());
+        if matches!(format, Format::Opus { .. }) && !OPUS_RATES.contains(&sample_rate) {
+            bail!("Opus does not encode at the device's {sample_rate} Hz; record to WAV or FLAC instead");
+        }
+        let tags = Tags { title, copyright, length_us: None };
+        Ok(Self(Encoder::create(format, path, sample_rate, &tags, Dither::default())?))
+    }
+
+    /// Append interleaved stereo samples.
+    pub fn write(&mut self, samples: &[f32]) -> Result<()> {
+        self.0.write(samples)
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.0.finish()
+    }
+}
+
+/// The file being written. 16-bit formats bring the samples down to 16 bits through a
+/// `Quantizer`; raw float output has one it does not use.
+enum Encoder {
+    Wav(WavWriter, Quantizer),
+    Flac(FlacWriter, Quantizer),
+    Raw(RawWriter, Quantizer),
+    #[cfg(feature = "opus")]
+    Opus(crate::ogg::OpusWriter),
+    #[cfg(feature = "vorbis")]
+    Vorbis(crate::ogg::VorbisWriter),
+    #[cfg(feature = "mp3")]
+    Mp3(crate::mp3::Mp3Writer),
+}
+
+impl Encoder {
+    /// Create `path` in `format` for stereo audio at `sample_rate`, tagged with `tags` if
+    /// the format has tags and dithered with `dither` if it is 16-bit. Raw PCM goes to
+    /// stdout if `path` is `-`.
+    fn create(format: Format, path: &Path, sample_rate: u32, tags: &Tags, dither: Dither) -> Result<Self> {
+        let quantizer = || Quantizer::new(dither, CHANNELS);
+        Ok(match format {
+            Format::Wav => Self::Wav(WavWriter::create(path, sample_rate, CHANNELS)?, quantizer()),
+            Format::Flac => {
+                Self::Flac(FlacWriter::create(path, sample_rate, CHANNELS, &tags.comments())?, quantizer())
+            }
+            Format::Raw(sample) => Self::Raw(RawWriter::create(path, sample)?, quantizer()),
+            #[cfg(feature = "opus")]
+            Format::Opus { kbps } => {
+                Self::Opus(crate::ogg::OpusWriter::create(path, sample_rate, CHANNELS, kbps, &tags.comments())?)
+            }
+            #[cfg(feature = "vorbis")]
+            Format::Vorbis { kbps } => {
+                Self::Vorbis(crate::ogg::VorbisWriter::create(path, sample_rate, CHANNELS, kbps)?)
+            }
+            #[cfg(feature = "mp3")]
+            Format::Mp3 { kbps, vbr } => {
+                use crate::mp3::{Id3, Mp3Writer, Rate};
+                let id3 = Id3 { title: &tags.title, copyright: tags.copyright.as_deref(), length_us: tags.length_us };
+                let rate = vbr.map_or(Rate::Constant(kbps), Rate::Variable);
+                Self::Mp3(Mp3Writer::create(path, sample_rate, CHANNELS, rate, &id3)?)
+            }
+            // Encoders behind a cargo feature are missing from builds without it.
+            #[cfg(not(feature = "opus"))]
+            Format::Opus { .. } => anyhow::bail!("Opus output needs a build with `--features opus`"),
+            #[cfg(not(feature = "vorbis"))]
+            Format::Vorbis { .. } => anyhow::bail!("Vorbis output needs a build with `--features vorbis`"),
+            #[cfg(not(feature = "mp3"))]
+            Format::Mp3 { .. } => anyhow::bail!("MP3 output needs a build with `--features mp3`"),
+        })
+    }
+
+    /// Append interleaved samples in -1.0..=1.0. 16-bit formats clip anything outside.
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        match self {
+            Self::Wav(wav, q) => wav.write(&q.quantize(samples)),
+            Self::Flac(flac, q) => flac.write(&q.quantize(samples)),
+            Self::Raw(raw, q) if raw.sample == Sample::S16 => raw.write_s16(&q.quantize(samples)),
+            Self::Raw(raw, _) => raw.write_f32(samples),
+            #[cfg(feature = "opus")]
+            Self::Opus(opus) => opus.write(samples),
+            #[cfg(feature = "vorbis")]
+            Self::Vorbis(vorbis) => vorbis.write(samples),
+            #[cfg(feature = "mp3")]
+            Self::Mp3(mp3) => mp3.write(samples),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Wav(wav, _) => wav.finish(),
+            Self::Flac(flac, _) => flac.finish(),
+            Self::Raw(raw, _) => raw.finish(),
+            #[cfg(feature = "opus")]
+            Self::Opus(opus) => opus.finish(),
+            #[cfg(feature = "vorbis")]
+            Self::Vorbis(vorbis) => vorbis.finish(),
+            #[cfg(feature = "mp3")]
+            Self::Mp3(mp3) => mp3.finish(),
+        }
+    }
+}
+
+/// A minimal streaming writer for 16-bit PCM WAV files. The sizes in the header are
+/// patched in by `finish`, once the length is known.
+struct WavWriter {
+    out: BufWriter<File>,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, sample_rate: u32, channels: u16) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?; // patched in `finish`
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?; // patched in `finish`
+        Ok(Self { out, data_bytes: 0 })
+    }
+
+    /// Append interleaved samples.
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        for &v in samples {
+            self.out.write_all(&v.to_le_bytes())?;
+        }
+        self.data_bytes = self
+            .data_bytes
+            .checked_add(samples.len() as u32 * 2)
+            .context("rendered audio is too long for a WAV file")?;
+        Ok(())
+    }
+
+    /// Fill in the RIFF and data chunk sizes and flush.
+    fn finish(mut self) -> Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + self.data_bytes).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&self.data_bytes.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Interleaved little-endian samples with no header, to a file or stdout.
+struct RawWriter {
+    out: BufWriter<Box<dyn Write>>,
+    sample: Sample,
+}
+
+impl RawWriter {
+    fn create(path: &Path, sample: Sample) -> Result<Self> {
+        let out: Box<dyn Write> = if path.as_os_str() == "-" {
+            Box::new(io::stdout().lock())
+        } else {
+            Box::new(File::create(path).with_context(|| format!("creating {}", path.display()))?)
+        };
+        Ok(Self { out: BufWriter::new(out), sample })
+    }
+
+    fn write_f32(&mut self, samples: &[f32]) -> Result<()> {
+        for &v in samples {
+            self.out.write_all(&v.to_le_bytes()).context("writing raw PCM")?;
+        }
+        Ok(())
+    }
+
+    fn write_s16(&mut self, samples: &[i16]) -> Result<()> {
+        for &v in samples {
+            self.out.write_all(&v.to_le_bytes()).context("writing raw PCM")?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.out.flush().context("writing raw PCM")?;
+        Ok(())
+    }
+}
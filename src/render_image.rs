@@ -0,0 +1,209 @@
+//! The `render-image` subcommand: draw a song as a piano roll, time across and pitch up,
+//! each note a bar coloured by its channel, to a PNG or SVG file.
+//!
+//! The roll spans the song's length and only the keys it uses, with a faint line under
+//! every C to read octaves by.
+
+use crate::export_notes::{Note, notes};
+use crate::png;
+use crate::song::DRUM_CHANNEL;
+use crate::tempo::TempoMap;
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use midly::Smf;
+use std::{fmt::Write, fs, path::PathBuf};
+use tracing::info;
+
+/// `render-image` options:
+/// - midi: the file to draw
+/// - output: the image to write, PNG or SVG by its extension
+/// - width / height: the image's size in pixels
+#[derive(Args, Debug)]
+pub struct RenderImageArgs {
+    /// Path to .mid file
+    midi: String,
+    /// Write the image to this file, .png or .svg
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+    /// Width of the image in pixels
+    #[arg(long, value_name = "PX", default_value_t = 1200, value_parser = clap::value_parser!(u32).range(16..=16384))]
+    width: u32,
+    /// Height of the image in pixels
+    #[arg(long, value_name = "PX", default_value_t = 300, value_parser = clap::value_parser!(u32).range(16..=16384))]
+    height: u32,
+}
+
+/// The background, and the lines under each C.
+const BACKGROUND: [u8; 3] = [0x1e, 0x1e, 0x24];
+const OCTAVE_LINE: [u8; 3] = [0x34, 0x34, 0x3c];
+
+/// One colour per channel, the drums grey.
+const CHANNEL_COLOURS: [[u8; 3]; 16] = [
+    [0x4e, 0x9a, 0xf1],
+    [0xf1, 0x6c, 0x4e],
+    [0x5c, 0xd6, 0x6b],
+    [0xf1, 0xc4, 0x4e],
+    [0xb5, 0x6c, 0xf1],
+    [0x4e, 0xd6, 0xd0],
+    [0xf1, 0x4e, 0x9e],
+    [0xa8, 0xd6, 0x4e],
+    [0xf1, 0x9a, 0x4e],
+    [0x9a, 0x9a, 0xa4],
+    [0x6c, 0x7c, 0xf1],
+    [0xd6, 0x4e, 0x4e],
+    [0x4e, 0xb0, 0x8a],
+    [0xd6, 0xa8, 0x9a],
+    [0x8a, 0xc4, 0xf1],
+    [0xe0, 0xe0, 0x70],
+];
+
+/// The fewest keys the roll shows, so a melody of a few notes is not drawn as fat blocks.
+const MIN_KEYS: u8 = 24;
+
+/// Where things go on the image, in pixels from the top left.
+struct Layout {
+    width: f64,
+    height: f64,
+    /// The highest key shown, at the top.
+    top_key: u8,
+    /// The lowest key shown, at the bottom.
+    bottom_key: u8,
+    length_us: u64,
+}
+
+impl Layout {
+    fn row_height(&self) -> f64 {
+        self.height / f64::from(self.top_key - self.bottom_key + 1)
+    }
+
+    /// The top and bottom of a key's row.
+    fn row(&self, key: u8) -> (f64, f64) {
+        let top = f64::from(self.top_key - key) * self.row_height();
+        (top, top + self.row_height())
+    }
+
+    fn x(&self, t_us: u64) -> f64 {
+        t_us as f64 / self.length_us as f64 * self.width
+    }
+
+    /// The keys with an octave line under them.
+    fn cs(&self) -> impl Iterator<Item = u8> {
+        (self.bottom_key..=self.top_key).filter(|key| key % 12 == 0)
+    }
+}
+
+/// Draw the song and write the image.
+pub fn run(opt: RenderImageArgs) -> Result<()> {
+    let ext = opt.output.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    if ext != "png" && ext != "svg" {
+        bail!("cannot tell what kind of image {} is; name it .png or .svg", opt.output.display());
+    }
+    let bytes = fs::read(&opt.midi).with_context(|| "reading MIDI file")?;
+    let smf = Smf::parse(&bytes).with_context(|| "parsing MIDI")?;
+    let tempo = TempoMap::new(&smf);
+    let notes = notes(&smf);
+    if notes.is_empty() {
+        bail!("the song has no notes to draw");
+    }
+
+    let (lowest, highest) = notes.iter().fold((127, 0), |(lo, hi), n| (n.key.min(lo), n.key.max(hi)));
+    // A key free above and below, then widened to `MIN_KEYS` about the middle, within the
+    // 128 keys there are.
+    let (mut bottom_key, mut top_key) = (lowest.saturating_sub(1), (highest + 1).min(127));
+    let short = MIN_KEYS.saturating_sub(top_key - bottom_key + 1);
+    bottom_key = bottom_key.saturating_sub(short / 2);
+    top_key = (top_key + short - short / 2).min(127);
+    let length_us = notes.iter().map(|n| tempo.to_us(n.end)).max().unwrap_or_default().max(1);
+    let layout = Layout { width: f64::from(opt.width), height: f64::from(opt.height), top_key, bottom_key, length_us };
+
+    if ext == "png" {
+        write_png(&opt, &layout, &notes, &tempo)?;
+    } else {
+        let svg = svg(&layout, &notes, &tempo);
+        fs::write(&opt.output, svg).with_context(|| format!("writing {}", opt.output.display()))?;
+    }
+    info!("Drew {} notes from {} channels", notes.len(), channels(&notes).count());
+    info!("Wrote {}", opt.output.display());
+    Ok(())
+}
+
+/// The channels that play, in order.
+fn channels(notes: &[Note]) -> impl Iterator<Item = u8> {
+    (0..16).filter(|&ch| notes.iter().any(|n| n.channel == ch))
+}
+
+/// Rasterise the roll with a palette of the background, the octave line and the channels.
+fn write_png(opt: &RenderImageArgs, layout: &Layout, notes: &[Note], tempo: &TempoMap) -> Result<()> {
+    let (width, height) = (opt.width as usize, opt.height as usize);
+    let mut pixels = vec![0u8; width * height];
+    let px = |v: f64, max: usize| (v.round() as usize).min(max);
+    let mut fill = |x0: usize, x1: usize, y0: usize, y1: usize, colour: u8| {
+        for row in pixels.chunks_exact_mut(width).take(y1).skip(y0) {
+            row[x0..x1].fill(colour);
+        }
+    };
+
+    for key in layout.cs() {
+        let bottom = px(layout.row(key).1, height).max(1);
+        fill(0, width, bottom - 1, bottom, 1);
+    }
+    for note in notes {
+        let (top, bottom) = layout.row(note.key);
+        let (y0, mut y1) = (px(top, height), px(bottom, height));
+        let x0 = px(layout.x(tempo.to_us(note.start)), width - 1);
+        let mut x1 = px(layout.x(tempo.to_us(note.end)), width);
+        // A pixel's gap between bars where there is room for it, so repeated notes and
+        // neighbouring keys stay apart.
+        if y1 - y0 >= 4 {
+            y1 -= 1;
+        }
+        if x1 - x0.min(x1) >= 3 {
+            x1 -= 1;
+        }
+        fill(x0, x1.max(x0 + 1), y0, y1.max(y0 + 1).min(height), note.channel + 2);
+    }
+
+    let mut palette = vec![BACKGROUND, OCTAVE_LINE];
+    palette.extend(CHANNEL_COLOURS);
+    png::write(&opt.output, opt.width, opt.height, &palette, &pixels)
+}
+
+/// The roll as SVG, the notes grouped by channel.
+fn svg(layout: &Layout, notes: &[Note], tempo: &TempoMap) -> String {
+    let (width, height) = (layout.width, layout.height);
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    );
+    let _ = writeln!(svg, r#"<rect width="{width}" height="{height}" fill="{}"/>"#, hex(BACKGROUND));
+    let _ = writeln!(svg, r#"<g fill="{}">"#, hex(OCTAVE_LINE));
+    for key in layout.cs() {
+        let _ = writeln!(svg, r#"<rect y="{:.2}" width="{width}" height="1"/>"#, layout.row(key).1 - 1.0);
+    }
+    svg += "</g>\n";
+
+    let gap = if layout.row_height() >= 4.0 { 1.0 } else { 0.0 };
+    for ch in channels(notes) {
+        let name = if ch == DRUM_CHANNEL { "Drums".to_string() } else { format!("Channel {}", ch + 1) };
+        let _ = writeln!(svg, r#"<g fill="{}"><title>{name}</title>"#, hex(CHANNEL_COLOURS[ch as usize]));
+        for note in notes.iter().filter(|n| n.channel == ch) {
+            let (x0, x1) = (layout.x(tempo.to_us(note.start)), layout.x(tempo.to_us(note.end)));
+            let (top, bottom) = layout.row(note.key);
+            let w = if x1 - x0 >= 3.0 { x1 - x0 - 1.0 } else { (x1 - x0).max(1.0) };
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{x0:.2}" y="{top:.2}" width="{w:.2}" height="{:.2}"/>"#,
+                bottom - top - gap
+            );
+        }
+        svg += "</g>\n";
+    }
+    svg += "</svg>\n";
+    svg
+}
+
+/// A colour as `#rrggbb`.
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
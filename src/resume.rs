@@ -0,0 +1,45 @@
+//! Saved playback positions for `--resume`.
+
+use anyhow::{Context, Result};
+use std::{fs, io, path::PathBuf};
+
+/// Where saved playback positions live: `$XDG_DATA_HOME/midi-play/positions`,
+/// falling back to `~/.local/share/midi-play/positions`.
+fn positions_dir() -> Option<PathBuf> {
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share")))?;
+    Some(data.join("midi-play").join("positions"))
+}
+
+/// The position saved for the file with this key, in microseconds.
+pub fn load_position(key: u64) -> Option<u64> {
+    let path = positions_dir()?.join(format!("{key:016x}"));
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Save (or with `None`, forget) the position for the file with this key.
+pub fn save_position(key: u64, pos: Option<u64>) -> Result<()> {
+    let dir = positions_dir().context("no home directory")?;
+    let path = dir.join(format!("{key:016x}"));
+    match pos {
+        Some(t) => {
+            fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+            fs::write(&path, format!("{t}\n")).with_context(|| format!("writing {}", path.display()))?;
+        }
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("removing {}", path.display()));
+            }
+            _ => {}
+        },
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a hash. Keys saved positions by file contents, so renaming or moving a file
+/// keeps its position and editing it starts afresh.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3))
+}
@@ -0,0 +1,98 @@
+//! RIFF, the container SoundFonts, WAV files and RMID files come in: a run of chunks, each
+//! a four-letter id, a 32-bit little-endian size and the body, padded to an even length. A
+//! `LIST` chunk, and the `RIFF` chunk the whole file is, hold chunks of their own after a
+//! four-letter type.
+//!
+//! A file can be walked in memory, or, to leave out large chunks such as a SoundFont's
+//! samples, on disk by seeking from header to header.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// A chunk read from memory. The body is cut short if the file is.
+#[derive(Clone, Copy)]
+pub struct Chunk<'a> {
+    id: &'a [u8; 4],
+    whole: &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    pub fn id(&self) -> &'a [u8; 4] {
+        self.id
+    }
+
+    pub fn body(&self) -> &'a [u8] {
+        &self.whole[8..]
+    }
+
+    /// The chunk as it is in the file, header and all.
+    pub fn whole(&self) -> &'a [u8] {
+        self.whole
+    }
+}
+
+/// The chunks in a RIFF file of the type `form` (`sfbk`, `WAVE`, `RMID`), or `None` if
+/// `bytes` is not one. The size in the file's header is not trusted: a file written as it
+/// was recorded can be left without it.
+pub fn form<'a>(bytes: &'a [u8], form: &[u8; 4]) -> Option<&'a [u8]> {
+    (bytes.get(..4)? == b"RIFF" && bytes.get(8..12)? == form).then(|| &bytes[12..])
+}
+
+/// Iterate over the chunks in `data`, up to the first one whose header is cut short.
+pub fn chunks(mut data: &[u8]) -> impl Iterator<Item = Chunk<'_>> {
+    std::iter::from_fn(move || {
+        let id = data.get(..4)?.try_into().ok()?;
+        let size = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+        let (whole, next) = data.split_at((8 + size).min(data.len()));
+        data = next.get(size % 2..).unwrap_or_default();
+        Some(Chunk { id, whole })
+    })
+}
+
+/// The body of the first chunk in `data` with this id.
+pub fn chunk<'a>(data: &'a [u8], id: &[u8; 4]) -> Option<&'a [u8]> {
+    chunks(data).find(|c| c.id() == id).map(|c| c.body())
+}
+
+/// Where a chunk is in a file: its id, and the offset and size of its body.
+pub struct Span {
+    pub id: [u8; 4],
+    pub start: u64,
+    /// Cut short if the file is.
+    pub size: u64,
+}
+
+/// The chunks in `file` from offset `start` to `end`, found by reading only their headers.
+pub fn spans<R: Read + Seek>(file: &mut R, start: u64, end: u64) -> io::Result<Vec<Span>> {
+    let mut spans = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        let mut header = [0; 8];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+        let (id, size) = header.split_at(4);
+        let size = u64::from(u32::from_le_bytes(size.try_into().unwrap()));
+        let start = pos + 8;
+        spans.push(Span { id: id.try_into().unwrap(), start, size: size.min(end - start) });
+        pos = start + size + size % 2;
+    }
+    Ok(spans)
+}
+
+/// Read the body of the chunk at `span`.
+pub fn read<R: Read + Seek>(file: &mut R, span: &Span) -> io::Result<Vec<u8>> {
+    let mut body = vec![0; span.size as usize];
+    file.seek(SeekFrom::Start(span.start))?;
+    file.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// The type of the `LIST` chunk at `span`, or `None` if it is some other chunk.
+pub fn list_type<R: Read + Seek>(file: &mut R, span: &Span) -> io::Result<Option<[u8; 4]>> {
+    if &span.id != b"LIST" || span.size < 4 {
+        return Ok(None);
+    }
+    let mut kind = [0; 4];
+    file.seek(SeekFrom::Start(span.start))?;
+    file.read_exact(&mut kind)?;
+    Ok(Some(kind))
+}
@@ -0,0 +1,117 @@
+//! RIFF MIDI (`.rmi`) files: a Standard MIDI File wrapped in a RIFF `RMID` container, as
+//! Windows once saved them, sometimes with a sound bank for the song alongside it.
+//!
+//! midly unwraps the MIDI data itself; what is left here is the bank. One in SoundFont form
+//! (a RIFF `sfbk` chunk, as the 2023 revision of the format allows) is layered over the
+//! song's SoundFonts, as `--soundfont` would. A DLS bank (RIFF `DLS `) is older and more
+//! common, but neither synth reads DLS, so the song plays with the SoundFont instead.
+
+use crate::riff;
+use crate::song::SongArgs;
+use crate::soundfont;
+use anyhow::{Context, Result};
+use std::fs;
+use tracing::{debug, info, warn};
+
+/// A sound bank carried in an RMID file.
+enum Bank<'a> {
+    /// A whole SoundFont file.
+    SoundFont(&'a [u8]),
+    Dls,
+}
+
+/// The chunks of an RMID file: the MIDI data and the bank, if any. `None` if `bytes` is not
+/// an RMID file.
+fn chunks(bytes: &[u8]) -> Option<(&[u8], Option<Bank<'_>>)> {
+    let (mut data, mut bank) = (None, None);
+    for chunk in riff::chunks(riff::form(bytes, b"RMID")?) {
+        match (chunk.id(), chunk.body().get(..4)) {
+            (b"data", _) => data = Some(chunk.body()),
+            // The whole nested file, header and all, is the bank.
+            (b"RIFF", Some(b"sfbk")) => bank = Some(Bank::SoundFont(chunk.whole())),
+            (b"RIFF", Some(b"DLS ")) => bank = Some(Bank::Dls),
+            (id, _) => debug!("Skipping RMID chunk {}", String::from_utf8_lossy(id)),
+        }
+    }
+    Some((data?, bank))
+}
+
+/// The Standard MIDI File inside `bytes` if it is an RMID file, or `bytes` as they are.
+pub fn smf_data(bytes: &[u8]) -> &[u8] {
+    chunks(bytes).map_or(bytes, |(data, _)| data)
+}
+
+/// Layer the SoundFont an RMID song carries over the ones `args` names, so its instruments
+/// win. Only a SoundFont can be layered; a DLS bank is reported and left out.
+pub fn layer_bank(args: &mut SongArgs) -> Result<()> {
+    let bytes = fs::read(&args.midi).with_context(|| "reading MIDI file")?;
+    match chunks(&bytes) {
+        Some((_, Some(Bank::SoundFont(font)))) => {
+            // The format also allows a bank offset (a `DBNK` chunk) for its banks, which the
+            // synths have no way to apply; the banks are taken as they are.
+            let path = soundfont::cached("rmid", font)?;
+            info!("Using the SoundFont in {}", args.midi);
+            args.soundfonts.insert(0, path.display().to_string());
+        }
+        Some((_, Some(Bank::Dls))) => {
+            warn!("{} carries a DLS sound bank, which cannot be played; using the SoundFont instead", args.midi);
+        }
+        Some((_, None)) => debug!("RIFF MIDI file without a sound bank"),
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMF: &[u8] = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x04\0\xff\x2f\0";
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        if data.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn rmid(chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(b"RIFF", &[b"RMID".as_slice(), &chunks.concat()].concat())
+    }
+
+    #[test]
+    fn unwraps_the_midi_data() {
+        let bytes = rmid(&[chunk(b"INFO", b"odd"), chunk(b"data", SMF)]);
+        assert_eq!(smf_data(&bytes), SMF);
+        assert!(matches!(chunks(&bytes), Some((_, None))));
+    }
+
+    #[test]
+    fn leaves_a_standard_midi_file_as_it_is() {
+        assert_eq!(smf_data(SMF), SMF);
+        let wave = chunk(b"RIFF", b"WAVEdata");
+        assert_eq!(smf_data(&wave), wave);
+    }
+
+    #[test]
+    fn finds_the_bank_past_an_odd_length_chunk() {
+        let odd = &SMF[..SMF.len() - 1];
+        let font = chunk(b"RIFF", b"sfbkLIST");
+        let bytes = rmid(&[chunk(b"data", odd), font.clone()]);
+        let Some((data, Some(Bank::SoundFont(bank)))) = chunks(&bytes) else { panic!("no SoundFont found") };
+        assert_eq!((data, bank), (odd, font.as_slice()));
+
+        let bytes = rmid(&[chunk(b"data", SMF), chunk(b"RIFF", b"DLS colh")]);
+        assert!(matches!(chunks(&bytes), Some((_, Some(Bank::Dls)))));
+    }
+
+    #[test]
+    fn reads_a_file_cut_short() {
+        let mut bytes = rmid(&[chunk(b"data", SMF)]);
+        bytes.truncate(bytes.len() - 4);
+        assert_eq!(smf_data(&bytes), &SMF[..SMF.len() - 4]);
+    }
+}
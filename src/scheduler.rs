@@ -0,0 +1,550 @@
+//! Sample-accurate event timing for live playback.
+//!
+//! The conductor wakes up about once a millisecond, and the audio callback renders a whole
+//! buffer at a time, so a message sent straight to the synth lands at the start of whichever
+//! buffer comes next: fast arpeggios and tight drum patterns come out ragged. Instead the
+//! conductor stamps each message with the moment it is due, and the callback renders up to
+//! that moment and applies it at its exact frame. Everything plays one buffer (and a little
+//! slack) later than stamped, so each message is queued before its buffer is rendered.
+//!
+//! Moments are told in audio time, the length of the audio rendered so far, which the
+//! conductor's song clock runs on too: against the wall clock the device's clock drifts.
+//!
+//! The callback owns the synth outright, and nothing else touches it: seeking, pausing and
+//! the fade go through the same lock-free queue as the song, and the voice count and the
+//! silence after each buffer come back through atomics. So the callback never waits for a
+//! lock, however busy the conductor is.
+
+use crate::interrupt::INTERRUPTED;
+use crate::queue::{self, Consumer, Handoff, Producer};
+use crate::song::Msg;
+use crate::synth::{Synth, Synthesizer};
+use anyhow::Result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Time allowed on top of a buffer for the conductor to be late with a message.
+const SLACK: Duration = Duration::from_millis(3);
+
+/// How many messages can wait to be played: far more than are ever due within a buffer.
+const QUEUE_LEN: usize = 4096;
+
+/// How many live messages can wait for the next buffer.
+const LIVE_LEN: usize = 256;
+
+/// How long `Events::send` waits for room in a full queue, and `Events::replace` for the
+/// callback to take the new synth, before giving up on a stream that is not running.
+const WAIT: Duration = Duration::from_secs(1);
+
+/// How much audio the synth has rendered, readable from any thread.
+#[derive(Clone, Default)]
+pub struct AudioTime(Arc<Rendered>);
+
+#[derive(Default)]
+struct Rendered {
+    total_ns: AtomicU64,
+    /// The length of the last buffer.
+    block_ns: AtomicU64,
+    /// How far what is heard lags behind the messages stamped now: the delay they are
+    /// played with, and the output latency on top.
+    lag_ns: AtomicU64,
+}
+
+impl AudioTime {
+    /// The audio rendered so far, and how much of it the last buffer held.
+    pub fn rendered(&self) -> (Duration, Duration) {
+        let total = self.0.total_ns.load(Ordering::Acquire);
+        (Duration::from_nanos(total), Duration::from_nanos(self.0.block_ns.load(Ordering::Relaxed)))
+    }
+
+    /// How far what is heard lags behind the messages stamped now.
+    pub fn lag(&self) -> Duration {
+        Duration::from_nanos(self.0.lag_ns.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, block: Duration) {
+        self.0.block_ns.store(block.as_nanos() as u64, Ordering::Relaxed);
+        self.0.total_ns.fetch_add(block.as_nanos() as u64, Ordering::Release);
+    }
+}
+
+/// What the conductor has the synth do: the song's messages, and the transport's.
+#[derive(Clone, Copy)]
+pub enum Event {
+    Msg(Msg),
+    /// `Synthesizer::silence`.
+    Silence,
+    /// `Synthesizer::reset`.
+    Reset,
+    /// `Synthesizer::release_notes`.
+    ReleaseNotes,
+    /// `Synthesizer::midi_panic`.
+    Panic,
+    Gain(f32),
+}
+
+/// What the two sides share besides the queues. The callback reports on the synth after
+/// every buffer.
+struct Shared {
+    /// A synth to play from the next buffer on, and the one it took over from, left for
+    /// the conductor's side to drop so the callback never frees it.
+    incoming: Handoff<Synth>,
+    retired: Handoff<Synth>,
+    /// The voices sounding and the most there can be, `usize::MAX` for an engine that
+    /// cannot count them.
+    voices: AtomicUsize,
+    polyphony: AtomicUsize,
+    /// How long the output has been silent, `u64::MAX` for a synth that cannot tell.
+    quiet_ns: AtomicU64,
+    /// How many of the events queued with `Events::push` and `Events::send` have been applied.
+    applied: AtomicU64,
+    /// The rate the synth renders at, as `f32` bits.
+    sample_rate: AtomicU32,
+}
+
+impl Shared {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            incoming: Handoff::new(None),
+            retired: Handoff::new(None),
+            voices: AtomicUsize::new(usize::MAX),
+            polyphony: AtomicUsize::new(0),
+            quiet_ns: AtomicU64::new(u64::MAX),
+            applied: AtomicU64::new(0),
+            sample_rate: AtomicU32::new(sample_rate.to_bits()),
+        }
+    }
+}
+
+/// The conductor's side of a `Scheduler`.
+pub struct Events {
+    queue: Producer<(Duration, Event)>,
+    live: Option<Producer<Msg>>,
+    time: AudioTime,
+    shared: Arc<Shared>,
+    /// How many events have been queued with `push` and `send`.
+    sent: u64,
+    /// The synth's gain, as last set.
+    gain: f32,
+}
+
+impl Events {
+    /// Queue `msg` to be played at audio time `at`. False if the queue is full.
+    pub fn push(&mut self, at: Duration, msg: Msg) -> bool {
+        let queued = self.queue.push((at, Event::Msg(msg))).is_ok();
+        self.sent += u64::from(queued);
+        queued
+    }
+
+    /// Queue `event` to be applied as soon as what was queued before it, waiting for room if
+    /// the queue is full. Without a stream to empty it, the event is dropped after `WAIT`.
+    pub fn send(&mut self, event: Event) {
+        let waited = Instant::now();
+        let mut entry = (Duration::ZERO, event);
+        while let Err(back) = self.queue.push(entry) {
+            if waited.elapsed() >= WAIT || INTERRUPTED.load(Ordering::SeqCst) {
+                warn!("The audio output is not taking events; dropping one");
+                return;
+            }
+            entry = back;
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.sent += 1;
+        if let Event::Gain(gain) = event {
+            self.gain = gain;
+        }
+    }
+
+    /// The audio time the messages are stamped in.
+    pub fn time(&self) -> AudioTime {
+        self.time.clone()
+    }
+
+    /// The synth's gain, as last set.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// The voices sounding after the last buffer and the most there can be, or `None` for
+    /// an engine that cannot count them.
+    pub fn voices(&self) -> Option<(usize, usize)> {
+        let voices = self.shared.voices.load(Ordering::Relaxed);
+        (voices != usize::MAX).then(|| (voices, self.shared.polyphony.load(Ordering::Relaxed)))
+    }
+
+    /// How long the output has been silent since the events queued were applied, for a synth
+    /// that listens to it; none of that silence counts until they have been.
+    pub fn silent_for(&self) -> Option<Duration> {
+        let quiet = self.shared.quiet_ns.load(Ordering::Acquire);
+        if quiet == u64::MAX {
+            return None;
+        }
+        let applied = self.shared.applied.load(Ordering::Acquire);
+        Some(if applied < self.sent { Duration::ZERO } else { Duration::from_nanos(quiet) })
+    }
+
+    /// The way in for messages played live, which skip the queue so they are heard with
+    /// the next buffer. There is one, so only the first call has it.
+    pub fn live(&mut self) -> Option<Live> {
+        self.live.take().map(Live)
+    }
+
+    /// Play on `synth` from the next buffer on, at the rate the old one renders at, and drop
+    /// the old one once the callback has let go of it. If the stream is not running the
+    /// swap waits for it to start again.
+    pub fn replace(&mut self, mut synth: Synth) {
+        synth.set_sample_rate(f32::from_bits(self.shared.sample_rate.load(Ordering::Relaxed)));
+        self.gain = synth.gain();
+        drop(self.shared.retired.take());
+        drop(self.shared.incoming.put(Box::new(synth)));
+        let waited = Instant::now();
+        while self.shared.incoming.is_full() && waited.elapsed() < WAIT {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(self.shared.retired.take());
+    }
+}
+
+/// Messages played on an instrument, for the callback to apply at the start of the next
+/// buffer.
+pub struct Live(Producer<Msg>);
+
+impl Live {
+    /// Play `msg` with the next buffer. If a flood of them has filled the queue, it is lost.
+    pub fn play(&mut self, msg: Msg) {
+        let _ = self.0.push(msg);
+    }
+}
+
+/// A synth that plays scheduled messages at their frame within the buffer being rendered.
+pub struct Scheduler {
+    synth: Synth,
+    sample_rate: f32,
+    /// Events waiting to be applied, in the order they are due.
+    queue: Consumer<(Duration, Event)>,
+    /// The first of them, taken off the queue but not yet due.
+    next: Option<(Duration, Event)>,
+    live: Consumer<Msg>,
+    /// The audio rendered so far, which the buffer about to be rendered starts at.
+    time: AudioTime,
+    /// How much later than stamped messages are played: the longest buffer yet, plus `SLACK`.
+    delay: Duration,
+    /// How long the output takes to reach the speakers, as last reported.
+    latency: Duration,
+    shared: Arc<Shared>,
+    /// How many events from `Events::push` and `Events::send` have been applied.
+    applied: u64,
+}
+
+impl Scheduler {
+    /// Wrap `synth`, and return it with the conductor's side.
+    pub fn wrap(synth: Synth, sample_rate: f32) -> (Synth, Events) {
+        let (events, queue) = queue::channel(QUEUE_LEN);
+        let (live_in, live) = queue::channel(LIVE_LEN);
+        let time = AudioTime::default();
+        let shared = Arc::new(Shared::new(sample_rate));
+        let gain = synth.gain();
+        let scheduler = Self {
+            synth,
+            sample_rate,
+            queue,
+            next: None,
+            live,
+            time: time.clone(),
+            delay: SLACK,
+            latency: Duration::ZERO,
+            shared: Arc::clone(&shared),
+            applied: 0,
+        };
+        let events = Events { queue: events, live: Some(live_in), time, shared, sent: 0, gain };
+        (Box::new(scheduler), events)
+    }
+
+    /// The frame of the buffer starting at audio time `start` at which a message stamped
+    /// `at` is played; 0 if it is late.
+    fn frame_of(&self, start: Duration, at: Duration) -> usize {
+        ((at + self.delay).saturating_sub(start).as_secs_f64() * f64::from(self.sample_rate)) as usize
+    }
+
+    /// Apply the events due at or before `frame` of the buffer starting at `start`, and
+    /// return the frame the next one is due at, or `frames` if none is due in this buffer.
+    fn apply_due(&mut self, start: Duration, frame: usize, frames: usize) -> usize {
+        loop {
+            if self.next.is_none() {
+                self.next = self.queue.pop();
+            }
+            let Some((at, event)) = self.next else { return frames };
+            let due = self.frame_of(start, at);
+            if due > frame {
+                return due.min(frames);
+            }
+            self.next = None;
+            match event {
+                Event::Msg(msg) => self.synth.send(msg),
+                Event::Silence => self.synth.silence(),
+                Event::Reset => self.synth.reset(),
+                Event::ReleaseNotes => self.synth.release_notes(),
+                Event::Panic => self.synth.midi_panic(),
+                Event::Gain(gain) => self.synth.set_gain(gain),
+            }
+            self.applied += 1;
+        }
+    }
+
+    /// Play the synth handed over by `Events::replace`, if there is one, and leave the old
+    /// one for the conductor's side to drop.
+    /// Not while the one before is still waiting there, so the callback never has one to
+    /// drop itself.
+    fn take_over(&mut self) {
+        if self.shared.retired.is_full() {
+            return;
+        }
+        if let Some(mut fresh) = self.shared.incoming.take() {
+            std::mem::swap(&mut self.synth, &mut *fresh);
+            let _ = self.shared.retired.put(fresh);
+        }
+    }
+
+    /// Render `frames` in stretches between the events due in them, with `render`
+    /// rendering each stretch, and report on the synth afterwards.
+    fn render_with(
+        &mut self,
+        frames: usize,
+        mut render: impl FnMut(&mut Synth, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        self.take_over();
+        while let Some(msg) = self.live.pop() {
+            self.synth.send(msg);
+        }
+        let block = Duration::from_secs_f64(frames as f64 / f64::from(self.sample_rate));
+        self.delay = self.delay.max(block + SLACK);
+        let start = self.time.rendered().0;
+        let mut done = 0;
+        let rendered = loop {
+            let next = self.apply_due(start, done, frames);
+            if done == frames {
+                break Ok(());
+            }
+            if let Err(e) = render(&mut self.synth, done, next) {
+                break Err(e);
+            }
+            done = next;
+        };
+        self.time.add(block);
+        self.time.0.lag_ns.store((self.delay + self.latency).as_nanos() as u64, Ordering::Relaxed);
+        self.report();
+        rendered
+    }
+
+    fn report(&self) {
+        let shared = &*self.shared;
+        let (voices, polyphony) = self.synth.voices().unwrap_or((usize::MAX, 0));
+        shared.voices.store(voices, Ordering::Relaxed);
+        shared.polyphony.store(polyphony, Ordering::Relaxed);
+        let quiet = self.synth.silent_for().map_or(u64::MAX, |quiet| quiet.as_nanos() as u64);
+        shared.quiet_ns.store(quiet, Ordering::Release);
+        shared.applied.store(self.applied, Ordering::Release);
+    }
+}
+
+/// The output renders through here and sets the rate and latency; everything else reaches the synth
+/// through the queues.
+impl Synthesizer for Scheduler {
+    fn note_on(&mut self, ch: u8, key: u8, vel: u8) {
+        self.synth.note_on(ch, key, vel);
+    }
+    fn note_off(&mut self, ch: u8, key: u8) {
+        self.synth.note_off(ch, key);
+    }
+    fn program(&mut self, ch: u8, program: u8) {
+        self.synth.program(ch, program);
+    }
+    fn cc(&mut self, ch: u8, cc: u8, value: u8) {
+        self.synth.cc(ch, cc, value);
+    }
+    fn bank(&mut self, ch: u8, bank: u16) {
+        self.synth.bank(ch, bank);
+    }
+    fn bend_range(&mut self, ch: u8, semitones: u8) {
+        self.synth.bend_range(ch, semitones);
+    }
+    fn pitch_bend(&mut self, ch: u8, bend: u16) {
+        self.synth.pitch_bend(ch, bend);
+    }
+    fn key_pressure(&mut self, ch: u8, key: u8, value: u8) {
+        self.synth.key_pressure(ch, key, value);
+    }
+    fn channel_pressure(&mut self, ch: u8, value: u8) {
+        self.synth.channel_pressure(ch, value);
+    }
+    fn reset(&mut self) {
+        self.synth.reset();
+    }
+    fn gain(&self) -> f32 {
+        self.synth.gain()
+    }
+    fn set_gain(&mut self, gain: f32) {
+        self.synth.set_gain(gain);
+    }
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        self.shared.sample_rate.store(rate.to_bits(), Ordering::Relaxed);
+        self.synth.set_sample_rate(rate);
+    }
+
+    fn render(&mut self, out: &mut [f32]) -> Result<()> {
+        self.render_with(out.len() / 2, |synth, from, to| synth.render(&mut out[from * 2..to * 2]))
+    }
+    #[cfg(feature = "jack")]
+    fn render_split(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<()> {
+        self.render_with(left.len(), |synth, from, to| synth.render_split(&mut left[from..to], &mut right[from..to]))
+    }
+
+    fn voices(&self) -> Option<(usize, usize)> {
+        self.synth.voices()
+    }
+    fn silent_for(&self) -> Option<Duration> {
+        self.synth.silent_for()
+    }
+    fn set_output_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::Metered;
+    use crate::synth::fake::{Call, Fake};
+
+    /// A frame a millisecond, so frames and milliseconds read the same.
+    const RATE: f32 = 1000.0;
+
+    fn render(synth: &mut Synth, frames: usize) {
+        synth.render(&mut vec![0.0; frames * 2]).unwrap();
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn plays_a_buffer_and_the_slack_after_its_stamp() {
+        let (fake, log) = Fake::open();
+        let (mut synth, mut events) = Scheduler::wrap(fake, RATE);
+        assert!(events.push(ms(0), Msg::NoteOn(0, 60, 100)));
+        assert!(events.push(ms(50), Msg::NoteOff(0, 60, 0)));
+        render(&mut synth, 100);
+        assert!(log.lock().unwrap().is_empty());
+        render(&mut synth, 100);
+        render(&mut synth, 100);
+        assert_eq!(*log.lock().unwrap(), [(103, Call::NoteOn(0, 60, 100)), (153, Call::NoteOff(0, 60))]);
+        assert_eq!(events.time().rendered(), (ms(300), ms(100)));
+    }
+
+    #[test]
+    fn plays_a_late_message_at_the_start_of_the_buffer() {
+        let (fake, log) = Fake::open();
+        let (mut synth, mut events) = Scheduler::wrap(fake, RATE);
+        render(&mut synth, 100);
+        render(&mut synth, 100);
+        events.push(ms(10), Msg::Program(3, 5));
+        render(&mut synth, 100);
+        assert_eq!(*log.lock().unwrap(), [(200, Call::Program(3, 5))]);
+    }
+
+    #[test]
+    fn keeps_the_longest_buffer_as_the_delay() {
+        let (fake, log) = Fake::open();
+        let (mut synth, mut events) = Scheduler::wrap(fake, RATE);
+        render(&mut synth, 100);
+        render(&mut synth, 10);
+        events.push(ms(110), Msg::NoteOn(0, 60, 100));
+        for _ in 0..20 {
+            render(&mut synth, 10);
+        }
+        assert_eq!(*log.lock().unwrap(), [(213, Call::NoteOn(0, 60, 100))]);
+    }
+
+    #[test]
+    fn reports_the_delay_and_the_output_latency_as_the_lag() {
+        let (fake, _) = Fake::open();
+        let (mut synth, events) = Scheduler::wrap(fake, RATE);
+        synth.set_output_latency(ms(20));
+        render(&mut synth, 10);
+        assert_eq!(events.time().lag(), ms(33));
+    }
+
+    #[test]
+    fn applies_an_event_sent_after_those_queued_before_it() {
+        let (fake, log) = Fake::open();
+        let (mut synth, mut events) = Scheduler::wrap(fake, RATE);
+        events.push(ms(0), Msg::NoteOn(0, 60, 100));
+        events.send(Event::ReleaseNotes);
+        render(&mut synth, 100);
+        assert!(log.lock().unwrap().is_empty());
+        render(&mut synth, 100);
+        let log = log.lock().unwrap();
+        assert_eq!(log[0], (103, Call::NoteOn(0, 60, 100)));
+        assert_eq!(log[1..4], [(103, Call::Cc(0, 64, 0)), (103, Call::Cc(0, 66, 0)), (103, Call::Cc(0, 123, 0))]);
+        assert_eq!(log.len(), 1 + 16 * 3);
+    }
+
+    #[test]
+    fn counts_silence_only_once_the_events_sent_are_applied() {
+        let (fake, _log) = Fake::open();
+        let (mut synth, mut events) = Scheduler::wrap(Metered::wrap(fake, RATE), RATE);
+        render(&mut synth, 100);
+        render(&mut synth, 100);
+        assert_eq!(events.silent_for(), Some(ms(200)));
+        events.send(Event::ReleaseNotes);
+        assert_eq!(events.silent_for(), Some(ms(0)));
+        render(&mut synth, 100);
+        assert_eq!(events.silent_for(), Some(ms(100)));
+    }
+
+    #[test]
+    fn counts_a_message_sent_as_applied() {
+        let (fake, _log) = Fake::open();
+        let (mut synth, mut events) = Scheduler::wrap(Metered::wrap(fake, RATE), RATE);
+        events.send(Event::Msg(Msg::Control(0, 64, 0)));
+        render(&mut synth, 100);
+        assert_eq!(events.silent_for(), Some(ms(0)));
+        render(&mut synth, 100);
+        assert_eq!(events.silent_for(), Some(ms(200)));
+    }
+
+    #[test]
+    fn plays_live_messages_with_the_next_buffer() {
+        let (fake, log) = Fake::open();
+        let (mut synth, mut events) = Scheduler::wrap(fake, RATE);
+        let mut live = events.live().unwrap();
+        assert!(events.live().is_none());
+        render(&mut synth, 100);
+        live.play(Msg::NoteOn(2, 64, 90));
+        render(&mut synth, 100);
+        assert_eq!(*log.lock().unwrap(), [(100, Call::NoteOn(2, 64, 90))]);
+    }
+
+    #[test]
+    fn hands_over_to_a_new_synth() {
+        let (old, old_log) = Fake::open();
+        let (new, new_log) = Fake::open();
+        let (mut synth, mut events) = Scheduler::wrap(old, RATE);
+        let replacing = thread::spawn(move || {
+            events.replace(new);
+            events
+        });
+        while !replacing.is_finished() {
+            render(&mut synth, 10);
+        }
+        let mut events = replacing.join().unwrap();
+        events.send(Event::Reset);
+        render(&mut synth, 10);
+        assert!(old_log.lock().unwrap().is_empty());
+        assert!(matches!(new_log.lock().unwrap().last(), Some((_, Call::Reset))));
+    }
+}
@@ -0,0 +1,309 @@
+//! Per-song settings from a `song.mid.toml` file next to the MIDI file.
+//!
+//! ```toml
+//! soundfont = "piano.sf2"   # relative to the MIDI file's folder
+//! transpose = -2
+//! speed = 0.85
+//! mute = [10]
+//! solo = []
+//! program = ["1=0:48"]      # as --program
+//! drum-channels = [10, 11]  # as --drum-channels; [] for none
+//! replaygain = -3.2         # dB for `play`, as `gain-scan --write-sidecar` measures it
+//! reverb = true
+//! reverb-params = [0.9, 0.2, 0.9, 0.6]   # as --reverb-params
+//! chorus = false
+//! polyphony = 1024          # for a dense file, as --polyphony
+//! lenient = true            # a damaged file, as --lenient
+//!
+//! [[marker]]
+//! name = "Solo"
+//! time = "1:32"
+//! ```
+//!
+//! Options given on the command line win over the sidecar.
+
+use crate::conductor::{MAX_SPEED, MIN_SPEED};
+use crate::song::{self, DrumChannels, Marker, ProgramOverride, SongArgs};
+use crate::soundfont;
+use crate::synth::{self, EffectsArgs};
+use crate::time::parse_time;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::{fs, io, path::Path};
+use tracing::{info, warn};
+
+/// Settings read from a sidecar file. Empty fields leave the command line alone.
+#[derive(Default)]
+pub struct Sidecar {
+    pub soundfont: Option<String>,
+    pub transpose: Option<i8>,
+    pub mute: Vec<u8>,
+    pub solo: Vec<u8>,
+    pub programs: Vec<ProgramOverride>,
+    pub speed: Option<f64>,
+    pub markers: Vec<Marker>,
+    /// Level for `play`, in dB, to bring the song to a common loudness.
+    pub replaygain: Option<f64>,
+    pub lenient: bool,
+    pub effects: EffectsArgs,
+}
+
+/// Load the sidecar for `args.midi`, unless `--no-sidecar` was given, and apply it to the
+/// song options. Returned for the settings that are not song options.
+pub fn load_for(args: &mut SongArgs) -> Result<Option<Sidecar>> {
+    if args.no_sidecar {
+        return Ok(None);
+    }
+    let sidecar = load(&args.midi)?;
+    if let Some(sc) = &sidecar {
+        sc.apply(args);
+    }
+    Ok(sidecar)
+}
+
+/// Set `key = value` at the top level of the sidecar for `midi`, creating it if there is
+/// none. Everything else in the file, comments included, is kept as it is.
+pub fn set(midi: &str, key: &str, value: &str) -> Result<()> {
+    let path = format!("{midi}.toml");
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("reading {path}")),
+    };
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    // Top-level keys come before the first [section].
+    let top = lines.iter().position(|l| l.trim_start().starts_with('[')).unwrap_or(lines.len());
+    let setting = format!("{key} = {value}");
+    let is_key = |l: &str| l.trim_start().strip_prefix(key).is_some_and(|rest| rest.trim_start().starts_with('='));
+    match lines[..top].iter().position(|l| is_key(l)) {
+        Some(i) => lines[i] = setting,
+        None => {
+            let mut at = top;
+            while at > 0 && lines[at - 1].trim().is_empty() {
+                at -= 1;
+            }
+            if at == 0 && top < lines.len() {
+                lines.insert(0, String::new());
+            }
+            lines.insert(at, setting);
+        }
+    }
+    fs::write(&path, lines.join("\n") + "\n").with_context(|| format!("writing {path}"))
+}
+
+/// Load `<midi>.toml` if there is one.
+fn load(midi: &str) -> Result<Option<Sidecar>> {
+    let path = format!("{midi}.toml");
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {path}")),
+    };
+    let file: File = toml::from_str(&text).with_context(|| format!("reading {path}"))?;
+    let sidecar = file.into_sidecar(midi).with_context(|| format!("reading {path}"))?;
+    info!("Using settings from {path}");
+    Ok(Some(sidecar))
+}
+
+/// The sidecar as written. Numbers are checked in `into_sidecar`, to say which are allowed.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct File {
+    soundfont: Option<String>,
+    transpose: Option<i64>,
+    speed: Option<f64>,
+    replaygain: Option<f64>,
+    #[serde(default)]
+    lenient: bool,
+    reverb: Option<bool>,
+    chorus: Option<bool>,
+    reverb_params: Option<Vec<f64>>,
+    chorus_params: Option<Vec<f64>>,
+    polyphony: Option<i64>,
+    #[serde(default)]
+    mute: Vec<i64>,
+    #[serde(default)]
+    solo: Vec<i64>,
+    drum_channels: Option<Vec<i64>>,
+    #[serde(default)]
+    program: Vec<String>,
+    #[serde(default)]
+    marker: Vec<MarkerEntry>,
+    /// Keys not named above, warned about and otherwise left alone.
+    #[serde(flatten)]
+    unknown: toml::Table,
+}
+
+/// A `[[marker]]` section.
+#[derive(Deserialize)]
+struct MarkerEntry {
+    name: String,
+    time: Option<MarkerTime>,
+}
+
+/// A marker's time, as mm:ss or seconds.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MarkerTime {
+    Text(String),
+    Seconds(f64),
+}
+
+impl File {
+    fn into_sidecar(self, midi: &str) -> Result<Sidecar> {
+        for key in self.unknown.keys() {
+            warn!("Ignoring unknown setting `{key}`");
+        }
+        let mut sc = Sidecar::default();
+        if let Some(path) = self.soundfont {
+            // Relative paths are relative to the song, so the pair can move together.
+            let dir = Path::new(midi).parent().unwrap_or(Path::new(""));
+            sc.soundfont = Some(if soundfont::is_url(&path) { path } else { dir.join(path).display().to_string() });
+        }
+        if let Some(n) = self.transpose {
+            if !(-48..=48).contains(&n) {
+                bail!("`transpose` must be between -48 and 48");
+            }
+            sc.transpose = Some(n as i8);
+        }
+        if let Some(v) = self.speed {
+            if !(MIN_SPEED..=MAX_SPEED).contains(&v) {
+                bail!("`speed` must be between {MIN_SPEED} and {MAX_SPEED}");
+            }
+            sc.speed = Some(v);
+        }
+        if let Some(v) = self.replaygain {
+            if !(-60.0..=60.0).contains(&v) {
+                bail!("`replaygain` must be between -60 and 60 dB");
+            }
+            sc.replaygain = Some(v);
+        }
+        sc.lenient = self.lenient;
+        sc.effects.reverb = self.reverb;
+        sc.effects.chorus = self.chorus;
+        if let Some(numbers) = self.reverb_params {
+            sc.effects.reverb_params = Some(effect_params(&numbers, synth::parse_reverb)?);
+        }
+        if let Some(numbers) = self.chorus_params {
+            sc.effects.chorus_params = Some(effect_params(&numbers, synth::parse_chorus)?);
+        }
+        if let Some(n) = self.polyphony {
+            if !(1..=i64::from(u16::MAX)).contains(&n) {
+                bail!("`polyphony` must be between 1 and {}", u16::MAX);
+            }
+            sc.effects.polyphony = Some(n as u16);
+        }
+        sc.mute = channels(&self.mute, "mute")?;
+        sc.solo = channels(&self.solo, "solo")?;
+        if let Some(list) = self.drum_channels {
+            sc.effects.drum_channels = Some(DrumChannels::from_numbers(&channels(&list, "drum-channels")?));
+        }
+        sc.programs = self
+            .program
+            .iter()
+            .map(|s| song::parse_program_override(s).map_err(anyhow::Error::msg))
+            .collect::<Result<_>>()?;
+        for MarkerEntry { name, time } in self.marker {
+            let t_us = match time {
+                Some(MarkerTime::Text(s)) => parse_time(&s),
+                Some(MarkerTime::Seconds(s)) => (s >= 0.0).then_some((s * 1_000_000.0) as u64),
+                None => None,
+            };
+            let t_us = t_us.with_context(|| format!("marker `{name}` needs a `time` (seconds or mm:ss)"))?;
+            sc.markers.push(Marker { t_us, name });
+        }
+        sc.markers.sort_by_key(|m| m.t_us);
+        Ok(sc)
+    }
+}
+
+/// A list of MIDI channel numbers, 1–16.
+fn channels(list: &[i64], key: &str) -> Result<Vec<u8>> {
+    list.iter()
+        .map(|&n| match n {
+            1..=16 => Ok(n as u8),
+            _ => bail!("`{key}` channels must be numbers from 1 to 16"),
+        })
+        .collect()
+}
+
+/// Reverb or chorus settings, a list of numbers as `--reverb-params` or `--chorus-params`
+/// takes them.
+fn effect_params<T>(numbers: &[f64], parse: fn(&str) -> Result<T, String>) -> Result<T> {
+    let text = numbers.iter().map(f64::to_string).collect::<Vec<_>>().join(",");
+    parse(&text).map_err(anyhow::Error::msg)
+}
+
+impl Sidecar {
+    /// Fill in the song options the command line left at their defaults.
+    fn apply(&self, args: &mut SongArgs) {
+        if args.soundfont.is_none() {
+            args.soundfont = self.soundfont.clone();
+        }
+        if args.transpose == 0
+            && let Some(t) = self.transpose
+        {
+            args.transpose = t;
+        }
+        if args.mute_channel.is_empty() {
+            args.mute_channel = self.mute.clone();
+        }
+        if args.solo_channel.is_empty() {
+            args.solo_channel = self.solo.clone();
+        }
+        if args.programs.is_empty() {
+            args.programs = self.programs.clone();
+        }
+        args.lenient |= self.lenient;
+        let effects = &mut args.effects;
+        effects.reverb = effects.reverb.or(self.effects.reverb);
+        effects.reverb_params = effects.reverb_params.or(self.effects.reverb_params);
+        effects.chorus = effects.chorus.or(self.effects.chorus);
+        effects.chorus_params = effects.chorus_params.or(self.effects.chorus_params);
+        effects.polyphony = effects.polyphony.or(self.effects.polyphony);
+        effects.drum_channels = effects.drum_channels.or(self.effects.drum_channels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> Result<Sidecar> {
+        toml::from_str::<File>(text)?.into_sidecar("songs/song.mid")
+    }
+
+    #[test]
+    fn reads_settings_and_markers() {
+        let sc = read(
+            "soundfont = \"piano.sf2\"\n\
+             transpose = -2\n\
+             speed = 1\n\
+             mute = [10]\n\
+             drum-channels = [10, 11]\n\
+             volume = 3  # not a setting\n\
+             [[marker]]\n\
+             name = \"Solo\"\n\
+             time = \"1:32\"\n\
+             [[marker]]\n\
+             name = \"Intro\"\n\
+             time = 4.5\n",
+        )
+        .unwrap();
+        assert_eq!(sc.soundfont.as_deref(), Some("songs/piano.sf2"));
+        assert_eq!(sc.transpose, Some(-2));
+        assert_eq!(sc.speed, Some(1.0));
+        assert_eq!(sc.mute, [10]);
+        let markers: Vec<_> = sc.markers.iter().map(|m| (m.name.as_str(), m.t_us)).collect();
+        assert_eq!(markers, [("Intro", 4_500_000), ("Solo", 92_000_000)]);
+    }
+
+    #[test]
+    fn rejects_settings_out_of_range() {
+        let error = |text| read(text).err().unwrap().to_string();
+        assert_eq!(error("transpose = 49\n"), "`transpose` must be between -48 and 48");
+        assert_eq!(error("solo = [0]\n"), "`solo` channels must be numbers from 1 to 16");
+        assert_eq!(error("[[marker]]\nname = \"A\"\n"), "marker `A` needs a `time` (seconds or mm:ss)");
+        assert!(read("speed = \"fast\"\n").is_err());
+    }
+}
@@ -0,0 +1,1043 @@
+//! Loading a Standard MIDI File into a timeline of timestamped messages.
+
+use crate::bars::BarMap;
+use crate::lenient;
+use crate::synth::EffectsArgs;
+use crate::sysex::{self, Standard, SysEx};
+use crate::tempo::{DEFAULT_US_PER_QN, TempoMap};
+use crate::time::format_duration;
+use anyhow::{Context, Result};
+use clap::Args;
+use midly::{MetaMessage, Smf, TrackEventKind};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+/// What to play, shared by every subcommand that renders a song:
+/// - midi: path to a Standard MIDI file
+/// - soundfont: path to a GM .sf2 SoundFont, found automatically if left out
+/// - soundfonts: SoundFonts layered over it, in priority order
+/// - transpose: pitch shift in semitones for all but the drum channels
+/// - mute_channel / solo_channel: channels (1–16) to silence or play exclusively
+/// - tracks: tracks to include, by number or name
+/// - programs: bank and program to hold channels to, whatever the file picks
+/// - no_sidecar: ignore the song's `.mid.toml` settings file
+/// - lenient: play what can be recovered from a damaged file
+/// - effects: the synth's reverb and chorus, and its drum channels
+#[derive(Args, Clone, Debug)]
+pub struct SongArgs {
+    /// Path to .mid file
+    pub midi: String,
+    /// Path to GM SoundFont (.sf2 or .sf3); searched for in the usual places if left out
+    pub soundfont: Option<String>,
+    /// Layer this SoundFont over the main one, so its presets (say, a better piano) are used
+    /// instead; repeat for more, those given first winning
+    #[arg(long = "soundfont", value_name = "FONT.sf2")]
+    pub soundfonts: Vec<String>,
+    /// Transpose every channel except percussion (the drum channels) by this many semitones
+    #[arg(long, value_name = "SEMITONES", default_value_t = 0, allow_negative_numbers = true,
+          value_parser = clap::value_parser!(i8).range(-48..=48))]
+    pub transpose: i8,
+    /// Mute these MIDI channels (1–16, comma separated)
+    #[arg(long, value_name = "CH,...", value_delimiter = ',', value_parser = clap::value_parser!(u8).range(1..=16))]
+    pub mute_channel: Vec<u8>,
+    /// Play only these MIDI channels (1–16, comma separated)
+    #[arg(long, value_name = "CH,...", value_delimiter = ',', value_parser = clap::value_parser!(u8).range(1..=16))]
+    pub solo_channel: Vec<u8>,
+    /// Play only these tracks, by number (1 = first track) or TrackName (comma separated)
+    #[arg(long, value_name = "TRACK,...", value_delimiter = ',')]
+    pub tracks: Vec<String>,
+    /// Play a channel with this bank and program, ignoring the file's Program Changes on it,
+    /// e.g. 1=0:48 (comma separated, or repeat)
+    #[arg(long = "program", value_name = "CH=BANK:PROG,...", value_delimiter = ',', value_parser = parse_program_override)]
+    pub programs: Vec<ProgramOverride>,
+    /// Ignore the settings in SONG.mid.toml next to the MIDI file
+    #[arg(long)]
+    pub no_sidecar: bool,
+    /// Play a damaged file anyway: skip junk, unreadable events and missing bytes with a
+    /// warning, and keep whatever can be read
+    #[arg(long)]
+    pub lenient: bool,
+    #[command(flatten)]
+    pub effects: EffectsArgs,
+}
+
+/// The channels that play drum kits, a bit for each (0–15).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DrumChannels(u16);
+
+impl Default for DrumChannels {
+    /// General MIDI's: channel 10 alone.
+    fn default() -> Self {
+        Self(1 << DRUM_CHANNEL)
+    }
+}
+
+impl DrumChannels {
+    /// The channels numbered 1–16, as on the command line.
+    pub fn from_numbers(numbers: &[u8]) -> Self {
+        Self(numbers.iter().fold(0, |bits, &n| bits | 1 << (n - 1)))
+    }
+
+    /// Whether channel `ch` (0–15) plays drums.
+    pub fn contains(self, ch: u8) -> bool {
+        self.0 & 1 << ch != 0
+    }
+}
+
+impl std::fmt::Display for DrumChannels {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let numbers: Vec<String> = (0..16).filter(|&ch| self.contains(ch)).map(|ch| (ch + 1).to_string()).collect();
+        if numbers.is_empty() { f.write_str("none") } else { f.write_str(&numbers.join(", ")) }
+    }
+}
+
+/// Parse `--drum-channels`: channels 1–16, comma separated, or `none`.
+pub fn parse_drum_channels(s: &str) -> Result<DrumChannels, String> {
+    if s.trim().eq_ignore_ascii_case("none") {
+        return Ok(DrumChannels(0));
+    }
+    let numbers = s
+        .split(',')
+        .map(|n| match n.trim().parse::<u8>() {
+            Ok(n @ 1..=16) => Ok(n),
+            _ => Err(format!("`{n}` is not a channel from 1 to 16")),
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    Ok(DrumChannels::from_numbers(&numbers))
+}
+
+/// A `--program` override: the bank and program a channel plays whatever the file says.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProgramOverride {
+    /// 0–15
+    pub channel: u8,
+    /// 0–128, 128 being the percussion bank
+    pub bank: u16,
+    pub program: u8,
+}
+
+/// Parse `--program`: `CH=BANK:PROGRAM`, with the channel counted from 1, or `CH=PROGRAM`
+/// for bank 0.
+pub fn parse_program_override(s: &str) -> Result<ProgramOverride, String> {
+    let (ch, patch) = s.split_once('=').ok_or_else(|| format!("`{s}` is not CH=BANK:PROGRAM, e.g. 1=0:48"))?;
+    let (bank, program) = patch.split_once(':').unwrap_or(("0", patch));
+    let channel = match ch.trim().parse::<u8>() {
+        Ok(n @ 1..=16) => n - 1,
+        _ => return Err(format!("`{ch}` is not a channel from 1 to 16")),
+    };
+    let bank = match bank.trim().parse::<u16>() {
+        Ok(b @ 0..=128) => b,
+        _ => return Err(format!("`{bank}` is not a bank from 0 to 128")),
+    };
+    let program = match program.trim().parse::<u8>() {
+        Ok(p @ 0..=127) => p,
+        _ => return Err(format!("`{program}` is not a program from 0 to 127")),
+    };
+    Ok(ProgramOverride { channel, bank, program })
+}
+
+/// Represents a MIDI message extracted from the timeline.
+///
+/// Each variant corresponds to a MIDI event type.
+/// Fields follow the MIDI message structure:
+/// - First parameter is usually the channel (0–15)
+/// - Subsequent parameters depend on the event type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Msg {
+    /// Note On: Start playing a note.
+    /// - channel: 0–15
+    /// - key: MIDI note number (0–127)
+    /// - velocity: 0–127
+    NoteOn(u8, u8, u8),
+
+    /// Note Off: Stop playing a note.
+    /// - channel: 0–15
+    /// - key: MIDI note number (0–127)
+    /// - velocity: release velocity (0–127, often unused)
+    NoteOff(u8, u8, u8),
+
+    /// Program Change: Change the program (also known as instrument) for a channel.
+    /// - channel: 0–15
+    /// - program: instrument/patch number (0–127)
+    Program(u8, u8),
+
+    /// Control: Modify the value of a MIDI controller.
+    /// - channel: 0–15
+    /// - controller: controller number (0–127)
+    /// - value: controller value (0–127)
+    Control(u8, u8, u8),
+
+    /// Bank Select, from the Bank Select MSB and LSB controllers (CC0, CC32): the SoundFont
+    /// bank the channel's next Program Change picks its instrument from.
+    /// - channel: 0–15
+    /// - bank: 0–128, 128 being the percussion bank
+    Bank(u8, u16),
+
+    /// Pitch Bend Sensitivity, set through RPN 0 with the Data Entry controllers: how far a
+    /// full pitch bend reaches either way.
+    /// - channel: 0–15
+    /// - semitones: 0–127
+    /// - cents: 0–127, though a cent past 99 is a whole semitone
+    BendRange(u8, u8, u8),
+
+    /// Pitch Bend: Set the pitch bend value for the entire channel.
+    /// - channel: 0–15
+    /// - bend value: 14-bit signed value, 0–16383
+    ///   - center (no bend) = 8192
+    ///   - <8192 = bend down, >8192 = bend up
+    PitchBend(u8, u16),
+
+    /// Aftertouch (Polyphonic): Modify the velocity of a note after it has been played.
+    /// - channel: 0–15
+    /// - key: MIDI note number (0–127)
+    /// - velocity: 0–127, The velocity of the key
+    AfterTouch(u8, u8, u8),
+
+    /// ChannelAftertouch: Change the note velocity of a whole channel at once, without starting new notes.
+    /// - channel: 0–15
+    /// - pressure: 0–127
+    ChannelAftertouch(u8, u8),
+
+    /// A GM, GM2, GS or XG reset from a SysEx message: every channel back to its power-on
+    /// state.
+    /// - standard: the one the reset names, which decides how later Bank Selects are read
+    Reset(Standard),
+
+    /// Master Volume from a SysEx message, 0.0–1.0 of full amplitude. `Song::parse` turns
+    /// it into Volume controllers, so the synth never sees it.
+    MasterVolume(f32),
+
+    /// Any other SysEx message, whole from 0xF0 to 0xF7 (see `sysex::keep`), for the synth
+    /// to act on as far as it can: FluidLite on MIDI Tuning, a MIDI port on all of it.
+    SysEx(&'static [u8]),
+
+    /// Tempo change: (microseconds per quarter note)
+    /// - value is in µs per quarter note (not BPM)
+    /// - To convert to BPM: bpm = 60_000_000 / value
+    #[allow(dead_code)]
+    Tempo(f64),
+}
+
+#[derive(Clone, Copy)]
+pub struct Timed {
+    pub t_us: u64, // absolute time in microseconds since start
+    pub msg: Msg,
+    pub track: usize, // 0-based index of the track the event came from
+}
+
+/// A named position from a Marker or Cue Point meta event.
+#[derive(Clone)]
+pub struct Marker {
+    pub t_us: u64,
+    pub name: String,
+}
+
+/// A parsed song: the merged timeline plus what the player needs to know about its timing.
+pub struct Song {
+    /// Every channel message and tempo change, ordered by time.
+    pub timeline: Vec<Timed>,
+    /// Marker and Cue Point meta events, ordered by time.
+    pub markers: Vec<Marker>,
+    /// Time of the end of the song: its last End of Track, which can come after the last
+    /// event (a closing rest).
+    pub length_us: u64,
+    /// The first tempo in the file, in microseconds per quarter note (120 BPM if none).
+    pub initial_us_per_qn: f64,
+    /// The first time signature as (numerator, denominator as a power of two), 4/4 if none.
+    /// The numerator is at least 1 and the denominator at most `MAX_DENOMINATOR`.
+    pub initial_time_sig: (u8, u8),
+    /// The first track's TrackName, which is usually the song's title.
+    pub title: Option<String>,
+    /// The first Copyright meta event.
+    pub copyright: Option<String>,
+    /// Each track's TrackName, including tracks left out with `--tracks`.
+    pub track_names: Vec<Option<String>>,
+    /// The standard the file's first reset message names, if it has one.
+    pub standard: Option<Standard>,
+    /// The channels playing drum kits.
+    pub drums: DrumChannels,
+    /// The file's bars and beats, if it counts time in them.
+    pub bars: Option<BarMap>,
+}
+
+impl Song {
+    /// Parse SMF bytes, keeping only the selected `tracks`, transposing by `transpose` and
+    /// playing drum kits on the `drums` channels. A `lenient` parse recovers what it can
+    /// from a damaged file.
+    pub fn parse(bytes: &[u8], tracks: &[String], transpose: i8, drums: DrumChannels, lenient: bool) -> Result<Self> {
+        let smf = if lenient {
+            lenient::parse(bytes).with_context(|| "parsing MIDI")?
+        } else {
+            Smf::parse(bytes).with_context(|| "parsing MIDI (--lenient plays what can be read of a damaged file)")?
+        };
+
+        // Work out which tracks to play. Excluded tracks still contribute their tempo changes,
+        // except in format 2 files.
+        let track_names: Vec<Option<String>> = smf.tracks.iter().map(|tr| track_name(tr)).collect();
+        let included = select_tracks(&track_names, tracks)?;
+        if !tracks.is_empty() {
+            for (n, name) in track_names.iter().enumerate().filter(|(n, _)| included[*n]) {
+                info!("Playing track {}: {}", n + 1, name.as_deref().unwrap_or("(unnamed)"));
+            }
+        }
+
+        // Timing setup.
+        // A tempo change holds for every track from its tick on, whichever track it is in
+        // (usually the first), so ticks are turned into time through one map for the file.
+        // Format 2 files are the exception: each track is a pattern of its own, with its own
+        // tempo, and the patterns play one after another.
+        match smf.header.timing {
+            midly::Timing::Metrical(t) => debug!("PPQ (ticks per quarter note): {}", t.as_int()),
+            midly::Timing::Timecode(fps, sub) => debug!("SMPTE timing: {} fps, {} ticks per frame", fps.as_f32(), sub),
+        }
+        let sequential = smf.header.format == midly::Format::Sequential;
+        let maps: Vec<TempoMap> = if sequential {
+            info!(
+                "Format 2 file: playing its {} patterns one after another",
+                included.iter().filter(|&&i| i).count()
+            );
+            (0..smf.tracks.len()).map(|track| TempoMap::of_track(&smf, track)).collect()
+        } else {
+            vec![TempoMap::new(&smf)]
+        };
+        let first = if sequential { included.iter().position(|&i| i).unwrap_or(0) } else { 0 };
+        // The tempo at the start, 120 BPM if the file does not set one.
+        let initial_us_per_qn =
+            maps.get(first).and_then(|map| map.changes().first()).map_or(DEFAULT_US_PER_QN, |c| c.us_per_qn);
+        debug!("Initial tempo: {} µs per quarter note (~{:.1} BPM)",
+             initial_us_per_qn, 60_000_000.0 / initial_us_per_qn);
+
+        // Likewise the first Time Signature (numerator, denominator as a power of two), 4/4 if none.
+        // One with no beats or a beat shorter than a 1/64 note can only come from a damaged
+        // file, and is taken as 4/4 too.
+        let initial_time_sig = smf
+            .tracks
+            .iter()
+            .flatten()
+            .find_map(|ev| match ev.kind {
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numer, denom, _, _)) => Some((numer, denom)),
+                _ => None,
+            })
+            .filter(|&(numer, denom)| numer > 0 && denom <= MAX_DENOMINATOR)
+            .unwrap_or((4, 2));
+
+        // Build a single timeline of timestamped events.
+        // We convert each track’s delta ticks to absolute time in microseconds, then merge.
+        let mut timeline: Vec<Timed> = Vec::new();
+        let mut markers: Vec<Marker> = Vec::new();
+        let mut copyright = None;
+        // The SysEx messages left unused, by kind.
+        let mut passed: BTreeMap<&str, usize> = BTreeMap::new();
+
+        // Walk every track and accumulate absolute tick count, converted to time through the
+        // tempo map. A format 2 pattern starts where the one before it ended, and one left
+        // out is skipped altogether.
+        let mut offset_us = 0;
+        let mut length_us = 0;
+        for (track, (tr, &include)) in smf.tracks.iter().zip(&included).enumerate() {
+            if sequential && !include {
+                continue;
+            }
+            let tempo = &maps[if sequential { track } else { 0 }];
+            let mut abs_ticks: u64 = 0;
+
+            for ev in tr {
+                abs_ticks += ev.delta.as_int() as u64;
+                let t_us = offset_us + tempo.to_us(abs_ticks);
+
+                match ev.kind {
+                    // Metadata
+                    TrackEventKind::Meta(m) => {
+                        match m {
+                            // Already in the tempo map; kept in the timeline for the record.
+                            MetaMessage::Tempo(tp) => {
+                                let us_per_qn = tp.as_int() as f64;
+                                timeline.push(Timed { t_us, msg: Msg::Tempo(us_per_qn), track });
+                                debug!("Tempo change at {} µs: {:.1} BPM", t_us, 60_000_000.0 / us_per_qn);
+                            }
+                            MetaMessage::TimeSignature(numer, denom, _, _) => {
+                                match 1u32.checked_shl(denom.into()) {
+                                    Some(denom) => debug!("Time signature: {numer}/{denom}"),
+                                    None => debug!("Time signature: {numer}/2^{denom}, out of range"),
+                                }
+                            }
+                            MetaMessage::KeySignature(key, scale) => {
+                                debug!("Key signature: {:?} ({})", key, if !scale { "major" } else { "minor" });
+                            }
+                            MetaMessage::TrackName(name) => {
+                                if let Ok(s) = std::str::from_utf8(name) {
+                                    debug!("Track name: {}", s);
+                                }
+                            }
+                            MetaMessage::Copyright(text) if copyright.is_none() => {
+                                let text = String::from_utf8_lossy(text).trim().to_string();
+                                copyright = Some(text).filter(|c| !c.is_empty());
+                            }
+                            // Named positions to jump between during playback.
+                            MetaMessage::Marker(name) | MetaMessage::CuePoint(name) => {
+                                let name = String::from_utf8_lossy(name).trim().to_string();
+                                markers.push(Marker { t_us, name });
+                            }
+                            _ => {}
+                        }
+                    }
+                    // Setup for the whole synth, so taken from every track, as tempo is.
+                    TrackEventKind::SysEx(data) => match sysex::decode(data) {
+                        SysEx::Reset(standard) => {
+                            debug!("{} reset at {} µs", standard.name(), t_us);
+                            timeline.push(Timed { t_us, msg: Msg::Reset(standard), track });
+                        }
+                        SysEx::MasterVolume(level) => {
+                            debug!("Master volume at {} µs: {:.0}%", t_us, level * 100.0);
+                            timeline.push(Timed { t_us, msg: Msg::MasterVolume(level), track });
+                        }
+                        SysEx::Other(kind) => {
+                            *passed.entry(kind).or_default() += 1;
+                            timeline.push(Timed { t_us, msg: Msg::SysEx(sysex::keep(data)), track });
+                        }
+                    },
+                    // MIDI messages
+                    TrackEventKind::Midi { channel, message } if include => {
+                        let ch = u8::from(channel);
+                        let key_of = |key: midly::num::u7| transpose_key(drums, ch, key.as_int(), transpose);
+                        use midly::MidiMessage::*;
+                        match message {
+                            NoteOn { key, vel } if vel.as_int() == 0 => {
+                                // normalize to NoteOff to avoid any synth-specific ambiguity
+                                timeline.push(Timed { t_us, msg: Msg::NoteOff(ch, key_of(key), 0), track });
+                            }
+                            NoteOn { key, vel } => {
+                                timeline.push(Timed { t_us, msg: Msg::NoteOn(ch, key_of(key), vel.as_int()), track });
+                            }
+                            NoteOff { key, vel } => {
+                                timeline.push(Timed { t_us, msg: Msg::NoteOff(ch, key_of(key), vel.as_int()), track });
+                            }
+                            ProgramChange { program } => {
+                                timeline.push(Timed { t_us, msg: Msg::Program(ch, program.as_int()), track });
+                            }
+                            Controller { controller, value } => {
+                                timeline.push(Timed { t_us, msg: Msg::Control(ch, controller.as_int(), value.as_int()), track });
+                            }
+                            PitchBend { bend } => {
+                                let raw = bend.0.as_int(); 
+                                timeline.push(Timed { t_us, msg: Msg::PitchBend(ch, raw), track });
+                            }
+                            Aftertouch { key, vel } => {
+                                timeline.push(Timed { t_us, msg: Msg::AfterTouch(ch, key_of(key), vel.as_int()), track }); 
+                            }
+                            ChannelAftertouch { vel } => {
+                                timeline.push(Timed { t_us, msg: Msg::ChannelAftertouch(ch, vel.as_int()), track });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // Up to the track's End of Track.
+            length_us = length_us.max(offset_us + tempo.to_us(abs_ticks));
+            if sequential {
+                offset_us = length_us;
+            }
+        }
+
+        // Merge and order events from all tracks by absolute time.
+        timeline.sort_by_key(|e| e.t_us);
+        let standard = timeline.iter().find_map(|ev| match ev.msg {
+            Msg::Reset(standard) => Some(standard),
+            _ => None,
+        });
+        match standard {
+            Some(standard) => info!("Standard: {}", standard.name()),
+            None => debug!("No GM, GS or XG reset; playing as General MIDI"),
+        }
+        resolve_banks(&mut timeline);
+        if drums != DrumChannels::default() {
+            info!("Drum channels: {drums}");
+            timeline = apply_drum_channels(timeline, drums);
+        }
+        resolve_bend_ranges(&mut timeline);
+        hold_overlapping_notes(&mut timeline);
+        release_hanging_notes(&mut timeline, length_us, drums);
+        if timeline.iter().any(|ev| matches!(ev.msg, Msg::MasterVolume(_))) {
+            timeline = apply_master_volume(timeline);
+        }
+        if passed.contains_key("MT-32") {
+            warn!("This file sets up a Roland MT-32 with SysEx messages, which a SoundFont cannot follow; \
+                   it plays with General MIDI instruments instead, unless sent to an MT-32 with --midi-out");
+        }
+        if !passed.is_empty() {
+            let kinds: Vec<String> = passed.iter().map(|(kind, n)| format!("{kind} ×{n}")).collect();
+            debug!("SysEx messages passed to the synth as they are: {}", kinds.join(", "));
+        }
+
+        markers.sort_by_key(|m| m.t_us);
+        markers.dedup_by(|a, b| a.t_us == b.t_us && a.name == b.name);
+        if !markers.is_empty() {
+            info!("Markers:");
+            for m in &markers {
+                info!("  {}  {}", format_duration(m.t_us), m.name);
+            }
+        }
+
+        debug!("Total events parsed: {}", timeline.len());
+        info!("Length: {}", format_duration(length_us));
+
+        Ok(Song {
+            timeline,
+            markers,
+            length_us,
+            initial_us_per_qn,
+            initial_time_sig,
+            title: track_names.first().cloned().flatten().filter(|name| !name.is_empty()),
+            copyright,
+            track_names,
+            standard,
+            drums,
+            bars: BarMap::new(&smf),
+        })
+    }
+
+    /// Hold the channels in `overrides` to their bank and program: the file's own Bank
+    /// Selects and Program Changes on them are dropped, and the override is set at the
+    /// start and again after every reset, which would put the channel back to piano.
+    pub fn override_programs(&mut self, overrides: &[ProgramOverride]) {
+        if overrides.is_empty() {
+            return;
+        }
+        for o in overrides {
+            let name = if o.bank == 128 { "drum kit" } else { GM_PROGRAMS[o.program as usize] };
+            info!("Channel {}: bank {}, program {} ({name})", o.channel + 1, o.bank, o.program);
+        }
+        let held = |ch: u8| overrides.iter().any(|o| o.channel == ch);
+        let set = |t_us: u64, track: usize| {
+            overrides.iter().flat_map(move |o| {
+                [Msg::Bank(o.channel, o.bank), Msg::Program(o.channel, o.program)].map(|msg| Timed { t_us, msg, track })
+            })
+        };
+        let mut timeline = Vec::with_capacity(self.timeline.len() + 2 * overrides.len());
+        timeline.extend(set(0, 0));
+        for ev in self.timeline.drain(..) {
+            match ev.msg {
+                Msg::Bank(ch, _) | Msg::Program(ch, _) if held(ch) => continue,
+                Msg::Reset(_) => {
+                    timeline.push(ev);
+                    timeline.extend(set(ev.t_us, ev.track));
+                }
+                _ => timeline.push(ev),
+            }
+        }
+        self.timeline = timeline;
+    }
+}
+
+/// MIDI channel 10 (index 9) is reserved for percussion in General MIDI.
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// The largest Time Signature denominator taken from a file, as a power of two: a 1/64 note.
+const MAX_DENOMINATOR: u8 = 6;
+
+/// Shift a note by `semitones`, leaving the percussion channels alone (their keys pick drum
+/// sounds, not pitches). Notes pushed outside 0–127 are folded back by octaves, so they keep
+/// their pitch class instead of piling up on the highest or lowest key.
+pub fn transpose_key(drums: DrumChannels, ch: u8, key: u8, semitones: i8) -> u8 {
+    if drums.contains(ch) {
+        return key;
+    }
+    let mut k = key as i16 + semitones as i16;
+    while k > 127 {
+        k -= 12;
+    }
+    while k < 0 {
+        k += 12;
+    }
+    k as u8
+}
+
+/// Whether `path` is named like a MIDI file.
+pub fn is_midi(path: &Path) -> bool {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    matches!(ext.as_str(), "mid" | "midi" | "kar" | "smf" | "rmi")
+}
+
+/// The first TrackName meta event of a track, if any.
+pub fn track_name(track: &[midly::TrackEvent]) -> Option<String> {
+    track.iter().find_map(|ev| match ev.kind {
+        TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+            Some(String::from_utf8_lossy(name).trim().to_string())
+        }
+        _ => None,
+    })
+}
+
+/// Resolve `--tracks` selectors (1-based numbers or case-insensitive track names) into an
+/// include flag per track. No selectors means every track plays.
+pub fn select_tracks(names: &[Option<String>], selectors: &[String]) -> Result<Vec<bool>> {
+    if selectors.is_empty() {
+        return Ok(vec![true; names.len()]);
+    }
+    let mut included = vec![false; names.len()];
+    for sel in selectors {
+        let sel = sel.trim();
+        let hits: Vec<usize> = match sel.parse::<usize>() {
+            Ok(n) if (1..=names.len()).contains(&n) => vec![n - 1],
+            Ok(_) => Vec::new(),
+            Err(_) => (0..names.len())
+                .filter(|&i| names[i].as_deref().is_some_and(|name| name.eq_ignore_ascii_case(sel)))
+                .collect(),
+        };
+        if hits.is_empty() {
+            let available: Vec<String> = names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| format!("  {}: {}", i + 1, name.as_deref().unwrap_or("(unnamed)")))
+                .collect();
+            anyhow::bail!("no track matches `{sel}`. Available tracks:\n{}", available.join("\n"));
+        }
+        for i in hits {
+            included[i] = true;
+        }
+    }
+    Ok(included)
+}
+
+/// Turn the Bank Select controllers (CC0, CC32) into `Msg::Bank`s, read the way the
+/// standard of the last reset has them. GS files pick their variation banks with the MSB,
+/// and SoundFonts number those banks the same way; the LSB only chooses which Sound Canvas
+/// generation's map to use. XG files set the kind of voice with the MSB: 0 for the normal
+/// voices, with the variation bank in the LSB, and 127 for drum kits, which a SoundFont
+/// keeps in bank 128. Left to itself FluidLite would add the LSB to the MSB times 128 and
+/// look for a bank no SoundFont has.
+fn resolve_banks(timeline: &mut [Timed]) {
+    let mut xg = false;
+    let (mut msb, mut lsb) = ([0u8; 16], [0u8; 16]);
+    for ev in timeline {
+        match ev.msg {
+            Msg::Reset(standard) => {
+                xg = standard == Standard::Xg;
+                (msb, lsb) = ([0; 16], [0; 16]);
+            }
+            Msg::Control(ch, cc @ (0 | 32), value) => {
+                let c = ch as usize;
+                if cc == 0 {
+                    msb[c] = value;
+                } else {
+                    lsb[c] = value;
+                }
+                let bank = match (xg, msb[c]) {
+                    (true, 127) => 128,
+                    (true, 0) => u16::from(lsb[c]),
+                    (_, msb) => u16::from(msb),
+                };
+                ev.msg = Msg::Bank(ch, bank);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Turn the Data Entry controllers that set RPN 0, Pitch Bend Sensitivity, into
+/// `Msg::BendRange`s. Which parameter Data Entry (CC6, CC38) and Data Increment / Decrement
+/// (CC96, CC97) change is whatever the last RPN (CC101, CC100) or NRPN (CC99, CC98) select
+/// chose, so each channel's selection is followed through the song. Increments step by a
+/// semitone, and Reset All Controllers leaves nothing selected, as does the RPN null 127,127.
+fn resolve_bend_ranges(timeline: &mut [Timed]) {
+    const NONE: (u8, u8) = (127, 127);
+    // Per channel: the RPN selected, and the bend range in semitones and cents.
+    let mut selected = [NONE; 16];
+    let mut range = [(2u8, 0u8); 16];
+    for ev in timeline {
+        if let Msg::Reset(_) = ev.msg {
+            (selected, range) = ([NONE; 16], [(2, 0); 16]);
+        }
+        let Msg::Control(ch, cc, value) = ev.msg else { continue };
+        let (selected, range) = (&mut selected[ch as usize], &mut range[ch as usize]);
+        match cc {
+            101 => selected.0 = value,
+            100 => selected.1 = value,
+            98 | 99 | 121 => *selected = NONE,
+            6 | 38 | 96 | 97 if *selected == (0, 0) => {
+                *range = match cc {
+                    6 => (value, 0),
+                    38 => (range.0, value),
+                    96 => (range.0.saturating_add(1).min(127), range.1),
+                    _ => (range.0.saturating_sub(1), range.1),
+                };
+                ev.msg = Msg::BendRange(ch, range.0, range.1);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Let a key struck again before it was released sound until its last Note Off. The synth
+/// ends every voice on a key at a single Note Off, so a repeated piano note would be cut
+/// short by the Note Off of the strike before it: Note Ons are counted per channel and key,
+/// and each Note Off that leaves one still held is dropped. All Notes Off (CC123), All Sound
+/// Off (CC120) and a reset release everything on the channel, so they clear its counts.
+fn hold_overlapping_notes(timeline: &mut Vec<Timed>) {
+    let mut held = [[0u16; 128]; 16];
+    timeline.retain(|ev| match ev.msg {
+        Msg::NoteOn(ch, key, _) => {
+            let n = &mut held[ch as usize][key as usize];
+            *n = n.saturating_add(1);
+            true
+        }
+        Msg::NoteOff(ch, key, _) => {
+            let n = &mut held[ch as usize][key as usize];
+            *n = n.saturating_sub(1);
+            *n == 0
+        }
+        Msg::Control(ch, 120 | 123, _) => {
+            held[ch as usize] = [0; 128];
+            true
+        }
+        Msg::Reset(_) => {
+            held = [[0; 128]; 16];
+            true
+        }
+        _ => true,
+    });
+}
+
+/// End the notes the file never releases (a Note Off left out, or a track that ends
+/// mid-note) with a Note Off at `end_us`, the end of the song, so none drones on past it.
+/// Drum channels often leave their Note Offs out on purpose, as a drum hit plays out anyway,
+/// so only the other channels' hanging notes are warned about.
+fn release_hanging_notes(timeline: &mut Vec<Timed>, end_us: u64, drums: DrumChannels) {
+    // When each key's note started, and in which track, while it sounds.
+    let mut sounding: [[Option<(u64, usize)>; 128]; 16] = [[None; 128]; 16];
+    for ev in timeline.iter() {
+        match ev.msg {
+            Msg::NoteOn(ch, key, _) => {
+                sounding[ch as usize][key as usize].get_or_insert((ev.t_us, ev.track));
+            }
+            Msg::NoteOff(ch, key, _) => sounding[ch as usize][key as usize] = None,
+            Msg::Control(ch, 120 | 123, _) => sounding[ch as usize] = [None; 128],
+            Msg::Reset(_) => sounding = [[None; 128]; 16],
+            _ => {}
+        }
+    }
+    let mut hanging = Vec::new();
+    for (ch, keys) in (0..16u8).zip(&sounding) {
+        for (key, &started) in (0..128u8).zip(keys) {
+            let Some((t_us, track)) = started else { continue };
+            timeline.push(Timed { t_us: end_us, msg: Msg::NoteOff(ch, key, 0), track });
+            if !drums.contains(ch) {
+                hanging.push(format!("channel {} {} at {}", ch + 1, key_name(key), format_duration(t_us)));
+            }
+        }
+    }
+    if !hanging.is_empty() {
+        const SHOWN: usize = 5;
+        let more = hanging.len().saturating_sub(SHOWN);
+        hanging.truncate(SHOWN);
+        let list = hanging.join(", ") + &if more > 0 { format!(" and {more} more") } else { String::new() };
+        warn!("Notes never released, so ended with the song: {list}");
+    }
+}
+
+/// A key's note name, with middle C (60) as C4.
+fn key_name(key: u8) -> String {
+    const NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    format!("{}{}", NAMES[key as usize % 12], i32::from(key / 12) - 1)
+}
+
+/// Put the drum channels besides channel 10 on the percussion bank, at the start and again
+/// after every reset. Channel 10 is the synth's own to set up. The synth only changes kit at
+/// a Program Change, so one follows each bank. The file's own Bank Selects on these channels
+/// are dropped, as the synth ignores them on channel 10: a GM file sets bank 0 on every
+/// channel.
+fn apply_drum_channels(timeline: Vec<Timed>, drums: DrumChannels) -> Vec<Timed> {
+    let extra = |ch: u8| ch != DRUM_CHANNEL && drums.contains(ch);
+    let switch = |t_us: u64, track: usize| {
+        (0..16u8)
+            .filter(move |&ch| extra(ch))
+            .flat_map(move |ch| [Msg::Bank(ch, 128), Msg::Program(ch, 0)].map(|msg| Timed { t_us, msg, track }))
+    };
+    let mut out = Vec::with_capacity(timeline.len() + 32);
+    out.extend(switch(0, 0));
+    for ev in timeline {
+        match ev.msg {
+            Msg::Bank(ch, _) if extra(ch) => continue,
+            Msg::Reset(_) => {
+                out.push(ev);
+                out.extend(switch(ev.t_us, ev.track));
+            }
+            _ => out.push(ev),
+        }
+    }
+    out
+}
+
+/// Apply the master volume changes through the channel volumes. FluidLite has only its
+/// gain, which the player uses for itself, so each `Msg::MasterVolume` becomes a Volume
+/// (CC7) for every channel, scaled by the square root of the level as a SoundFont's volume
+/// curve squares it, and later Volumes are scaled the same. A reset brings the master
+/// volume back to full and the channels to 100.
+fn apply_master_volume(timeline: Vec<Timed>) -> Vec<Timed> {
+    let mut volume = [100u8; 16];
+    let mut scale = 1f32;
+    let scaled = |v: u8, scale: f32| (f32::from(v) * scale).round() as u8;
+    let mut out = Vec::with_capacity(timeline.len());
+    for ev in timeline {
+        match ev.msg {
+            Msg::Reset(_) => (volume, scale) = ([100; 16], 1.0),
+            Msg::MasterVolume(level) => {
+                scale = level.clamp(0.0, 1.0).sqrt();
+                let msg = |ch: u8| Msg::Control(ch, 7, scaled(volume[ch as usize], scale));
+                out.extend((0..16).map(|ch| Timed { msg: msg(ch), ..ev }));
+                continue;
+            }
+            Msg::Control(ch, 7, v) => {
+                volume[ch as usize] = v;
+                out.push(Timed { msg: Msg::Control(ch, 7, scaled(v, scale)), ..ev });
+                continue;
+            }
+            _ => {}
+        }
+        out.push(ev);
+    }
+    out
+}
+
+/// Describe the instrument on a channel: the GM name of its first Program Change, the GM
+/// default (Acoustic Grand Piano) if it never gets one, or the drum kit on a drum channel or
+/// one the file puts on the percussion bank.
+pub fn channel_instrument(song: &Song, ch: u8) -> &'static str {
+    if song.drums.contains(ch) {
+        return "Drum kit";
+    }
+    let mut bank = 0;
+    let mut program = None;
+    for e in &song.timeline {
+        match e.msg {
+            Msg::Bank(c, b) if c == ch => bank = b,
+            Msg::Program(c, p) if c == ch => {
+                program = Some(p);
+                break;
+            }
+            _ => {}
+        }
+    }
+    if bank == 128 {
+        return "Drum kit";
+    }
+    GM_PROGRAMS[program.unwrap_or(0) as usize & 0x7f]
+}
+
+/// General MIDI Level 1 program names, indexed by program number (0–127).
+pub const GM_PROGRAMS: [&str; 128] = [
+    "Acoustic Grand Piano", "Bright Acoustic Piano", "Electric Grand Piano", "Honky-tonk Piano",
+    "Electric Piano 1", "Electric Piano 2", "Harpsichord", "Clavinet",
+    "Celesta", "Glockenspiel", "Music Box", "Vibraphone",
+    "Marimba", "Xylophone", "Tubular Bells", "Dulcimer",
+    "Drawbar Organ", "Percussive Organ", "Rock Organ", "Church Organ",
+    "Reed Organ", "Accordion", "Harmonica", "Tango Accordion",
+    "Acoustic Guitar (nylon)", "Acoustic Guitar (steel)", "Electric Guitar (jazz)", "Electric Guitar (clean)",
+    "Electric Guitar (muted)", "Overdriven Guitar", "Distortion Guitar", "Guitar Harmonics",
+    "Acoustic Bass", "Electric Bass (finger)", "Electric Bass (pick)", "Fretless Bass",
+    "Slap Bass 1", "Slap Bass 2", "Synth Bass 1", "Synth Bass 2",
+    "Violin", "Viola", "Cello", "Contrabass",
+    "Tremolo Strings", "Pizzicato Strings", "Orchestral Harp", "Timpani",
+    "String Ensemble 1", "String Ensemble 2", "Synth Strings 1", "Synth Strings 2",
+    "Choir Aahs", "Voice Oohs", "Synth Voice", "Orchestra Hit",
+    "Trumpet", "Trombone", "Tuba", "Muted Trumpet",
+    "French Horn", "Brass Section", "Synth Brass 1", "Synth Brass 2",
+    "Soprano Sax", "Alto Sax", "Tenor Sax", "Baritone Sax",
+    "Oboe", "English Horn", "Bassoon", "Clarinet",
+    "Piccolo", "Flute", "Recorder", "Pan Flute",
+    "Blown Bottle", "Shakuhachi", "Whistle", "Ocarina",
+    "Lead 1 (square)", "Lead 2 (sawtooth)", "Lead 3 (calliope)", "Lead 4 (chiff)",
+    "Lead 5 (charang)", "Lead 6 (voice)", "Lead 7 (fifths)", "Lead 8 (bass + lead)",
+    "Pad 1 (new age)", "Pad 2 (warm)", "Pad 3 (polysynth)", "Pad 4 (choir)",
+    "Pad 5 (bowed)", "Pad 6 (metallic)", "Pad 7 (halo)", "Pad 8 (sweep)",
+    "FX 1 (rain)", "FX 2 (soundtrack)", "FX 3 (crystal)", "FX 4 (atmosphere)",
+    "FX 5 (brightness)", "FX 6 (goblins)", "FX 7 (echoes)", "FX 8 (sci-fi)",
+    "Sitar", "Banjo", "Shamisen", "Koto",
+    "Kalimba", "Bagpipe", "Fiddle", "Shanai",
+    "Tinkle Bell", "Agogo", "Steel Drums", "Woodblock",
+    "Taiko Drum", "Melodic Tom", "Synth Drum", "Reverse Cymbal",
+    "Guitar Fret Noise", "Breath Noise", "Seashore", "Bird Tweet",
+    "Telephone Ring", "Helicopter", "Applause", "Gunshot",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A timeline of `msgs`, a millisecond apart, all from the first track.
+    fn timeline(msgs: &[Msg]) -> Vec<Timed> {
+        (0..).zip(msgs).map(|(n, &msg)| Timed { t_us: n * 1000, msg, track: 0 }).collect()
+    }
+
+    fn msgs(timeline: &[Timed]) -> Vec<Msg> {
+        timeline.iter().map(|ev| ev.msg).collect()
+    }
+
+    #[test]
+    fn takes_the_bank_from_the_msb_outside_xg() {
+        let mut events = timeline(&[Msg::Control(0, 0, 8), Msg::Control(0, 32, 2), Msg::Control(1, 32, 5)]);
+        resolve_banks(&mut events);
+        assert_eq!(msgs(&events), [Msg::Bank(0, 8), Msg::Bank(0, 8), Msg::Bank(1, 0)]);
+    }
+
+    #[test]
+    fn reads_xg_banks_from_the_lsb_and_drum_kits_from_msb_127() {
+        let mut events = timeline(&[
+            Msg::Reset(Standard::Xg),
+            Msg::Control(0, 0, 0),
+            Msg::Control(0, 32, 3),
+            Msg::Control(9, 0, 127),
+            Msg::Reset(Standard::Gs),
+            Msg::Control(0, 32, 3),
+        ]);
+        resolve_banks(&mut events);
+        let expected = [Msg::Bank(0, 0), Msg::Bank(0, 3), Msg::Bank(9, 128), Msg::Reset(Standard::Gs), Msg::Bank(0, 0)];
+        assert_eq!(msgs(&events)[1..], expected);
+    }
+
+    #[test]
+    fn sets_the_bend_range_through_rpn_0() {
+        let mut events = timeline(&[
+            Msg::Control(2, 101, 0),
+            Msg::Control(2, 100, 0),
+            Msg::Control(2, 6, 12),
+            Msg::Control(2, 38, 50),
+            Msg::Control(2, 96, 0),
+            Msg::Control(2, 97, 0),
+            Msg::Control(2, 97, 0),
+        ]);
+        resolve_bend_ranges(&mut events);
+        let expected = [
+            Msg::BendRange(2, 12, 0),
+            Msg::BendRange(2, 12, 50),
+            Msg::BendRange(2, 13, 50),
+            Msg::BendRange(2, 12, 50),
+            Msg::BendRange(2, 11, 50),
+        ];
+        assert_eq!(msgs(&events)[2..], expected);
+    }
+
+    #[test]
+    fn leaves_data_entry_for_other_parameters_alone() {
+        let mut events = timeline(&[
+            Msg::Control(0, 6, 12),
+            Msg::Control(0, 101, 0),
+            Msg::Control(0, 100, 1),
+            Msg::Control(0, 6, 64),
+            Msg::Control(0, 100, 0),
+            Msg::Control(0, 99, 1),
+            Msg::Control(0, 6, 24),
+            Msg::Control(0, 101, 0),
+            Msg::Control(0, 100, 0),
+            Msg::Control(0, 121, 0),
+            Msg::Control(0, 6, 24),
+        ]);
+        let before = msgs(&events);
+        resolve_bend_ranges(&mut events);
+        assert_eq!(msgs(&events), before);
+    }
+
+    #[test]
+    fn puts_the_extra_drum_channels_on_the_percussion_bank_after_each_reset() {
+        let drums = DrumChannels::from_numbers(&[10, 11]);
+        let events = timeline(&[Msg::Bank(10, 0), Msg::Bank(9, 0), Msg::Reset(Standard::Gm), Msg::NoteOn(10, 36, 100)]);
+        let events = apply_drum_channels(events, drums);
+        let expected = [
+            Msg::Bank(10, 128),
+            Msg::Program(10, 0),
+            Msg::Bank(9, 0),
+            Msg::Reset(Standard::Gm),
+            Msg::Bank(10, 128),
+            Msg::Program(10, 0),
+            Msg::NoteOn(10, 36, 100),
+        ];
+        assert_eq!(msgs(&events), expected);
+        assert_eq!(events[4].t_us, 2000);
+    }
+
+    #[test]
+    fn leaves_general_midi_drums_alone() {
+        let events = timeline(&[Msg::Bank(9, 0), Msg::Reset(Standard::Gm)]);
+        let events = apply_drum_channels(events, DrumChannels::default());
+        assert_eq!(msgs(&events), [Msg::Bank(9, 0), Msg::Reset(Standard::Gm)]);
+    }
+
+    #[test]
+    fn holds_a_restruck_key_until_its_last_note_off() {
+        let mut events = timeline(&[
+            Msg::NoteOn(0, 60, 100),
+            Msg::NoteOn(0, 60, 90),
+            Msg::NoteOff(0, 60, 0),
+            Msg::NoteOn(1, 60, 80),
+            Msg::NoteOff(1, 60, 0),
+            Msg::NoteOff(0, 60, 0),
+        ]);
+        hold_overlapping_notes(&mut events);
+        let expected = [
+            Msg::NoteOn(0, 60, 100),
+            Msg::NoteOn(0, 60, 90),
+            Msg::NoteOn(1, 60, 80),
+            Msg::NoteOff(1, 60, 0),
+            Msg::NoteOff(0, 60, 0),
+        ];
+        assert_eq!(msgs(&events), expected);
+    }
+
+    #[test]
+    fn forgets_held_notes_at_all_notes_off() {
+        let mut events = timeline(&[
+            Msg::NoteOn(0, 60, 100),
+            Msg::NoteOn(0, 60, 100),
+            Msg::Control(0, 123, 0),
+            Msg::NoteOn(0, 60, 100),
+            Msg::NoteOff(0, 60, 0),
+        ]);
+        hold_overlapping_notes(&mut events);
+        assert_eq!(events.len(), 5);
+    }
+
+    #[test]
+    fn ends_unreleased_notes_with_the_song() {
+        let mut events = timeline(&[
+            Msg::NoteOn(0, 60, 100),
+            Msg::NoteOn(0, 64, 100),
+            Msg::NoteOff(0, 64, 0),
+            Msg::NoteOn(1, 67, 100),
+            Msg::Control(1, 123, 0),
+        ]);
+        events[0].track = 2;
+        release_hanging_notes(&mut events, 9000, DrumChannels::default());
+        assert_eq!(events.len(), 6);
+        let last = events[5];
+        assert_eq!((last.t_us, last.msg, last.track), (9000, Msg::NoteOff(0, 60, 0), 2));
+    }
+
+    #[test]
+    fn ends_drum_hits_too_but_not_notes_a_reset_ended() {
+        let mut events = timeline(&[Msg::NoteOn(9, 36, 100), Msg::NoteOn(9, 38, 100), Msg::Reset(Standard::Gm)]);
+        release_hanging_notes(&mut events, 5000, DrumChannels::default());
+        assert_eq!(events.len(), 3);
+        let mut events = timeline(&[Msg::NoteOn(9, 36, 100)]);
+        release_hanging_notes(&mut events, 5000, DrumChannels::default());
+        assert_eq!(msgs(&events), [Msg::NoteOn(9, 36, 100), Msg::NoteOff(9, 36, 0)]);
+    }
+
+    #[test]
+    fn applies_master_volume_through_every_channel_volume() {
+        let events = timeline(&[
+            Msg::Control(3, 7, 80),
+            Msg::MasterVolume(0.25),
+            Msg::Control(3, 7, 120),
+            Msg::Reset(Standard::Gm),
+            Msg::Control(3, 7, 120),
+        ]);
+        let events = apply_master_volume(events);
+        assert_eq!(events.len(), 1 + 16 + 3);
+        assert_eq!(events[1 + 3].msg, Msg::Control(3, 7, 40));
+        assert_eq!(events[1 + 4].msg, Msg::Control(4, 7, 50));
+        assert!(events[1..17].iter().all(|ev| ev.t_us == 1000));
+        assert_eq!(msgs(&events)[17..], [Msg::Control(3, 7, 60), Msg::Reset(Standard::Gm), Msg::Control(3, 7, 120)]);
+    }
+}
@@ -0,0 +1,418 @@
+//! Finding a SoundFont when none is given on the command line, and fetching one given as
+//! a URL.
+
+use crate::riff;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    env, fs,
+    io::{self, Read, Seek},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tracing::{debug, info};
+
+/// Environment variable naming the SoundFont to use when none is given.
+const SOUNDFONT_ENV: &str = "MIDI_PLAY_SOUNDFONT";
+
+/// Well-known General MIDI SoundFonts, best first. Matched case-insensitively against the
+/// start of the file name, so `FluidR3_GM.sf2` and `GeneralUser GS v1.471.sf2` both count.
+const PREFERRED: [&str; 8] = [
+    "fluidr3_gm",
+    "generaluser",
+    "musescore_general",
+    "default-gm",
+    "default",
+    "arachno",
+    "timgm6mb",
+    "fluidr3",
+];
+
+/// The SoundFont to load: `given` if there is one, then `$MIDI_PLAY_SOUNDFONT`, then the best
+/// GM SoundFont found in the usual install locations, then the one built in, if any. A URL,
+/// given or in the variable, is downloaded to the cache.
+pub fn resolve(given: Option<&str>) -> Result<String> {
+    if let Some(path) = given {
+        return local(path);
+    }
+    if let Some(path) = env::var_os(SOUNDFONT_ENV).filter(|p| !p.is_empty()) {
+        if let Some(url) = path.to_str().filter(|p| is_url(p)) {
+            return local(url);
+        }
+        let path = PathBuf::from(path);
+        if !path.is_file() {
+            anyhow::bail!("${SOUNDFONT_ENV} points to {}, which is not a file", path.display());
+        }
+        return Ok(path.display().to_string());
+    }
+
+    let dirs = search_dirs();
+    let mut found: Vec<PathBuf> = dirs.iter().flat_map(|d| soundfonts_in(d)).collect();
+    found.sort_by_key(|p| rank(p));
+    match found.first() {
+        Some(path) => {
+            info!("Found SoundFont: {}", path.display());
+            Ok(path.display().to_string())
+        }
+        #[cfg(feature = "embedded-soundfont")]
+        None => {
+            let path = embedded()?;
+            info!("No SoundFont found; using the one built in");
+            Ok(path.display().to_string())
+        }
+        #[cfg(not(feature = "embedded-soundfont"))]
+        None => {
+            let searched: Vec<String> = dirs.iter().map(|d| format!("  {}", d.display())).collect();
+            anyhow::bail!(
+                "no SoundFont found. Pass one after the MIDI file or set ${SOUNDFONT_ENV}. Searched:\n{}",
+                searched.join("\n")
+            )
+        }
+    }
+}
+
+/// The SoundFonts to load, in priority order: those given with `--soundfont` first, then
+/// the main one, which `resolve` finds if it is not given. With `--soundfont` and no main
+/// one, nothing is searched for.
+pub fn resolve_all(given: Option<&str>, over: &[String]) -> Result<Vec<String>> {
+    if given.is_none() && !over.is_empty() {
+        return Ok(over.to_vec());
+    }
+    let main = resolve(given)?;
+    over.iter().map(|sf| local(sf)).chain([Ok(main)]).collect()
+}
+
+/// Whether a SoundFont argument is a URL to download rather than a path. A plain `http://`
+/// one is downloaded only with a checksum; see `download`.
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("https://") || s.starts_with("http://")
+}
+
+/// `path` itself, or for a URL the downloaded copy in the cache.
+fn local(path: &str) -> Result<String> {
+    if is_url(path) {
+        return Ok(download(path)?.display().to_string());
+    }
+    Ok(path.to_string())
+}
+
+/// Download the SoundFont at `url` into `<cache>/soundfonts`, under a hash of the URL, or
+/// reuse the copy there from an earlier run. A `#sha256=HEX` fragment gives the file's
+/// checksum, which the download must match; it is part of the URL hashed, so giving a new
+/// one downloads afresh.
+///
+/// Without a checksum only HTTPS is trusted, redirects included, so nothing on the way can
+/// swap the font for another; a plain `http://` URL needs the checksum.
+///
+/// The download is left to `curl`, which every desktop system now has, rather than
+/// building an HTTPS client in.
+fn download(url: &str) -> Result<PathBuf> {
+    let (address, sha256) = match url.split_once("#sha256=") {
+        Some((address, hex)) => (address, Some(hex.to_ascii_lowercase())),
+        None if url.starts_with("http://") => {
+            anyhow::bail!("{url} is plain HTTP; use https:// or end it with #sha256= and the file's checksum")
+        }
+        None => (url, None),
+    };
+    let ext = Path::new(address.split(['?', '#']).next().unwrap_or(address))
+        .extension()
+        .and_then(|e| e.to_str())
+        .filter(|e| e.eq_ignore_ascii_case("sf3"))
+        .map_or("sf2", |_| "sf3");
+    let dir = cache_dir().join("soundfonts");
+    let path = dir.join(format!("{:016x}.{ext}", crate::resume::fnv1a(url.as_bytes())));
+    if path.is_file() {
+        debug!("Using the copy of {address} in {}", path.display());
+        return Ok(path);
+    }
+
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    // Downloaded under another name and renamed, so an interrupted download is never taken
+    // for the font; the number keeps render workers fetching the same font apart.
+    static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+    let n = DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+    let part = path.with_extension(format!("part{}-{n}", std::process::id()));
+    info!("Downloading SoundFont {address}");
+    let mut curl = Command::new("curl");
+    if sha256.is_none() {
+        curl.args(["--proto", "=https", "--proto-redir", "=https"]);
+    }
+    let status = curl
+        .args(["--fail", "--location", "--silent", "--show-error", "--output"])
+        .arg(&part)
+        .arg(address)
+        .status()
+        .context("running curl to download the SoundFont; is curl installed?")?;
+    if !status.success() {
+        let _ = fs::remove_file(&part);
+        anyhow::bail!("downloading {address} failed (curl {status})");
+    }
+    if let Some(expected) = sha256 {
+        let data = fs::read(&part).with_context(|| format!("reading {}", part.display()))?;
+        let actual: String = Sha256::digest(&data).iter().map(|b| format!("{b:02x}")).collect();
+        if actual != expected {
+            let _ = fs::remove_file(&part);
+            anyhow::bail!("{address} has SHA-256 {actual}, not the {expected} given");
+        }
+    }
+    fs::rename(&part, &path).with_context(|| format!("writing {}", path.display()))?;
+    Ok(path)
+}
+
+/// Where downloaded and built-in SoundFonts are kept: `$XDG_CACHE_HOME/midi-play`, falling
+/// back to `~/.cache/midi-play`, or the temporary directory without a home.
+fn cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("midi-play")
+}
+
+/// The SoundFont built in with the `embedded-soundfont` feature: the file
+/// `$MIDI_PLAY_EMBED_SOUNDFONT` named at build time.
+#[cfg(feature = "embedded-soundfont")]
+const EMBEDDED: &[u8] = include_bytes!(env!(
+    "MIDI_PLAY_EMBED_SOUNDFONT",
+    "the embedded-soundfont feature needs MIDI_PLAY_EMBED_SOUNDFONT set to the absolute path of the SoundFont to build in"
+));
+
+/// The built-in SoundFont as a file, since the synths load SoundFonts by path.
+#[cfg(feature = "embedded-soundfont")]
+fn embedded() -> Result<PathBuf> {
+    cached("embedded", EMBEDDED)
+}
+
+/// A SoundFont held in memory as a file: written to the cache directory the first time,
+/// named `<prefix>-<hash>.sf2` after its contents, so another font is never taken for it.
+pub fn cached(prefix: &str, font: &[u8]) -> Result<PathBuf> {
+    let dir = cache_dir();
+    let path = dir.join(format!("{prefix}-{:016x}.sf2", crate::resume::fnv1a(font)));
+    if fs::metadata(&path).is_ok_and(|m| m.len() == font.len() as u64) {
+        return Ok(path);
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    // Written under another name and renamed, so an interrupted write is never taken for it;
+    // the number keeps render workers writing the same font apart.
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let n = WRITES.fetch_add(1, Ordering::Relaxed);
+    let part = path.with_extension(format!("part{}-{n}", std::process::id()));
+    fs::write(&part, font).with_context(|| format!("writing {}", part.display()))?;
+    fs::rename(&part, &path).with_context(|| format!("writing {}", path.display()))?;
+    Ok(path)
+}
+
+/// Where distributions and users put SoundFonts, most specific first.
+fn search_dirs() -> Vec<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).map(PathBuf::from);
+    let data = env::var_os("XDG_DATA_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|h| h.join(".local/share")));
+
+    let mut dirs = Vec::new();
+    if let Some(data) = &data {
+        dirs.push(data.join("soundfonts"));
+        dirs.push(data.join("sounds/sf2"));
+    }
+    if cfg!(target_os = "macos") {
+        if let Some(home) = &home {
+            dirs.push(home.join("Library/Audio/Sounds/Banks"));
+        }
+        dirs.push("/Library/Audio/Sounds/Banks".into());
+        dirs.push("/opt/homebrew/share/soundfonts".into());
+        dirs.push("/usr/local/share/soundfonts".into());
+    } else if cfg!(windows) {
+        if let Some(home) = &home {
+            dirs.push(home.join("soundfonts"));
+            dirs.push(home.join("Documents\\soundfonts"));
+        }
+        if let Some(local) = env::var_os("LOCALAPPDATA") {
+            dirs.push(PathBuf::from(local).join("soundfonts"));
+        }
+        dirs.push("C:\\soundfonts".into());
+    } else {
+        dirs.push("/usr/share/sounds/sf2".into());
+        dirs.push("/usr/share/soundfonts".into());
+        dirs.push("/usr/local/share/sounds/sf2".into());
+        dirs.push("/usr/local/share/soundfonts".into());
+    }
+    dirs
+}
+
+/// The `.sf2` and `.sf3` files directly inside `dir`. A missing directory just has none.
+fn soundfonts_in(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("sf2") || ext.eq_ignore_ascii_case("sf3"))
+                && p.is_file()
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Sort key: well-known GM fonts in `PREFERRED` order, then anything with "gm" in its name,
+/// then the rest. The sort is stable, so ties keep search order.
+fn rank(path: &Path) -> usize {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_lowercase()).unwrap_or_default();
+    PREFERRED
+        .iter()
+        .position(|p| name.starts_with(p))
+        .unwrap_or(if name.contains("gm") { PREFERRED.len() } else { PREFERRED.len() + 1 })
+}
+
+/// One preset header from a SoundFont's `phdr` chunk.
+pub struct Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+}
+
+/// What a SoundFont holds, from its headers.
+pub struct Font {
+    /// The name in its `INAM` chunk.
+    pub name: Option<String>,
+    /// The `ifil` version: 2 for SF2, 3 for SF3 with Ogg Vorbis samples.
+    pub version: (u16, u16),
+    pub presets: Vec<Preset>,
+    pub samples: usize,
+    /// The size of the sample data as stored, compressed in an SF3.
+    pub sample_bytes: u64,
+}
+
+/// Read a SoundFont's headers without loading its samples: only the `INFO` and `pdta` lists
+/// are read, the `sdta` list's headers being enough for the size of the samples.
+pub fn read(path: &str) -> Result<Font> {
+    let mut file = fs::File::open(path).with_context(|| format!("reading {path}"))?;
+    let mut head = [0; 12];
+    if file.read_exact(&mut head).is_err() || riff::form(&head, b"sfbk").is_none() {
+        anyhow::bail!("{path} is not a SoundFont file");
+    }
+    let lists = file.metadata().and_then(|meta| read_lists(&mut file, meta.len()));
+    let Lists { info, pdta, sample_bytes } = lists.with_context(|| format!("reading {path}"))?;
+    let info = info.as_deref();
+    let pdta = pdta.as_deref().context("SoundFont has no preset data")?;
+    let phdr = riff::chunk(pdta, b"phdr").context("SoundFont has no preset headers")?;
+    let u16_at = |r: &[u8], i: usize| u16::from_le_bytes([r[i], r[i + 1]]);
+    // Names are zero-padded, or run to the end of their field.
+    let text = |b: &[u8]| String::from_utf8_lossy(b.split(|&c| c == 0).next().unwrap_or(b)).trim().to_string();
+
+    // 38-byte records (a 20-byte name, then program and bank); the last is the "EOP" terminator.
+    let records: Vec<&[u8]> = phdr.chunks_exact(38).collect();
+    let presets = records[..records.len().saturating_sub(1)]
+        .iter()
+        .map(|r| Preset {
+            name: text(&r[..20]),
+            program: u16_at(r, 20),
+            bank: u16_at(r, 22),
+        })
+        .collect();
+    Ok(Font {
+        name: info.and_then(|info| riff::chunk(info, b"INAM")).map(text).filter(|n| !n.is_empty()),
+        version: info
+            .and_then(|info| riff::chunk(info, b"ifil"))
+            .filter(|v| v.len() >= 4)
+            .map_or((2, 1), |v| (u16_at(v, 0), u16_at(v, 2))),
+        presets,
+        // 46-byte records, again with a terminator.
+        samples: riff::chunk(pdta, b"shdr").map_or(0, |shdr| (shdr.len() / 46).saturating_sub(1)),
+        sample_bytes,
+    })
+}
+
+/// The parts of a SoundFont `read` looks at.
+struct Lists {
+    /// The chunks in the `INFO` list.
+    info: Option<Vec<u8>>,
+    /// The chunks in the `pdta` list.
+    pdta: Option<Vec<u8>>,
+    /// The size of the `smpl` chunk in the `sdta` list.
+    sample_bytes: u64,
+}
+
+/// Read the lists of a SoundFont `len` bytes long, all but the `sdta` one whole.
+fn read_lists<R: Read + Seek>(file: &mut R, len: u64) -> io::Result<Lists> {
+    let (mut info, mut pdta, mut sample_bytes) = (None, None, 0);
+    for span in riff::spans(file, 12, len)? {
+        match riff::list_type(file, &span)?.as_ref() {
+            Some(b"INFO") => info = Some(riff::read(file, &span)?.split_off(4)),
+            Some(b"pdta") => pdta = Some(riff::read(file, &span)?.split_off(4)),
+            Some(b"sdta") => {
+                let sdta = riff::spans(file, span.start + 4, span.start + span.size)?;
+                sample_bytes = sdta.iter().find(|s| &s.id == b"smpl").map_or(0, |smpl| smpl.size);
+            }
+            _ => {}
+        }
+    }
+    Ok(Lists { info, pdta, sample_bytes })
+}
+
+/// Read the preset headers of an SF2 or SF3 file without loading its samples.
+pub fn presets(path: &str) -> Result<Vec<Preset>> {
+    Ok(read(path)?.presets)
+}
+
+/// The (bank, program) of every preset in `soundfonts`, sorted, each once: a preset in more
+/// than one layer is only played from the top one.
+pub fn preset_numbers(soundfonts: &[String]) -> Result<Vec<(u16, u16)>> {
+    let mut presets = Vec::new();
+    for soundfont in soundfonts {
+        presets.extend(self::presets(soundfont)?.iter().map(|p| (p.bank, p.program)));
+    }
+    presets.sort_unstable();
+    presets.dedup();
+    Ok(presets)
+}
+
+/// The General MIDI instruments missing from bank 0 of `presets`, and whether the standard
+/// drum kit (bank 128, program 0) is there.
+pub fn missing_gm(presets: &[Preset]) -> (Vec<u8>, bool) {
+    let has = |bank, program| presets.iter().any(|p| p.bank == bank && p.program == program);
+    ((0..128).filter(|&program| !has(0, program.into())).collect(), has(128, 0))
+}
+
+/// Whether the SoundFont at `path` is an SF3, its samples compressed with Ogg Vorbis. Only
+/// its headers are read, the `ifil` version among them.
+#[cfg(feature = "oxisynth")]
+pub fn compressed(path: &str) -> Result<bool> {
+    Ok(read(path)?.version.0 == 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        if data.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(b"LIST", &[kind.as_slice(), &chunks.concat()].concat())
+    }
+
+    #[test]
+    fn reads_the_lists_around_the_samples() {
+        let info = list(b"INFO", &[chunk(b"ifil", &[3, 0, 1, 0]), chunk(b"INAM", b"Piano\0")]);
+        let sdta = list(b"sdta", &[chunk(b"smpl", &[0; 1001])]);
+        let pdta = list(b"pdta", &[chunk(b"phdr", &[0; 76])]);
+        let body = [b"sfbk".as_slice(), &info, &sdta, &pdta].concat();
+        let file = chunk(b"RIFF", &body);
+        let lists = read_lists(&mut io::Cursor::new(&file), file.len() as u64).unwrap();
+        assert_eq!(lists.info.as_deref(), Some(&info[12..]));
+        assert_eq!(lists.pdta.as_deref(), Some(&pdta[12..]));
+        assert_eq!(lists.sample_bytes, 1001);
+    }
+}
@@ -0,0 +1,106 @@
+//! The `soundfont` subcommand: look inside a SoundFont, for when a channel is silent because
+//! the font lacks the preset it asks for.
+
+use crate::output::{self, OutputArgs};
+use crate::song::GM_PROGRAMS;
+use crate::soundfont::{self, Preset};
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use serde_json::json;
+
+/// `soundfont` options: which thing to do with the font.
+#[derive(Args, Debug)]
+pub struct SoundfontArgs {
+    #[command(subcommand)]
+    pub command: SoundfontCmd,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SoundfontCmd {
+    /// List the font's banks and presets and its samples, and the General MIDI presets it lacks
+    Info(SoundfontInfoArgs),
+}
+
+/// `soundfont info` options.
+#[derive(Args, Debug)]
+pub struct SoundfontInfoArgs {
+    /// Path or URL of the SoundFont (.sf2 or .sf3); the one `play` would use if left out
+    #[arg(value_name = "FONT.sf2")]
+    soundfont: Option<String>,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+impl SoundfontArgs {
+    /// The `--output` of whichever subcommand was given.
+    pub fn output(&self) -> &OutputArgs {
+        match &self.command {
+            SoundfontCmd::Info(args) => &args.output,
+        }
+    }
+}
+
+pub fn run(opt: SoundfontArgs) -> Result<()> {
+    match opt.command {
+        SoundfontCmd::Info(args) => info(args),
+    }
+}
+
+/// Print the font's name and version, its samples, every preset by bank and program, and
+/// the General MIDI presets it is missing.
+fn info(opt: SoundfontInfoArgs) -> Result<()> {
+    let path = soundfont::resolve(opt.soundfont.as_deref())?;
+    let font = soundfont::read(&path)?;
+    let mut presets: Vec<&Preset> = font.presets.iter().collect();
+    presets.sort_by_key(|p| (p.bank, p.program));
+    let (missing, drums) = soundfont::missing_gm(&font.presets);
+    let (major, minor) = font.version;
+
+    if output::is_json() {
+        let preset = |p: &&Preset| {
+            json!({ "bank": p.bank, "program": p.program, "name": p.name.as_str() })
+        };
+        output::print(&json!({
+            "file": path.as_str(),
+            "name": font.name,
+            "version": format!("{major}.{minor:02}"),
+            "compressed": major == 3,
+            "samples": font.samples,
+            "sample_bytes": font.sample_bytes,
+            "presets": presets.iter().map(preset).collect::<Vec<_>>(),
+            "missing_gm_programs": missing,
+            "gm_drum_kit": drums,
+        }));
+        return Ok(());
+    }
+
+    println!("File: {path}");
+    if let Some(name) = &font.name {
+        println!("Name: {name}");
+    }
+    let kind = if major == 3 { "SF3, samples compressed with Ogg Vorbis" } else { "SF2" };
+    println!("Version: {major}.{minor:02} ({kind})");
+    println!("Samples: {}, {:.1} MB", font.samples, font.sample_bytes as f64 / 1_000_000.0);
+    println!("Presets: {}", presets.len());
+    let mut bank = None;
+    for p in &presets {
+        if bank != Some(p.bank) {
+            bank = Some(p.bank);
+            println!("  Bank {}{}:", p.bank, if p.bank == 128 { " (drum kits)" } else { "" });
+        }
+        println!("    {:>3}  {}", p.program, p.name);
+    }
+
+    if missing.is_empty() {
+        println!("General MIDI: all 128 instruments");
+    } else {
+        println!("General MIDI: {} of 128 instruments missing from bank 0:", missing.len());
+        for &program in &missing {
+            println!("    {:>3}  {}", program, GM_PROGRAMS[program as usize]);
+        }
+    }
+    if !drums {
+        println!("General MIDI: no standard drum kit (bank 128, program 0) for channel 10");
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Spectrogram images of rendered audio (`render --spectrogram`), for checking a SoundFont
+//! by eye: time across, frequency up to half the sample rate on a linear scale so aliasing
+//! shows as lines folding back down, and level as colour from -120 dBFS to full scale.
+//! A strip along the top turns red wherever a sample reached full scale, which a 16-bit or
+//! lossy file clips.
+//!
+//! It is built as the audio is written, a column per `HOP` frames of the left and right
+//! channels mixed, and squeezed to `WIDTH` at the end, keeping each pixel's loudest column.
+
+use crate::png;
+use anyhow::Result;
+use std::f32::consts::PI;
+use std::path::Path;
+
+/// Frames per FFT, about 46 ms at 44.1 kHz: 21.5 Hz between bins.
+const FFT_SIZE: usize = 2048;
+
+/// Frames from one FFT to the next, half overlapping.
+const HOP: usize = FFT_SIZE / 2;
+
+/// Image rows for the spectrum, two FFT bins each.
+const ROWS: usize = FFT_SIZE / 4;
+
+/// Image width, however long the song.
+const WIDTH: usize = 1200;
+
+/// Height of the clipping strip along the top, and the row between it and the spectrum.
+const STRIP: usize = 6;
+
+/// The quietest level shown; anything below is black.
+const FLOOR_DB: f32 = -120.0;
+
+/// Palette entries: the levels from `FLOOR_DB` up, then the strip's two colours.
+const LEVELS: usize = 254;
+const STRIP_COLOUR: u8 = 254;
+const CLIPPED_COLOUR: u8 = 255;
+
+/// A spectrogram being built from interleaved stereo.
+pub struct Spectrogram {
+    window: Vec<f32>,
+    /// Twiddle factors for the FFT.
+    twiddles: Vec<(f32, f32)>,
+    /// Mono samples not yet used up by a column, with the overlap carried from the last.
+    pending: Vec<f32>,
+    /// Each column's rows from the bottom up, as palette entries.
+    columns: Vec<[u8; ROWS]>,
+    /// Whether a sample reached full scale in each column's hop.
+    clipped: Vec<bool>,
+    clipping: bool,
+}
+
+impl Default for Spectrogram {
+    fn default() -> Self {
+        let window = (0..FFT_SIZE).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos()).collect();
+        let twiddles = (0..FFT_SIZE / 2)
+            .map(|i| {
+                let angle = -2.0 * PI * i as f32 / FFT_SIZE as f32;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+        Self {
+            window,
+            twiddles,
+            pending: Vec::with_capacity(FFT_SIZE + HOP),
+            columns: Vec::new(),
+            clipped: Vec::new(),
+            clipping: false,
+        }
+    }
+}
+
+impl Spectrogram {
+    /// Add a block of interleaved stereo.
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(2) {
+            self.clipping |= frame[0].abs() >= 1.0 || frame[1].abs() >= 1.0;
+            self.pending.push((frame[0] + frame[1]) / 2.0);
+            if self.pending.len() == FFT_SIZE {
+                self.column();
+                self.pending.drain(..HOP);
+            }
+        }
+    }
+
+    /// Write the image, first padding out the last column with silence.
+    pub fn write(mut self, path: &Path) -> Result<()> {
+        if self.pending.len() > FFT_SIZE - HOP || self.columns.is_empty() {
+            self.pending.resize(FFT_SIZE, 0.0);
+            self.column();
+        }
+        let columns = self.columns.len();
+        let height = STRIP + ROWS;
+        let mut pixels = vec![0u8; WIDTH * height];
+        for x in 0..WIDTH {
+            // The columns this pixel covers, at least one.
+            let first = x * columns / WIDTH;
+            let last = ((x + 1) * columns / WIDTH).max(first + 1);
+            let clipped = self.clipped[first..last].iter().any(|&c| c);
+            for y in 0..STRIP - 1 {
+                pixels[y * WIDTH + x] = if clipped { CLIPPED_COLOUR } else { STRIP_COLOUR };
+            }
+            for row in 0..ROWS {
+                let level = self.columns[first..last].iter().map(|c| c[row]).max().unwrap_or_default();
+                pixels[(height - 1 - row) * WIDTH + x] = level;
+            }
+        }
+        png::write(path, WIDTH as u32, height as u32, &palette(), &pixels)
+    }
+
+    /// Turn the first `FFT_SIZE` pending samples into a column.
+    fn column(&mut self) {
+        let mut re: Vec<f32> = self.pending.iter().zip(&self.window).map(|(s, w)| s * w).collect();
+        let mut im = vec![0.0; FFT_SIZE];
+        fft(&mut re, &mut im, &self.twiddles);
+
+        // A full-scale sine comes out of the Hann window at a quarter of FFT_SIZE.
+        let full_scale = (FFT_SIZE / 4) as f32;
+        let mut column = [0u8; ROWS];
+        for (row, level) in column.iter_mut().enumerate() {
+            let power = (2 * row..2 * row + 2).map(|bin| re[bin] * re[bin] + im[bin] * im[bin]).fold(0.0, f32::max);
+            let db = 10.0 * (power / (full_scale * full_scale)).max(1e-20).log10();
+            *level = ((db - FLOOR_DB) / -FLOOR_DB * (LEVELS - 1) as f32).clamp(0.0, (LEVELS - 1) as f32) as u8;
+        }
+        self.columns.push(column);
+        self.clipped.push(std::mem::take(&mut self.clipping));
+    }
+}
+
+/// In-place radix-2 FFT of `FFT_SIZE` complex values.
+fn fft(re: &mut [f32], im: &mut [f32], twiddles: &[(f32, f32)]) {
+    let n = re.len();
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut size = 2;
+    while size <= n {
+        let step = n / size;
+        for start in (0..n).step_by(size) {
+            for k in 0..size / 2 {
+                let (wr, wi) = twiddles[k * step];
+                let (a, b) = (start + k, start + k + size / 2);
+                let tr = re[b] * wr - im[b] * wi;
+                let ti = re[b] * wi + im[b] * wr;
+                (re[b], im[b]) = (re[a] - tr, im[a] - ti);
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        size *= 2;
+    }
+}
+
+/// Black through purple, red and yellow to white for the levels, then the strip's grey and
+/// the clipping red.
+fn palette() -> Vec<[u8; 3]> {
+    const STOPS: [[f32; 3]; 6] =
+        [[0.0, 0.0, 0.0], [40.0, 0.0, 90.0], [170.0, 0.0, 120.0], [240.0, 70.0, 20.0], [255.0, 210.0, 0.0], [255.0; 3]];
+    let mut palette: Vec<[u8; 3]> = (0..LEVELS)
+        .map(|i| {
+            let at = i as f32 / (LEVELS - 1) as f32 * (STOPS.len() - 1) as f32;
+            let stop = (at as usize).min(STOPS.len() - 2);
+            let frac = at - stop as f32;
+            std::array::from_fn(|c| (STOPS[stop][c] + (STOPS[stop + 1][c] - STOPS[stop][c]) * frac) as u8)
+        })
+        .collect();
+    palette.push([0x30, 0x30, 0x30]);
+    palette.push([0xff, 0x20, 0x20]);
+    palette
+}
@@ -0,0 +1,552 @@
+//! The synthesizer: the engines behind one trait, their setup and the MIDI messages the
+//! player sends them.
+
+#[cfg(feature = "fluidlite")]
+use crate::fluid;
+#[cfg(feature = "oxisynth")]
+use crate::oxi;
+use crate::song::{DrumChannels, Msg, Timed, parse_drum_channels};
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use clap::builder::BoolishValueParser;
+use std::time::Duration;
+use tracing::warn;
+
+/// A synthesizer engine, as the player drives it. Channels, keys and values are as in MIDI.
+///
+/// An engine need only take the channel messages and render; sending timeline messages and
+/// silencing are built on those, and one can do them its own way instead.
+pub trait Synthesizer: Send {
+    fn note_on(&mut self, ch: u8, key: u8, vel: u8);
+    fn note_off(&mut self, ch: u8, key: u8);
+    fn program(&mut self, ch: u8, program: u8);
+    fn cc(&mut self, ch: u8, cc: u8, value: u8);
+    /// Select the bank for the channel's next Program Change: 0–127, or 128 for percussion.
+    fn bank(&mut self, ch: u8, bank: u16);
+    /// Set the channel's pitch bend range, in semitones.
+    fn bend_range(&mut self, ch: u8, semitones: u8);
+    /// Bend the channel's pitch: 0–16383, centred on 8192.
+    fn pitch_bend(&mut self, ch: u8, bend: u16);
+    fn key_pressure(&mut self, ch: u8, key: u8, value: u8);
+    fn channel_pressure(&mut self, ch: u8, value: u8);
+    /// Put every channel back to its power-on state, keeping the interpolation chosen.
+    fn reset(&mut self);
+    /// Act on a SysEx message, whole from 0xF0 to 0xF7 and kept by `sysex::keep`, as far as
+    /// the engine can.
+    fn sysex(&mut self, _data: &'static [u8]) {}
+    fn gain(&self) -> f32;
+    fn set_gain(&mut self, gain: f32);
+    fn set_sample_rate(&mut self, rate: f32);
+    /// Render interleaved stereo to fill `out`.
+    fn render(&mut self, out: &mut [f32]) -> Result<()>;
+    /// Render into separate left and right buffers of the same length.
+    #[cfg(feature = "jack")]
+    fn render_split(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<()>;
+    /// The voices sounding now and the most there can be (`--polyphony`), or `None` for an
+    /// engine that cannot count them.
+    fn voices(&self) -> Option<(usize, usize)>;
+    /// How long the output has been silent, for a synth that listens to it.
+    fn silent_for(&self) -> Option<Duration> {
+        None
+    }
+    /// How long the output takes from the callback to the speakers, as the device reports
+    /// it, for a synth that keeps time by what is heard.
+    fn set_output_latency(&mut self, _latency: Duration) {}
+
+    /// Forward one timeline message to the synth.
+    fn send(&mut self, msg: Msg) {
+        match msg {
+            Msg::NoteOn(ch, key, vel) => self.note_on(ch, key, vel),
+            Msg::NoteOff(ch, key, _vel) => self.note_off(ch, key),
+            Msg::Program(ch, prog) => self.program(ch, prog),
+            Msg::Control(ch, cc, val) => self.cc(ch, cc, val),
+            Msg::Bank(ch, bank) => self.bank(ch, bank),
+            // The engines take whole semitones, so the cents round to the nearest.
+            Msg::BendRange(ch, semitones, cents) => self.bend_range(ch, semitones + u8::from(cents >= 50)),
+            Msg::PitchBend(ch, bend) => {
+                if bend > 16383 {
+                    warn!("Dropping out-of-range raw bend {}", bend);
+                } else {
+                    self.pitch_bend(ch, bend);
+                }
+            }
+            Msg::AfterTouch(ch, key, vel) => self.key_pressure(ch, key, vel),
+            Msg::ChannelAftertouch(ch, vel) => self.channel_pressure(ch, vel),
+            Msg::Reset(_) => self.reset(),
+            Msg::SysEx(data) => self.sysex(data),
+            // Already turned into Volume controllers.
+            Msg::MasterVolume(_) => {}
+            Msg::Tempo(_) => {
+                // Timeline already has absolute times, so no rescale is needed here.
+            }
+        }
+    }
+
+    /// Release the sustain and sostenuto pedals and send note-off to every note on all 16
+    /// channels; with a pedal down, a note-off would leave the note ringing. Voices go into
+    /// their release phase, so reverb and release tails still ring.
+    fn release_notes(&mut self) {
+        for ch in 0..16 {
+            self.cc(ch, 64, 0);  // Sustain off
+            self.cc(ch, 66, 0);  // Sostenuto off
+            self.cc(ch, 123, 0); // All Notes Off
+        }
+    }
+
+    /// MIDI panic: for stuck notes that a plain All Notes Off does not catch (some synths and
+    /// files ignore it), send an explicit note-off for every key, then All Sound Off and a
+    /// centred pitch bend on all 16 channels. Programs and other controllers are kept, so
+    /// playback carries on normally with the next note.
+    fn midi_panic(&mut self) {
+        self.silence();
+        for ch in 0..16 {
+            for key in 0..128 {
+                self.note_off(ch, key);
+            }
+            self.pitch_bend(ch, 8192);
+        }
+    }
+
+    /// Release every note and cut all sound immediately on all 16 channels.
+    /// Controller values and programs are left alone so playback can pick up again.
+    fn silence(&mut self) {
+        self.release_notes();
+        for ch in 0..16 {
+            self.cc(ch, 120, 0); // All Sound Off
+        }
+    }
+}
+
+/// What moving playback to `pos_us` takes: the index of the first event at or after it, and
+/// the messages to send a silenced and reset synth first.
+///
+/// Seeking cannot simply skip ahead: programs, controllers and pitch bend set before the
+/// target would be lost, or (seeking backwards) later values would leak into earlier
+/// passages. So every state-changing event before the target is replayed ("chased") so each
+/// channel lands exactly where it would have been. Tempo needs no chasing because timeline
+/// timestamps are already absolute.
+pub fn chase(timeline: &[Timed], pos_us: u64) -> (usize, impl Iterator<Item = Msg> + '_) {
+    let idx = timeline.partition_point(|e| e.t_us < pos_us);
+    let chased = timeline[..idx].iter().map(|ev| ev.msg).filter(|msg| {
+        // Notes before the target are over (or will not be restarted mid-way).
+        !matches!(msg, Msg::NoteOn(..) | Msg::NoteOff(..) | Msg::AfterTouch(..))
+    });
+    (idx, chased)
+}
+
+/// The synth the player owns, whichever engine it is.
+pub type Synth = Box<dyn Synthesizer>;
+
+/// Output below this level counts as silence, once it has lasted `SILENCE_HOLD`: the sound
+/// after the last event has died away. If it never does (a drone the file leaves sounding),
+/// the tail is cut after `MAX_TAIL`.
+pub const SILENCE_DBFS: f32 = -70.0;
+pub const SILENCE_HOLD: Duration = Duration::from_millis(250);
+pub const MAX_TAIL: Duration = Duration::from_secs(30);
+
+/// A synth that listens to what it renders, so the player can tell when the release and
+/// reverb tails have died away instead of waiting a fixed time. Whichever output pulls the
+/// audio, it comes through here.
+pub struct Metered {
+    synth: Synth,
+    sample_rate: f32,
+    /// Frames rendered since the output was last above `SILENCE_DBFS`.
+    quiet_frames: u64,
+}
+
+impl Metered {
+    pub fn wrap(synth: Synth, sample_rate: f32) -> Synth {
+        Box::new(Self { synth, sample_rate, quiet_frames: 0 })
+    }
+
+    /// Count `frames` more of silence, or start over if any of `samples` was heard.
+    fn listen(&mut self, frames: usize, samples: &[&[f32]]) {
+        let silence = 10f32.powf(SILENCE_DBFS / 20.0);
+        if samples.iter().all(|buf| buf.iter().all(|s| s.abs() < silence)) {
+            self.quiet_frames += frames as u64;
+        } else {
+            self.quiet_frames = 0;
+        }
+    }
+}
+
+impl Synthesizer for Metered {
+    fn note_on(&mut self, ch: u8, key: u8, vel: u8) {
+        // The note is heard from the next block on; until then it must not look silent.
+        self.quiet_frames = 0;
+        self.synth.note_on(ch, key, vel);
+    }
+    fn note_off(&mut self, ch: u8, key: u8) {
+        self.synth.note_off(ch, key);
+    }
+    fn program(&mut self, ch: u8, program: u8) {
+        self.synth.program(ch, program);
+    }
+    fn cc(&mut self, ch: u8, cc: u8, value: u8) {
+        self.synth.cc(ch, cc, value);
+    }
+    fn bank(&mut self, ch: u8, bank: u16) {
+        self.synth.bank(ch, bank);
+    }
+    fn bend_range(&mut self, ch: u8, semitones: u8) {
+        self.synth.bend_range(ch, semitones);
+    }
+    fn pitch_bend(&mut self, ch: u8, bend: u16) {
+        self.synth.pitch_bend(ch, bend);
+    }
+    fn key_pressure(&mut self, ch: u8, key: u8, value: u8) {
+        self.synth.key_pressure(ch, key, value);
+    }
+    fn channel_pressure(&mut self, ch: u8, value: u8) {
+        self.synth.channel_pressure(ch, value);
+    }
+    fn reset(&mut self) {
+        self.synth.reset();
+    }
+    fn sysex(&mut self, data: &'static [u8]) {
+        self.synth.sysex(data);
+    }
+    fn gain(&self) -> f32 {
+        self.synth.gain()
+    }
+    fn set_gain(&mut self, gain: f32) {
+        self.synth.set_gain(gain);
+    }
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        self.synth.set_sample_rate(rate);
+    }
+    fn render(&mut self, out: &mut [f32]) -> Result<()> {
+        self.synth.render(out)?;
+        self.listen(out.len() / 2, &[out]);
+        Ok(())
+    }
+    #[cfg(feature = "jack")]
+    fn render_split(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<()> {
+        self.synth.render_split(left, right)?;
+        self.listen(left.len(), &[left, right]);
+        Ok(())
+    }
+    fn voices(&self) -> Option<(usize, usize)> {
+        self.synth.voices()
+    }
+    fn silent_for(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f64(self.quiet_frames as f64 / f64::from(self.sample_rate)))
+    }
+
+    // The rest are passed on whole, in case the synth within does them its own way.
+    fn send(&mut self, msg: Msg) {
+        if let Msg::NoteOn(..) = msg {
+            self.quiet_frames = 0;
+        }
+        self.synth.send(msg);
+    }
+    /// A release starts a tail, so the silence before it no longer counts.
+    fn release_notes(&mut self) {
+        self.quiet_frames = 0;
+        self.synth.release_notes();
+    }
+    fn midi_panic(&mut self) {
+        self.synth.midi_panic();
+    }
+    fn silence(&mut self) {
+        self.synth.silence();
+    }
+}
+
+/// The synthesizer engines, those left out of the build included so `--engine` can say so.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    /// FluidLite, FluidSynth's C core
+    #[value(name = "fluidlite")]
+    FluidLite,
+    /// OxiSynth, a port of FluidSynth to pure Rust
+    #[value(name = "oxisynth")]
+    OxiSynth,
+}
+
+impl Engine {
+    /// The engine's name, which in lower case is the cargo feature that builds it in.
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::FluidLite => "FluidLite",
+            Engine::OxiSynth => "OxiSynth",
+        }
+    }
+
+    /// FluidLite when the build has it, otherwise OxiSynth.
+    pub const DEFAULT: Engine = if cfg!(feature = "fluidlite") { Engine::FluidLite } else { Engine::OxiSynth };
+}
+
+/// Reverb settings, as the engines take them.
+#[derive(Clone, Copy, Debug)]
+pub struct Reverb {
+    pub room: f64,
+    pub damp: f64,
+    pub width: f64,
+    pub level: f64,
+}
+
+/// Chorus settings, as the engines take them.
+#[derive(Clone, Copy, Debug)]
+pub struct Chorus {
+    pub voices: u32,
+    pub level: f64,
+    /// Modulation speed in Hz.
+    pub speed: f64,
+    /// Modulation depth in milliseconds.
+    pub depth: f64,
+}
+
+/// The reverb used unless `--reverb-params` says otherwise: a medium room, fairly wet.
+pub const DEFAULT_REVERB: Reverb = Reverb { room: 0.7, damp: 0.2, width: 0.9, level: 0.5 };
+
+/// The chorus used unless `--chorus-params` says otherwise.
+pub const DEFAULT_CHORUS: Chorus = Chorus { voices: 3, level: 1.2, speed: 0.3, depth: 8.0 };
+
+/// Reverb and chorus options, part of the song options so a sidecar can set them too,
+/// and the synth itself:
+/// - engine: FluidLite or OxiSynth
+/// - reverb / chorus: switch the effect on or off
+/// - reverb_params / chorus_params: tune it
+/// - no_effects: switch both off and leave their processing out, for slow machines
+/// - interp: how samples are interpolated when pitched, trading quality for speed
+/// - polyphony: the most voices that can sound at once
+/// - drum_channels: the channels that play drum kits
+#[derive(Args, Clone, Copy, Debug, Default)]
+pub struct EffectsArgs {
+    /// The synthesizer: `fluidlite`, or `oxisynth` in pure Rust, which a build without
+    /// FluidLite has instead [default: fluidlite]
+    #[arg(long, value_name = "ENGINE", value_enum, hide_possible_values = true)]
+    pub engine: Option<Engine>,
+    /// Switch the synth's reverb on or off [default: on]
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    pub reverb: Option<bool>,
+    /// Reverb room size (0–1), damping (0–1), width (0–100) and level (0–1)
+    /// [default: 0.7,0.2,0.9,0.5]
+    #[arg(long, value_name = "ROOM,DAMP,WIDTH,LEVEL", value_parser = parse_reverb)]
+    pub reverb_params: Option<Reverb>,
+    /// Switch the synth's chorus on or off [default: on]
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), hide_possible_values = true)]
+    pub chorus: Option<bool>,
+    /// Chorus voices (0–99), level (0–10), speed in Hz (0.3–5) and depth in ms (0–256)
+    /// [default: 3,1.2,0.3,8]
+    #[arg(long, value_name = "N,LEVEL,HZ,MS", value_parser = parse_chorus)]
+    pub chorus_params: Option<Chorus>,
+    /// Switch off the reverb and chorus and skip their processing, for machines too slow
+    /// to render them in real time
+    #[arg(long, conflicts_with_all = ["reverb", "reverb_params", "chorus", "chorus_params"])]
+    pub no_effects: bool,
+    /// How samples are interpolated when played at another pitch: `linear` for slow
+    /// machines, `7th` for the best offline renders [default: 4th]
+    #[arg(long, value_name = "ORDER", value_enum, hide_possible_values = true)]
+    pub interp: Option<Interp>,
+    /// The most voices that can sound at once; the quietest are cut off past it. Dense
+    /// files need more [default: 256]
+    #[arg(long, value_name = "VOICES", value_parser = clap::value_parser!(u16).range(1..))]
+    pub polyphony: Option<u16>,
+    /// Play these MIDI channels (1–16, comma separated) with drum kits, or `none`; without
+    /// 10 in the list channel 10 plays a melodic instrument, as some XG files want [default: 10]
+    #[arg(long, value_name = "CH,...|none", value_parser = parse_drum_channels)]
+    pub drum_channels: Option<DrumChannels>,
+}
+
+/// The engines' interpolation methods, cheapest first.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Interp {
+    /// Nearest sample, which aliases audibly
+    None,
+    /// Straight lines between samples
+    Linear,
+    /// Fourth-order polynomial, FluidLite's own default
+    #[value(name = "4th")]
+    Fourth,
+    /// Seventh-order polynomial, the smoothest and about twice the work
+    #[value(name = "7th")]
+    Seventh,
+}
+
+/// Comma-separated numbers, exactly `N` of them.
+fn numbers<const N: usize>(s: &str) -> Option<[f64; N]> {
+    let values: Vec<f64> = s.split(',').map(|v| v.trim().parse().ok()).collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+/// clap value parser for `--reverb-params`, and the sidecar's `reverb-params`.
+pub fn parse_reverb(s: &str) -> Result<Reverb, String> {
+    let Some([room, damp, width, level]) = numbers(s) else {
+        return Err(format!("invalid reverb `{s}`, expected ROOM,DAMP,WIDTH,LEVEL such as 0.7,0.2,0.9,0.5"));
+    };
+    let within = |v: f64, max: f64| (0.0..=max).contains(&v);
+    if !(within(room, 1.0) && within(damp, 1.0) && within(width, 100.0) && within(level, 1.0)) {
+        return Err(format!("invalid reverb `{s}`: room, damping and level go from 0 to 1, width from 0 to 100"));
+    }
+    Ok(Reverb { room, damp, width, level })
+}
+
+/// clap value parser for `--chorus-params`, and the sidecar's `chorus-params`.
+pub fn parse_chorus(s: &str) -> Result<Chorus, String> {
+    let Some([voices, level, speed, depth]) = numbers(s) else {
+        return Err(format!("invalid chorus `{s}`, expected N,LEVEL,HZ,MS such as 3,1.2,0.3,8"));
+    };
+    if !(0.0..=99.0).contains(&voices)
+        || voices.fract() != 0.0
+        || !(0.0..=10.0).contains(&level)
+        || !(0.29..=5.0).contains(&speed)
+        || !(0.0..=256.0).contains(&depth)
+    {
+        return Err(format!("invalid chorus `{s}`: 0 to 99 voices, level 0 to 10, 0.3 to 5 Hz and 0 to 256 ms"));
+    }
+    Ok(Chorus { voices: voices as u32, level, speed, depth })
+}
+
+/// Create a synth on the engine `effects` asks for, rendering at `sample_rate`, with the
+/// SoundFonts loaded, master `gain` applied and the reverb and chorus set up as `effects`
+/// asks, on by default.
+///
+/// `soundfonts` are in priority order. The engines look a preset up in the SoundFont loaded
+/// last first, falling back to the ones below it, so they are loaded the other way round:
+/// a small font given first takes over just the presets it has from a full GM font below.
+pub fn open(soundfonts: &[String], gain: f32, sample_rate: f32, effects: EffectsArgs) -> Result<Synth> {
+    match effects.engine.unwrap_or(Engine::DEFAULT) {
+        #[cfg(feature = "fluidlite")]
+        Engine::FluidLite => fluid::open(soundfonts, gain, sample_rate, effects),
+        #[cfg(feature = "oxisynth")]
+        Engine::OxiSynth => oxi::open(soundfonts, gain, sample_rate, effects),
+        #[allow(unreachable_patterns)]
+        engine => anyhow::bail!(
+            "this build has no {}; build with `--features {}` for it",
+            engine.name(),
+            engine.name().to_lowercase()
+        ),
+    }
+}
+
+/// A synth for tests: it renders silence, and logs each call with the frame it came at.
+#[cfg(test)]
+pub mod fake {
+    use super::{Synth, Synthesizer};
+    use anyhow::Result;
+    use std::sync::{Arc, Mutex};
+
+    /// A call the fake was given.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Call {
+        NoteOn(u8, u8, u8),
+        NoteOff(u8, u8),
+        Program(u8, u8),
+        Cc(u8, u8, u8),
+        Bank(u8, u16),
+        BendRange(u8, u8),
+        PitchBend(u8, u16),
+        KeyPressure(u8, u8, u8),
+        ChannelPressure(u8, u8),
+        Reset,
+    }
+
+    /// The calls so far, each with the number of frames rendered before it.
+    pub type Log = Arc<Mutex<Vec<(usize, Call)>>>;
+
+    pub struct Fake {
+        log: Log,
+        frames: usize,
+        gain: f32,
+    }
+
+    impl Fake {
+        pub fn open() -> (Synth, Log) {
+            let log = Log::default();
+            (Box::new(Self { log: Arc::clone(&log), frames: 0, gain: 1.0 }), log)
+        }
+
+        fn call(&mut self, call: Call) {
+            self.log.lock().unwrap().push((self.frames, call));
+        }
+    }
+
+    impl Synthesizer for Fake {
+        fn note_on(&mut self, ch: u8, key: u8, vel: u8) {
+            self.call(Call::NoteOn(ch, key, vel));
+        }
+        fn note_off(&mut self, ch: u8, key: u8) {
+            self.call(Call::NoteOff(ch, key));
+        }
+        fn program(&mut self, ch: u8, program: u8) {
+            self.call(Call::Program(ch, program));
+        }
+        fn cc(&mut self, ch: u8, cc: u8, value: u8) {
+            self.call(Call::Cc(ch, cc, value));
+        }
+        fn bank(&mut self, ch: u8, bank: u16) {
+            self.call(Call::Bank(ch, bank));
+        }
+        fn bend_range(&mut self, ch: u8, semitones: u8) {
+            self.call(Call::BendRange(ch, semitones));
+        }
+        fn pitch_bend(&mut self, ch: u8, bend: u16) {
+            self.call(Call::PitchBend(ch, bend));
+        }
+        fn key_pressure(&mut self, ch: u8, key: u8, value: u8) {
+            self.call(Call::KeyPressure(ch, key, value));
+        }
+        fn channel_pressure(&mut self, ch: u8, value: u8) {
+            self.call(Call::ChannelPressure(ch, value));
+        }
+        fn reset(&mut self) {
+            self.call(Call::Reset);
+        }
+        fn gain(&self) -> f32 {
+            self.gain
+        }
+        fn set_gain(&mut self, gain: f32) {
+            self.gain = gain;
+        }
+        fn set_sample_rate(&mut self, _rate: f32) {}
+
+        fn render(&mut self, out: &mut [f32]) -> Result<()> {
+            out.fill(0.0);
+            self.frames += out.len() / 2;
+            Ok(())
+        }
+        #[cfg(feature = "jack")]
+        fn render_split(&mut self, left: &mut [f32], right: &mut [f32]) -> Result<()> {
+            left.fill(0.0);
+            right.fill(0.0);
+            self.frames += left.len();
+            Ok(())
+        }
+
+        fn voices(&self) -> Option<(usize, usize)> {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(t_us: u64, msg: Msg) -> Timed {
+        Timed { t_us, msg, track: 0 }
+    }
+
+    #[test]
+    fn chases_the_state_before_the_target_but_not_its_notes() {
+        let timeline = [
+            at(0, Msg::Program(0, 5)),
+            at(0, Msg::NoteOn(0, 60, 100)),
+            at(100, Msg::Control(0, 7, 90)),
+            at(100, Msg::AfterTouch(0, 60, 30)),
+            at(200, Msg::NoteOff(0, 60, 0)),
+            at(200, Msg::PitchBend(0, 9000)),
+            at(300, Msg::Program(0, 6)),
+        ];
+        let (i, chased) = chase(&timeline, 200);
+        assert_eq!(i, 4);
+        assert_eq!(chased.collect::<Vec<_>>(), [Msg::Program(0, 5), Msg::Control(0, 7, 90)]);
+        let (i, chased) = chase(&timeline, 0);
+        assert_eq!((i, chased.count()), (0, 0));
+        let (i, chased) = chase(&timeline, 1000);
+        assert_eq!((i, chased.count()), (7, 4));
+    }
+}
@@ -0,0 +1,125 @@
+//! System Exclusive messages in MIDI files. FluidLite has no use for SysEx beyond MIDI
+//! Tuning, so the ones that set a song up on any General MIDI synth are recognised here and
+//! turned into what FluidLite understands: the resets of each standard, and master volume.
+//! The rest are passed to the synth whole, for FluidLite to act on MIDI Tuning and a synth
+//! on `--midi-out` on all of them; they are named in the log.
+
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+/// A MIDI standard, as a reset message names it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Standard {
+    Gm,
+    Gm2,
+    Gs,
+    Xg,
+}
+
+impl Standard {
+    pub fn name(self) -> &'static str {
+        match self {
+            Standard::Gm => "GM",
+            Standard::Gm2 => "GM2",
+            Standard::Gs => "GS",
+            Standard::Xg => "XG",
+        }
+    }
+
+    /// The standard's reset message, whole, for a synth that takes SysEx.
+    pub fn reset_message(self) -> &'static [u8] {
+        match self {
+            Standard::Gm => &[0xf0, NON_REALTIME, 0x7f, 0x09, 0x01, 0xf7],
+            Standard::Gm2 => &[0xf0, NON_REALTIME, 0x7f, 0x09, 0x03, 0xf7],
+            Standard::Gs => &[0xf0, ROLAND, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41, 0xf7],
+            Standard::Xg => &[0xf0, YAMAHA, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00, 0xf7],
+        }
+    }
+}
+
+/// What a SysEx message means here.
+#[derive(Debug, PartialEq)]
+pub enum SysEx {
+    /// GM System On, GM2 System On, GS Reset or XG System On: every channel back to its
+    /// power-on state.
+    Reset(Standard),
+    /// The level of the whole synth, 0.0–1.0 of full amplitude.
+    MasterVolume(f32),
+    /// MIDI Tuning, or meant for one kind of synth.
+    Other(&'static str),
+}
+
+/// Roland's, Yamaha's and the MIDI standards' manufacturer IDs.
+const ROLAND: u8 = 0x41;
+const YAMAHA: u8 = 0x43;
+const NON_REALTIME: u8 = 0x7e;
+const REALTIME: u8 = 0x7f;
+
+/// Every message `keep` was given.
+static KEPT: Mutex<BTreeSet<&'static [u8]>> = Mutex::new(BTreeSet::new());
+
+/// Keep a message from a file, its bytes after the 0xF0, whole from 0xF0 to 0xF7 for as
+/// long as the program runs, so the timeline can carry it in a `Msg`, which is copied
+/// through the queues to the synth. Each message is kept once, however many songs or
+/// reloads have it.
+pub fn keep(data: &[u8]) -> &'static [u8] {
+    let mut whole = vec![0xf0];
+    whole.extend_from_slice(data);
+    if whole.last() != Some(&0xf7) {
+        whole.push(0xf7);
+    }
+    let mut kept = KEPT.lock().unwrap_or_else(|e| e.into_inner());
+    match kept.get(&whole[..]) {
+        Some(&message) => message,
+        None => {
+            let message: &'static [u8] = Vec::leak(whole);
+            kept.insert(message);
+            message
+        }
+    }
+}
+
+/// Decode a message from a file, its bytes after the 0xF0 with or without the closing 0xF7.
+pub fn decode(data: &[u8]) -> SysEx {
+    let data = data.strip_suffix(&[0xf7]).unwrap_or(data);
+    match data {
+        [NON_REALTIME, _, 0x09, 0x01, ..] => SysEx::Reset(Standard::Gm),
+        [NON_REALTIME, _, 0x09, 0x03, ..] => SysEx::Reset(Standard::Gm2),
+        [NON_REALTIME, _, 0x09, 0x02, ..] => SysEx::Other("GM System Off"),
+        [NON_REALTIME | REALTIME, _, 0x08, ..] => SysEx::Other("MIDI Tuning"),
+        [REALTIME, _, 0x04, 0x01, lsb, msb, ..] => {
+            SysEx::MasterVolume(f32::from(u16::from(*msb) << 7 | u16::from(*lsb)) / 16383.0)
+        }
+        // Roland data set (DT1) to a GS synth: an address, then data and a checksum.
+        [ROLAND, _, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, ..] => SysEx::Reset(Standard::Gs),
+        [ROLAND, _, 0x42, 0x12, 0x00, 0x00, 0x7f, _, ..] => SysEx::Reset(Standard::Gs), // SC-88 mode set
+        [ROLAND, _, 0x42, 0x12, 0x40, 0x00, 0x04, volume, ..] => SysEx::MasterVolume(f32::from(*volume) / 127.0),
+        [ROLAND, _, 0x42, 0x12, ..] => SysEx::Other("GS parameter"),
+        [ROLAND, _, 0x45, 0x12, ..] => SysEx::Other("Sound Canvas display"),
+        [ROLAND, _, 0x16, ..] => SysEx::Other("MT-32"),
+        [ROLAND, ..] => SysEx::Other("Roland"),
+        // Yamaha parameter change to an XG synth.
+        [YAMAHA, _, 0x4c, 0x00, 0x00, 0x7e, 0x00, ..] => SysEx::Reset(Standard::Xg),
+        [YAMAHA, _, 0x4c, 0x00, 0x00, 0x04, volume, ..] => SysEx::MasterVolume(f32::from(*volume) / 127.0),
+        [YAMAHA, _, 0x4c, ..] => SysEx::Other("XG parameter"),
+        [YAMAHA, ..] => SysEx::Other("Yamaha"),
+        _ => SysEx::Other("unknown"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_each_message_whole_and_once() {
+        // A MIDI Tuning single note change, as a file has it: without the 0xF0.
+        let tuning = [REALTIME, 0x7f, 0x08, 0x02, 0x00, 0x01, 0x45, 0x46, 0x00, 0x00, 0xf7];
+        let kept = keep(&tuning);
+        assert_eq!(kept[0], 0xf0);
+        assert_eq!(&kept[1..], tuning);
+        assert!(std::ptr::eq(kept, keep(&tuning)));
+        assert!(std::ptr::eq(kept, keep(&tuning[..tuning.len() - 1])));
+        assert_eq!(decode(&tuning), SysEx::Other("MIDI Tuning"));
+    }
+}
@@ -0,0 +1,194 @@
+//! Converting MIDI ticks to real time across the whole file.
+
+use midly::{Format, MetaMessage, Smf, Timing, Track, TrackEventKind};
+
+/// Default tempo if the file does not set one: 120 BPM = 500_000 microseconds per quarter note.
+pub const DEFAULT_US_PER_QN: f64 = 500_000.0;
+
+/// A tempo that holds from `tick` until the next change.
+#[derive(Clone, Copy)]
+pub struct TempoChange {
+    pub tick: u64,
+    /// Time at `tick`, in microseconds.
+    pub t_us: f64,
+    pub us_per_qn: f64,
+}
+
+/// The file's tempo map.
+///
+/// In format 0 and 1 files a tempo change applies to every track from its tick on, whichever
+/// track it is stored in (usually the first). So the map is built from the Tempo events of
+/// all tracks together, rather than track by track. SMPTE-timed files have a fixed number of
+/// ticks per second and ignore Tempo events.
+pub struct TempoMap {
+    /// Ticks per quarter note, or `None` for SMPTE timing.
+    ppq: Option<f64>,
+    /// Ticks per second for SMPTE timing.
+    ticks_per_sec: f64,
+    /// Tempo segments ordered by tick; the first one starts at tick 0.
+    changes: Vec<TempoChange>,
+}
+
+impl TempoMap {
+    pub fn new(smf: &Smf) -> Self {
+        Self::from_tracks(smf, &smf.tracks)
+    }
+
+    /// The tempo map of one track alone, for format 2 files, where each track is a pattern
+    /// with a tempo of its own.
+    pub fn of_track(smf: &Smf, track: usize) -> Self {
+        Self::from_tracks(smf, &smf.tracks[track..=track])
+    }
+
+    fn from_tracks(smf: &Smf, tracks: &[Track]) -> Self {
+        let (ppq, ticks_per_sec) = match smf.header.timing {
+            Timing::Metrical(t) => (Some(f64::from(t.as_int().max(1))), 0.0),
+            Timing::Timecode(fps, sub) => (None, f64::from(fps.as_f32()) * f64::from(sub.max(1))),
+        };
+
+        let mut events: Vec<(u64, f64)> = Vec::new();
+        for track in tracks {
+            let mut tick = 0u64;
+            for ev in track {
+                tick += u64::from(ev.delta.as_int());
+                if let TrackEventKind::Meta(MetaMessage::Tempo(tp)) = ev.kind {
+                    events.push((tick, f64::from(tp.as_int())));
+                }
+            }
+        }
+        // Stable, so simultaneous changes keep their file order and the last one wins.
+        events.sort_by_key(|&(tick, _)| tick);
+
+        let mut changes = vec![TempoChange { tick: 0, t_us: 0.0, us_per_qn: DEFAULT_US_PER_QN }];
+        for (tick, us_per_qn) in events {
+            let last = *changes.last().unwrap();
+            if tick == last.tick {
+                changes.last_mut().unwrap().us_per_qn = us_per_qn;
+            } else {
+                let t_us = last.t_us + (tick - last.tick) as f64 * last.us_per_qn / ppq.unwrap_or(1.0);
+                changes.push(TempoChange { tick, t_us, us_per_qn });
+            }
+        }
+        Self { ppq, ticks_per_sec, changes }
+    }
+
+    /// Absolute time of `tick`, in microseconds.
+    pub fn to_us(&self, tick: u64) -> u64 {
+        let Some(ppq) = self.ppq else {
+            return (tick as f64 * 1_000_000.0 / self.ticks_per_sec) as u64;
+        };
+        let i = self.changes.partition_point(|c| c.tick <= tick) - 1;
+        let c = &self.changes[i];
+        (c.t_us + (tick - c.tick) as f64 * c.us_per_qn / ppq) as u64
+    }
+
+    /// The tick playing at `t_us`, the inverse of [`to_us`](Self::to_us).
+    pub fn to_tick(&self, t_us: u64) -> u64 {
+        let t_us = t_us as f64;
+        let Some(ppq) = self.ppq else {
+            return (t_us * self.ticks_per_sec / 1_000_000.0) as u64;
+        };
+        let i = self.changes.partition_point(|c| c.t_us <= t_us) - 1;
+        let c = &self.changes[i];
+        c.tick + ((t_us - c.t_us) * ppq / c.us_per_qn) as u64
+    }
+
+    /// The tempo segments, starting with the tempo in force at tick 0. Empty for SMPTE timing.
+    pub fn changes(&self) -> &[TempoChange] {
+        if self.ppq.is_some() { &self.changes } else { &[] }
+    }
+}
+
+/// The length of the whole file, in microseconds: up to its last End of Track through the
+/// tempo map, or for format 2 files each pattern's length through its own, added up.
+pub fn length_us(smf: &Smf) -> u64 {
+    let end_tick = |track: &Track| track.iter().map(|ev| u64::from(ev.delta.as_int())).sum::<u64>();
+    if smf.header.format == Format::Sequential {
+        let patterns = smf.tracks.iter().enumerate();
+        patterns.map(|(n, track)| TempoMap::of_track(smf, n).to_us(end_tick(track))).sum()
+    } else {
+        TempoMap::new(smf).to_us(smf.tracks.iter().map(end_tick).max().unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u15, u24, u28};
+    use midly::{Fps, Header, TrackEvent};
+
+    /// A file of `ppq` ticks a quarter note with tempo changes at the given ticks, split
+    /// across two tracks as a format 1 file may.
+    fn smf(ppq: u16, tempos: &[(u32, u32)]) -> Smf<'static> {
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(u15::new(ppq))));
+        let mut tracks = [Vec::new(), Vec::new()];
+        let mut last = [0; 2];
+        for (n, &(tick, us_per_qn)) in tempos.iter().enumerate() {
+            let delta = u28::new(tick - last[n % 2]);
+            last[n % 2] = tick;
+            let kind = TrackEventKind::Meta(MetaMessage::Tempo(u24::new(us_per_qn)));
+            tracks[n % 2].push(TrackEvent { delta, kind });
+        }
+        smf.tracks.extend(tracks);
+        smf
+    }
+
+    #[test]
+    fn defaults_to_120_bpm() {
+        let map = TempoMap::new(&smf(480, &[]));
+        assert_eq!(map.to_us(480), 500_000);
+        assert_eq!(map.to_tick(1_000_000), 960);
+    }
+
+    #[test]
+    fn follows_changes_in_any_track() {
+        // A millisecond a tick, then half that from tick 1000, then two from 1500.
+        let map = TempoMap::new(&smf(500, &[(0, 500_000), (1000, 250_000), (1500, 1_000_000)]));
+        let points = [(0, 0), (1000, 1_000_000), (1200, 1_100_000), (1500, 1_250_000), (1600, 1_450_000)];
+        for (tick, t_us) in points {
+            assert_eq!(map.to_us(tick), t_us);
+            assert_eq!(map.to_tick(t_us), tick);
+        }
+        assert_eq!(map.changes().len(), 3);
+    }
+
+    #[test]
+    fn takes_the_last_of_simultaneous_changes() {
+        let map = TempoMap::new(&smf(500, &[(0, 500_000), (0, 250_000)]));
+        assert_eq!(map.to_us(1000), 500_000);
+        assert_eq!(map.changes().len(), 1);
+    }
+
+    #[test]
+    fn round_trips_within_a_tick() {
+        let map = TempoMap::new(&smf(480, &[(0, 461_538), (1234, 652_174), (5000, 333_333)]));
+        for tick in (0..10_000).step_by(7) {
+            assert!(map.to_tick(map.to_us(tick)).abs_diff(tick) <= 1, "tick {tick}");
+        }
+        for t_us in (0..5_000_000).step_by(3_331) {
+            assert!(map.to_us(map.to_tick(t_us)) <= t_us, "{t_us} µs");
+        }
+    }
+
+    #[test]
+    fn ignores_tempo_with_smpte_timing() {
+        let mut smf = smf(480, &[(0, 250_000)]);
+        // 25 frames a second of 40 ticks: a millisecond a tick.
+        smf.header.timing = Timing::Timecode(Fps::Fps25, 40);
+        let map = TempoMap::new(&smf);
+        assert_eq!(map.to_us(1500), 1_500_000);
+        assert_eq!(map.to_tick(1_500_000), 1500);
+        assert!(map.changes().is_empty());
+    }
+
+    #[test]
+    fn measures_format_2_patterns_one_after_another() {
+        let mut smf = smf(500, &[(1000, 250_000), (1000, 1_000_000)]);
+        smf.header.format = Format::Sequential;
+        let end = TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) };
+        smf.tracks[0].push(TrackEvent { delta: u28::new(1000), ..end });
+        smf.tracks[1].push(end);
+        // Each pattern at its own tempo: 1000 ms and 500 ms, then 1000 ms.
+        assert_eq!(length_us(&smf), 1_500_000 + 1_000_000);
+    }
+}
@@ -0,0 +1,77 @@
+//! Song positions: parsing from the command line and control channel, and display.
+
+use crate::bars::BarMap;
+
+/// Format a position as mm:ss.
+pub fn format_duration(us: u64) -> String {
+    let total_secs = us / 1_000_000;
+    let mins = total_secs / 60;
+    let secs = total_secs % 60;
+    format!("{:02}:{:02}", mins, secs)
+}
+
+/// A song position given as a time, a percentage of the song length, or a bar and beat.
+#[derive(Clone, Copy, Debug)]
+pub enum Position {
+    /// Microseconds from the start.
+    Time(u64),
+    /// Percent of the song, 0–100.
+    Percent(f64),
+    /// Bar and beat, both counted from 1.
+    Bar(u64, u32),
+}
+
+impl Position {
+    /// The position in microseconds for a song of `song_us` microseconds with `bars`, or
+    /// `None` for a bar the song does not count or a beat its bar does not have.
+    pub fn resolve(self, song_us: u64, bars: Option<&BarMap>) -> Option<u64> {
+        match self {
+            Position::Time(t) => Some(t),
+            Position::Percent(pct) => Some((song_us as f64 * pct / 100.0).round() as u64),
+            Position::Bar(bar, beat) => bars?.to_us(bar, beat),
+        }
+    }
+}
+
+/// clap value parser for positions, see `parse_position`.
+pub fn parse_position_arg(s: &str) -> Result<Position, String> {
+    parse_position(s)
+        .ok_or_else(|| format!("invalid position `{s}`, expected seconds, mm:ss, a percentage or a bar such as b17:1"))
+}
+
+/// Parse a position: a time as for `parse_time`, a percentage such as `35%`, or a bar and
+/// beat such as `b17` or `b17:3`. The `b` tells a bar from a time in minutes.
+pub fn parse_position(s: &str) -> Option<Position> {
+    let s = s.trim();
+    if let Some(bar) = s.strip_prefix('b') {
+        let (bar, beat) = bar.split_once(':').unwrap_or((bar, "1"));
+        let (bar, beat) = (bar.parse().ok()?, beat.parse().ok()?);
+        return (bar > 0 && beat > 0).then_some(Position::Bar(bar, beat));
+    }
+    match s.strip_suffix('%') {
+        Some(pct) => {
+            let pct: f64 = pct.trim().parse().ok()?;
+            (0.0..=100.0).contains(&pct).then_some(Position::Percent(pct))
+        }
+        None => parse_time(s).map(Position::Time),
+    }
+}
+
+/// clap value parser for time arguments, see `parse_time`.
+pub fn parse_time_arg(s: &str) -> Result<u64, String> {
+    parse_time(s).ok_or_else(|| format!("invalid time `{s}`, expected seconds or mm:ss"))
+}
+
+/// Parse a song position such as `90`, `12.5`, `1:30` or `1:02:03.5` into microseconds.
+pub fn parse_time(s: &str) -> Option<u64> {
+    let mut secs = 0.0;
+    for (n, part) in s.trim().split(':').enumerate() {
+        let v: f64 = part.parse().ok()?;
+        // Only the first field may exceed 59 (e.g. `90` or `75:00`).
+        if !v.is_finite() || v < 0.0 || (n > 0 && v >= 60.0) || n > 2 {
+            return None;
+        }
+        secs = secs * 60.0 + v;
+    }
+    Some((secs * 1_000_000.0).round() as u64)
+}
@@ -0,0 +1,170 @@
+//! Timing of the audio callbacks, to tell where crackles come from: how long each callback
+//! took against how long its buffer plays, and the underruns seen. Summed up after
+//! playback, and warned about as they happen with `--xrun-warnings`.
+//!
+//! The callback only adds to atomic counters, and never logs: the output thread looks at
+//! them a few times a second and does the warning.
+
+use crate::output;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// A callback this much later than the previous buffer lasted means the device ran dry.
+const GAP_FACTOR: f64 = 1.5;
+
+/// What the callbacks of one run measured.
+#[derive(Default)]
+pub struct Stats {
+    callbacks: AtomicU64,
+    busy_ns: AtomicU64,
+    max_busy_ns: AtomicU64,
+    /// Callbacks that took longer than their buffer plays, so the next one starts late.
+    late: AtomicU64,
+    /// Callbacks that came so long after the previous one that the device must have run out.
+    gaps: AtomicU64,
+    /// Underruns the backend reported itself.
+    xruns: AtomicU64,
+    /// Buffers the synth failed to render.
+    failed: AtomicU64,
+    /// The buffer length of the last callback.
+    buffer_ns: AtomicU64,
+}
+
+/// A stream's own timing of its callbacks, which it owns: the gap to the first callback of
+/// a new stream says nothing.
+pub struct Timer {
+    last_callback: Option<cpal::StreamInstant>,
+}
+
+/// The counts already warned about.
+#[derive(Default)]
+pub struct Seen {
+    late: u64,
+    gaps: u64,
+    xruns: u64,
+    failed: u64,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self { last_callback: None }
+    }
+
+    /// Record in `stats` one callback that took `busy` to fill `frames` frames at
+    /// `sample_rate`.
+    pub fn callback(
+        &mut self,
+        stats: &Stats,
+        frames: usize,
+        sample_rate: u32,
+        busy: Duration,
+        info: &cpal::OutputCallbackInfo,
+    ) {
+        let buffer = Duration::from_secs_f64(frames as f64 / f64::from(sample_rate));
+        stats.callbacks.fetch_add(1, Ordering::Relaxed);
+        stats.busy_ns.fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+        stats.max_busy_ns.fetch_max(busy.as_nanos() as u64, Ordering::Relaxed);
+        if busy > buffer {
+            stats.late.fetch_add(1, Ordering::Relaxed);
+        }
+        let now = info.timestamp().callback;
+        let last_buffer = Duration::from_nanos(stats.buffer_ns.load(Ordering::Relaxed));
+        if let Some(gap) = self.last_callback.and_then(|last| now.duration_since(&last))
+            && gap.as_secs_f64() > last_buffer.as_secs_f64() * GAP_FACTOR
+        {
+            stats.gaps.fetch_add(1, Ordering::Relaxed);
+        }
+        self.last_callback = Some(now);
+        stats.buffer_ns.store(buffer.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Stats {
+    /// Count an underrun (xrun) the backend reported.
+    pub fn xrun(&self) {
+        self.xruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a buffer the synth failed to render.
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Warn about what went wrong since the counts in `seen`: failed buffers always, late
+    /// callbacks and underruns only if `xruns` is set.
+    pub fn warn_new(&self, seen: &mut Seen, xruns: bool) {
+        let new = |count: &AtomicU64, seen: &mut u64| {
+            let count = count.load(Ordering::Relaxed);
+            count - std::mem::replace(seen, count)
+        };
+        let failed = new(&self.failed, &mut seen.failed);
+        if failed > 0 {
+            error!("The synth failed to render {failed} audio buffer(s)");
+        }
+        let (late, gaps, reported) =
+            (new(&self.late, &mut seen.late), new(&self.gaps, &mut seen.gaps), new(&self.xruns, &mut seen.xruns));
+        if !xruns {
+            return;
+        }
+        let buffer = ms(Duration::from_nanos(self.buffer_ns.load(Ordering::Relaxed)));
+        if late > 0 {
+            let slowest = ms(Duration::from_nanos(self.max_busy_ns.load(Ordering::Relaxed)));
+            warn!("{late} audio callback(s) took longer than their {buffer:.1} ms buffer (slowest yet {slowest:.1} ms)");
+        }
+        if gaps > 0 {
+            warn!("Underrun: {gaps} gap(s) between callbacks longer than a {buffer:.1} ms buffer");
+        }
+        if reported > 0 {
+            warn!("Underrun reported by the audio backend ({reported})");
+        }
+    }
+
+    /// Print the summary, with the likely cause if there were underruns, and send it as an
+    /// `audio_stats` event.
+    pub fn report(&self) {
+        let load = |count: &AtomicU64| count.load(Ordering::Relaxed);
+        let callbacks = load(&self.callbacks);
+        if callbacks == 0 {
+            return;
+        }
+        let buffer = Duration::from_nanos(load(&self.buffer_ns));
+        let max_busy = Duration::from_nanos(load(&self.max_busy_ns));
+        let mean_busy = Duration::from_nanos(load(&self.busy_ns) / callbacks);
+        let (late, gaps, xruns) = (load(&self.late), load(&self.gaps), load(&self.xruns));
+        let cpu = ms(mean_busy) / ms(buffer).max(f64::EPSILON) * 100.0;
+        output::event(
+            "audio_stats",
+            [
+                ("callbacks", callbacks.into()),
+                ("buffer_ms", ms(buffer).into()),
+                ("mean_busy_ms", ms(mean_busy).into()),
+                ("max_busy_ms", ms(max_busy).into()),
+                ("late", late.into()),
+                ("gaps", gaps.into()),
+                ("xruns", xruns.into()),
+            ],
+        );
+        let summary = format!(
+            "{callbacks} callbacks of {:.1} ms, {cpu:.0}% load on average, slowest {:.1} ms",
+            ms(buffer),
+            ms(max_busy)
+        );
+        if late + gaps + xruns == 0 {
+            debug!("Audio: {summary}; no underruns");
+            return;
+        }
+        info!("Audio: {summary}; {late} late callbacks, {gaps} gaps, {xruns} underruns reported by the backend");
+        // Late callbacks point at the CPU, gaps alone at the buffer size.
+        if late > 0 {
+            warn!("Rendering takes longer than the buffer plays: the CPU cannot keep up; try a larger --buffer-size");
+        } else {
+            warn!("Callbacks were on time but came too late: the buffer is too small; try a larger --buffer-size");
+        }
+    }
+}
+
+/// Milliseconds, to the microsecond.
+fn ms(d: Duration) -> f64 {
+    (d.as_secs_f64() * 1e6).round() / 1000.0
+}
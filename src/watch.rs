@@ -0,0 +1,52 @@
+//! `--watch`: notice when the MIDI file or SoundFont changes on disk.
+
+use crate::controls::Command;
+use std::{
+    fs,
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How often the watched files are checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What identifies a version of a file: its modification time and size.
+pub type Stamp = Option<(SystemTime, u64)>;
+
+/// The file's current stamp, or `None` while it does not exist (e.g. mid-save).
+pub fn stamp(path: &str) -> Stamp {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Poll `paths` on a background thread and send `Command::Reload` each time one of them
+/// has changed. Programs often save in several writes, so a change only counts once the
+/// file has stayed the same for a whole poll interval. The thread ends when the conductor
+/// side has gone away.
+pub fn spawn_watcher(paths: Vec<String>, commands: Sender<Command>) {
+    thread::spawn(move || {
+        let mut seen: Vec<Stamp> = paths.iter().map(|p| stamp(p)).collect();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let now: Vec<Stamp> = paths.iter().map(|p| stamp(p)).collect();
+            if now == seen {
+                continue;
+            }
+            // Wait for the writes to settle before reloading.
+            let mut settled = now;
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                let again: Vec<Stamp> = paths.iter().map(|p| stamp(p)).collect();
+                if again == settled && again.iter().all(Option::is_some) {
+                    break;
+                }
+                settled = again;
+            }
+            seen = settled;
+            if commands.send(Command::Reload).is_err() {
+                break;
+            }
+        }
+    });
+}